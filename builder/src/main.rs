@@ -21,14 +21,21 @@ static TEST_FILES: &[&str] = &[
     "./stuff/asmgen/array/sem.cm",
     "./stuff/asmgen/array/cmp.cm",
     "./stuff/asmgen/array/shift.cm",
+    "./stuff/asmgen/array/bounds.cm",
     "./stuff/asmgen/while/cmp.cm",
     "./stuff/asmgen/bool/print.cm",
     "./stuff/asmgen/call/call_obj.cm",
     "./stuff/asmgen/call/call.cm",
     "./stuff/asmgen/enum/two.cm",
+    "./stuff/asmgen/match/pats.cm",
+    "./stuff/asmgen/match/table.cm",
+    "./stuff/asmgen/match/destructure.cm",
     "./stuff/asmgen/gen/gen.cm",
     "./stuff/types/string/string.cm",
     "./stuff/asmgen/ifs/simp.cm",
+    "./stuff/asmgen/ifs/value.cm",
+    "./stuff/asmgen/const/eval.cm",
+    "./stuff/asmgen/const/generic.cm",
     "./stuff/asmgen/while/bubble.cm",
     "./stuff/asmgen/while/sort.cm",
     "./stuff/asmgen/args/args.cm",
@@ -36,12 +43,35 @@ static TEST_FILES: &[&str] = &[
     "./stuff/types/func/fnptr.cm",
     "./stuff/asmgen/asm/assert.cm",
     "./stuff/asmgen/asm/asm.cm",
+    "./stuff/asmgen/asm/operands.cm",
+    "./stuff/asmgen/asm/expr.cm",
+    "./stuff/asmgen/regalloc/regalloc.cm",
     "./stuff/asmgen/float/floats.cm",
     "./stuff/assert/assert.cm",
     "./stuff/types/size_of/size.cm",
+    "./stuff/asmgen/int/sized.cm",
+    "./stuff/asmgen/cast/cast.cm",
+    "./stuff/asmgen/builtin/location.cm",
+    "./stuff/asmgen/builtin/reflect.cm",
+    "./stuff/asmgen/defer/defer.cm",
+    "./stuff/asmgen/try/try.cm",
+    "./stuff/asmgen/trait/add.cm",
+    "./stuff/asmgen/trait/hard.cm",
+    "./stuff/asmgen/alloc/alloc.cm",
+    "./stuff/types/dynarr/dynarr.cm",
+    "./stuff/asmgen/collections/vec.cm",
+    "./stuff/asmgen/collections/map.cm",
+    "./stuff/asmgen/abi/abi.cm",
     // "./stuff/types/dynarr/field_ptr.cm", // field that is pointer
 ];
 
+/// Test files built with `--overflow-checks`.
+static CHECKED_TEST_FILES: &[&str] =
+    &["./stuff/asmgen/int/overflow.cm", "./stuff/asmgen/collections/map.cm"];
+
+/// Test files built with `--freestanding` and linked without libc.
+static FREESTANDING_TEST_FILES: &[&str] = &["./stuff/asmgen/freestanding/sys.cm"];
+
 const ENUMC_DEBUG: &str = "./target/debug/enumc";
 // const ENUMC_RELEASE: &str = "./target/release/enumc";
 
@@ -64,9 +94,16 @@ fn main() {
                 std::process::exit(1);
             }
         }
+        ["obj" | "o", more @ ..] => {
+            cmd!("cargo b").run().unwrap();
+            if let Err(e) = build_files(more, "-s --emit obj") {
+                writeln_red("Error: ", &e.to_string()).unwrap();
+                std::process::exit(1);
+            }
+        }
         ["asm" | "a", more @ ..] => {
             for file in more {
-                if let Err(e) = build_run_asm(Path::new(file)) {
+                if let Err(e) = build_run_asm(Path::new(file), false, false) {
                     writeln_red("Error: ", &e.to_string()).unwrap();
                     std::process::exit(1);
                 }
@@ -104,12 +141,42 @@ fn main() {
                 writeln_red("Error: ", &e.to_string()).unwrap();
                 std::process::exit(1);
             }
+            if let Err(e) = build_files(CHECKED_TEST_FILES, "-asc") {
+                writeln_red("Error: ", &e.to_string()).unwrap();
+                std::process::exit(1);
+            }
+            if let Err(e) = build_files(FREESTANDING_TEST_FILES, "-asf") {
+                writeln_red("Error: ", &e.to_string()).unwrap();
+                std::process::exit(1);
+            }
+
+            // The same programs written as object files without an assembler
+            if let Err(e) = build_files(TEST_FILES, "-s --emit obj") {
+                writeln_red("Error: ", &e.to_string()).unwrap();
+                std::process::exit(1);
+            }
+            if let Err(e) = build_files(CHECKED_TEST_FILES, "-sc --emit obj") {
+                writeln_red("Error: ", &e.to_string()).unwrap();
+                std::process::exit(1);
+            }
+            if let Err(e) = build_files(FREESTANDING_TEST_FILES, "-sf --emit obj") {
+                writeln_red("Error: ", &e.to_string()).unwrap();
+                std::process::exit(1);
+            }
         }
         [..] => {}
     }
 }
 
-fn build_run_asm(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+/// Link and run the assembly built for `path`, or the object file if `obj`, a `freestanding`
+/// program is linked without libc.
+///
+/// A C file next to `path` with the same name is compiled and linked into the program.
+fn build_run_asm(
+    path: &Path,
+    freestanding: bool,
+    obj: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
     let mut build_dir = path.to_path_buf();
     let file = build_dir
         .file_name()
@@ -120,12 +187,21 @@ fn build_run_asm(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
     build_dir.push(file);
 
     let mut asm = build_dir.clone();
-    asm.set_extension("s");
+    asm.set_extension(if obj { "o" } else { "s" });
 
     let mut out = build_dir.clone();
     out.set_extension("");
 
-    cmd!("gcc -no-pie {asm} -o {out}").run()?;
+    let mut c_file = path.to_path_buf();
+    c_file.set_extension("c");
+
+    if freestanding {
+        cmd!("gcc -nostdlib -static {asm} -o {out}").run()?;
+    } else if c_file.exists() {
+        cmd!("cc -no-pie {asm} {c_file} -o {out}").run()?;
+    } else {
+        cmd!("gcc -no-pie {asm} -o {out}").run()?;
+    }
     if cmd!("{out}").read_stderr()?.contains("SEG") {
         return Err("SIGSEGV assembly crashed with segmentation fault".into());
     };
@@ -142,11 +218,13 @@ fn build_files(files: &[&str], args: &str) -> Result<(), Box<dyn std::error::Err
         if args.is_empty() {
             cmd!("{ENUMC_DEBUG} -i {path}").run()?;
         } else {
-            cmd!("{ENUMC_DEBUG} {args} -i {path}").run()?;
+            let args = args.split_whitespace();
+            cmd!("{ENUMC_DEBUG} {args...} -i {path}").run()?;
         }
 
-        if args.contains('a') {
-            build_run_asm(&path)?;
+        let obj = args.contains("--emit obj");
+        if args.contains('a') || obj {
+            build_run_asm(&path, args.contains('f'), obj)?;
         }
     }
     Ok(())
@@ -213,7 +291,7 @@ fn ui_test_run(files: &[&str], args: &str) -> Result<(), Box<dyn std::error::Err
         }
 
         if args.contains('a') {
-            build_run_asm(&path)?;
+            build_run_asm(&path, args.contains('f'), false)?;
         }
     }
     Ok(())
@@ -1,3 +1,4 @@
 crate mod lex;
+crate mod mnemonic;
 crate mod parse;
 crate mod types;
@@ -0,0 +1,217 @@
+use std::fmt;
+
+use crate::ast::types::{Location, Val};
+
+use Operand::*;
+
+/// The kind of an operand in an `asm` block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    /// A general purpose register `%rax`.
+    Reg,
+    /// A float register `%xmm0`.
+    Float,
+    /// A variable in memory `(x)`.
+    Mem,
+    /// An integer or string constant `10`.
+    Imm,
+}
+
+impl Operand {
+    /// The kind of `loc`, a float constant can not be encoded in an instruction.
+    crate fn of(loc: &Location) -> Option<Operand> {
+        Some(match loc {
+            Location::Register(_) => Reg,
+            Location::FloatReg(_) => Float,
+            Location::InlineVar(_) | Location::NamedOffset(_) | Location::Offset { .. } => {
+                Mem
+            }
+            Location::Const(Val::Float(_)) => return None,
+            Location::Const(_) => Imm,
+        })
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reg => "reg".fmt(f),
+            Float => "xmm".fmt(f),
+            Mem => "mem".fmt(f),
+            Imm => "imm".fmt(f),
+        }
+    }
+}
+
+/// An instruction `asm` blocks can use and each set of operands it can be written with, the
+/// operands are in AT&T order `src, dst`.
+pub struct Mnemonic {
+    pub name: &'static str,
+    /// The instruction takes a size suffix `addq`.
+    pub sized: bool,
+    pub forms: &'static [&'static [&'static [Operand]]],
+    /// The names of the registers the instruction reads or writes without naming them
+    /// `mul %rcx` writes `%rdx:%rax`.
+    pub implicit: &'static [&'static str],
+}
+
+impl Mnemonic {
+    /// Find the instruction `name`, a `b`, `w`, `l` or `q` suffix is allowed when it is sized.
+    crate fn lookup(name: &str) -> Option<&'static Mnemonic> {
+        MNEMONICS.iter().find(|m| m.name == name).or_else(|| {
+            let base = name.strip_suffix(&['b', 'w', 'l', 'q'][..])?;
+            MNEMONICS.iter().find(|m| m.sized && m.name == base)
+        })
+    }
+
+    /// The registers the instruction uses without naming them when it is written with
+    /// `operands`, only a form with at most one operand has any `imul %rcx, %rax` does not.
+    crate fn implicit(&self, operands: usize) -> &'static [&'static str] {
+        if operands <= 1 {
+            self.implicit
+        } else {
+            &[]
+        }
+    }
+
+    /// The operands match one of the forms of this instruction.
+    crate fn accepts(&self, ops: &[Operand]) -> bool {
+        self.forms.iter().any(|form| {
+            form.len() == ops.len() && form.iter().zip(ops).all(|(kinds, op)| kinds.contains(op))
+        })
+    }
+
+    /// Each form this instruction can be written with `add reg|mem|imm, reg|mem`.
+    crate fn describe(&self) -> String {
+        self.forms
+            .iter()
+            .map(|form| {
+                let ops = form
+                    .iter()
+                    .map(|kinds| kinds.iter().map(|k| k.to_string()).collect::<Vec<_>>().join("|"))
+                    .collect::<Vec<_>>();
+                if ops.is_empty() {
+                    format!("`{}`", self.name)
+                } else {
+                    format!("`{} {}`", self.name, ops.join(", "))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" or ")
+    }
+}
+
+const R: &[Operand] = &[Reg];
+const RM: &[Operand] = &[Reg, Mem];
+const RMI: &[Operand] = &[Reg, Mem, Imm];
+const RI: &[Operand] = &[Reg, Imm];
+const M: &[Operand] = &[Mem];
+const I: &[Operand] = &[Imm];
+const X: &[Operand] = &[Float];
+const XM: &[Operand] = &[Float, Mem];
+
+const NONE: &[&[Operand]] = &[];
+/// `add src, dst`
+const BINARY: &[&[&[Operand]]] = &[&[RMI, RM]];
+/// `inc dst`
+const UNARY: &[&[&[Operand]]] = &[&[RM]];
+/// `addsd src, %xmm`
+const FLOAT_BINARY: &[&[&[Operand]]] = &[&[XM, X]];
+/// `movsd` loads or stores a float register.
+const FLOAT_MOV: &[&[&[Operand]]] = &[&[XM, X], &[X, M]];
+/// `shl 2, dst` or `shl dst` shifts by one.
+const SHIFT: &[&[&[Operand]]] = &[&[I, RM], &[RM]];
+/// `cmove src, %reg`
+const CMOV: &[&[&[Operand]]] = &[&[RM, R]];
+
+/// `%rdx:%rax` the registers of a wide multiply or divide.
+const RAX_RDX: &[&str] = &["%rax", "%rdx"];
+
+macro_rules! mnemonics {
+    (@implicit) => { &[] };
+    (@implicit $regs:expr) => { $regs };
+    ($($name:literal $sized:literal => $forms:expr $(; $implicit:expr)?,)*) => {
+        &[$(Mnemonic {
+            name: $name,
+            sized: $sized,
+            forms: $forms,
+            implicit: mnemonics!(@implicit $($implicit)?),
+        },)*]
+    };
+}
+
+/// The x86-64 instructions an `asm` block is checked against.
+static MNEMONICS: &[Mnemonic] = mnemonics! {
+    "mov" true => &[&[RMI, RM], &[X, RM], &[RM, X]],
+    "movabs" true => &[&[I, R]],
+    "lea" true => &[&[M, R]],
+    "push" true => &[&[RMI]],
+    "pop" true => &[&[RM]],
+    "xchg" true => &[&[R, RM], &[RM, R]],
+    "add" true => BINARY,
+    "adc" true => BINARY,
+    "sub" true => BINARY,
+    "sbb" true => BINARY,
+    "and" true => BINARY,
+    "or" true => BINARY,
+    "xor" true => BINARY,
+    "cmp" true => BINARY,
+    "test" true => &[&[RI, RM]],
+    "imul" true => &[&[RM], &[RM, R]]; RAX_RDX,
+    "mul" true => UNARY; RAX_RDX,
+    "div" true => UNARY; RAX_RDX,
+    "idiv" true => UNARY; RAX_RDX,
+    "inc" true => UNARY,
+    "dec" true => UNARY,
+    "neg" true => UNARY,
+    "not" true => UNARY,
+    "shl" true => SHIFT,
+    "sal" true => SHIFT,
+    "shr" true => SHIFT,
+    "sar" true => SHIFT,
+    "rol" true => SHIFT,
+    "ror" true => SHIFT,
+    "cmove" true => CMOV,
+    "cmovne" true => CMOV,
+    "cmovl" true => CMOV,
+    "cmovle" true => CMOV,
+    "cmovg" true => CMOV,
+    "cmovge" true => CMOV,
+    "cmovb" true => CMOV,
+    "cmovbe" true => CMOV,
+    "cmova" true => CMOV,
+    "cmovae" true => CMOV,
+    "movsd" false => FLOAT_MOV,
+    "movss" false => FLOAT_MOV,
+    "addsd" false => FLOAT_BINARY,
+    "subsd" false => FLOAT_BINARY,
+    "mulsd" false => FLOAT_BINARY,
+    "divsd" false => FLOAT_BINARY,
+    "sqrtsd" false => FLOAT_BINARY,
+    "minsd" false => FLOAT_BINARY,
+    "maxsd" false => FLOAT_BINARY,
+    "ucomisd" false => FLOAT_BINARY,
+    "comisd" false => FLOAT_BINARY,
+    "xorpd" false => FLOAT_BINARY,
+    "pxor" false => FLOAT_BINARY,
+    "cvtss2sd" false => FLOAT_BINARY,
+    "cvtsd2ss" false => FLOAT_BINARY,
+    "cvtsi2sd" true => &[&[RM, X]],
+    "cvtsd2si" true => &[&[XM, R]],
+    "cvttsd2si" true => &[&[XM, R]],
+    "int" false => &[&[I]],
+    "syscall" false => &[NONE],
+    "ret" false => &[NONE],
+    "leave" false => &[NONE],
+    "nop" false => &[NONE],
+    "ud2" false => &[NONE],
+    "hlt" false => &[NONE],
+    "pause" false => &[NONE],
+    "cqto" false => &[NONE]; RAX_RDX,
+    "cqo" false => &[NONE]; RAX_RDX,
+    "cltq" false => &[NONE]; &["%rax"],
+    "cpuid" false => &[NONE]; &["%rax", "%rbx", "%rcx", "%rdx"],
+    "rdtsc" false => &[NONE]; RAX_RDX,
+    "mfence" false => &[NONE],
+    "int3" false => &[NONE],
+};
@@ -1,10 +1,13 @@
 use std::{
     convert::TryInto,
     future::Future,
+    path::PathBuf,
     pin::Pin,
     sync::mpsc::{channel, Receiver, Sender},
 };
 
+use rustc_hash::FxHashSet as HashSet;
+
 use crate::{
     ast::{
         lex::{self, Base, LiteralKind, Token, TokenKind, TokenMatch},
@@ -36,7 +39,8 @@ pub struct ParsedBlob {
     pub file: &'static str,
     pub input: &'static str,
     pub count: usize,
-    pub decl: ast::Declaration,
+    /// `None` when the file was already sent or has no items, the import still names the file.
+    pub decl: Option<ast::Declaration>,
 }
 // TODO: this is basically one file = one mod/crate/program unit add mod linking or
 // whatever.
@@ -59,6 +63,89 @@ pub struct AstBuilder<'a> {
     stack_idx: usize,
 
     snd: Option<AstSender>,
+    /// The directory every `import ::std::item;` is parsed from.
+    stdlib: PathBuf,
+}
+
+/// A file that was imported, parsed but not sent to the type checker yet.
+struct ImportedFile {
+    file: &'static str,
+    input: &'static str,
+    items: Vec<ast::Declaration>,
+}
+
+/// The file `import path;` in the file `from` is parsed from.
+///
+/// `::std::sys` is the module `sys.cm` of the `stdlib` directory, any other `::std::item` is in its
+/// `lib.cm`. Every other import is a file next to `from`, `::foo::item` is `foo.cm`.
+fn import_file(path: &Path, from: &str, stdlib: &std::path::Path) -> String {
+    if path.segs[0] == "std" {
+        let module = path.segs.get(1).map(|m| stdlib.join(format!("{}.cm", m)));
+        return module
+            .filter(|m| m.is_file())
+            .unwrap_or_else(|| stdlib.join("lib.cm"))
+            .to_string_lossy()
+            .to_string();
+    }
+    let mut p = PathBuf::from(from);
+    p.pop();
+    p.push(path.segs[0].name());
+    p.set_extension("cm");
+    p.to_string_lossy().to_string()
+}
+
+/// Parse the files imported by `items` into `files`, a file that can't be read or parsed is kept
+/// as the error.
+///
+/// The type checker visits an import as soon as it finds it, so a file comes right before the
+/// files it imports. A file is only parsed the first time it is imported, later imports of it only
+/// name the file.
+fn parse_imports(
+    items: &mut Vec<ast::Declaration>,
+    from: &str,
+    stdlib: &std::path::Path,
+    snd: &AstSender,
+    loaded: &mut HashSet<String>,
+    files: &mut Vec<ParseResult<ImportedFile>>,
+) {
+    let mut idx = 0;
+    while idx < items.len() {
+        let (file, span) = match &items[idx].val {
+            ast::Decl::Import(path) => (import_file(path, from, stdlib), items[idx].span),
+            _ => {
+                idx += 1;
+                continue;
+            }
+        };
+        idx += 1;
+        if !loaded.insert(file.clone()) {
+            files.push(Ok(ImportedFile { file: Box::leak(box file), input: "", items: vec![] }));
+            continue;
+        }
+
+        let input = match std::fs::read_to_string(&file) {
+            Ok(input) => input,
+            Err(_) => {
+                files.push(Err(ParseError::Error("invalid file name", span)));
+                continue;
+            }
+        };
+        let file = Box::leak(box file);
+        let input = Box::leak(box input);
+        let mut parser = AstBuilder::new(input, file, snd.clone());
+        if let Err(err) = parser.parse_file() {
+            files.push(Err(err));
+            continue;
+        }
+
+        let mut imported = parser.into_items();
+        let at = files.len();
+        files.push(Ok(ImportedFile { file, input, items: vec![] }));
+        parse_imports(&mut imported, file, stdlib, snd, loaded, files);
+        if let Ok(f) = &mut files[at] {
+            f.items = imported;
+        }
+    }
 }
 
 // FIXME: audit the whitespace eating, pretty sure I call it unnecessarily
@@ -78,6 +165,11 @@ impl<'a> AstBuilder<'a> {
         }
     }
 
+    /// Read `import ::std::item;` from the directory `stdlib`.
+    pub fn set_stdlib(&mut self, stdlib: PathBuf) {
+        self.stdlib = stdlib;
+    }
+
     pub fn items(&self) -> &[ast::Declaration] {
         &self.items
     }
@@ -90,7 +182,41 @@ impl<'a> AstBuilder<'a> {
         self.call_stack.push(s)
     }
 
+    /// Parse the file then the files it imports, the items of an imported file are sent to the
+    /// type checker.
     pub fn parse(&mut self) -> ParseResult<()> {
+        self.parse_file()?;
+
+        // This is always valid it's just so AstBuilder can impl Default
+        let snd = self.snd.as_ref().unwrap();
+        let mut loaded = HashSet::default();
+        loaded.insert(self.file.to_owned());
+        let mut files = vec![];
+        parse_imports(&mut self.items, self.file, &self.stdlib, snd, &mut loaded, &mut files);
+
+        // Each import receives its file's items or the error, in the order the type checker visits
+        // them
+        for imported in files {
+            let ImportedFile { file, input, items } = match imported {
+                Ok(f) => f,
+                Err(err) => {
+                    let _ = snd.send(Err(err));
+                    continue;
+                }
+            };
+            if items.is_empty() {
+                let _ = snd.send(Ok(ParsedBlob { file, input, count: 0, decl: None }));
+            }
+            let mut cnt = items.len();
+            for item in items {
+                cnt -= 1;
+                let _ = snd.send(Ok(ParsedBlob { file, input, count: cnt, decl: Some(item) }));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_file(&mut self) -> ParseResult<()> {
         loop {
             if self.curr.kind == TokenKind::Eof {
                 break;
@@ -136,53 +262,7 @@ impl<'a> AstBuilder<'a> {
                             self.items.push(item);
                         }
                         kw::Import => {
-                            let start = self.curr_span();
                             let item = self.parse_import()?;
-
-                            if let ast::Decl::Import(path) = &item.val {
-                                // TODO: handle ::foo::bar::item; not just ::foo::item;
-                                let path = path.segs[0];
-                                let mut p = std::path::PathBuf::from(self.file);
-
-                                // This is always valid it's just so AstBuilder can impl Default
-                                let snd = self.snd.as_ref().unwrap().clone();
-                                std::thread::spawn(move || {
-                                    p.pop();
-                                    p.push(path.name());
-                                    p.set_extension("cm");
-
-                                    let s = p.to_string_lossy().to_string();
-                                    let input = std::fs::read_to_string(&s).map_err(|e| {
-                                        ParseError::Error("invalid file name", start)
-                                    })?;
-
-                                    let mut parser = AstBuilder::new(&input, &s, snd.clone());
-                                    if let Err(err) = parser.parse() {
-                                        snd.send(Err(err));
-                                        return Ok::<_, ParseError>(());
-                                    }
-
-                                    let mut cnt = parser.items().len();
-                                    let items = parser.into_items();
-                                    let file = Box::leak(box s);
-                                    let input = Box::leak(box input);
-                                    // TODO: the receiver has to wait on all items so we really
-                                    // don't get much benefit from threading now
-                                    for item in items {
-                                        cnt -= 1;
-                                        snd.send(Ok(ParsedBlob {
-                                            file,
-                                            input,
-                                            count: cnt,
-                                            decl: item,
-                                        }));
-                                    }
-                                    Ok(())
-                                });
-                            } else {
-                                return Err(ParseError::Error("malformed import", start));
-                            }
-
                             self.items.push(item);
                         }
                         tkn => unreachable!("Token is unaccounted for `{}`", tkn.text()),
@@ -216,6 +296,18 @@ impl<'a> AstBuilder<'a> {
         self.eat_if_kw(kw::Const);
         self.eat_whitespace();
 
+        // `const fn name() {}` is a function that can be evaluated at compile time
+        if kw::Fn.text() == self.input_curr() {
+            let mut item = self.parse_fn()?;
+            let span = ast::to_rng(start..self.input_idx, self.file_id);
+            if let ast::Decl::Func(func) = &mut item.val {
+                func.kind = FuncKind::Const;
+                func.span = span;
+            }
+            item.span = span;
+            return Ok(item);
+        }
+
         let mutable = self.eat_if_kw(kw::Mut);
         self.eat_whitespace();
 
@@ -321,7 +413,7 @@ impl<'a> AstBuilder<'a> {
 
         let span = self.curr_span();
         let stmts = if self.eat_if(&TokenMatch::Semi) {
-            ast::Block { stmts: crate::raw_vec![], span }
+            ast::Block { stmts: crate::raw_vec![], tail: None, span }
         } else {
             self.make_block()?
         };
@@ -353,11 +445,17 @@ impl<'a> AstBuilder<'a> {
         self.eat_if(&TokenMatch::OpenBrace);
         self.eat_whitespace();
 
-        let method = if let ast::Decl::Func(func) = self.parse_fn()?.val {
+        let mut method = if let ast::Decl::Func(func) = self.parse_fn()?.val {
             func
         } else {
             unreachable!("we should error before this [parse func in impl]")
         };
+        // Each impl of a trait is its own function, `impl add<int>` is `addint`
+        let types = type_arguments
+            .iter()
+            .map(|t| t.val.to_string().replace(|c: char| !(c.is_alphanumeric() || c == '_'), ""))
+            .collect::<String>();
+        method.ident = Ident::new(method.ident.span(), &format!("{}{}", method.ident, types));
 
         self.eat_if(&TokenMatch::CloseBrace);
         let span = ast::to_rng(start..self.input_idx, self.file_id);
@@ -487,7 +585,7 @@ impl<'a> AstBuilder<'a> {
                 span,
             })
         } else {
-            let stmts = ast::Block { stmts: crate::raw_vec![], span: self.curr_span() };
+            let stmts = ast::Block { stmts: crate::raw_vec![], tail: None, span: self.curr_span() };
             let span = ast::to_rng(start..self.input_idx, self.file_id);
             ast::TraitMethod::NoBody(ast::Func {
                 ident,
@@ -710,34 +808,10 @@ impl<'a> AstBuilder<'a> {
 
             let span = ast::to_rng(start..self.input_idx, self.file_id);
             Ok(ast::Expr::TraitMeth { trait_, type_args, args }.into_spanned(span))
-        } else if self.curr.kind == TokenMatch::At {
-            let start = self.input_idx;
-
-            self.eat_if(&TokenMatch::At);
-            Ok(match self.input_curr() {
-                "bottom" => {
-                    self.eat_if(&TokenMatch::Ident);
-                    ast::Expr::Builtin(ast::Builtin::Bottom)
-                }
-                "size_of" => {
-                    self.eat_if(&TokenMatch::Ident);
-                    let mut ty = self.make_type_args()?;
-                    if ty.len() != 1 {
-                        return Err(ParseError::Error(
-                            "@size_of takes one type argument",
-                            self.curr_span(),
-                        ));
-                    }
-                    ast::Expr::Builtin(ast::Builtin::SizeOf(crate::rawptr!(ty.remove(0))))
-                }
-                _ => {
-                    return Err(ParseError::Error("builtin", self.curr_span()));
-                }
-            }
-            .into_spanned(ast::to_rng(start..self.input_idx, self.file_id)))
         } else if matches!(
             self.curr.kind,
             TokenKind::Ident
+                | TokenKind::At             // compiler builtins
                 | TokenKind::Literal { .. } // cstr, numbers, etc.
                 | TokenKind::Star           // deref
                 | TokenKind::Minus          // negative numbers
@@ -763,6 +837,9 @@ impl<'a> AstBuilder<'a> {
                             ast::Expr::Value(self.make_literal()?).into_spanned(self.curr_span())
                         );
                     }
+                    kw::If => return self.make_if_expr(),
+                    kw::Match => return self.make_match_expr(),
+                    kw::Asm => return self.make_asm_expr(),
                     t => {
                         return Err(ParseError::Error("unexpected keyword", self.curr_span()));
                     }
@@ -891,6 +968,7 @@ impl<'a> AstBuilder<'a> {
             let id = self.make_lh_expr()?;
             self.eat_whitespace();
 
+            let id = self.make_postfix(id)?;
             let op = self.make_op()?;
             (id, op)
         } else if matches!(self.curr.kind, TokenKind::Minus | TokenKind::Literal { .. }) {
@@ -899,6 +977,7 @@ impl<'a> AstBuilder<'a> {
                 .into_spanned(ast::to_rng(start..self.input_idx, self.file_id));
             self.eat_whitespace();
 
+            let ex = self.make_postfix(ex)?;
             let op = self.make_op()?;
             (ex, op)
         } else if self.curr.kind == TokenMatch::Bang {
@@ -912,21 +991,18 @@ impl<'a> AstBuilder<'a> {
 
             self.eat_whitespace();
 
+            let expr = self.make_postfix(expr)?;
             let op = self.make_op()?;
             (expr, op)
         } else if self.curr.kind == TokenMatch::Minus {
             // negative number `-10;`
             let start = self.input_idx;
 
-            self.eat_if(&TokenMatch::Minus);
-            let mut lit = self.make_literal()?;
+            // `make_literal` eats the `-` so the range of a sized int literal is checked
+            // after it is negated `-128i8`
+            let lit = self.make_literal()?;
             match lit.val {
-                ast::Val::Int(ref mut v) => {
-                    *v = -(*v);
-                }
-                ast::Val::Float(ref mut v) => {
-                    *v = -(*v);
-                }
+                ast::Val::Int(_) | ast::Val::Float(_) | ast::Val::SizedInt(..) => {}
                 _ => {
                     return Err(ParseError::Error(
                         "float or int can be negative",
@@ -938,6 +1014,7 @@ impl<'a> AstBuilder<'a> {
                 .into_spanned(ast::to_rng(start..self.input_idx, self.file_id));
             self.eat_whitespace();
 
+            let ex = self.make_postfix(ex)?;
             let op = self.make_op()?;
             (ex, op)
         } else if self.curr.kind == TokenMatch::Tilde {
@@ -951,6 +1028,7 @@ impl<'a> AstBuilder<'a> {
 
             self.eat_whitespace();
 
+            let expr = self.make_postfix(expr)?;
             let op = self.make_op()?;
             (expr, op)
         } else if self.curr.kind == TokenMatch::Star {
@@ -970,6 +1048,7 @@ impl<'a> AstBuilder<'a> {
 
             self.eat_whitespace();
 
+            let expr = self.make_postfix(expr)?;
             let op = self.make_op()?;
             (expr, op)
         } else if self.curr.kind == TokenMatch::And {
@@ -982,6 +1061,25 @@ impl<'a> AstBuilder<'a> {
 
             self.eat_whitespace();
 
+            let expr = self.make_postfix(expr)?;
+            let op = self.make_op()?;
+            (expr, op)
+        } else if self.curr.kind == TokenMatch::At {
+            let start = self.input_idx;
+
+            self.eat_if(&TokenMatch::At);
+            let builtin = self.make_builtin()?;
+            if let ast::Builtin::StaticAssert(..) = builtin {
+                return Err(ParseError::Error(
+                    "@static_assert can only be used as a statement",
+                    ast::to_rng(start..self.input_idx, self.file_id),
+                ));
+            }
+            let expr = ast::Expr::Builtin(builtin)
+                .into_spanned(ast::to_rng(start..self.input_idx, self.file_id));
+            self.eat_whitespace();
+
+            let expr = self.make_postfix(expr)?;
             let op = self.make_op()?;
             (expr, op)
         } else if self.curr.kind == TokenMatch::OpenParen {
@@ -999,10 +1097,14 @@ impl<'a> AstBuilder<'a> {
             self.eat_if(&TokenMatch::CloseParen);
             self.eat_whitespace();
 
+            let expr = self.make_postfix(expr)?;
             let op = self.make_op()?;
             (expr, op)
         } else {
-            return Err(ParseError::Error("lit, ptr, parens, ones comp, nots", self.curr_span()));
+            return Err(ParseError::Error(
+                "lit, ptr, parens, ones comp, nots, builtins",
+                self.curr_span(),
+            ));
         })
     }
 
@@ -1131,6 +1233,30 @@ impl<'a> AstBuilder<'a> {
         })
     }
 
+    /// Wrap `expr` in any number of `expr as ty` casts and `expr?` unwraps.
+    fn make_postfix(&mut self, mut expr: ast::Expression) -> ParseResult<ast::Expression> {
+        self.push_call_stack("make_postfix");
+        loop {
+            if self.eat_if(&TokenMatch::Question) {
+                let span = ast::to_rng(expr.span.start..self.input_idx, self.file_id);
+                expr = ast::Expr::Try(box expr).into_spanned(span);
+                self.eat_whitespace();
+            } else if self.curr.kind == TokenMatch::Ident && self.input_curr() == kw::As.text() {
+                self.eat_if(&TokenMatch::Ident);
+                self.eat_whitespace();
+
+                let ty = self.make_ty()?;
+                self.eat_whitespace();
+
+                let span = ast::to_rng(expr.span.start..self.input_idx, self.file_id);
+                expr = ast::Expr::Cast { expr: box expr, ty }.into_spanned(span);
+            } else {
+                break;
+            }
+        }
+        Ok(expr)
+    }
+
     /// Build an optional `AssocOp`.
     fn make_op(&mut self) -> ParseResult<Option<AssocOp>> {
         self.push_call_stack("make_op");
@@ -1192,6 +1318,8 @@ impl<'a> AstBuilder<'a> {
                 self.eat_if(&TokenMatch::Plus);
                 Some(AssocOp::Add)
             }
+            // The `->` of a match arm ends a guard expression
+            TokenKind::Minus if self.cmp_seq(&[TokenMatch::Minus, TokenMatch::Gt]) => None,
             TokenKind::Minus => {
                 self.eat_if(&TokenMatch::Minus);
                 Some(AssocOp::Subtract)
@@ -1252,7 +1380,6 @@ impl<'a> AstBuilder<'a> {
     fn make_block(&mut self) -> ParseResult<ast::Block> {
         self.push_call_stack("make_block");
         let start = self.input_idx;
-        let mut stmts = crate::raw_vec![];
 
         // If the function body is empty
         if self.cmp_seq_ignore_ws(&[TokenMatch::OpenBrace, TokenMatch::CloseBrace]) {
@@ -1260,10 +1387,27 @@ impl<'a> AstBuilder<'a> {
             let span = ast::to_rng(start..self.input_idx, self.file_id);
             return Ok(ast::Block {
                 stmts: crate::raw_vec![ast::Stmt::Exit.into_spanned(span)],
+                tail: None,
                 span,
             });
         }
 
+        self.make_block_stmts(false)
+    }
+
+    /// Parse the block of an `if` or `match` expression.
+    ///
+    /// The last expression of the block, if it is not followed by a `;`, is the value.
+    fn make_value_block(&mut self) -> ParseResult<ast::Block> {
+        self.push_call_stack("make_value_block");
+        self.make_block_stmts(true)
+    }
+
+    fn make_block_stmts(&mut self, has_value: bool) -> ParseResult<ast::Block> {
+        let start = self.input_idx;
+        let mut stmts = crate::raw_vec![];
+        let mut tail = None;
+
         self.eat_whitespace();
         if self.eat_if(&TokenMatch::OpenBrace) {
             loop {
@@ -1273,6 +1417,18 @@ impl<'a> AstBuilder<'a> {
                     self.eat_if(&TokenMatch::CloseBrace);
                     break;
                 }
+                if has_value && self.is_block_tail() {
+                    tail = Some(box self.make_expr()?);
+                    self.eat_whitespace();
+                    if !self.eat_if(&TokenMatch::CloseBrace) {
+                        return Err(ParseError::Expected(
+                            "`}` after the value of a block",
+                            self.input_curr().to_string(),
+                            self.curr_span(),
+                        ));
+                    }
+                    break;
+                }
                 stmts.push(self.make_stmt()?);
                 self.eat_whitespace();
 
@@ -1282,7 +1438,77 @@ impl<'a> AstBuilder<'a> {
             }
         }
         let span = ast::to_rng(start..self.input_idx(), self.file_id);
-        Ok(ast::Block { stmts, span })
+        Ok(ast::Block { stmts, tail, span })
+    }
+
+    /// Check if the rest of the block is an expression, non destructively.
+    ///
+    /// The tail of a block runs to the closing `}` without a `;` and is not an assignment. An
+    /// `if` or `match` is only the tail if nothing follows it's blocks.
+    fn is_block_tail(&self) -> bool {
+        let key: Result<kw::Keywords, _> = self.input_curr().try_into();
+        if matches!(
+            key,
+            Ok(kw::Let | kw::While | kw::Return | kw::Exit | kw::Break | kw::Defer | kw::Asm)
+        ) {
+            return false;
+        }
+        let is_branch = matches!(key, Ok(kw::If | kw::Match));
+
+        let mut depth = 0_usize;
+        let mut idx = self.input_idx;
+        let mut prev = None;
+        let mut closed_branch = false;
+        let mut tkns = Some(&self.curr).into_iter().chain(&self.tokens).peekable();
+        while let Some(tkn) = tkns.next() {
+            let text = &self.input[idx..idx + tkn.len];
+            idx += tkn.len;
+            if matches!(
+                tkn.kind,
+                TokenKind::Whitespace
+                    | TokenKind::LineComment { .. }
+                    | TokenKind::BlockComment { .. }
+            ) {
+                continue;
+            }
+
+            if closed_branch {
+                // `if expr {} else {}` keeps going
+                if text == kw::Else.text() {
+                    closed_branch = false;
+                    continue;
+                }
+                return tkn.kind == TokenMatch::CloseBrace;
+            }
+
+            match tkn.kind {
+                TokenKind::OpenBrace | TokenKind::OpenParen | TokenKind::OpenBracket => depth += 1,
+                TokenKind::CloseBrace | TokenKind::CloseParen | TokenKind::CloseBracket => {
+                    // This is the end of the enclosing block
+                    if depth == 0 {
+                        return true;
+                    }
+                    depth -= 1;
+                    closed_branch = is_branch && depth == 0;
+                }
+                TokenKind::Semi if depth == 0 => return false,
+                // `==`, `<=`, `>=` and `!=` are fine anything else is an assignment
+                TokenKind::Eq if depth == 0 => {
+                    let is_cmp =
+                        matches!(
+                            prev,
+                            Some(TokenKind::Eq | TokenKind::Lt | TokenKind::Gt | TokenKind::Bang)
+                        ) || matches!(tkns.peek(), Some(Token { kind: TokenKind::Eq, .. }));
+                    if !is_cmp {
+                        return false;
+                    }
+                }
+                TokenKind::Eof => return false,
+                _ => {}
+            }
+            prev = Some(tkn.kind);
+        }
+        false
     }
 
     fn make_stmt(&mut self) -> ParseResult<ast::Statement> {
@@ -1306,27 +1532,17 @@ impl<'a> AstBuilder<'a> {
         } else if self.eat_if_kw(kw::Exit) {
             self.eat_whitespace();
             ast::Stmt::Exit
+        } else if self.eat_if_kw(kw::Break) {
+            self.eat_whitespace();
+            ast::Stmt::Break
+        } else if self.eat_if_kw(kw::Defer) {
+            self.eat_whitespace();
+            // The deferred statement already ate the `;`
+            let stmt = self.make_stmt()?;
+            let span = ast::to_rng(start..self.input_idx, self.file_id);
+            return Ok(ast::Stmt::Defer(box stmt).into_spanned(span));
         } else if self.eat_if(&TokenMatch::At) {
-            match self.input_curr() {
-                "bottom" => {
-                    self.eat_if(&TokenMatch::Ident);
-                    ast::Stmt::Builtin(ast::Builtin::Bottom)
-                }
-                "size_of" => {
-                    self.eat_if(&TokenMatch::Ident);
-                    let mut ty = self.make_type_args()?;
-                    if ty.len() != 1 {
-                        return Err(ParseError::Error(
-                            "@size_of takes one type argument",
-                            self.curr_span(),
-                        ));
-                    }
-                    ast::Stmt::Builtin(ast::Builtin::SizeOf(crate::rawptr!(ty.remove(0))))
-                }
-                _ => {
-                    return Err(ParseError::Error("builtin", self.curr_span()));
-                }
-            }
+            ast::Stmt::Builtin(self.make_builtin()?)
         } else {
             self.make_expr_stmt()?
         };
@@ -1380,6 +1596,43 @@ impl<'a> AstBuilder<'a> {
         Ok(ast::Stmt::If { cond, blk, els })
     }
 
+    fn make_if_expr(&mut self) -> ParseResult<ast::Expression> {
+        self.push_call_stack("make_if_expr");
+        let start = self.input_idx;
+        self.eat_if_kw(kw::If);
+        self.eat_whitespace();
+
+        // HACK: this stops `if cond {` being parsed as a struct literal
+        let in_match = std::mem::replace(&mut self.in_match_stmt, true);
+        let cond = self.make_expr();
+        self.in_match_stmt = in_match;
+        let cond = cond?;
+        self.eat_whitespace();
+
+        let blk = self.make_value_block()?;
+        self.eat_whitespace();
+
+        if !self.eat_if_kw(kw::Else) {
+            return Err(ParseError::Expected(
+                "`else` block of `if` expression",
+                self.input_curr().to_string(),
+                self.curr_span(),
+            ));
+        }
+        self.eat_whitespace();
+
+        // `else if` is sugar for `else { if .. }`
+        let els = if matches!(self.input_curr().try_into(), Ok(kw::If)) {
+            let nested = self.make_if_expr()?;
+            ast::Block { stmts: crate::raw_vec![], span: nested.span, tail: Some(box nested) }
+        } else {
+            self.make_value_block()?
+        };
+
+        let span = ast::to_rng(start..self.input_idx(), self.file_id);
+        Ok(ast::Expr::If { cond: box cond, blk, els }.into_spanned(span))
+    }
+
     fn make_while_stmt(&mut self) -> ParseResult<ast::Stmt> {
         self.push_call_stack("make_while_stmt");
         self.eat_whitespace();
@@ -1401,7 +1654,7 @@ impl<'a> AstBuilder<'a> {
         self.eat_whitespace();
 
         self.eat_if(&TokenMatch::OpenBrace);
-        let arms = self.make_arms()?;
+        let arms = self.make_arms(false)?;
 
         self.eat_whitespace();
         self.eat_if(&TokenMatch::CloseBrace);
@@ -1410,6 +1663,31 @@ impl<'a> AstBuilder<'a> {
         Ok(ast::Stmt::Match { expr, arms })
     }
 
+    fn make_match_expr(&mut self) -> ParseResult<ast::Expression> {
+        self.push_call_stack("make_match_expr");
+        let start = self.input_idx;
+        self.eat_if_kw(kw::Match);
+        self.eat_whitespace();
+
+        let in_match = std::mem::replace(&mut self.in_match_stmt, true);
+        let arms: ParseResult<_> = try {
+            let expr = self.make_expr()?;
+            self.eat_whitespace();
+
+            self.eat_if(&TokenMatch::OpenBrace);
+            let arms = self.make_arms(true)?;
+
+            self.eat_whitespace();
+            self.eat_if(&TokenMatch::CloseBrace);
+            (expr, arms)
+        };
+        self.in_match_stmt = in_match;
+        let (expr, arms) = arms?;
+
+        let span = ast::to_rng(start..self.input_idx(), self.file_id);
+        Ok(ast::Expr::Match { expr: box expr, arms }.into_spanned(span))
+    }
+
     fn make_return_stmt(&mut self) -> ParseResult<ast::Stmt> {
         self.push_call_stack("make_return_stmt");
         self.eat_whitespace();
@@ -1424,9 +1702,64 @@ impl<'a> AstBuilder<'a> {
 
     fn make_asm_stmt(&mut self) -> ParseResult<ast::Stmt> {
         self.push_call_stack("make_asm_stmt");
+        let (asm, _) = self.make_asm_block(false)?;
+        self.eat_whitespace();
+        self.eat_if(&TokenMatch::Semi);
+        Ok(ast::Stmt::InlineAsm(asm))
+    }
+
+    /// Parse an `asm` block used as a value `asm -> float { cvtsi2sd (a), %xmm0 }`.
+    fn make_asm_expr(&mut self) -> ParseResult<ast::Expression> {
+        self.push_call_stack("make_asm_expr");
+        let start = self.input_idx;
+        self.eat_if_kw(kw::Asm);
+
+        let (asm, ty) = self.make_asm_block(true)?;
+        let span = ast::to_rng(start..self.input_idx(), self.file_id);
+        Ok(ast::Expr::InlineAsm { asm, ty: ty.unwrap() }.into_spanned(span))
+    }
+
+    /// Parse the operands and instructions of an `asm` block, `has_value` when it is an
+    /// expression and the result type `-> ty` is required.
+    fn make_asm_block(&mut self, has_value: bool) -> ParseResult<(AsmBlock, Option<ast::Type>)> {
         self.eat_whitespace();
 
         let start = self.input_idx;
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        let mut clobbers = vec![];
+        // The operands can come in any order before the block
+        loop {
+            if self.eat_if_kw(kw::In) {
+                inputs = self.make_asm_operands()?;
+            } else if self.input_curr() == "out" {
+                self.eat_tkn();
+                outputs = self.make_asm_operands()?;
+            } else if self.input_curr() == "clobber" {
+                self.eat_tkn();
+                clobbers = self.make_asm_clobbers()?;
+            } else {
+                break;
+            }
+            self.eat_whitespace();
+        }
+
+        let ty = if has_value {
+            if !self.eat_seq(&[TokenMatch::Minus, TokenMatch::Gt]) {
+                return Err(ParseError::Expected(
+                    "`-> type` of asm expression",
+                    self.input_curr().to_string(),
+                    self.curr_span(),
+                ));
+            }
+            self.eat_whitespace();
+            let ty = self.make_ty()?;
+            self.eat_whitespace();
+            Some(ty)
+        } else {
+            None
+        };
+
         let assembly = if self.eat_if(&TokenMatch::OpenBrace) {
             self.eat_whitespace();
 
@@ -1437,10 +1770,6 @@ impl<'a> AstBuilder<'a> {
             }
 
             self.eat_if(&TokenMatch::CloseBrace);
-            self.eat_whitespace();
-
-            self.eat_if(&TokenMatch::Semi);
-
             assembly
         } else {
             return Err(ParseError::Error(
@@ -1449,25 +1778,109 @@ impl<'a> AstBuilder<'a> {
             ));
         };
 
-        Ok(ast::Stmt::InlineAsm(AsmBlock {
-            assembly,
-            span: ast::to_rng(start..self.input_idx, self.file_id),
-        }))
+        let span = ast::to_rng(start..self.input_idx, self.file_id);
+        Ok((AsmBlock { assembly, inputs, outputs, clobbers, span }, ty))
+    }
+
+    /// Parse `(%reg = expr, ...)` the operands of an `in` or `out` list.
+    fn make_asm_operands(&mut self) -> ParseResult<Vec<ast::AsmOperand>> {
+        self.eat_whitespace();
+        if !self.eat_if(&TokenMatch::OpenParen) {
+            return Err(ParseError::Error("expected `(` to start asm operands", self.curr_span()));
+        }
+        let mut operands = vec![];
+        loop {
+            self.eat_whitespace();
+            if self.eat_if(&TokenMatch::CloseParen) {
+                break;
+            }
+            let reg = self.make_asm_register()?;
+            self.eat_whitespace();
+            if !self.eat_if(&TokenMatch::Eq) {
+                return Err(ParseError::Error("expected `=` after asm register", self.curr_span()));
+            }
+            self.eat_whitespace();
+            operands.push(ast::AsmOperand { reg, expr: self.make_expr()? });
+            self.eat_whitespace();
+            if !self.eat_if(&TokenMatch::Comma) {
+                if !self.eat_if(&TokenMatch::CloseParen) {
+                    return Err(ParseError::Error("expected `,` or `)`", self.curr_span()));
+                }
+                break;
+            }
+        }
+        Ok(operands)
+    }
+
+    /// Parse `(%reg, ...)` the registers of a `clobber` list.
+    fn make_asm_clobbers(&mut self) -> ParseResult<Vec<Location>> {
+        self.eat_whitespace();
+        if !self.eat_if(&TokenMatch::OpenParen) {
+            return Err(ParseError::Error("expected `(` to start clobbers", self.curr_span()));
+        }
+        let mut regs = vec![];
+        loop {
+            self.eat_whitespace();
+            if self.eat_if(&TokenMatch::CloseParen) {
+                break;
+            }
+            regs.push(self.make_asm_register()?);
+            self.eat_whitespace();
+            if !self.eat_if(&TokenMatch::Comma) {
+                if !self.eat_if(&TokenMatch::CloseParen) {
+                    return Err(ParseError::Error("expected `,` or `)`", self.curr_span()));
+                }
+                break;
+            }
+        }
+        Ok(regs)
+    }
+
+    /// Parse `%reg` a general purpose or float register.
+    fn make_asm_register(&mut self) -> ParseResult<Location> {
+        if !matches!(self.curr.kind, TokenKind::Percent) {
+            return Err(ParseError::Error("asm operands must be registers", self.curr_span()));
+        }
+        Ok(self.make_location()?.expect("a `%` is always a register"))
+    }
+
+    /// Parse `(%reg)` the general purpose register a memory operand is relative to.
+    fn make_asm_base(&mut self) -> ParseResult<Location> {
+        self.eat_if(&TokenMatch::OpenParen);
+        let span = self.curr_span();
+        let reg = match self.make_location()? {
+            Some(reg @ Location::Register(_)) => reg,
+            _ => {
+                return Err(ParseError::Error(
+                    "a memory operand is relative to a general purpose register",
+                    span,
+                ));
+            }
+        };
+        if !self.eat_if(&TokenMatch::CloseParen) {
+            return Err(ParseError::Error("expected `)` after the register", self.curr_span()));
+        }
+        Ok(reg)
     }
 
     fn make_assembly_inst(&mut self) -> ParseResult<Instruction> {
-        let mut inst = if self.curr.kind == TokenMatch::Ident {
-            Instruction { inst: self.make_ident()?, src: None, dst: None }
+        let start = self.input_idx;
+        let inst = if self.curr.kind == TokenMatch::Ident {
+            self.make_ident()?
         } else {
             return Err(ParseError::Error("invalid assembly instruction", self.curr_span()));
         };
 
+        let (mut src, mut dst) = (None, None);
+        let mut end = self.input_idx;
         if !self.eat_if(&TokenMatch::Semi) {
-            inst.src = self.make_location()?;
+            src = self.make_location()?;
+            end = self.input_idx;
             self.eat_whitespace();
             if self.eat_if(&TokenMatch::Comma) {
                 self.eat_whitespace();
-                inst.dst = self.make_location()?;
+                dst = self.make_location()?;
+                end = self.input_idx;
             }
 
             if !self.eat_if(&TokenMatch::Semi) {
@@ -1475,7 +1888,7 @@ impl<'a> AstBuilder<'a> {
             }
         }
 
-        Ok(inst)
+        Ok(Instruction { inst, src, dst, span: ast::to_rng(start..end, self.file_id) })
     }
 
     fn make_location(&mut self) -> ParseResult<Option<Location>> {
@@ -1488,7 +1901,35 @@ impl<'a> AstBuilder<'a> {
 
             Some(loc)
         } else if matches!(self.curr.kind, TokenKind::Minus | TokenKind::Literal { .. }) {
-            Some(Location::Const(self.make_literal()?.val))
+            let lit = self.make_literal()?;
+            if self.curr.kind == TokenMatch::OpenParen {
+                // `8(%rdi)` the memory a register plus a displacement points to
+                let amt = match lit.val {
+                    Val::Int(amt) => amt,
+                    _ => {
+                        return Err(ParseError::Error(
+                            "the displacement of a memory operand must be an integer",
+                            lit.span,
+                        ));
+                    }
+                };
+                Some(Location::Offset { amt, reg: box self.make_asm_base()? })
+            } else {
+                Some(Location::Const(lit.val))
+            }
+        } else if matches!(self.curr.kind, TokenKind::Ident) {
+            // `main(%rip)` the address of a function
+            let name = self.make_ident()?;
+            let span = self.curr_span();
+            if !(self.eat_if(&TokenMatch::OpenParen)
+                && self.eat_if(&TokenMatch::Percent)
+                && self.input_curr() == "rip"
+                && self.eat_if(&TokenMatch::Ident)
+                && self.eat_if(&TokenMatch::CloseParen))
+            {
+                return Err(ParseError::Error("a named operand is written `name(%rip)`", span));
+            }
+            Some(Location::NamedOffset(name))
         } else if matches!(self.curr.kind, TokenKind::Percent) {
             self.eat_if(&TokenMatch::Percent);
 
@@ -1524,7 +1965,6 @@ impl<'a> AstBuilder<'a> {
             self.eat_if(&TokenMatch::Ident);
             Some(reg)
         } else {
-            panic!("{:?}", self.curr);
             return Err(ParseError::Error("invalid operand", self.curr_span()));
         })
     }
@@ -1542,6 +1982,11 @@ impl<'a> AstBuilder<'a> {
                 //
                 // `x[0] = 6; x = call; v.v.f = yo;
                 ast::Expr::Ident(_) => self.make_assign_stmt_expr(expr)?,
+                // `call()?;` the unwrapped value is thrown away
+                ast::Expr::Call { .. } if self.curr.kind == TokenMatch::Question => {
+                    let expr = self.make_postfix(expr)?;
+                    ast::Stmt::Call(expr)
+                }
                 ast::Expr::Call { .. } => ast::Stmt::Call(expr),
                 ast::Expr::Deref { .. } => self.make_assign_stmt_expr(expr)?,
                 ast::Expr::Array { .. } => self.make_assign_stmt_expr(expr)?,
@@ -1557,6 +2002,11 @@ impl<'a> AstBuilder<'a> {
                 | ast::Expr::EnumInit { .. }
                 | ast::Expr::ArrayInit { .. }
                 | ast::Expr::Builtin(..)
+                | ast::Expr::If { .. }
+                | ast::Expr::Match { .. }
+                | ast::Expr::Cast { .. }
+                | ast::Expr::Try(..)
+                | ast::Expr::InlineAsm { .. }
                 | ast::Expr::Value(_) => {
                     return Err(ParseError::Error(
                         "invalid left hand side of statement",
@@ -1565,7 +2015,9 @@ impl<'a> AstBuilder<'a> {
                 }
             }
         } else if self.curr.kind == TokenMatch::Lt {
-            todo!("Trait method calls {}", self.call_stack.join("\n"))
+            // `<<T>::meth>(a);` the returned value is thrown away
+            let expr = self.make_expr()?;
+            ast::Stmt::TraitMeth(expr)
         } else if self.curr.kind == TokenMatch::OpenBrace {
             let blk = self.make_block()?;
             ast::Stmt::Block(blk)
@@ -1621,7 +2073,8 @@ impl<'a> AstBuilder<'a> {
         })
     }
 
-    fn make_arms(&mut self) -> ParseResult<Vec<ast::MatchArm>> {
+    /// Parse the arms of a match, `has_value` when the match is used as an expression.
+    fn make_arms(&mut self, has_value: bool) -> ParseResult<Vec<ast::MatchArm>> {
         self.push_call_stack("make_arms");
         self.eat_whitespace();
         let mut arms = vec![];
@@ -1636,30 +2089,91 @@ impl<'a> AstBuilder<'a> {
             let pat = self.make_pat()?;
 
             self.eat_whitespace();
+            let guard = if self.eat_if_kw(kw::If) {
+                // The expression stops at the `->` see `make_op`
+                let guard = self.make_expr()?;
+                self.eat_whitespace();
+                Some(guard)
+            } else {
+                None
+            };
+
             self.eat_if(&TokenMatch::Minus);
             self.eat_if(&TokenMatch::Gt);
             self.eat_whitespace();
 
-            let blk = self.make_block()?;
+            let blk = if has_value { self.make_value_block()? } else { self.make_block()? };
 
             let span = ast::to_rng(start..self.input_idx(), self.file_id);
             self.eat_if(&TokenMatch::Comma);
-            arms.push(ast::MatchArm { pat, blk, span })
+            arms.push(ast::MatchArm { pat, guard, blk, span })
         }
         Ok(arms)
     }
 
+    /// Parse a pattern and any `| pat` alternatives that follow it.
     fn make_pat(&mut self) -> ParseResult<ast::Pattern> {
         self.push_call_stack("make_pat");
         let start = self.input_idx;
 
+        let first = self.make_single_pat()?;
+        self.eat_whitespace();
+        if self.curr.kind != TokenMatch::Or {
+            return Ok(first);
+        }
+
+        let mut alts = vec![first];
+        while self.eat_if(&TokenMatch::Or) {
+            self.eat_whitespace();
+            alts.push(self.make_single_pat()?);
+            self.eat_whitespace();
+        }
+        let span = ast::to_rng(start..self.input_idx(), self.file_id);
+        Ok(ast::Pat::Or(alts).into_spanned(span))
+    }
+
+    fn make_single_pat(&mut self) -> ParseResult<ast::Pattern> {
+        self.push_call_stack("make_single_pat");
+        let start = self.input_idx;
+
+        let is_bool = matches!(self.input_curr().try_into(), Ok(kw::True | kw::False));
+
         // TODO: make this more robust
         // could be `::mod::Name::Variant`
-        Ok(if self.curr.kind == TokenKind::Ident {
-            let mut path = self.make_path()?;
+        Ok(if self.curr.kind == TokenKind::Ident && self.input_curr() == "_" {
+            self.eat_if(&TokenMatch::Ident);
+            let span = ast::to_rng(start..self.input_idx(), self.file_id);
+            ast::Pat::Wild.into_spanned(span)
+        } else if self.curr.kind == TokenKind::Ident && !is_bool {
+            // `make_path` would happily eat `x if` as a path so only use it for `a::b`
+            let mut path = if self.cmp_seq_ignore_ws(&[
+                TokenMatch::Ident,
+                TokenMatch::Colon,
+                TokenMatch::Colon,
+            ]) {
+                self.make_path()?
+            } else {
+                let ident = self.make_ident()?;
+                Path {
+                    segs: vec![ident],
+                    gen: vec![],
+                    span: ast::to_rng(start..self.input_idx(), self.file_id),
+                }
+            };
+            self.eat_whitespace();
+
+            if self.eat_if(&TokenMatch::OpenBrace) {
+                let (fields, rest) = self.make_field_pats()?;
+                let span = ast::to_rng(start..self.input_idx(), self.file_id);
+                ast::Pat::Struct { path, fields, rest }.into_spanned(span)
+            } else if path.segs.len() == 1 && self.eat_if(&TokenMatch::At) {
+                self.eat_whitespace();
+                let pat = self.make_single_pat()?;
+                let span = ast::to_rng(start..self.input_idx(), self.file_id);
+                ast::Pat::At { ident: path.segs.remove(0), pat: box pat }.into_spanned(span)
             // TODO: make this more robust
             // eventually calling an enum by variant needs to work which is the same as an ident
-            if path.segs.len() > 1 {
+            } else if path.segs.len() > 1 {
                 let variant = path.segs.pop().ok_or_else(|| {
                     ParseError::Expected("pattern", "nothing".to_string(), self.curr_span())
                 })?;
@@ -1692,15 +2206,74 @@ impl<'a> AstBuilder<'a> {
 
             let span = ast::to_rng(start..self.input_idx(), self.file_id);
             ast::Pat::Array { size: pats.len(), items: pats }.into_spanned(span)
-        } else if matches!(self.curr.kind, TokenKind::Minus | TokenKind::Literal { .. }) {
+        } else if is_bool || matches!(self.curr.kind, TokenKind::Minus | TokenKind::Literal { .. })
+        {
             // Literal
-            let span = ast::to_rng(start..self.input_idx(), self.file_id);
-            ast::Pat::Bind(ast::Binding::Value(self.make_literal()?)).into_spanned(span)
+            let lit = self.make_literal()?;
+            // `eat_seq_ignore_ws` eats the whitespace after the literal even when it fails
+            let lit_span = ast::to_rng(start..self.input_idx(), self.file_id);
+            if self.eat_seq_ignore_ws(&[TokenMatch::Dot, TokenMatch::Dot, TokenMatch::Eq]) {
+                self.eat_whitespace();
+                let end = self.make_literal()?;
+                let span = ast::to_rng(start..self.input_idx(), self.file_id);
+                ast::Pat::Range { start: lit, end }.into_spanned(span)
+            } else {
+                ast::Pat::Bind(ast::Binding::Value(lit)).into_spanned(lit_span)
+            }
         } else {
-            todo!("{:?}", self.curr)
+            return Err(ParseError::Expected(
+                "pattern",
+                self.input_curr().to_string(),
+                self.curr_span(),
+            ));
         })
     }
 
+    /// Parse `ident: pat, ident, ..}` the inside of a struct pattern, eats the closing brace.
+    ///
+    /// Returns the fields and if there was a `..` rest pattern.
+    fn make_field_pats(&mut self) -> ParseResult<(Vec<ast::FieldPat>, bool)> {
+        self.push_call_stack("make_field_pats");
+        let mut fields = vec![];
+        let mut rest = false;
+        loop {
+            self.eat_whitespace();
+            if self.eat_if(&TokenMatch::CloseBrace) {
+                break;
+            }
+
+            if self.eat_seq(&[TokenMatch::Dot, TokenMatch::Dot]) {
+                rest = true;
+            } else {
+                let start = self.input_idx;
+                let ident = self.make_ident()?;
+                let pat = if self.eat_if(&TokenMatch::Colon) {
+                    self.eat_whitespace();
+                    self.make_pat()?
+                } else {
+                    // Shorthand `point { x }` binds the field `x`
+                    let span = ast::to_rng(start..self.input_idx(), self.file_id);
+                    ast::Pat::Bind(ast::Binding::Wild(ident)).into_spanned(span)
+                };
+                fields.push(ast::FieldPat { ident, pat });
+            }
+
+            self.eat_whitespace();
+            if !self.eat_if(&TokenMatch::Comma) {
+                self.eat_whitespace();
+                if !self.eat_if(&TokenMatch::CloseBrace) {
+                    return Err(ParseError::Expected(
+                        "`,` or `}`",
+                        self.input_curr().to_string(),
+                        self.curr_span(),
+                    ));
+                }
+                break;
+            }
+        }
+        Ok((fields, rest))
+    }
+
     fn make_pat_list(&mut self) -> ParseResult<Vec<ast::Pattern>> {
         let mut pats = vec![];
         loop {
@@ -1757,22 +2330,44 @@ impl<'a> AstBuilder<'a> {
         Ok(params)
     }
 
-    /// Parse `<ident: ident, ident: ident>[ws]` all optional.
+    /// Parse `<ident: ident + ident, const ident: int>[ws]` all optional.
     fn make_generics(&mut self) -> ParseResult<Vec<ast::Generic>> {
         self.push_call_stack("make_generics");
         let mut gens = vec![];
         if self.eat_if(&TokenMatch::Lt) {
             loop {
                 let start = self.input_idx;
+                let is_const = self.eat_if_kw(kw::Const);
+                self.eat_whitespace();
+
                 let ident = self.make_ident()?;
-                let bound = if self.eat_if(&TokenMatch::Colon) {
+                let mut bounds = vec![];
+                if self.eat_if(&TokenMatch::Colon) {
                     self.eat_whitespace();
-                    Some(self.make_path()?)
-                } else {
-                    None
-                };
+                    if is_const {
+                        // `const N: int` is the only kind of const generic
+                        if self.input_curr() != "int" {
+                            return Err(ParseError::Expected(
+                                "int",
+                                self.input_curr().to_string(),
+                                self.curr_span(),
+                            ));
+                        }
+                        self.eat_if(&TokenMatch::Ident);
+                    } else {
+                        // `T: hash + eq` has to implement every trait
+                        loop {
+                            bounds.push(self.make_path()?);
+                            self.eat_whitespace();
+                            if !self.eat_if(&TokenMatch::Plus) {
+                                break;
+                            }
+                            self.eat_whitespace();
+                        }
+                    }
+                }
                 let span = ast::to_rng(start..self.input_idx(), self.file_id);
-                gens.push(ast::Generic { ident, bound, span });
+                gens.push(ast::Generic { ident, bounds, is_const, span });
 
                 self.eat_whitespace();
                 if self.eat_if(&TokenMatch::Comma) {
@@ -1820,6 +2415,24 @@ impl<'a> AstBuilder<'a> {
                 let text = self.input_curr();
 
                 let val = match kind {
+                    LiteralKind::Int { base, empty_int } if suffix_start < text.len() => {
+                        let (digits, suffix) = text.split_at(suffix_start);
+                        let ity = ast::IntTy::from_name(suffix).ok_or_else(|| {
+                            ParseError::Expected(
+                                "integer suffix",
+                                suffix.to_string(),
+                                self.curr_span(),
+                            )
+                        })?;
+                        let val = (neg_ident as i128)
+                            * digits
+                                .parse::<i128>()
+                                .map_err(|_| ParseError::InvalidIntLiteral(self.curr_span()))?;
+                        if !ity.fits(val) {
+                            return Err(ParseError::InvalidIntLiteral(self.curr_span()));
+                        }
+                        Val::SizedInt(ity.wrap(val), ity)
+                    }
                     LiteralKind::Int { base, empty_int } => Val::Int(
                         neg_ident
                             * text
@@ -1853,6 +2466,12 @@ impl<'a> AstBuilder<'a> {
                         Val::Str(Ident::new(self.curr_span(), &text))
                     }
 
+                    LiteralKind::Char { .. } | LiteralKind::Str { .. } => {
+                        return Err(ParseError::Error(
+                            "float or int can be negative",
+                            self.curr_span(),
+                        ));
+                    }
                     LiteralKind::ByteStr { .. }
                     | LiteralKind::RawStr { .. }
                     | LiteralKind::RawByteStr { .. }
@@ -1869,6 +2488,97 @@ impl<'a> AstBuilder<'a> {
         })
     }
 
+    /// Parse the builtin after the `@`.
+    fn make_builtin(&mut self) -> ParseResult<ast::Builtin> {
+        let name = self.input_curr().to_owned();
+        self.eat_if(&TokenMatch::Ident);
+        Ok(match name.as_str() {
+            "bottom" => ast::Builtin::Bottom,
+            "size_of" => ast::Builtin::SizeOf(crate::rawptr!(self.make_builtin_type_arg(&name)?)),
+            "align_of" => ast::Builtin::AlignOf(crate::rawptr!(self.make_builtin_type_arg(&name)?)),
+            "type_name" => {
+                ast::Builtin::TypeName(crate::rawptr!(self.make_builtin_type_arg(&name)?))
+            }
+            "offset_of" => {
+                let ty = self.make_builtin_type_arg(&name)?;
+                self.eat_whitespace();
+                if !self.eat_if(&TokenMatch::OpenParen) {
+                    return Err(ParseError::Error("@offset_of takes a field", self.curr_span()));
+                }
+                self.eat_whitespace();
+                let field = self.make_ident()?;
+                if !self.eat_if(&TokenMatch::CloseParen) {
+                    return Err(ParseError::Error("@offset_of takes one field", self.curr_span()));
+                }
+                ast::Builtin::OffsetOf(crate::rawptr!(ty), field)
+            }
+            "static_assert" => {
+                self.eat_whitespace();
+                self.eat_if(&TokenMatch::OpenParen);
+                let mut args = self.make_arg_list()?;
+                self.eat_whitespace();
+                self.eat_if(&TokenMatch::CloseParen);
+                match args.pop() {
+                    Some(ast::Spanned { val: ast::Expr::Value(msg), .. }) if args.len() == 1 => {
+                        match msg.val {
+                            ast::Val::Str(msg) => {
+                                ast::Builtin::StaticAssert(box args.remove(0), msg)
+                            }
+                            _ => {
+                                return Err(ParseError::Error(
+                                    "@static_assert message must be a string",
+                                    msg.span,
+                                ))
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err(ParseError::Error(
+                            "@static_assert takes a condition and a message",
+                            self.curr_span(),
+                        ));
+                    }
+                }
+            }
+            "len" => {
+                self.eat_whitespace();
+                if !self.eat_if(&TokenMatch::OpenParen) {
+                    return Err(ParseError::Error("@len takes an array", self.curr_span()));
+                }
+                let mut args = self.make_arg_list()?;
+                self.eat_whitespace();
+                self.eat_if(&TokenMatch::CloseParen);
+                if args.len() != 1 {
+                    return Err(ParseError::Error("@len takes one array", self.curr_span()));
+                }
+                ast::Builtin::Len(box args.remove(0))
+            }
+            "line" => ast::Builtin::Line,
+            "file" => ast::Builtin::File,
+            "caller_location" => ast::Builtin::CallerLocation,
+            _ => {
+                return Err(ParseError::Error("builtin", self.curr_span()));
+            }
+        })
+    }
+
+    /// Parse the single `::<type>` a builtin like `@size_of` takes.
+    fn make_builtin_type_arg(&mut self, name: &str) -> ParseResult<Type> {
+        let mut ty = self.make_type_args()?;
+        if ty.len() != 1 {
+            return Err(ParseError::Error(
+                match name {
+                    "size_of" => "@size_of takes one type argument",
+                    "align_of" => "@align_of takes one type argument",
+                    "offset_of" => "@offset_of takes one type argument",
+                    _ => "@type_name takes one type argument",
+                },
+                self.curr_span(),
+            ));
+        }
+        Ok(ty.remove(0))
+    }
+
     fn make_arg_list(&mut self) -> ParseResult<Vec<ast::Expression>> {
         let mut args = vec![];
         loop {
@@ -1944,7 +2654,8 @@ impl<'a> AstBuilder<'a> {
 
     /// Parse `type[ws]`.
     ///
-    /// This also handles `*type, [lit_int, type], foo<T>` and user defined items.
+    /// This also handles `*type, [lit_int, type], foo<T>` and user defined items, an int literal is
+    /// the argument to a const generic `foo::<4>`.
     fn make_ty(&mut self) -> ParseResult<ast::Type> {
         self.push_call_stack("make_ty");
 
@@ -2002,13 +2713,18 @@ impl<'a> AstBuilder<'a> {
                         "char" => ast::Ty::Char.into_spanned(span),
                         "int" => ast::Ty::Int.into_spanned(span),
                         "float" => ast::Ty::Float.into_spanned(span),
-                        "cstr" => ast::Ty::ConstStr(0).into_spanned(span),
+                        // A string is a pointer to its nul terminated bytes
+                        "cstr" | "str" => ast::Ty::ConstStr(0).into_spanned(span),
+                        name if ast::IntTy::from_name(name).is_some() => {
+                            ast::Ty::SizedInt(ast::IntTy::from_name(name).unwrap())
+                                .into_spanned(span)
+                        }
                         _ => {
                             let start = self.input_idx;
-                            let path = self.make_path()?;
+                            let mut path = self.make_path()?;
 
                             if self.curr.kind == TokenMatch::Lt {
-                                let tys = self.make_types(&TokenMatch::Lt, &TokenMatch::Gt)?;
+                                path.gen = self.make_types(&TokenMatch::Lt, &TokenMatch::Gt)?;
                                 ast::Ty::Path(path).into_spanned(span)
                             } else {
                                 let span = ast::to_rng(start..self.input_idx(), self.file_id);
@@ -2027,45 +2743,90 @@ impl<'a> AstBuilder<'a> {
                 let start = self.input_idx;
                 self.eat_if(&TokenMatch::OpenBracket);
                 self.eat_whitespace();
-                self.make_array_type(start)?
+                if self.is_slice_type() {
+                    // [ -->type]
+                    let ty = self.make_ty()?;
+                    self.eat_whitespace();
+                    self.eat_if(&TokenMatch::CloseBracket);
+                    ast::Ty::Slice(box ty)
+                        .into_spanned(ast::to_rng(start..self.input_idx(), self.file_id))
+                } else {
+                    self.make_array_type(start)?
+                }
             }
             TokenKind::Star => {
                 // Eat `*`
                 self.eat_tkn();
                 ast::Ty::Ptr(box self.make_ty()?).into_spanned(self.curr_span())
             }
+            TokenKind::Literal { kind: LiteralKind::Int { base: Base::Decimal, .. }, .. } => {
+                let span = self.curr_span();
+                let val = self
+                    .input_curr()
+                    .parse()
+                    .map_err(|_| ParseError::InvalidIntLiteral(self.curr_span()))?;
+                self.eat_if(&TokenMatch::Literal);
+                ast::Ty::ConstArg(val).into_spanned(span)
+            }
             // TokenKind::Lt => {}
             // TokenKind::Gt => {}
             tkn => todo!("Unknown token {:?} {}", tkn, &self.call_stack.join("\n")),
         })
     }
 
-    /// Any type that follows `lit_int; type][ws]`.
+    /// After the `[` of a type, `[str]` is a slice and `[3; str]` an array.
+    fn is_slice_type(&self) -> bool {
+        let mut depth = 0;
+        for tkn in std::iter::once(&self.curr).chain(&self.tokens) {
+            match tkn.kind {
+                TokenKind::OpenBracket => depth += 1,
+                TokenKind::CloseBracket if depth == 0 => return true,
+                TokenKind::CloseBracket => depth -= 1,
+                TokenKind::Semi if depth == 0 => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Any type that follows `lit_int; type][ws]` or `expr; type][ws]`.
     fn make_array_type(&mut self, start: usize) -> ParseResult<ast::Type> {
-        let size = if let TokenKind::Literal {
-            kind: LiteralKind::Int { base: Base::Decimal, .. },
-            ..
-        } = self.curr.kind
+        let (size, size_expr) = if self.cmp_seq_ignore_ws(&[TokenMatch::Literal, TokenMatch::Semi])
         {
-            self.input_curr()
-                .parse()
-                .map_err(|_| ParseError::InvalidIntLiteral(self.curr_span()))?
+            if let TokenKind::Literal {
+                kind: LiteralKind::Int { base: Base::Decimal, .. }, ..
+            } = self.curr.kind
+            {
+                let size = self
+                    .input_curr()
+                    .parse()
+                    .map_err(|_| ParseError::InvalidIntLiteral(self.curr_span()))?;
+                // [ -->lit; -->type]
+                self.eat_if(&TokenMatch::Literal);
+                (size, None)
+            } else {
+                return Err(ParseError::Expected(
+                    "lit",
+                    self.input_curr().to_string(),
+                    self.curr_span(),
+                ));
+            }
         } else {
-            return Err(ParseError::Expected(
-                "lit",
-                self.input_curr().to_string(),
-                self.curr_span(),
-            ));
+            // A const expression, evaluated during type checking
+            // [ -->expr; -->type]
+            (0, Some(self.make_expr()?))
         };
-        // [ -->lit; -->type]
-        self.eat_if(&TokenMatch::Literal);
         self.eat_whitespace();
         self.eat_if(&TokenMatch::Semi);
         self.eat_whitespace();
 
         let ty = self.make_ty()?;
-        let x = Ok(ast::Ty::Array { size, ty: box ty }
-            .into_spanned(ast::to_rng(start..self.input_idx(), self.file_id)));
+        let span = ast::to_rng(start..self.input_idx(), self.file_id);
+        let x = Ok(if let Some(size) = size_expr {
+            ast::Ty::ConstArray { size: box size, ty: box ty }.into_spanned(span)
+        } else {
+            ast::Ty::Array { size, ty: box ty }.into_spanned(span)
+        });
         self.eat_if(&TokenMatch::CloseBracket);
         x
     }
@@ -2074,7 +2835,7 @@ impl<'a> AstBuilder<'a> {
     fn make_path(&mut self) -> ParseResult<Path> {
         let start = self.input_idx;
         let segs = self.make_seg()?;
-        Ok(Path { segs, span: ast::to_rng(start..self.input_idx(), self.file_id) })
+        Ok(Path { segs, gen: vec![], span: ast::to_rng(start..self.input_idx(), self.file_id) })
     }
 
     /// Parse `ident[ws]::ident[ws]...`.
@@ -2102,8 +2863,9 @@ impl<'a> AstBuilder<'a> {
             }
 
             ids.push(self.make_ident()?);
+            // `expr as ty` is a cast not a path segment
             if self.cmp_seq(&[TokenMatch::Colon, TokenMatch::Colon])
-                || self.cmp_seq(&[TokenMatch::Ident])
+                || (self.cmp_seq(&[TokenMatch::Ident]) && self.input_curr() != kw::As.text())
             {
                 self.eat_whitespace();
                 continue;
@@ -68,6 +68,7 @@ keywords! {
     Break:              "break",
     Const:              "const",
     Continue:           "continue",
+    Defer:              "defer",
     Else:               "else",
     Enum:               "enum",
     False:              "false",
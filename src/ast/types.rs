@@ -9,7 +9,7 @@ use crate::{
     data_struc::{rawptr::RawPtr, rawvec::RawVec},
     error::Error,
     gen::asm::inst as asm,
-    typeck::{check::fold_ty, TyCheckRes},
+    typeck::{check::fold_ty, const_eval, TyCheckRes},
 };
 
 crate trait TypeEquality<T = Self> {
@@ -28,6 +28,9 @@ crate trait Spany: Sized {
 pub enum Val {
     Float(f64),
     Int(isize),
+    /// An integer literal with a type suffix `10u8`, the value is already wrapped to the width
+    /// of the type.
+    SizedInt(isize, IntTy),
     Char(char),
     Bool(bool),
     Str(Ident),
@@ -38,11 +41,35 @@ impl Val {
         match self {
             Val::Float(_) => Ty::Float,
             Val::Int(_) => Ty::Int,
+            Val::SizedInt(_, ity) => Ty::SizedInt(*ity),
             Val::Char(_) => Ty::Char,
             Val::Bool(_) => Ty::Bool,
             Val::Str(s) => Ty::ConstStr(s.name().len()),
         }
     }
+
+    /// The value of `self as to`, `None` if `to` is not a type a constant can be cast to.
+    ///
+    /// Floats are truncated and integers wrap to the width of `to`.
+    crate fn cast(&self, to: &Ty) -> Option<Val> {
+        let int = match (self, to) {
+            (Val::Float(_), Ty::Float) => return Some(self.clone()),
+            (Val::Float(f), _) => *f as i128,
+            (Val::Int(i), _) => *i as i128,
+            (Val::SizedInt(i, ity), _) => ity.widen(*i),
+            (Val::Char(c), _) => *c as i128,
+            (Val::Bool(b), _) => *b as i128,
+            (Val::Str(_), _) => return None,
+        };
+        Some(match to {
+            Ty::Int => Val::Int(int as isize),
+            Ty::SizedInt(ity) => Val::SizedInt(ity.wrap(int), *ity),
+            Ty::Char => Val::Char(int as u8 as char),
+            Ty::Bool => Val::Bool(int != 0),
+            Ty::Float => Val::Float(int as f64),
+            _ => return None,
+        })
+    }
 }
 
 impl hash::Hash for Val {
@@ -50,6 +77,7 @@ impl hash::Hash for Val {
         match self {
             Val::Float(f) => format!("float{}", f).hash(state),
             Val::Int(i) => format!("int{}", i).hash(state),
+            Val::SizedInt(i, ity) => format!("{}{}", ity, i).hash(state),
             Val::Char(c) => format!("char{}", c).hash(state),
             Val::Bool(b) => format!("bool{}", b).hash(state),
             Val::Str(s) => format!("str{}", s).hash(state),
@@ -64,6 +92,8 @@ impl PartialEq for Val {
             (Val::Float(_), _) => false,
             (Val::Int(a), Val::Int(b)) => a.eq(b),
             (Val::Int(_), _) => false,
+            (Val::SizedInt(a, t1), Val::SizedInt(b, t2)) => a.eq(b) && t1.eq(t2),
+            (Val::SizedInt(..), _) => false,
             (Val::Char(a), Val::Char(b)) => a.eq(b),
             (Val::Char(_), _) => false,
             (Val::Str(a), Val::Str(b)) => a.eq(b),
@@ -76,11 +106,26 @@ impl PartialEq for Val {
 
 impl Eq for Val {}
 
+/// Display a `Val` the way it would be written in source code.
+impl fmt::Display for Val {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Val::Float(v) => write!(f, "{}", v),
+            Val::Int(v) => write!(f, "{}", v),
+            Val::SizedInt(v, ity) => write!(f, "{}{}", ity.widen(*v), ity),
+            Val::Char(v) => write!(f, "'{}'", v),
+            Val::Bool(v) => write!(f, "{}", v),
+            Val::Str(v) => write!(f, "\"{}\"", v),
+        }
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.val {
             Val::Float(v) => write!(f, "float {}", v),
             Val::Int(v) => write!(f, "int {}", v),
+            Val::SizedInt(v, ity) => write!(f, "{} {}", ity, ity.widen(*v)),
             Val::Char(v) => write!(f, "char {}", v),
             Val::Bool(v) => write!(f, "bool {}", v),
             Val::Str(v) => write!(f, "string '{}'", v),
@@ -188,6 +233,20 @@ pub enum Expr {
     // FIXME: no builtins
     /// A builtin compiler implemented thing.
     Builtin(Builtin),
+    /// An `if` used as a value `if expr { expr } else { expr }`, the `else` is required.
+    If { cond: Box<Expression>, blk: Block, els: Block },
+    /// A `match` used as a value, each arm evaluates to the tail of it's block.
+    Match { expr: Box<Expression>, arms: Vec<MatchArm> },
+    /// Convert a value to another primitive type `expr as ty`.
+    Cast { expr: Box<Expression>, ty: Type },
+    /// Unwrap an `option` or `result`, returning `none` or the error early `expr?`.
+    ///
+    /// This is desugared into a `match` before type checking the function body.
+    Try(Box<Expression>),
+    /// An `asm` block used as a value `asm -> float { cvtsi2sd (a), %xmm0 }`.
+    ///
+    /// The value is read from `%xmm0` for a `float` and `%rax` for anything else.
+    InlineAsm { asm: AsmBlock, ty: Type },
 }
 
 impl Spany for Expr {}
@@ -213,6 +272,7 @@ impl Expr {
                 *ident.segs.last().unwrap()
             }
             Expr::FieldAccess { lhs, .. } => lhs.val.debug_ident(),
+            Expr::Cast { expr, .. } | Expr::Try(expr) => expr.val.debug_ident(),
             Expr::StructInit { .. }
             | Expr::EnumInit { .. }
             | Expr::Urnary { .. }
@@ -220,6 +280,9 @@ impl Expr {
             | Expr::Parens(..)
             | Expr::ArrayInit { .. }
             | Expr::Builtin(..)
+            | Expr::If { .. }
+            | Expr::Match { .. }
+            | Expr::InlineAsm { .. }
             | Expr::Value(..) => Ident::new(DUMMY, "invalid expression identifier"),
         }
     }
@@ -246,6 +309,11 @@ impl Expr {
             | Expr::Parens(..)
             | Expr::ArrayInit { .. }
             | Expr::Builtin(..)
+            | Expr::If { .. }
+            | Expr::Match { .. }
+            | Expr::Cast { .. }
+            | Expr::Try(..)
+            | Expr::InlineAsm { .. }
             | Expr::Value(..) => todo!(),
         }
     }
@@ -272,6 +340,7 @@ impl Expr {
                 ty: box items[0].val.type_of().unwrap().into_spanned(DUMMY),
             }),
             Expr::Value(v) => Some(v.val.to_type()),
+            Expr::Cast { ty, .. } | Expr::InlineAsm { ty, .. } => Some(ty.val.clone()),
             _ => None,
         }
     }
@@ -300,6 +369,14 @@ impl Expr {
             }
             Expr::ArrayInit { items } => items.iter().any(|e| e.val.has_bottom_type()),
             Expr::Builtin(Builtin::Bottom) => true,
+            // Like the statement versions only the condition counts, a diverging branch is
+            // coerced to the type of the other branches
+            Expr::If { cond, .. } => cond.val.has_bottom_type(),
+            Expr::Match { expr, .. } => expr.val.has_bottom_type(),
+            Expr::Cast { expr, .. } | Expr::Try(expr) => expr.val.has_bottom_type(),
+            Expr::InlineAsm { asm, .. } => {
+                asm.inputs.iter().any(|op| op.expr.val.has_bottom_type())
+            }
             Expr::Value(..) | Expr::Ident(..) | Expr::Builtin(..) => false,
         }
     }
@@ -308,13 +385,15 @@ impl Expr {
 #[derive(Clone, Debug, Eq)]
 pub struct Path {
     pub segs: Vec<Ident>,
+    /// The type arguments of a path used as a type `option<int>`, empty anywhere else.
+    pub gen: Vec<Type>,
     pub span: Range,
 }
 
 impl Path {
     // This will use a `DUMMY` span. DO NOT USE until after type checking.
     crate fn single(seg: Ident) -> Self {
-        Self { segs: vec![seg], span: DUMMY }
+        Self { segs: vec![seg], gen: vec![], span: DUMMY }
     }
 
     /// Return the file local identifier for this declaration.
@@ -345,14 +424,128 @@ impl fmt::Display for Path {
     }
 }
 
+/// The fixed width integer types `i8` through `u64`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IntTy {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl IntTy {
+    /// Parse the name of the type, used for both type names and literal suffixes `10u8`.
+    crate fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "i8" => IntTy::I8,
+            "i16" => IntTy::I16,
+            "i32" => IntTy::I32,
+            "i64" => IntTy::I64,
+            "u8" => IntTy::U8,
+            "u16" => IntTy::U16,
+            "u32" => IntTy::U32,
+            "u64" => IntTy::U64,
+            _ => return None,
+        })
+    }
+
+    crate fn name(&self) -> &'static str {
+        match self {
+            IntTy::I8 => "i8",
+            IntTy::I16 => "i16",
+            IntTy::I32 => "i32",
+            IntTy::I64 => "i64",
+            IntTy::U8 => "u8",
+            IntTy::U16 => "u16",
+            IntTy::U32 => "u32",
+            IntTy::U64 => "u64",
+        }
+    }
+
+    /// The size of the type in bytes.
+    crate fn size(&self) -> usize {
+        match self {
+            IntTy::I8 | IntTy::U8 => 1,
+            IntTy::I16 | IntTy::U16 => 2,
+            IntTy::I32 | IntTy::U32 => 4,
+            IntTy::I64 | IntTy::U64 => 8,
+        }
+    }
+
+    crate fn is_signed(&self) -> bool {
+        matches!(self, IntTy::I8 | IntTy::I16 | IntTy::I32 | IntTy::I64)
+    }
+
+    crate fn min(&self) -> i128 {
+        if self.is_signed() {
+            -(1 << (self.size() * 8 - 1))
+        } else {
+            0
+        }
+    }
+
+    crate fn max(&self) -> i128 {
+        if self.is_signed() {
+            (1 << (self.size() * 8 - 1)) - 1
+        } else {
+            (1 << (self.size() * 8)) - 1
+        }
+    }
+
+    /// If `val` can be represented by this type without wrapping.
+    crate fn fits(&self, val: i128) -> bool {
+        self.min() <= val && val <= self.max()
+    }
+
+    /// Truncate `val` to the width of the type, the bits are stored sign extended for signed types
+    /// and zero extended for unsigned types (a `u64` above `isize::MAX` is stored negative).
+    crate fn wrap(&self, val: i128) -> isize {
+        let bits = self.size() * 8;
+        let trunc = (val as u64) & (u64::MAX >> (64 - bits));
+        if self.is_signed() && bits < 64 && trunc >> (bits - 1) == 1 {
+            (trunc | !(u64::MAX >> (64 - bits))) as isize
+        } else {
+            trunc as isize
+        }
+    }
+
+    /// The value a stored (wrapped) `val` represents.
+    crate fn widen(&self, val: isize) -> i128 {
+        if self.is_signed() {
+            val as i128
+        } else {
+            val as u64 as i128
+        }
+    }
+}
+
+impl fmt::Display for IntTy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Ty {
     /// A generic type parameter `<T>`.
     ///
     /// N.B. This may be used as a type argument but should not be.
-    Generic { ident: Ident, bound: Option<Path> },
+    Generic { ident: Ident, bounds: Vec<Path> },
     /// A static array of `size` containing item of `ty`.
     Array { size: usize, ty: Box<Type> },
+    /// A static array whose size is a const expression `[LEN * 2; int]`.
+    ///
+    /// Type checking evaluates the size and turns this into a `Ty::Array`, only a size that is a
+    /// const generic parameter `[N; int]` is left until monomorphization.
+    ConstArray { size: Box<Expression>, ty: Box<Type> },
+    /// A view of a run of `ty` whose length is only known at runtime `[str]`.
+    Slice(Box<Type>),
+    /// The value given to a const generic parameter `sum::<4>(arr)`.
+    ConstArg(usize),
     /// A struct defined by the user.
     ///
     /// The `ident` is the name of the "type" and there are 'gen' generics.
@@ -379,6 +572,10 @@ pub enum Ty {
     ConstStr(usize),
     /// A positive or negative number.
     Int,
+    /// An integer with a fixed width and signedness `u8`, `i32`.
+    ///
+    /// There are no implicit conversions between these and `int`.
+    SizedInt(IntTy),
     /// An ascii character.
     ///
     /// todo: Could be bound to between 0-255
@@ -413,16 +610,21 @@ impl Ty {
         match self {
             Ty::Generic { ident, .. } => vec![ident],
             Ty::Array { ty, .. } => ty.val.generics(),
+            Ty::ConstArray { size, ty } => match &size.val {
+                Expr::Ident(ident) => std::iter::once(ident).chain(ty.val.generics()).collect(),
+                _ => ty.val.generics(),
+            },
             Ty::Struct { gen, .. } => gen.iter().flat_map(|t| t.val.generics()).collect(),
             Ty::Enum { gen, .. } => gen.iter().flat_map(|t| t.val.generics()).collect(),
-            Ty::Ptr(ty) => ty.val.generics(),
-            Ty::Ref(ty) => ty.val.generics(),
+            Ty::Ptr(ty) | Ty::Ref(ty) | Ty::Slice(ty) => ty.val.generics(),
             Ty::Func { ret, params, .. } => {
                 params.iter().flat_map(|p| p.generics()).chain(ret.generics()).collect()
             }
             Ty::Path(p) => todo!("{}", p),
             Ty::ConstStr(..)
+            | Ty::ConstArg(..)
             | Ty::Int
+            | Ty::SizedInt(..)
             | Ty::Char
             | Ty::Float
             | Ty::Bool
@@ -438,16 +640,19 @@ impl Ty {
         match self {
             Ty::Generic { .. } => true,
             Ty::Array { ty, .. } => ty.val.has_generics(),
+            // Any size left unevaluated is a const generic parameter
+            Ty::ConstArray { .. } => true,
             Ty::Struct { gen, .. } => !gen.is_empty(),
             Ty::Enum { gen, .. } => !gen.is_empty(),
-            Ty::Ptr(ty) => ty.val.has_generics(),
-            Ty::Ref(ty) => ty.val.has_generics(),
+            Ty::Ptr(ty) | Ty::Ref(ty) | Ty::Slice(ty) => ty.val.has_generics(),
             Ty::Func { ret, params, .. } => {
                 ret.has_generics() | params.iter().any(|t| t.has_generics())
             }
             Ty::Path(_) => false,
             Ty::ConstStr(..)
+            | Ty::ConstArg(..)
             | Ty::Int
+            | Ty::SizedInt(..)
             | Ty::Char
             | Ty::Float
             | Ty::Bool
@@ -463,6 +668,14 @@ impl Ty {
                 *t = subs.clone();
             }
             Ty::Array { size: _, ty } => ty.val.subst_generic(generic, subs),
+            Ty::ConstArray { size, ty } => {
+                ty.val.subst_generic(generic, subs);
+                if let (Expr::Ident(id), Ty::ConstArg(size)) = (&size.val, subs) {
+                    if *id == generic {
+                        *self = Ty::Array { size: *size, ty: ty.clone() };
+                    }
+                }
+            }
             Ty::Struct { ident: _, gen } => {
                 for t in gen {
                     t.val.subst_generic(generic, subs)
@@ -473,8 +686,7 @@ impl Ty {
                     t.val.subst_generic(generic, subs)
                 }
             }
-            Ty::Ptr(ty) => ty.val.subst_generic(generic, subs),
-            Ty::Ref(ty) => ty.val.subst_generic(generic, subs),
+            Ty::Ptr(ty) | Ty::Ref(ty) | Ty::Slice(ty) => ty.val.subst_generic(generic, subs),
             Ty::Func { ident: _, ret: _, params: _ } => {
                 todo!()
             }
@@ -515,7 +727,7 @@ impl Ty {
     crate fn array_dim(&self) -> usize {
         let mut dim = 0;
         let mut new = self;
-        while let Ty::Array { ty, .. } = new {
+        while let Ty::Array { ty, .. } | Ty::Slice(ty) = new {
             new = &ty.val;
             dim += 1;
         }
@@ -530,18 +742,39 @@ impl Ty {
     ) -> Option<Self> {
         let mut new = self.clone();
         for expr in exprs {
-            if let Ty::Array { ty, ref size } = &new {
-                if let Expr::Value(Spanned { val: Val::Int(i), .. }) = &expr.val {
-                    if i >= &(*size as isize) {
+            if let Ty::ConstArray { ty, .. } = &new {
+                new = ty.val.clone();
+            } else if let Ty::Array { ty, ref size } = &new {
+                // Only constant indexes are checked here, codegen checks the rest at runtime
+                if let Some(idx) = const_eval::eval_index(tcxt, expr) {
+                    if idx < 0 || idx >= *size as isize {
                         tcxt.errors.push_error(Error::error_with_span(
                             tcxt,
                             span,
-                            "out of bound of static array",
+                            &format!(
+                                "[E0ty] index out of bounds: the length is {} but the index is {}",
+                                size, idx
+                            ),
                         ));
                         tcxt.errors.poisoned(true);
                     }
                 }
                 new = ty.val.clone();
+            } else if let Ty::Slice(ty) = &new {
+                // The length of a slice is only known at runtime
+                if exprs.len() > 1 {
+                    tcxt.errors.push_error(Error::error_with_span(
+                        tcxt,
+                        span,
+                        &format!(
+                            "[E0ty] a slice can only be indexed once, found {} indexes",
+                            exprs.len()
+                        ),
+                    ));
+                    tcxt.errors.poisoned(true);
+                    return None;
+                }
+                new = ty.val.clone();
             } else {
                 break;
             }
@@ -560,6 +793,12 @@ impl fmt::Display for Ty {
                     write!(f, "{}[{}]", ty.val, size)
                 }
             }
+            Ty::ConstArray { size, ty } => match &size.val {
+                Expr::Ident(ident) => write!(f, "{}[{}]", ty.val, ident),
+                _ => write!(f, "{}[..]", ty.val),
+            },
+            Ty::Slice(ty) => write!(f, "[{}]", ty.val),
+            Ty::ConstArg(val) => write!(f, "{}", val),
             Ty::Generic { ident, .. } => write!(f, "<{}>", ident),
             Ty::Struct { ident, gen, .. } => write!(
                 f,
@@ -594,6 +833,7 @@ impl fmt::Display for Ty {
             }
             Ty::ConstStr(..) => write!(f, "string"),
             Ty::Int => write!(f, "int"),
+            Ty::SizedInt(ity) => write!(f, "{}", ity),
             Ty::Char => write!(f, "char"),
             Ty::Float => write!(f, "float"),
             Ty::Bool => write!(f, "bool"),
@@ -610,6 +850,22 @@ impl fmt::Display for Ty {
     }
 }
 
+/// The part of the name of a specialized function that comes from the type `ty`.
+///
+/// Only characters an assembler accepts in a label are kept, `foo` specialized for
+/// `struct vec<&int>` is named `foostructvec_pint_`.
+crate fn mangle_ty(ty: &impl fmt::Display) -> String {
+    ty.to_string()
+        .chars()
+        .filter(|c| *c != ' ')
+        .map(|c| match c {
+            '&' => 'p',
+            c if c.is_alphanumeric() => c,
+            _ => '_',
+        })
+        .collect()
+}
+
 impl Spany for Ty {}
 
 impl TypeEquality for Ty {
@@ -619,6 +875,11 @@ impl TypeEquality for Ty {
             (Ty::Array { size: s1, ty: t1 }, Ty::Array { size: s2, ty: t2 }) => {
                 s1.eq(s2) && t1.is_ty_eq(t2)
             }
+            (Ty::ConstArray { size: s1, ty: t1 }, Ty::ConstArray { size: s2, ty: t2 }) => {
+                s1.val.eq(&s2.val) && t1.is_ty_eq(t2)
+            }
+            (Ty::ConstArg(v1), Ty::ConstArg(v2)) => v1.eq(v2),
+            (Ty::SizedInt(i1), Ty::SizedInt(i2)) => i1.eq(i2),
             // TODO: generic comparison
             (Ty::Struct { ident: n1, .. }, Ty::Struct { ident: n2, .. }) => n1 == n2,
             (Ty::Enum { ident: n1, gen: g1 }, Ty::Enum { ident: n2, gen: g2 }) => {
@@ -626,6 +887,7 @@ impl TypeEquality for Ty {
             }
             (Ty::Ptr(t1), Ty::Ptr(t2)) => t1.val.is_ty_eq(&t2.val),
             (Ty::Ref(t1), Ty::Ref(t2)) => t1.val.is_ty_eq(&t2.val),
+            (Ty::Slice(t1), Ty::Slice(t2)) => t1.val.is_ty_eq(&t2.val),
             // TODO: we don't want/need the size to be ==
             (Ty::ConstStr(..), Ty::ConstStr(..))
             | (Ty::Int, Ty::Int)
@@ -647,7 +909,7 @@ impl TypeEquality for Ty {
 impl TypeEquality<Ty> for Generic {
     fn is_ty_eq(&self, other: &Ty) -> bool {
         match other {
-            Ty::Generic { ident, bound } => self.ident.eq(ident) && self.bound.eq(bound),
+            Ty::Generic { ident, bounds } => self.ident.eq(ident) && self.bounds.eq(bounds),
             _ => false,
         }
     }
@@ -663,15 +925,36 @@ pub struct Param {
 #[derive(Clone, Debug)]
 pub struct Block {
     pub stmts: RawVec<Statement>,
+    /// The value of the block, the last expression when it is not followed by a `;`.
+    ///
+    /// Only the blocks of `if` and `match` expressions have one.
+    pub tail: Option<Box<Expression>>,
     pub span: Range,
 }
 
+// Blocks and match arms can be part of an `Expr` which is hashed to find it's type, no two blocks
+// take up the same span
+impl Hash for Block {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.span.hash(state);
+    }
+}
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        self.span.eq(&other.span)
+    }
+}
+impl Eq for Block {}
+
 impl fmt::Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "}}")?;
         for _stmt in self.stmts.iter() {
             write!(f, "..;")?;
         }
+        if self.tail.is_some() {
+            write!(f, "..")?;
+        }
         write!(f, "}}")
     }
 }
@@ -686,11 +969,17 @@ impl fmt::Display for Binding {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Wild(id) => write!(f, "{}", id),
-            Self::Value(id) => write!(f, "{}", id),
+            Self::Value(val) => write!(f, "{}", val.val),
         }
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct FieldPat {
+    pub ident: Ident,
+    pub pat: Pattern,
+}
+
 #[derive(Clone, Debug)]
 pub enum Pat {
     /// Match an enum variant `option::some(bind)`
@@ -703,7 +992,29 @@ pub enum Pat {
         size: usize,
         items: Vec<Pattern>,
     },
+    /// Match a struct by it's fields `point { x: 0, y, .. }`.
+    ///
+    /// A field without a pattern binds the field by name, `rest` is true if `..` was given.
+    Struct {
+        path: Path,
+        fields: Vec<FieldPat>,
+        rest: bool,
+    },
+    /// An inclusive range of ints or chars `1..=9`.
+    Range {
+        start: Value,
+        end: Value,
+    },
+    /// Match if any of the alternatives match `a | b`.
+    Or(Vec<Pattern>),
+    /// Bind the matched value while also matching it `x @ 1..=9`.
+    At {
+        ident: Ident,
+        pat: Box<Pattern>,
+    },
     Bind(Binding),
+    /// Matches anything and binds nothing `_`.
+    Wild,
 }
 
 impl Spany for Pat {}
@@ -730,7 +1041,25 @@ impl fmt::Display for Pat {
                 "[{}]",
                 items.iter().map(|b| b.val.to_string()).collect::<Vec<_>>().join(", ")
             ),
+            Self::Struct { path, fields, rest } => {
+                let mut fields = fields
+                    .iter()
+                    .map(|f| format!("{}: {}", f.ident, f.pat.val))
+                    .collect::<Vec<_>>();
+                if *rest {
+                    fields.push("..".to_owned());
+                }
+                write!(f, "{} {{ {} }}", path, fields.join(", "))
+            }
+            Self::Range { start, end } => write!(f, "{}..={}", start.val, end.val),
+            Self::Or(alts) => write!(
+                f,
+                "{}",
+                alts.iter().map(|p| p.val.to_string()).collect::<Vec<_>>().join(" | ")
+            ),
+            Self::At { ident, pat } => write!(f, "{} @ {}", ident, pat.val),
             Self::Bind(b) => write!(f, "{}", b),
+            Self::Wild => write!(f, "_"),
         }
     }
 }
@@ -738,13 +1067,31 @@ impl fmt::Display for Pat {
 #[derive(Clone, Debug)]
 pub struct MatchArm {
     pub pat: Pattern,
+    /// An optional `if expr` that must also be true for the arm to match.
+    pub guard: Option<Expression>,
     pub blk: Block,
     pub span: Range,
 }
 
+impl Hash for MatchArm {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.span.hash(state);
+    }
+}
+impl PartialEq for MatchArm {
+    fn eq(&self, other: &Self) -> bool {
+        self.span.eq(&other.span)
+    }
+}
+impl Eq for MatchArm {}
+
 impl fmt::Display for MatchArm {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} => {}", self.pat.val, self.blk)
+        if self.guard.is_some() {
+            write!(f, "{} if .. => {}", self.pat.val, self.blk)
+        } else {
+            write!(f, "{} => {}", self.pat.val, self.blk)
+        }
     }
 }
 
@@ -754,11 +1101,10 @@ pub enum Location {
     Register(asm::Register),
     /// A float register `xmmN` where N is 0-7.
     FloatReg(asm::FloatRegister),
-    /// A named offset, this would be odd since you shouldn't know the names of locations in a
-    /// binary before compilation??
+    /// The address of the function `name(%rip)`.
     NamedOffset(Ident),
-    /// A numbered offset, this would also be odd, although if less so (structs, arrays, etc.).
-    Offset { amt: usize, reg: Box<Location> },
+    /// The memory a register plus a displacement points to `8(%rdi)`.
+    Offset { amt: isize, reg: Box<Location> },
     /// A variable referenced from the `AsmBlock`s parent scope.
     ///
     /// Any variable declared that is in scope where the assembly block is can be used.
@@ -772,14 +1118,55 @@ pub struct Instruction {
     pub inst: Ident,
     pub src: Option<Location>,
     pub dst: Option<Location>,
+    pub span: Range,
 }
 
+/// A register bound to a value for an `asm` block `%rdi = fd`.
 #[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmOperand {
+    /// A general purpose or float register.
+    pub reg: Location,
+    /// The value moved into `reg` before the block or the variable `reg` is moved to after it.
+    pub expr: Expression,
+}
+
+#[derive(Clone, Debug)]
 pub struct AsmBlock {
     pub assembly: Vec<Instruction>,
+    /// The values moved into registers before the block runs `in(%rdi = fd)`.
+    pub inputs: Vec<AsmOperand>,
+    /// The variables registers are moved to after the block runs `out(%rax = written)`.
+    pub outputs: Vec<AsmOperand>,
+    /// The registers the block changes that are not outputs `clobber(%rcx, %r11)`.
+    pub clobbers: Vec<Location>,
     pub span: Range,
 }
 
+// An `asm` expression is hashed to find it's type like `Block`
+impl Hash for AsmBlock {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.span.hash(state);
+    }
+}
+impl PartialEq for AsmBlock {
+    fn eq(&self, other: &Self) -> bool {
+        self.span.eq(&other.span)
+    }
+}
+impl Eq for AsmBlock {}
+
+impl AsmBlock {
+    /// The register an `asm` expression of type `ty` leaves it's value in, like the value a
+    /// function returns.
+    crate fn result_reg(ty: &Ty) -> Location {
+        if let Ty::Float = ty {
+            Location::FloatReg(asm::FloatRegister::XMM0)
+        } else {
+            Location::Register(asm::Register::RAX)
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Builtin {
     /// The bottom type which is covariant over all types.
@@ -787,13 +1174,39 @@ pub enum Builtin {
     // TODO: these will have to be expr's also then
     /// The type of operator
     SizeOf(RawPtr<Type>),
+    /// The alignment of a type in bytes.
+    AlignOf(RawPtr<Type>),
+    /// The offset in bytes of a field from the start of a struct.
+    OffsetOf(RawPtr<Type>, Ident),
+    /// The name of a type, generic types are named after monomorphization.
+    TypeName(RawPtr<Type>),
+    /// Fail to compile with the message if the condition is false, generic functions are checked
+    /// for each specialization.
+    StaticAssert(Box<Expression>, Ident),
+    /// The number of items in an array or slice.
+    Len(Box<Expression>),
+    /// The line number the builtin is written on.
+    Line,
+    /// The path of the file the builtin is written in.
+    File,
+    /// The `file:line` a function was called from, the function is given this as a hidden
+    /// argument.
+    CallerLocation,
 }
 
 impl fmt::Display for Builtin {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Builtin::Bottom => "@bottom".fmt(f),
-            Builtin::SizeOf(t) => write!(f, "size_of::<{}>", t.get().val),
+            Builtin::SizeOf(t) => write!(f, "@size_of::<{}>", t.get().val),
+            Builtin::AlignOf(t) => write!(f, "@align_of::<{}>", t.get().val),
+            Builtin::OffsetOf(t, field) => write!(f, "@offset_of::<{}>({})", t.get().val, field),
+            Builtin::TypeName(t) => write!(f, "@type_name::<{}>", t.get().val),
+            Builtin::StaticAssert(_, msg) => write!(f, "@static_assert(.., {:?})", msg),
+            Builtin::Len(..) => "@len(..)".fmt(f),
+            Builtin::Line => "@line".fmt(f),
+            Builtin::File => "@file".fmt(f),
+            Builtin::CallerLocation => "@caller_location".fmt(f),
         }
     }
 }
@@ -802,7 +1215,24 @@ impl Builtin {
     crate fn type_of(&self) -> Ty {
         match self {
             Builtin::Bottom => Ty::Bottom,
-            Builtin::SizeOf(..) => Ty::Int,
+            Builtin::SizeOf(..)
+            | Builtin::AlignOf(..)
+            | Builtin::OffsetOf(..)
+            | Builtin::Len(..)
+            | Builtin::Line => Ty::Int,
+            Builtin::TypeName(..) | Builtin::File | Builtin::CallerLocation => Ty::ConstStr(0),
+            Builtin::StaticAssert(..) => Ty::Void,
+        }
+    }
+
+    /// The type a builtin like `@size_of::<T>` asks about.
+    crate fn type_arg(&self) -> Option<&RawPtr<Type>> {
+        match self {
+            Builtin::SizeOf(t)
+            | Builtin::AlignOf(t)
+            | Builtin::OffsetOf(t, _)
+            | Builtin::TypeName(t) => Some(t),
+            _ => None,
         }
     }
 }
@@ -823,6 +1253,8 @@ pub enum Stmt {
     If { cond: Expression, blk: Block, els: Option<Block> },
     /// While loop `while (expr) { stmts }`
     While { cond: Expression, blk: Block },
+    /// Break out of the enclosing loop `break`.
+    Break,
     /// A match statement `match expr { variant1 => { stmts }, variant2 => { stmts } }`.
     Match { expr: Expression, arms: Vec<MatchArm> },
     /// Inline assembly block.
@@ -838,6 +1270,8 @@ pub enum Stmt {
     Builtin(Builtin),
     /// A block of statements `{ stmts }`
     Block(Block),
+    /// Run a statement when the enclosing block is left `defer stmt;`.
+    Defer(Box<Statement>),
 }
 
 impl Stmt {
@@ -854,8 +1288,10 @@ impl Stmt {
             Stmt::Builtin(Builtin::Bottom) => true,
             Stmt::InlineAsm(_)
             | Stmt::Exit
+            | Stmt::Break
             | Stmt::Const(_)
             | Stmt::Block(_)
+            | Stmt::Defer(_)
             | Stmt::Builtin(_) => false,
         }
     }
@@ -915,13 +1351,15 @@ impl Adt {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Generic {
     pub ident: Ident,
-    pub bound: Option<Path>,
+    pub bounds: Vec<Path>,
+    /// A const generic parameter `const N: int`, the argument is a value not a type.
+    pub is_const: bool,
     pub span: Range,
 }
 
 impl Generic {
     crate fn to_type(&self) -> Ty {
-        Ty::Generic { ident: self.ident, bound: self.bound.clone() }
+        Ty::Generic { ident: self.ident, bounds: self.bounds.clone() }
     }
 }
 
@@ -935,6 +1373,14 @@ pub enum FuncKind {
     /// }
     /// ```
     Normal,
+    /// A function that can be evaluated at compile time.
+    ///
+    /// ```
+    /// const fn square(n: int): int {
+    ///     return n * n;
+    /// }
+    /// ```
+    Const,
     /// A linked function, one with no body that is linked dynamically.
     Linked,
     // TODO: which keyword is better
@@ -973,7 +1419,7 @@ impl Default for Func {
             ident: Ident::dummy(),
             generics: vec![],
             params: vec![],
-            stmts: Block { stmts: crate::raw_vec![], span: DUMMY },
+            stmts: Block { stmts: crate::raw_vec![], tail: None, span: DUMMY },
             kind: FuncKind::Normal,
             span: DUMMY,
         }
@@ -14,6 +14,8 @@ crate struct ErrorReport<'input> {
     // TODO: SHIT this is ugly
     /// Errors collected during parsing and type checking.
     errors: RwLock<Vec<Error<'input>>>,
+    /// Warnings collected during type checking, these never stop compilation.
+    warnings: RwLock<Vec<Error<'input>>>,
     error_in_current_expr_tree: Cell<bool>,
 }
 
@@ -26,6 +28,13 @@ impl<'input> ErrorReport<'input> {
         }
     }
 
+    crate fn push_warning(&self, mut e: Error<'input>) {
+        e.level = Level::Warning;
+        let mut list = self.warnings.write();
+        list.push(e);
+        list.sort_by(|a, b| a.span.cmp(&b.span))
+    }
+
     crate fn is_poisoned(&self) -> bool {
         self.error_in_current_expr_tree.get()
     }
@@ -41,6 +50,16 @@ impl<'input> ErrorReport<'input> {
     crate fn errors(&self) -> RwLockReadGuard<'_, Vec<Error<'input>>> {
         self.errors.read()
     }
+
+    crate fn warnings(&self) -> RwLockReadGuard<'_, Vec<Error<'input>>> {
+        self.warnings.read()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+crate enum Level {
+    Error,
+    Warning,
 }
 
 #[derive(Debug)]
@@ -50,6 +69,7 @@ crate struct Error<'input> {
     crate span: Range,
     msg: String,
     help: Option<String>,
+    level: Level,
 }
 
 impl<'input> Error<'input> {
@@ -60,6 +80,7 @@ impl<'input> Error<'input> {
             span,
             msg: msg.to_owned(),
             help: None,
+            level: Level::Error,
         }
     }
 
@@ -69,7 +90,7 @@ impl<'input> Error<'input> {
         span: Range,
         msg: &str,
     ) -> Self {
-        Self { name, input, span, msg: msg.to_owned(), help: None }
+        Self { name, input, span, msg: msg.to_owned(), help: None, level: Level::Error }
     }
 }
 
@@ -80,7 +101,10 @@ impl fmt::Display for Error<'_> {
         write!(
             f,
             "{}{}\n  --> {}:{}:{}\n{}{}",
-            colorize(Color::Red, "Error: ")?,
+            match self.level {
+                Level::Error => colorize(Color::Red, "Error: ")?,
+                Level::Warning => colorize(Color::Yellow, "Warning: ")?,
+            },
             self.msg,
             self.name,
             row,
@@ -1,5 +1,5 @@
 use std::{
-    fs::{create_dir_all, OpenOptions},
+    fs::{create_dir_all, File, OpenOptions},
     io::{ErrorKind, Write},
     path::Path,
     vec,
@@ -13,20 +13,30 @@ use crate::{
         types::{self as ty, FuncKind},
     },
     data_struc::str_help::StripEscape,
-    gen::asm::inst::{
-        CondFlag, FloatRegister, Global, Instruction, JmpCond, Location, Register, ARG_FLOAT_REGS,
-        ARG_REGS, USABLE_FLOAT_REGS, USABLE_REGS,
+    gen::asm::{
+        abi::{ArgLoc, Pass},
+        encode::Assembler,
+        inst::{
+            CondFlag, FloatRegister, Global, Instruction, JmpCond, Location, Register,
+            ARG_FLOAT_REGS, ARG_REGS, USABLE_FLOAT_REGS, USABLE_REGS,
+        },
+        regalloc::{AsmClobbers, StackSlot, CALLEE_SAVED},
     },
     lir::{
         lower::{
-            BinOp, Binding, Builtin, CallExpr, Const, Expr, FieldInit, Func, LValue, MatchArm, Pat,
-            Stmt, Struct, Ty, UnOp, Val,
+            AsmBlock, BinOp, Binding, Block, Builtin, CallExpr, Const, Expr, FieldInit, Func,
+            LValue, MatchArm, Pat, SrcLoc, Stmt, Struct, Ty, UnOp, Val,
         },
         visit::Visit,
     },
 };
 
+crate mod abi;
+crate mod elf;
+crate mod encode;
 crate mod inst;
+crate mod peephole;
+crate mod regalloc;
 
 const STATIC_PREAMBLE: &str = r#"
 .text
@@ -35,6 +45,55 @@ const STATIC_PREAMBLE: &str = r#"
 .bool_false: .string "false"
 .bool_test: .quad 1"#;
 
+/// The entry point of a `--freestanding` program, calls `main` with `argc` and `argv` from the top
+/// of the stack and exits with what it returns.
+const START: &str = r#"
+.global _start
+_start:
+    xorq %rbp, %rbp
+    movq (%rsp), %rdi
+    leaq 8(%rsp), %rsi
+    call main
+    movq %rax, %rdi
+    movq $60, %rax
+    syscall"#;
+
+/// Called by a failed bounds check with the index in `%rdi` and the message in `%rsi` and `%rdx`,
+/// writes the message then the index to stderr and aborts.
+const BOUNDS_FAIL: &str = r#"
+.bounds_fail:
+    movq %rdi, %r8
+    movq $2, %rdi
+    movq $1, %rax
+    syscall
+    subq $32, %rsp
+    leaq 31(%rsp), %rsi
+    movb $10, (%rsi)
+    movq %r8, %rax
+    movq $10, %rcx
+    testq %rax, %rax
+    jns .bounds_fail_digit
+    negq %rax
+.bounds_fail_digit:
+    xorq %rdx, %rdx
+    divq %rcx
+    addb $48, %dl
+    decq %rsi
+    movb %dl, (%rsi)
+    testq %rax, %rax
+    jnz .bounds_fail_digit
+    testq %r8, %r8
+    jns .bounds_fail_write
+    decq %rsi
+    movb $45, (%rsi)
+.bounds_fail_write:
+    leaq 32(%rsp), %rdx
+    subq %rsi, %rdx
+    movq $2, %rdi
+    movq $1, %rax
+    syscall
+    ud2"#;
+
 const ZERO: Location = Location::Const { val: Val::Int(0) };
 const ONE: Location = Location::Const { val: Val::Int(1) };
 
@@ -42,9 +101,21 @@ const RAX: Location = Location::Register(Register::RAX);
 const RSP: Location = Location::Register(Register::RSP);
 const RBP: Location = Location::Register(Register::RBP);
 const RDX: Location = Location::Register(Register::RDX);
+const RCX: Location = Location::Register(Register::RCX);
+/// The 8 bytes at the top of the stack `(%rsp)`.
+const STACK_TOP: Location = Location::NumberedOffset { offset: 0, reg: Register::RSP };
 
 const XMM0: Location = Location::FloatReg(FloatRegister::XMM0);
 
+/// Where a failed runtime check jumps to, emitted after the function the check is in.
+#[derive(Debug)]
+struct Trap {
+    label: String,
+    msg: String,
+    /// The register holding an out of bounds index, it is written after `msg`.
+    index: Option<Register>,
+}
+
 #[derive(Clone, Copy, Debug)]
 enum CanClearRegs {
     Yes,
@@ -60,12 +131,43 @@ crate struct CodeGen<'ctx> {
     current_stack: usize,
     total_stack: usize,
     current_fn_params: HashSet<Ident>,
+    /// The variables of the current function that hold a slice, indexing one checks the length
+    /// stored with it.
+    slices: HashSet<Ident>,
+    /// The variables of the current function that can be moved to a register.
+    stack_slots: Vec<StackSlot>,
+    /// The registers each `asm` block in the current function clobbers.
+    asm_clobbers: Vec<AsmClobbers>,
+    /// The space each call in the current function that returns through memory returns to, or
+    /// that a value returned in two registers is stored to, by the span of the call.
+    ret_temps: HashMap<ty::Range, Location>,
+    /// The slot holding the address the current function returns it's value to.
+    sret_ptr: Option<Location>,
     vars: HashMap<Ident, Location>,
+    /// Used to name labels that can be created without any instructions between them, most
+    /// labels use the length of `asm_buf`.
+    label_count: usize,
+    /// Check integer arithmetic for overflow, `--overflow-checks`.
+    overflow_checks: bool,
+    /// Check dynamic array indexes against the length of the array, `--unchecked-indexing`
+    /// turns this off.
+    bounds_checks: bool,
+    /// Emit a `_start` entry point so the program links without libc, `--freestanding`.
+    freestanding: bool,
+    /// The traps of each overflow and bounds check in the current function.
+    traps: Vec<Trap>,
+    /// The label after each loop around the current statement, `break` jumps to the last one.
+    loop_ends: Vec<String>,
     path: &'ctx Path,
 }
 
 impl<'ctx> CodeGen<'ctx> {
-    crate fn new(path: &'ctx Path) -> CodeGen<'ctx> {
+    crate fn new(
+        path: &'ctx Path,
+        overflow_checks: bool,
+        bounds_checks: bool,
+        freestanding: bool,
+    ) -> CodeGen<'ctx> {
         Self {
             asm_buf: vec![],
             globals: HashMap::default(),
@@ -74,7 +176,18 @@ impl<'ctx> CodeGen<'ctx> {
             current_stack: 0,
             total_stack: 0,
             current_fn_params: HashSet::default(),
+            slices: HashSet::default(),
+            stack_slots: vec![],
+            asm_clobbers: vec![],
+            ret_temps: HashMap::default(),
+            sret_ptr: None,
             vars: HashMap::default(),
+            label_count: 0,
+            overflow_checks,
+            bounds_checks,
+            freestanding,
+            traps: vec![],
+            loop_ends: vec![],
             path,
         }
     }
@@ -87,7 +200,8 @@ impl<'ctx> CodeGen<'ctx> {
         const COMMENT: usize = 29;
         let mnemonic_from = |size: usize| match size {
             1 => "b",
-            4 => "q", // TODO: everything is 64 bits
+            2 => "w",
+            4 => "l",
             8 => "q",
             _ => "too_big_break",
             // _ => unreachable!("larger than 8 bytes isn't valid to move in one go"),
@@ -122,10 +236,26 @@ impl<'ctx> CodeGen<'ctx> {
                     b = COMMENT
                 )
             }
-            Instruction::Call(call) => format!("    call{:>a$}", call, a = FIRST),
-            Instruction::Jmp(label) => format!("    jmp{:>a$}", label, a = FIRST),
+            // A label as long as the padding would run into the mnemonic
+            Instruction::Call { loc, .. } => format!("    call {:>a$}", loc, a = FIRST - 1),
+            Instruction::Jmp(label) => format!("    jmp {:>a$}", label, a = FIRST - 1),
             Instruction::CondJmp { loc, cond } => {
-                format!("    j{}{:>a$}", cond.to_string(), loc, a = FIRST)
+                format!("    j{} {:>a$}", cond.to_string(), loc, a = FIRST - 1)
+            }
+            Instruction::JmpTable { table, idx } => {
+                let entry = format!("({},{},4)", table, idx);
+                format!(
+                    "    movslq{:>a$},{:>b$}\n    addq{:>c$},{:>b$}\n    jmp{:>d$}",
+                    entry,
+                    idx,
+                    table,
+                    idx,
+                    format!("*{}", idx),
+                    a = FIRST - 2,
+                    b = SECOND,
+                    c = FIRST,
+                    d = FIRST + 1,
+                )
             }
             Instruction::Leave => "    leave".to_owned(),
             Instruction::Ret => "    ret".to_owned(),
@@ -148,8 +278,44 @@ impl<'ctx> CodeGen<'ctx> {
                 format!("    movsd{:a$},{:b$}", src, dst, a = FIRST, b = SECOND)
             }
             Instruction::SizedMov { src, dst, size } => {
-                let mnemonic = mnemonic_from(*size);
-                format!("    mov{}{:a$},{:b$}", mnemonic, src, dst, a = FIRST + 1, b = SECOND)
+                let (mnemonic, src, dst) = match size {
+                    // Only the bytes of the value are written to memory
+                    1 | 2 | 4 if dst.is_stack_offset() => {
+                        (mnemonic_from(*size).to_owned(), src.sized(*size), dst.to_string())
+                    }
+                    1 | 2 if src.is_stack_offset() => {
+                        (format!("z{}q", mnemonic_from(*size)), src.to_string(), dst.to_string())
+                    }
+                    // Writing the 32 bit register clears the top half
+                    4 if src.is_stack_offset() => ("l".to_owned(), src.to_string(), dst.sized(4)),
+                    1 | 2 | 4 => ("q".to_owned(), src.to_string(), dst.to_string()),
+                    _ => (mnemonic_from(*size).to_owned(), src.to_string(), dst.to_string()),
+                };
+                format!(
+                    "    mov{}{:>a$},{:>b$}",
+                    mnemonic,
+                    src,
+                    dst,
+                    a = FIRST + 2 - mnemonic.len(),
+                    b = SECOND
+                )
+            }
+            Instruction::MovExt { src, dst, size, signed } => {
+                let (mnemonic, dst) = match (size, signed) {
+                    (8, _) => ("movq".to_owned(), dst.to_string()),
+                    // Writing the 32 bit register clears the top half
+                    (4, false) => ("movl".to_owned(), dst.sized(4)),
+                    (_, true) => (format!("movs{}q", mnemonic_from(*size)), dst.to_string()),
+                    (_, false) => (format!("movz{}q", mnemonic_from(*size)), dst.to_string()),
+                };
+                format!(
+                    "    {}{:>a$},{:>b$}",
+                    mnemonic,
+                    src.sized(*size),
+                    dst,
+                    a = FIRST + 5 - mnemonic.len(),
+                    b = SECOND
+                )
             }
             Instruction::CondMov { src, dst, cond } => {
                 format!("    cmov{}{:a$},{:b$}", cond.to_string(), src, dst, a = FIRST, b = SECOND)
@@ -181,13 +347,37 @@ impl<'ctx> CodeGen<'ctx> {
                 if ops == "imul" {
                     ops = ops.replace('i', "");
                 }
-                format!("    {}ss{:a$},{:b$}", ops, src, dst, a = FIRST, b = SECOND)
+                // Floats are doubles, `cvtss2sd` widens every float constant
+                format!("    {}sd{:a$},{:b$}", ops, src, dst, a = FIRST, b = SECOND)
             }
             Instruction::Idiv(loc) => format!("    idiv{:a$}", loc, a = FIRST + 1),
+            Instruction::Div(loc) => format!("    div{:a$}", loc, a = FIRST + 2),
+            Instruction::Mul(loc) => format!("    mul{:a$}", loc, a = FIRST + 2),
+            Instruction::Shift { src, dst, op, signed } => {
+                let mnemonic = match (op, signed) {
+                    (BinOp::LeftShift, _) => "shl",
+                    (_, true) => "sar",
+                    (_, false) => "shr",
+                };
+                format!(
+                    "    {}q{:>a$},{:b$}",
+                    mnemonic,
+                    src.sized(1),
+                    dst,
+                    a = FIRST + 1,
+                    b = SECOND
+                )
+            }
             Instruction::Cvt { src, dst } => {
                 format!("    cvtss2sd{:a$},{:b$}", src, dst, a = FIRST - 3, b = SECOND)
             }
-            Instruction::Extend => format!("    cdq"),
+            Instruction::IntToFloat { src, dst } => {
+                format!("    cvtsi2sdq{:a$},{:b$}", src, dst, a = FIRST - 4, b = SECOND)
+            }
+            Instruction::FloatToInt { src, dst } => {
+                format!("    cvttsd2siq{:a$},{:b$}", src, dst, a = FIRST - 5, b = SECOND)
+            }
+            Instruction::Extend => format!("    cqo"),
         }
     }
 
@@ -226,13 +416,27 @@ impl<'ctx> CodeGen<'ctx> {
                     "    .align 32\n    .type {}, @object\n    .size {}, {}\n{}:",
                     name,
                     name,
-                    content.len() * 8,
+                    content
+                        .iter()
+                        .map(|v| if let Val::SizedInt(_, ity) = v { ity.size() } else { 8 })
+                        .sum::<usize>(),
                     name,
                 );
                 for item in content {
                     match item {
                         Val::Float(v) => writeln!(buf, "    .quad {}", v.to_bits()),
                         Val::Int(v) => writeln!(buf, "    .quad {}", v),
+                        Val::SizedInt(v, ity) => writeln!(
+                            buf,
+                            "    {} {}",
+                            match ity.size() {
+                                1 => ".byte",
+                                2 => ".short",
+                                4 => ".long",
+                                _ => ".quad",
+                            },
+                            v
+                        ),
                         Val::Char(v) => writeln!(buf, "    .quad {}", (*v) as u8),
                         Val::Bool(v) => writeln!(buf, "    .quad {}", if *v { 1 } else { 0 }),
                         Val::Str(v) => writeln!(buf, "    .string {:?}", v),
@@ -240,11 +444,35 @@ impl<'ctx> CodeGen<'ctx> {
                 }
                 writeln!(buf, ".text");
             }
+            Global::JumpTable { name, labels } => {
+                writeln!(buf, "    .section    .rodata\n    .align 4\n{}:", name);
+                for label in labels {
+                    writeln!(buf, "    .long {}-{}", label, name);
+                }
+                writeln!(buf, ".text");
+            }
         };
         buf
     }
 
-    crate fn dump_asm(&self) -> Result<(), String> {
+    /// Run the peephole optimizer over the generated program, returns how many instructions it
+    /// removed.
+    crate fn optimize(&mut self) -> usize {
+        let tables = self
+            .globals
+            .values()
+            .filter_map(|decl| match decl {
+                Global::JumpTable { labels, .. } => Some(labels),
+                _ => None,
+            })
+            .flatten()
+            .map(|l| l.as_str())
+            .collect::<Vec<_>>();
+        peephole::optimize(&mut self.asm_buf, &tables)
+    }
+
+    /// Create the file `build/<name>.<ext>` next to the source file.
+    fn build_file(&self, ext: &str) -> Result<File, String> {
         let mut build_dir = self.path.to_path_buf();
         let file = build_dir.file_name().unwrap().to_os_string();
         build_dir.pop();
@@ -257,15 +485,19 @@ impl<'ctx> CodeGen<'ctx> {
         };
 
         build_dir.push(file);
-        build_dir.set_extension("s");
+        build_dir.set_extension(ext);
 
-        let mut file = OpenOptions::new()
+        OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .truncate(true)
             .open(build_dir)
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| e.to_string())
+    }
+
+    crate fn dump_asm(&self) -> Result<(), String> {
+        let mut file = self.build_file("s")?;
 
         let globals = self
             .globals
@@ -276,20 +508,50 @@ impl<'ctx> CodeGen<'ctx> {
         let assembly =
             self.asm_buf.iter().map(|inst| self.to_asm(inst)).collect::<Vec<String>>().join("\n");
 
-        file.write_all(format!("{}\n{}\n{}\n", STATIC_PREAMBLE, globals, assembly).as_bytes())
-            .map_err(|e| e.to_string())
+        let bounds_fail = if self.bounds_checks { BOUNDS_FAIL } else { "" };
+        let start = if self.freestanding { START } else { "" };
+
+        file.write_all(
+            format!("{}\n{}\n{}\n{}\n{}\n", STATIC_PREAMBLE, globals, assembly, bounds_fail, start)
+                .as_bytes(),
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    /// Encode the program and write it as the ELF object `build/<name>.o`, the same program
+    /// `dump_asm` writes without needing an assembler.
+    crate fn dump_obj(&self) -> Result<(), String> {
+        let mut asm = Assembler::new();
+        asm.text(STATIC_PREAMBLE)?;
+        for decl in self.globals.values() {
+            asm.global(decl);
+        }
+        for inst in &self.asm_buf {
+            asm.instruction(inst)?;
+        }
+        if self.bounds_checks {
+            asm.text(BOUNDS_FAIL)?;
+        }
+        if self.freestanding {
+            asm.text(START)?;
+        }
+
+        self.build_file("o")?.write_all(&elf::object(&asm)).map_err(|e| e.to_string())
     }
 
     fn free_reg(&mut self) -> Register {
-        let reg = *USABLE_REGS.difference(&self.used_regs).next().expect("ran out of registers");
+        let reg = *USABLE_REGS
+            .iter()
+            .find(|r| !self.used_regs.contains(r))
+            .expect("ran out of registers");
         self.use_reg(reg);
         reg
     }
 
     fn free_reg_except(&mut self, reg: Register) -> Register {
         let reg = *USABLE_REGS
-            .difference(&self.used_regs)
-            .find(|r| reg != **r)
+            .iter()
+            .find(|r| reg != **r && !self.used_regs.contains(r))
             .expect("ran out of registers");
         self.use_reg(reg);
         reg
@@ -319,14 +581,258 @@ impl<'ctx> CodeGen<'ctx> {
             | Location::NamedOffsetIndex { .. }
             | Location::FloatReg(_)
             | Location::Indexable { .. }
-            | Location::NumberedOffset { .. } => {}
+            | Location::NumberedOffset { .. }
+            | Location::ArgOffset { .. } => {}
+        };
+    }
+
+    /// A sized integer narrower than a register is only ever operated on in a register where it
+    /// is sign or zero extended to 64 bits, this loads it if `loc` is in memory.
+    fn load_narrow(&mut self, loc: Location, ty: &Ty) -> Location {
+        if matches!(ty, Ty::SizedInt(ity) if ity.size() < 8) && loc.is_stack_offset() {
+            let reg = self.free_reg();
+            self.asm_buf.push(mov_value(loc, Location::Register(reg), ty, ""));
+            Location::Register(reg)
+        } else {
+            loc
+        }
+    }
+
+    /// Wrap the result of an operation on a sized integer narrower than a register back around to
+    /// the width of the type.
+    fn wrap_narrow(&mut self, loc: &Location, ty: &Ty) {
+        if let (Ty::SizedInt(ity), Location::Register(_)) = (ty, loc) {
+            if ity.size() < 8 {
+                self.asm_buf.push(mov_value(loc.clone(), loc.clone(), ty, ""));
+            }
+        }
+    }
+
+    /// Jump to an overflow trap if the add, sub or mul that left its result in `res` overflowed.
+    ///
+    /// 64 bit values use the overflow or carry flag, narrower values are computed in a full
+    /// register so they overflowed if wrapping the result changes it.
+    fn check_overflow(&mut self, res: &Location, ty: &Ty, op: &BinOp, src_loc: &SrcLoc) {
+        match ty {
+            Ty::SizedInt(ity) if ity.size() < 8 => {
+                let tmp = self.free_reg();
+                let trap = self.overflow_trap(op, src_loc);
+                self.asm_buf.push(Instruction::Mov {
+                    src: res.clone(),
+                    dst: Location::Register(tmp),
+                    comment: "check overflow",
+                });
+                self.wrap_narrow(&Location::Register(tmp), ty);
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Cmp { src: Location::Register(tmp), dst: res.clone() },
+                    Instruction::CondJmp { loc: trap, cond: JmpCond::NotEq },
+                ]);
+                self.used_regs.remove(&tmp);
+            }
+            // Unsigned multiplication is a `mul` that sets the carry flag like add and sub
+            Ty::SizedInt(ity) if !ity.is_signed() => {
+                let trap = self.overflow_trap(op, src_loc);
+                self.asm_buf.push(Instruction::CondJmp { loc: trap, cond: JmpCond::Below });
+            }
+            _ => {
+                let trap = self.overflow_trap(op, src_loc);
+                self.asm_buf.push(Instruction::CondJmp { loc: trap, cond: JmpCond::Overflow });
+            }
+        }
+    }
+
+    /// Jump to an overflow trap if the shift `count` is not less than the bit width of `ty`.
+    fn check_shift(
+        &mut self,
+        count: &Location,
+        ty: &Ty,
+        op: &BinOp,
+        src_loc: &SrcLoc,
+    ) -> Vec<Instruction> {
+        let bits = match ty {
+            Ty::SizedInt(ity) => ity.size() * 8,
+            _ => 64,
+        };
+        match count {
+            Location::Const { val } => match val.as_int() {
+                Some(c) if (0..bits as isize).contains(&c) => vec![],
+                _ => vec![Instruction::Jmp(self.overflow_trap(op, src_loc))],
+            },
+            // Negative counts are huge unsigned numbers
+            _ => vec![
+                Instruction::Cmp {
+                    src: Location::Const { val: Val::Int(bits as isize - 1) },
+                    dst: count.clone(),
+                },
+                Instruction::CondJmp { loc: self.overflow_trap(op, src_loc), cond: JmpCond::Above },
+            ],
+        }
+    }
+
+    /// The label of a new trap reporting that `op` overflowed at `src_loc`.
+    fn overflow_trap(&mut self, op: &BinOp, src_loc: &SrcLoc) -> Location {
+        let label = self.next_label(".overflow");
+        let msg = format!("{}: attempt to {} with overflow\n", src_loc, op.overflow_verb());
+        self.traps.push(Trap { label: label.clone(), msg, index: None });
+        Location::Label(label)
+    }
+
+    /// Jump to a trap if the index in `idx` is not less than `len`, a negative index is a huge
+    /// unsigned number so it fails too.
+    fn check_bounds(&mut self, idx: Register, len: Option<usize>, src_loc: &SrcLoc) {
+        let len = match len {
+            Some(len) if self.bounds_checks => len,
+            _ => return,
+        };
+        let label = self.next_label(".bounds");
+        let msg =
+            format!("{}: index out of bounds: the length is {} but the index is ", src_loc, len);
+        self.traps.push(Trap { label: label.clone(), msg, index: Some(idx) });
+        self.asm_buf.extend_from_slice(&[
+            Instruction::Cmp {
+                src: Location::Const { val: Val::Int(len as isize) },
+                dst: Location::Register(idx),
+            },
+            Instruction::CondJmp { loc: Location::Label(label), cond: JmpCond::AboveEq },
+        ]);
+    }
+
+    /// Jump to a trap if the index in `idx` is not less than the length of the slice at the address
+    /// in `slice`.
+    fn check_slice_bounds(&mut self, idx: Register, slice: Register, src_loc: &SrcLoc) {
+        if !self.bounds_checks {
+            return;
+        }
+        let label = self.next_label(".bounds");
+        let msg =
+            format!("{}: index out of bounds: the slice is not longer than the index ", src_loc);
+        self.traps.push(Trap { label: label.clone(), msg, index: Some(idx) });
+        self.asm_buf.extend_from_slice(&[
+            Instruction::Cmp {
+                src: Location::NumberedOffset { offset: 8, reg: slice },
+                dst: Location::Register(idx),
+            },
+            Instruction::CondJmp { loc: Location::Label(label), cond: JmpCond::AboveEq },
+        ]);
+    }
+
+    /// Emit the traps of the current function, each writes its message to stderr and aborts.
+    fn gen_traps(&mut self) {
+        for Trap { label, msg, index } in std::mem::take(&mut self.traps) {
+            let name = format!(".S{}", &label[1..]);
+            let len = msg.len() as isize;
+            self.globals.insert(
+                Ident::new(ty::DUMMY, &name),
+                Global::Text { name: name.clone(), content: msg, mutable: false },
+            );
+            self.asm_buf.push(Instruction::Label(label));
+            if let Some(idx) = index {
+                self.asm_buf.push(Instruction::Mov {
+                    src: Location::Register(idx),
+                    dst: Location::Register(Register::RDI),
+                    comment: "out of bounds index",
+                });
+            }
+            self.asm_buf.extend_from_slice(&[
+                Instruction::Load {
+                    src: Location::NamedOffset(name),
+                    dst: Location::Register(Register::RSI),
+                    size: 8,
+                },
+                Instruction::Mov {
+                    src: Location::Const { val: Val::Int(len) },
+                    dst: RDX,
+                    comment: "message length",
+                },
+            ]);
+            if index.is_some() {
+                self.asm_buf.push(Instruction::Jmp(Location::Label(".bounds_fail".to_owned())));
+                continue;
+            }
+            self.asm_buf.extend_from_slice(&[
+                Instruction::Mov {
+                    src: Location::Const { val: Val::Int(2) },
+                    dst: Location::Register(Register::RDI),
+                    comment: "stderr",
+                },
+                Instruction::Mov { src: ONE, dst: RAX, comment: "write syscall" },
+                Instruction::Meta("    syscall".to_owned()),
+                Instruction::Meta("    ud2".to_owned()),
+            ]);
+        }
+    }
+
+    /// Convert the value of type `from` at `loc` to `to`, typeck has made sure the cast is valid.
+    fn gen_cast(&mut self, loc: Location, from: &Ty, to: &Ty) -> Location {
+        if matches!(from, Ty::Float) {
+            if matches!(to, Ty::Float) {
+                return loc;
+            }
+            let reg = self.free_reg();
+            self.asm_buf.push(Instruction::FloatToInt { src: loc, dst: Location::Register(reg) });
+            self.clear_float_regs_except(None, CanClearRegs::Yes);
+            let reg = Location::Register(reg);
+            self.wrap_narrow(&reg, to);
+            return reg;
+        }
+
+        // Every other type is an integer sign or zero extended to 64 bits once in a register
+        let reg = match loc {
+            Location::Register(_) => loc,
+            // A string literal is its label, the pointer is the address of the label
+            loc @ (Location::NamedOffset(_) | Location::Label(_))
+                if matches!(from, Ty::ConstStr(..)) =>
+            {
+                let reg = Location::Register(self.free_reg());
+                self.asm_buf.push(Instruction::Load { src: loc, dst: reg.clone(), size: 8 });
+                reg
+            }
+            loc => {
+                let reg = Location::Register(self.free_reg());
+                self.asm_buf.push(mov_value(loc, reg.clone(), from, "value to cast"));
+                reg
+            }
         };
+        match to {
+            // TODO: a `u64` above `i64::MAX` converts as a negative number
+            Ty::Float => {
+                let freg = Location::FloatReg(self.free_float_reg());
+                self.asm_buf.push(Instruction::IntToFloat { src: reg, dst: freg.clone() });
+                freg
+            }
+            Ty::Char => {
+                self.asm_buf.push(Instruction::MovExt {
+                    src: reg.clone(),
+                    dst: reg.clone(),
+                    size: 1,
+                    signed: false,
+                });
+                reg
+            }
+            Ty::Bool => {
+                let cond_reg = Location::Register(self.free_reg());
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Mov { src: ZERO, dst: cond_reg.clone(), comment: "cast to bool" },
+                    Instruction::Cmp { src: ZERO, dst: reg },
+                    Instruction::CondMov {
+                        src: Location::NamedOffset(".bool_test".into()),
+                        dst: cond_reg.clone(),
+                        cond: CondFlag::NotEq,
+                    },
+                ]);
+                cond_reg
+            }
+            _ => {
+                self.wrap_narrow(&reg, to);
+                reg
+            }
+        }
     }
 
     fn free_float_reg(&mut self) -> FloatRegister {
         let reg = *USABLE_FLOAT_REGS
-            .difference(&self.used_float_regs)
-            .next()
+            .iter()
+            .find(|r| !self.used_float_regs.contains(r))
             .expect("ran out of FloatRegister");
         self.use_float_reg(reg);
         reg
@@ -357,7 +863,8 @@ impl<'ctx> CodeGen<'ctx> {
             | Location::NamedOffset(_)
             | Location::NamedOffsetIndex { .. }
             | Location::Indexable { .. }
-            | Location::NumberedOffset { .. } => {}
+            | Location::NumberedOffset { .. }
+            | Location::ArgOffset { .. } => {}
         };
     }
 
@@ -385,13 +892,32 @@ impl<'ctx> CodeGen<'ctx> {
                 // TODO: fails for subtraction
                 // assert!(!swapped);
                 swapped = true;
-                let reg = self.free_reg();
-                self.asm_buf.push(Instruction::SizedMov {
-                    src: rval.clone(),
-                    dst: Location::Register(reg),
-                    size: 8,
-                });
-                *rval = Location::Register(reg);
+                if matches!(ty, Ty::Float) {
+                    // A float constant gets to a float register through the stack
+                    let freg = self.free_float_reg();
+                    self.asm_buf.extend_from_slice(&[
+                        Instruction::Push { loc: rval.clone(), size: 8, comment: "" },
+                        Instruction::Cvt {
+                            src: Location::NumberedOffset { offset: 0, reg: Register::RSP },
+                            dst: Location::FloatReg(freg),
+                        },
+                        Instruction::Math {
+                            src: Location::Const { val: Val::Int(8) },
+                            dst: RSP,
+                            op: BinOp::Add,
+                            cmt: "fix above push",
+                        },
+                    ]);
+                    *rval = Location::FloatReg(freg);
+                } else {
+                    let reg = self.free_reg();
+                    self.asm_buf.push(Instruction::SizedMov {
+                        src: rval.clone(),
+                        dst: Location::Register(reg),
+                        size: 8,
+                    });
+                    *rval = Location::Register(reg);
+                }
             }
             (lval, rval @ Location::Const { .. }) => {
                 assert!(!swapped);
@@ -403,13 +929,69 @@ impl<'ctx> CodeGen<'ctx> {
         swapped
     }
 
+    /// The address of the item at `exprs[0]` of the slice held in `slice`.
+    ///
+    /// The length of a slice is only known at runtime so every index is checked unless
+    /// `--unchecked-indexing` was passed.
+    fn index_slice(
+        &mut self,
+        slice: Location,
+        exprs: &'ctx [Expr],
+        ele_size: usize,
+        src_loc: &SrcLoc,
+    ) -> Option<Location> {
+        // Type checking rejects more than one index into a slice
+        let index_val = self.build_value(&exprs[0], None, CanClearRegs::No, false)?;
+
+        let tmpidx = self.free_reg();
+        let slice_reg = self.free_reg();
+        self.asm_buf.extend_from_slice(&[
+            Instruction::SizedMov {
+                src: index_val,
+                dst: Location::Register(tmpidx),
+                size: exprs[0].type_of().size(),
+            },
+            Instruction::Mov {
+                src: slice,
+                dst: Location::Register(slice_reg),
+                comment: "address of slice",
+            },
+        ]);
+        self.check_slice_bounds(tmpidx, slice_reg, src_loc);
+        self.asm_buf.extend_from_slice(&[
+            Instruction::Math {
+                src: Location::Const { val: Val::Int(ele_size as isize) },
+                dst: Location::Register(tmpidx),
+                op: BinOp::Mul,
+                cmt: "slice index * ele size",
+            },
+            Instruction::Mov {
+                src: Location::NumberedOffset { offset: 0, reg: slice_reg },
+                dst: Location::Register(slice_reg),
+                comment: "pointer to first item",
+            },
+            Instruction::Math {
+                src: Location::Register(tmpidx),
+                dst: Location::Register(slice_reg),
+                op: BinOp::Add,
+                cmt: "slice pointer + idx * ele size",
+            },
+        ]);
+        Some(Location::Register(slice_reg))
+    }
+
     /// This will ALWAYS return the address to the indexed value in the array.
+    ///
+    /// Constant indexes are checked by typeck, a dynamic index into an array of length `len` is
+    /// checked at runtime unless `--unchecked-indexing` was passed.
     fn index_arr(
         &mut self,
         arr: Location,
         exprs: &'ctx [Expr],
         ele_size: usize,
         is_addr: bool,
+        len: Option<usize>,
+        src_loc: &SrcLoc,
     ) -> Option<Location> {
         // println!("{}", is_addr);
 
@@ -447,19 +1029,19 @@ impl<'ctx> CodeGen<'ctx> {
                 } else {
                     todo!("multidim arrays")
                 }
-            // TODO: add bounds checking maybe
             // Dynamic indexing
             } else if exprs.len() == 1 {
                 let index_val = self.build_value(&exprs[0], None, CanClearRegs::No, false)?;
 
                 let tmpidx = self.free_reg();
                 let array_reg = self.free_reg();
+                self.asm_buf.push(Instruction::SizedMov {
+                    src: index_val,
+                    dst: Location::Register(tmpidx),
+                    size: exprs[0].type_of().size(),
+                });
+                self.check_bounds(tmpidx, len, src_loc);
                 self.asm_buf.extend_from_slice(&[
-                    Instruction::SizedMov {
-                        src: index_val,
-                        dst: Location::Register(tmpidx),
-                        size: exprs[0].type_of().size(),
-                    },
                     Instruction::Math {
                         src: Location::Const { val: Val::Int(ele_size as isize) },
                         dst: Location::Register(tmpidx),
@@ -497,19 +1079,19 @@ impl<'ctx> CodeGen<'ctx> {
                 } else {
                     todo!("multidim arrays")
                 }
-            // TODO: add bounds checking maybe
             // Dynamic indexing
             } else if exprs.len() == 1 {
                 let index_val = self.build_value(&exprs[0], None, CanClearRegs::No, false)?;
 
                 let tmpidx = self.free_reg();
                 let array_reg = self.free_reg();
+                self.asm_buf.push(Instruction::SizedMov {
+                    src: index_val,
+                    dst: Location::Register(tmpidx),
+                    size: exprs[0].type_of().size(),
+                });
+                self.check_bounds(tmpidx, len, src_loc);
                 self.asm_buf.extend_from_slice(&[
-                    Instruction::SizedMov {
-                        src: index_val,
-                        dst: Location::Register(tmpidx),
-                        size: exprs[0].type_of().size(),
-                    },
                     Instruction::Math {
                         src: Location::Const { val: Val::Int(ele_size as isize) },
                         dst: Location::Register(tmpidx),
@@ -543,188 +1125,85 @@ impl<'ctx> CodeGen<'ctx> {
         type_args: &[Ty],
         can_clear: CanClearRegs,
     ) -> Location {
-        let called_with_float = args.iter().any(|ex| matches!(ex.type_of(), Ty::Float));
+        let arg_tys = args.iter().map(|arg| arg.type_of()).collect::<Vec<_>>();
+        let layout = abi::call_layout(&arg_tys, ret_ty);
+        let ret = if layout.sret { Some(self.ret_temp(path.span, ret_ty)) } else { None };
+
+        // Registers a call around this one passes its arguments in
+        let mut spilled = vec![];
+        for reg in &ARG_REGS[..layout.int_regs] {
+            if self.used_regs.contains(reg) {
+                spilled.push(*reg);
+                self.total_stack += 8;
+                self.asm_buf.push(Instruction::Push {
+                    loc: Location::Register(*reg),
+                    size: 8,
+                    comment: "had to spill reg for call",
+                });
+            }
+        }
 
-        let mut pushed_to_align_float_stack = false;
-        if (self.total_stack % 16 != 0 || self.total_stack == 0) {
+        // The stack is 16 byte aligned at the `call`, after the arguments are pushed
+        let align = (16 - (self.total_stack + layout.stack) % 16) % 16;
+        if align != 0 {
+            self.total_stack += align;
             self.asm_buf.push(Instruction::Math {
-                src: Location::Const { val: Val::Int((16 - self.total_stack % 16) as isize) },
+                src: Location::Const { val: Val::Int(align as isize) },
                 dst: RSP,
                 op: BinOp::Sub,
-                cmt: "printf stack misaligned",
+                cmt: "align stack for call",
             });
+        }
 
-            if self.total_stack == 0 {
-                self.total_stack += 16;
+        // The arguments on the stack are evaluated first, last to first, so they can be pushed
+        for (arg, loc) in args.iter().zip(&layout.args).rev() {
+            if let ArgLoc::Stack(_) = loc {
+                self.push_arg(arg, can_clear);
             }
-            pushed_to_align_float_stack = true;
         }
 
-        let mut spilled = vec![];
-        // number of float arguments
-        let mut float_count = 0;
-        for (idx, arg) in args.iter().enumerate() {
-            if self.used_regs.contains(&ARG_REGS[idx]) {
-                spilled.push(ARG_REGS[idx]);
-                self.asm_buf.push(Instruction::Push {
-                    loc: Location::Register(ARG_REGS[idx]),
-                    size: arg.type_of().size(),
-                    comment: "had to spill reg for call",
-                });
+        for (arg, loc) in args.iter().zip(&layout.args) {
+            let regs = match loc {
+                ArgLoc::Regs(regs) => regs,
+                ArgLoc::Stack(_) => continue,
+            };
+            for reg in regs {
+                match reg {
+                    Location::Register(reg) => self.use_reg(*reg),
+                    Location::FloatReg(reg) => self.used_float_regs.insert(*reg),
+                    _ => unreachable!("arguments are passed in registers"),
+                };
             }
-            self.use_reg(ARG_REGS[idx]);
-
-            let ty = arg.type_of();
-
-            if let Ty::Array { size: _, ty } = ty {
-                if matches!(arg, Expr::Array { .. }) {
-                    let val = self
-                        .build_value(arg, None, can_clear, true)
-                        .unwrap_or_else(|| panic!("{:?}", arg));
-                    if let Location::Register(reg) = val {
-                        self.asm_buf.push(Instruction::SizedMov {
-                            src: Location::NumberedOffset { offset: 0, reg },
-                            dst: Location::Register(ARG_REGS[idx]),
-                            size: ty.size(),
-                        });
-                    } else {
-                        self.asm_buf.push(Instruction::SizedMov {
-                            src: val,
-                            dst: Location::Register(ARG_REGS[idx]),
-                            size: ty.size(),
-                        });
-                    }
-                } else if self.current_fn_params.contains(&arg.as_ident()) {
-                    let val = self
-                        .build_value(arg, None, can_clear, false)
-                        .unwrap_or_else(|| panic!("{:?}", arg));
+            if matches!(arg.type_of(), Ty::Struct { .. } | Ty::Enum { .. }) {
+                let val = self
+                    .build_value(arg, None, can_clear, false)
+                    .unwrap_or_else(|| panic!("{:?}", arg));
+                // The upper half first, the lower half could be passed in `%rdx`
+                for (idx, reg) in regs.iter().enumerate().rev() {
                     self.asm_buf.push(Instruction::SizedMov {
-                        src: val,
-                        dst: Location::Register(ARG_REGS[idx]),
-                        size: ty.size(),
-                    });
-                } else {
-                    let val = self
-                        .build_value(arg, None, can_clear, false)
-                        .unwrap_or_else(|| panic!("{:?}", arg));
-
-                    // TODO: do we always want to move by ref for arrays
-                    self.asm_buf.push(Instruction::Load {
-                        src: val,
-                        dst: Location::Register(ARG_REGS[idx]),
-                        size: ty.size(),
+                        src: eightbyte(&val, idx),
+                        dst: reg.clone(),
+                        size: 8,
                     });
                 }
-                continue;
+            } else {
+                self.gen_arg(arg, regs[0].clone(), can_clear);
             }
+        }
 
-            let val = self
-                .build_value(arg, None, can_clear, false)
-                .unwrap_or_else(|| panic!("{:?}", arg));
+        self.asm_buf.push(Instruction::Mov {
+            src: Location::Const { val: Val::Int(layout.float_regs as isize) },
+            dst: RAX,
+            comment: "float registers used",
+        });
 
-            if matches!(ty, Ty::Ptr(..)) {
-                // TODO: this should be ok since `CodeGen::build_value` deals with the load?
-                self.asm_buf.push(Instruction::SizedMov {
-                    src: val,
-                    dst: Location::Register(ARG_REGS[idx]),
-                    size: arg.type_of().size(),
-                });
-            } else if matches!(ty, Ty::Bool) {
-                if let Location::Const { val: Val::Bool(b) } = val {
-                    self.asm_buf.extend_from_slice(&[Instruction::SizedMov {
-                        src: if b { ONE } else { ZERO },
-                        dst: Location::Register(ARG_REGS[idx]),
-                        size: 8,
-                    }]);
-                } else {
-                    self.asm_buf.extend_from_slice(&[Instruction::SizedMov {
-                        src: val,
-                        dst: Location::Register(ARG_REGS[idx]),
-                        size: 8,
-                    }]);
-                }
-            } else if let Ty::ConstStr(..) = ty {
-                if matches!(val, Location::NumberedOffset { .. } | Location::Register(..)) {
-                    self.asm_buf.push(Instruction::Mov {
-                        src: val,
-                        dst: Location::Register(ARG_REGS[idx]),
-                        comment: "move address of const str",
-                    });
-                } else {
-                    self.asm_buf.push(Instruction::Load {
-                        src: val,
-                        dst: Location::Register(ARG_REGS[idx]),
-                        size: ty.size(),
-                    });
-                }
-            } else if matches!(ty, Ty::Float) {
-                if matches!(val, Location::Const { .. }) {
-                    self.used_float_regs.insert(ARG_FLOAT_REGS[float_count]);
-                    self.clear_float_regs_except(None, can_clear);
-
-                    self.asm_buf.extend_from_slice(&[
-                        // From stack pointer
-                        Instruction::Push { loc: val, size: 8, comment: "" },
-                        Instruction::Cvt {
-                            src: Location::NumberedOffset { offset: 0, reg: Register::RSP },
-                            dst: Location::FloatReg(ARG_FLOAT_REGS[float_count]),
-                        },
-                        Instruction::Math {
-                            src: Location::Const { val: Val::Int(8) },
-                            dst: RSP,
-                            op: BinOp::Add,
-                            cmt: "fix above push",
-                        },
-                    ]);
-                    // TODO: verify that these are ok, I know numbered offset is a-OK,
-                    // Location::Register is questionable
-                } else if matches!(val, Location::NumberedOffset { .. } | Location::Register(..)) {
-                    self.asm_buf.push(Instruction::SizedMov {
-                        src: val,
-                        dst: Location::FloatReg(ARG_FLOAT_REGS[float_count]),
-                        size: ty.size(),
-                    });
-                } else if val != Location::FloatReg(ARG_FLOAT_REGS[float_count]) {
-                    self.asm_buf.push(Instruction::FloatMov {
-                        src: val,
-                        dst: Location::FloatReg(ARG_FLOAT_REGS[float_count]),
-                    });
-                }
-                // use next float register
-                float_count += 1;
-            } else if let Ty::Func { .. } = ty {
-                self.asm_buf.push(Instruction::SizedMov {
-                    src: if let Location::Label(s) = val {
-                        // When we are moving a function ptr we refer to it like a value 🤷
-                        Location::Label(format!("${}", s))
-                    } else {
-                        val
-                    },
-                    dst: Location::Register(ARG_REGS[idx]),
-                    size: arg.type_of().size(),
-                });
-            } else {
-                self.asm_buf.push(Instruction::SizedMov {
-                    src: val,
-                    dst: Location::Register(ARG_REGS[idx]),
-                    size: arg.type_of().size(),
-                });
-            }
-        }
-
-        self.asm_buf.push(Instruction::Mov {
-            src: if called_with_float { ONE } else { ZERO },
-            dst: RAX,
-            comment: "set float flag",
-        });
-
-        let ident = if matches!(kind, FuncKind::Pointer) {
-            if let Some(offset) = self.vars.get(&path.segs[0]).cloned() {
-                let reg = self.free_reg_except(Register::RAX);
-                self.asm_buf.push(Instruction::Mov {
-                    src: offset,
-                    dst: Location::Register(reg),
-                    comment: "move fn ptr to register",
+        let ident = if matches!(kind, FuncKind::Pointer) {
+            if let Some(offset) = self.vars.get(&path.segs[0]).cloned() {
+                let reg = self.free_reg_except(Register::RAX);
+                self.asm_buf.push(Instruction::Mov {
+                    src: offset,
+                    dst: Location::Register(reg),
+                    comment: "move fn ptr to register",
                 });
 
                 format!("*{}", reg)
@@ -740,24 +1219,39 @@ impl<'ctx> CodeGen<'ctx> {
                 path,
                 type_args
                     .iter()
-                    .map(|t| t.to_string().replace(' ', ""))
+                    .map(ty::mangle_ty)
                     .collect::<Vec<_>>()
                     .join("0"),
             )
         };
 
-        self.asm_buf.push(Instruction::Call(Location::Label(ident)));
+        if let Some(ret) = &ret {
+            self.asm_buf.push(Instruction::Load {
+                src: ret.clone(),
+                dst: Location::Register(Register::RDI),
+                size: 8,
+            });
+        }
+
+        self.asm_buf.push(Instruction::Call {
+            loc: Location::Label(ident),
+            args: layout.int_regs,
+            float_args: layout.float_regs,
+            stack: layout.stack,
+        });
 
-        if pushed_to_align_float_stack {
+        if layout.stack + align != 0 {
+            self.total_stack -= layout.stack + align;
             self.asm_buf.push(Instruction::Math {
-                src: Location::Const { val: Val::Int((16 - (self.total_stack % 16)) as isize) },
+                src: Location::Const { val: Val::Int((layout.stack + align) as isize) },
                 dst: RSP,
                 op: BinOp::Add,
-                cmt: "stack was larger than 16bits and misaligned",
+                cmt: "remove stack arguments and alignment",
             });
         }
 
         for spill in spilled.into_iter().rev() {
+            self.total_stack -= 8;
             self.asm_buf.push(Instruction::Pop {
                 loc: Location::Register(spill),
                 size: 8,
@@ -765,12 +1259,199 @@ impl<'ctx> CodeGen<'ctx> {
             });
         }
 
-        if matches!(ret_ty, Ty::Float) {
-            self.use_float_reg(FloatRegister::XMM0);
-            XMM0
+        match abi::classify(ret_ty) {
+            Pass::Memory => ret.expect("returned through memory"),
+            Pass::Regs(classes) if matches!(ret_ty, Ty::Struct { .. }) => {
+                // A struct returned in registers is kept in `%rax` and `%rdx` like an enum
+                for (idx, reg) in abi::ret_regs(&classes).into_iter().enumerate().rev() {
+                    let dst = if idx == 0 { RAX } else { RDX };
+                    if reg != dst {
+                        self.asm_buf.push(Instruction::SizedMov { src: reg, dst, size: 8 });
+                    }
+                }
+                self.use_reg(Register::RAX);
+                RAX
+            }
+            _ if matches!(ret_ty, Ty::Float) => {
+                self.use_float_reg(FloatRegister::XMM0);
+                XMM0
+            }
+            _ => {
+                self.use_reg(Register::RAX);
+                RAX
+            }
+        }
+    }
+
+    /// Move the scalar or array argument `arg` to the register `dst`.
+    fn gen_arg(&mut self, arg: &'ctx Expr, dst: Location, can_clear: CanClearRegs) {
+        let ty = arg.type_of();
+
+        if let Ty::Array { size: _, ty } = ty {
+            if matches!(arg, Expr::Array { .. }) {
+                let val = self
+                    .build_value(arg, None, can_clear, true)
+                    .unwrap_or_else(|| panic!("{:?}", arg));
+                if let Location::Register(reg) = val {
+                    self.asm_buf.push(Instruction::SizedMov {
+                        src: Location::NumberedOffset { offset: 0, reg },
+                        dst,
+                        size: ty.size(),
+                    });
+                } else {
+                    self.asm_buf.push(Instruction::SizedMov { src: val, dst, size: ty.size() });
+                }
+            } else if self.current_fn_params.contains(&arg.as_ident()) {
+                let val = self
+                    .build_value(arg, None, can_clear, false)
+                    .unwrap_or_else(|| panic!("{:?}", arg));
+                self.asm_buf.push(Instruction::SizedMov { src: val, dst, size: ty.size() });
+            } else {
+                let val = self
+                    .build_value(arg, None, can_clear, false)
+                    .unwrap_or_else(|| panic!("{:?}", arg));
+
+                // TODO: do we always want to move by ref for arrays
+                self.asm_buf.push(Instruction::Load { src: val, dst, size: ty.size() });
+            }
+            return;
+        }
+
+        let val =
+            self.build_value(arg, None, can_clear, false).unwrap_or_else(|| panic!("{:?}", arg));
+
+        if matches!(ty, Ty::Ptr(..)) {
+            // TODO: this should be ok since `CodeGen::build_value` deals with the load?
+            self.asm_buf.push(Instruction::SizedMov { src: val, dst, size: ty.size() });
+        } else if matches!(ty, Ty::Bool) {
+            if let Location::Const { val: Val::Bool(b) } = val {
+                self.asm_buf.extend_from_slice(&[Instruction::SizedMov {
+                    src: if b { ONE } else { ZERO },
+                    dst,
+                    size: 8,
+                }]);
+            } else {
+                self.asm_buf.extend_from_slice(&[Instruction::SizedMov { src: val, dst, size: 8 }]);
+            }
+        } else if let Ty::ConstStr(..) = ty {
+            if matches!(val, Location::NumberedOffset { .. } | Location::Register(..)) {
+                self.asm_buf.push(Instruction::Mov {
+                    src: val,
+                    dst,
+                    comment: "move address of const str",
+                });
+            } else {
+                self.asm_buf.push(Instruction::Load { src: val, dst, size: ty.size() });
+            }
+        } else if matches!(ty, Ty::Float) {
+            if matches!(val, Location::Const { .. }) {
+                if let Location::FloatReg(reg) = dst {
+                    self.used_float_regs.insert(reg);
+                }
+                self.clear_float_regs_except(None, can_clear);
+
+                self.asm_buf.extend_from_slice(&[
+                    // From stack pointer
+                    Instruction::Push { loc: val, size: 8, comment: "" },
+                    Instruction::Cvt {
+                        src: Location::NumberedOffset { offset: 0, reg: Register::RSP },
+                        dst,
+                    },
+                    Instruction::Math {
+                        src: Location::Const { val: Val::Int(8) },
+                        dst: RSP,
+                        op: BinOp::Add,
+                        cmt: "fix above push",
+                    },
+                ]);
+                // TODO: verify that these are ok, I know numbered offset is a-OK,
+                // Location::Register is questionable
+            } else if matches!(val, Location::NumberedOffset { .. } | Location::Register(..)) {
+                self.asm_buf.push(Instruction::SizedMov { src: val, dst, size: ty.size() });
+            } else if val != dst {
+                self.asm_buf.push(Instruction::FloatMov { src: val, dst });
+            }
+        } else if let Ty::Func { .. } = ty {
+            self.asm_buf.push(Instruction::SizedMov {
+                src: if let Location::Label(s) = val {
+                    // When we are moving a function ptr we refer to it like a value 🤷
+                    Location::Label(format!("${}", s))
+                } else {
+                    val
+                },
+                dst,
+                size: arg.type_of().size(),
+            });
+        } else {
+            let val = self.load_narrow(val, &ty);
+            self.asm_buf.push(Instruction::SizedMov { src: val, dst, size: arg.type_of().size() });
+        }
+    }
+
+    /// Push the argument `arg` that is passed on the stack, each eightbyte takes 8 bytes.
+    fn push_arg(&mut self, arg: &'ctx Expr, can_clear: CanClearRegs) {
+        let ty = arg.type_of();
+        let locs = if matches!(ty, Ty::Struct { .. } | Ty::Enum { .. }) {
+            let val = self
+                .build_value(arg, None, can_clear, false)
+                .unwrap_or_else(|| panic!("{:?}", arg));
+            (0..abi::eightbytes(ty.size())).map(|idx| eightbyte(&val, idx)).collect()
+        } else if matches!(ty, Ty::Float) {
+            let reg = Location::FloatReg(self.free_float_reg());
+            self.gen_arg(arg, reg.clone(), can_clear);
+            self.asm_buf.extend_from_slice(&[
+                Instruction::Math {
+                    src: Location::Const { val: Val::Int(8) },
+                    dst: RSP,
+                    op: BinOp::Sub,
+                    cmt: "float argument on the stack",
+                },
+                Instruction::FloatMov { src: reg, dst: STACK_TOP },
+            ]);
+            self.clear_float_regs_except(None, can_clear);
+            self.total_stack += 8;
+            return;
         } else {
-            self.use_reg(Register::RAX);
-            RAX
+            let reg = self.free_reg();
+            self.gen_arg(arg, Location::Register(reg), can_clear);
+            self.used_regs.remove(&reg);
+            vec![Location::Register(reg)]
+        };
+        for loc in locs.into_iter().rev() {
+            self.total_stack += 8;
+            self.asm_buf.push(Instruction::Push { loc, size: 8, comment: "argument on the stack" });
+        }
+    }
+
+    /// The space the call at `span` with the return type `ret` returns it's value to.
+    fn ret_temp(&mut self, span: ty::Range, ret: &Ty) -> Location {
+        if let Some(loc) = self.ret_temps.get(&span) {
+            return loc.clone();
+        }
+        // Not found by `abi::ret_slots`, nothing of the call has been pushed yet
+        let size = abi::stack_size(ret);
+        self.current_stack += size;
+        self.total_stack += size;
+        self.asm_buf.push(Instruction::Math {
+            src: Location::Const { val: Val::Int(size as isize) },
+            dst: RSP,
+            op: BinOp::Sub,
+            cmt: "space for returned value",
+        });
+        Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP }
+    }
+
+    /// Copy `size` bytes from `src` to `dst`, only the bytes of the value are written.
+    fn copy_bytes(&mut self, src: &Location, dst: &Location, size: usize) {
+        let tmp = Location::Register(self.free_reg());
+        let mut at = 0;
+        while at < size {
+            let chunk = [8, 4, 2, 1].iter().copied().find(|chunk| at + chunk <= size).unwrap();
+            self.asm_buf.extend_from_slice(&[
+                Instruction::SizedMov { src: field_loc(src, at), dst: tmp.clone(), size: chunk },
+                Instruction::SizedMov { src: tmp.clone(), dst: field_loc(dst, at), size: chunk },
+            ]);
+            at += chunk;
         }
     }
 
@@ -784,29 +1465,38 @@ impl<'ctx> CodeGen<'ctx> {
                     cmt: "make stack for array",
                 });
             }
-            Ty::Struct { ident: _, gen: _, def } => {
-                for field in &def.fields {
-                    self.push_stack(&field.ty)
-                }
+            Ty::Struct { .. } => {
+                self.asm_buf.push(Instruction::Math {
+                    src: Location::Const { val: Val::Int(ty.size() as isize) },
+                    dst: RSP,
+                    op: BinOp::Sub,
+                    cmt: "stack for struct",
+                });
             }
-            Ty::Enum { ident: _, gen: _, def } => {
-                let mut largest_variant = 0;
-                for var in &def.variants {
-                    // Size is size of largest variant plus 8 for tag
-                    // TODO: optimize tag sizes using `variants.len()`?
-                    let curr = var.types.iter().map(|t| t.size()).sum::<usize>() + 8;
-                    if curr > largest_variant {
-                        largest_variant = curr;
-                    }
-                }
+            // TODO: optimize tag sizes using `variants.len()`?
+            Ty::Enum { .. } => {
                 self.asm_buf.push(Instruction::Math {
-                    src: Location::Const { val: Val::Int(largest_variant as isize) },
+                    src: Location::Const { val: Val::Int(ty.size() as isize) },
                     dst: RSP,
                     op: BinOp::Sub,
                     cmt: "stack for enum",
                 });
             }
-            Ty::ConstStr(..) | Ty::Ptr(_) | Ty::Int | Ty::Float | Ty::Char | Ty::Bool => {
+            Ty::SizedInt(ity) => {
+                self.asm_buf.push(Instruction::Math {
+                    src: Location::Const { val: Val::Int(ity.size() as isize) },
+                    dst: RSP,
+                    op: BinOp::Sub,
+                    cmt: "stack for sized int",
+                });
+            }
+            Ty::ConstStr(..)
+            | Ty::Ptr(_)
+            | Ty::Slice(_)
+            | Ty::Int
+            | Ty::Float
+            | Ty::Char
+            | Ty::Bool => {
                 self.asm_buf.push(Instruction::Push {
                     loc: Location::Const { val: ty.null_val() },
                     size: 8,
@@ -819,9 +1509,16 @@ impl<'ctx> CodeGen<'ctx> {
 
     fn alloc_stack(&mut self, name: Ident, ty: &Ty) -> Location {
         self.push_stack(ty);
+        if matches!(ty, Ty::Slice(_)) {
+            self.slices.insert(name);
+        }
 
         self.current_stack += ty.size();
         self.total_stack += ty.size();
+        if is_scalar(ty) {
+            self.stack_slots
+                .push(StackSlot { offset: self.current_stack, alloc: self.asm_buf.len() - 1 });
+        }
 
         let ref_loc = Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP };
 
@@ -829,39 +1526,144 @@ impl<'ctx> CodeGen<'ctx> {
         ref_loc
     }
 
-    fn alloc_arg(&mut self, count: usize, f_count: &mut usize, name: Ident, ty: &Ty) -> Location {
+    fn alloc_arg(&mut self, arg: &ArgLoc, name: Ident, ty: &Ty) -> Location {
         let size = match ty {
             // An array is converted to a pointer like thing
             Ty::Array { .. } => 8,
+            // Each eightbyte is stored whole
+            Ty::Struct { .. } | Ty::Enum { .. } => abi::stack_size(ty),
             t => t.size(),
         };
 
         self.current_stack += size;
         self.total_stack += size;
+        if matches!(ty, Ty::Slice(_)) {
+            self.slices.insert(name);
+        }
 
         let ref_loc = Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP };
+        if is_scalar(ty) {
+            let alloc = self.asm_buf.len();
+            self.stack_slots.push(StackSlot { offset: self.current_stack, alloc });
+        }
 
-        if matches!(ty, Ty::Float) {
-            self.asm_buf.extend_from_slice(&[
-                Instruction::Push { loc: ZERO, size, comment: "push/mov float arg" },
-                Instruction::FloatMov {
-                    src: Location::FloatReg(ARG_FLOAT_REGS[*f_count]),
-                    dst: ref_loc.clone(),
-                },
-            ]);
-            *f_count += 1;
-        } else {
-            self.asm_buf.push(Instruction::Push {
-                loc: Location::Register(ARG_REGS[count]),
-                size,
-                comment: "push argument to stack",
-            });
+        match (arg, ty) {
+            (ArgLoc::Regs(regs), Ty::Struct { .. } | Ty::Enum { .. }) => {
+                self.asm_buf.push(Instruction::Math {
+                    src: Location::Const { val: Val::Int(size as isize) },
+                    dst: RSP,
+                    op: BinOp::Sub,
+                    cmt: "stack for struct arg",
+                });
+                for (idx, reg) in regs.iter().enumerate() {
+                    self.asm_buf.push(Instruction::SizedMov {
+                        src: reg.clone(),
+                        dst: eightbyte(&ref_loc, idx),
+                        size: 8,
+                    });
+                }
+            }
+            (ArgLoc::Regs(regs), Ty::Float) => {
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Push { loc: ZERO, size, comment: "push/mov float arg" },
+                    Instruction::FloatMov { src: regs[0].clone(), dst: ref_loc.clone() },
+                ]);
+            }
+            (ArgLoc::Regs(regs), Ty::SizedInt(_)) => {
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Math {
+                        src: Location::Const { val: Val::Int(size as isize) },
+                        dst: RSP,
+                        op: BinOp::Sub,
+                        cmt: "stack for sized int arg",
+                    },
+                    Instruction::SizedMov { src: regs[0].clone(), dst: ref_loc.clone(), size },
+                ]);
+            }
+            (ArgLoc::Regs(regs), _) => {
+                self.asm_buf.push(Instruction::Push {
+                    loc: regs[0].clone(),
+                    size,
+                    comment: "push argument to stack",
+                });
+            }
+            // Above the saved `%rbp` and the return address
+            (ArgLoc::Stack(offset), Ty::SizedInt(_)) => {
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Math {
+                        src: Location::Const { val: Val::Int(size as isize) },
+                        dst: RSP,
+                        op: BinOp::Sub,
+                        cmt: "stack for sized int arg",
+                    },
+                    Instruction::SizedMov {
+                        src: Location::ArgOffset { offset: 16 + offset, reg: Register::RBP },
+                        dst: RAX,
+                        size,
+                    },
+                    Instruction::SizedMov { src: RAX, dst: ref_loc.clone(), size },
+                ]);
+            }
+            (ArgLoc::Stack(offset), _) => {
+                for idx in (0..size / 8).rev() {
+                    let offset = 16 + offset + idx * 8;
+                    self.asm_buf.push(Instruction::Push {
+                        loc: Location::ArgOffset { offset, reg: Register::RBP },
+                        size: 8,
+                        comment: "copy argument from the stack",
+                    });
+                }
+            }
         }
 
         self.vars.insert(name, ref_loc.clone());
         ref_loc
     }
 
+    /// Build the slice `main(args: [str])` takes from `argc` in `%rdi` and `argv` in `%rsi`.
+    ///
+    /// A slice is the address of the pointer to its first item, the length is stored below the
+    /// pointer.
+    fn alloc_main_args(&mut self, name: Ident) -> Location {
+        let argv = Location::NumberedOffset { offset: self.current_stack + 8, reg: Register::RBP };
+        self.asm_buf.extend_from_slice(&[
+            Instruction::Push { loc: Location::Register(Register::RSI), size: 8, comment: "argv" },
+            Instruction::Push { loc: Location::Register(Register::RDI), size: 8, comment: "argc" },
+            Instruction::Load { src: argv, dst: RAX, size: 8 },
+            Instruction::Push { loc: RAX, size: 8, comment: "args slice" },
+        ]);
+        self.current_stack += 24;
+        self.total_stack += 24;
+        self.slices.insert(name);
+
+        let ref_loc = Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP };
+        self.vars.insert(name, ref_loc.clone());
+        ref_loc
+    }
+
+    /// Load the pointer to a struct stored at `ptr` and move it to the end of the struct.
+    ///
+    /// Fields are found by counting down from the end of a struct on the stack, the same offsets
+    /// work from the end of a struct behind a pointer.
+    fn struct_ptr_end(&mut self, ptr: Location, def: &Struct) -> (usize, Register) {
+        let size = def.size();
+        let register = self.free_reg();
+        self.asm_buf.extend_from_slice(&[
+            Instruction::Mov {
+                src: ptr,
+                dst: Location::Register(register),
+                comment: "deref of lvalue",
+            },
+            Instruction::Math {
+                src: Location::Const { val: Val::Int(size as isize) },
+                dst: Location::Register(register),
+                op: BinOp::Add,
+                cmt: "end of the struct behind the pointer",
+            },
+        ]);
+        (size, register)
+    }
+
     fn get_pointer(&mut self, expr: &'ctx LValue) -> Option<Location> {
         Some(match expr {
             LValue::Ident { ident, ty: _ } => self.vars.get(ident)?.clone(),
@@ -879,31 +1681,31 @@ impl<'ctx> CodeGen<'ctx> {
                     todo!("pretty sure this is an error {:?}", loc)
                 }
             }
-            LValue::Array { ident, exprs, ty } => {
+            LValue::Array { ident, exprs, ty: Ty::Slice(ty), loc, .. } => {
+                let slice = self.vars.get(ident)?.clone();
+                self.index_slice(slice, exprs, ty.size(), loc)?
+            }
+            LValue::Array { ident, exprs, ty, len, loc } => {
                 let arr = self.vars.get(ident)?.clone();
                 let ele_size = if let Ty::Array { ty, .. } = ty {
                     ty.size()
                 } else {
                     unreachable!("array type must be array")
                 };
-                self.index_arr(arr, exprs, ele_size, true)?
+                // An array parameter already holds the address of the array
+                let is_addr = !self.current_fn_params.contains(ident);
+                self.index_arr(arr, exprs, ele_size, is_addr, *len, loc)?
             }
             LValue::FieldAccess { lhs, def, rhs, field_idx } => {
                 let left_loc = self.vars.get(&lhs.as_ident().unwrap()).cloned();
                 let lhs_ty = lhs.type_of();
 
-                if let Some(Location::NumberedOffset { offset, reg }) = left_loc {
-                    let accessor = construct_field_offset_lvalue(self, rhs, offset, reg, def)?;
+                if let Some(loc @ Location::NumberedOffset { offset, reg }) = left_loc {
                     if matches!(lhs_ty, Ty::Ptr(..)) {
-                        let register = self.free_reg();
-                        self.asm_buf.extend_from_slice(&[Instruction::Mov {
-                            src: accessor,
-                            dst: Location::Register(register),
-                            comment: "deref of lvalue",
-                        }]);
-                        Location::NumberedOffset { offset: 0, reg: register }
+                        let (end, register) = self.struct_ptr_end(loc, def);
+                        construct_field_offset_lvalue(self, rhs, end, register, def)?
                     } else {
-                        accessor
+                        construct_field_offset_lvalue(self, rhs, offset, reg, def)?
                     }
                 } else {
                     panic!("have not resolved field access")
@@ -952,7 +1754,11 @@ impl<'ctx> CodeGen<'ctx> {
                     loc
                 }
             }
-            Expr::Array { ident, exprs, ty } => {
+            Expr::Array { ident, exprs, ty, loc, .. } if self.slices.contains(ident) => {
+                let slice = self.vars.get(ident)?.clone();
+                self.index_slice(slice, exprs, ty.size(), loc)?
+            }
+            Expr::Array { ident, exprs, ty, len, loc } => {
                 let arr = self.vars.get(ident)?.clone();
                 let ele_size = if let Ty::Array { ty, .. } = ty { ty.size() } else { ty.size() };
 
@@ -963,6 +1769,8 @@ impl<'ctx> CodeGen<'ctx> {
                     exprs,
                     ele_size,
                     is_addr && !self.current_fn_params.contains(ident),
+                    *len,
+                    loc,
                 )?
             }
             Expr::Urnary { op, expr, ty } => {
@@ -970,11 +1778,12 @@ impl<'ctx> CodeGen<'ctx> {
 
                 let register = self.free_reg();
                 let val = if val.is_stack_offset() {
-                    self.asm_buf.push(Instruction::Mov {
-                        src: val,
-                        dst: Location::Register(register),
-                        comment: "urnary op was a memory location",
-                    });
+                    self.asm_buf.push(mov_value(
+                        val,
+                        Location::Register(register),
+                        ty,
+                        "urnary op was a memory location",
+                    ));
                     Location::Register(register)
                 } else {
                     val
@@ -1018,7 +1827,7 @@ impl<'ctx> CodeGen<'ctx> {
                     todo!("ones comp")
                 }
             }
-            Expr::Binary { op, lhs, rhs, ty } => {
+            Expr::Binary { op, lhs, rhs, ty, loc: src_loc } => {
                 // For `is_addr` we want it to be true when we have a local array and false if the
                 // array comes from an arguemnt.
                 let mut lloc = self.build_value(lhs, None, CanClearRegs::No, !matches!(&**lhs,
@@ -1031,6 +1840,11 @@ impl<'ctx> CodeGen<'ctx> {
                 // For operations where ordering doesn't matter we move consts around
                 // if it matters we load it into a register.
                 let swapped = self.order_operands(&mut lloc, &mut rloc, op, ty);
+                // Indexing an array gives us an element
+                let operand_ty = match lhs.type_of() {
+                    Ty::Array { ty, .. } => *ty,
+                    t => t,
+                };
 
                 if matches!(ty, Ty::Float) {
                     let register = self.free_float_reg();
@@ -1040,7 +1854,8 @@ impl<'ctx> CodeGen<'ctx> {
                             // This transfers the constant to the stack then we can push it to a
                             // xmm[x] reg
                             Instruction::Push { loc: lloc, size: 8, comment: "" },
-                            Instruction::FloatMov {
+                            // A float constant is the bits of an `f32`
+                            Instruction::Cvt {
                                 src: Location::NumberedOffset { offset: 0, reg: Register::RSP },
                                 dst: Location::FloatReg(register),
                             },
@@ -1095,13 +1910,14 @@ impl<'ctx> CodeGen<'ctx> {
                         match loc {
                             Location::Register(reg) => {
                                 let tmp = self.free_reg();
-                                self.asm_buf.push(Instruction::Mov {
+                                self.asm_buf.push(mov_value(
                                     // Move the value on the right hand side of the `= here`
-                                    src: Location::NumberedOffset { offset: 0, reg: *reg },
+                                    Location::NumberedOffset { offset: 0, reg: *reg },
                                     // to the left hand side of `here =`
-                                    dst: Location::Register(tmp),
-                                    comment: "mov array index for binop",
-                                });
+                                    Location::Register(tmp),
+                                    &operand_ty,
+                                    "mov array index for binop",
+                                ));
                                 *loc = Location::Register(tmp);
                             }
                             _ => {
@@ -1117,13 +1933,14 @@ impl<'ctx> CodeGen<'ctx> {
                         match loc {
                             Location::Register(reg) => {
                                 let tmp = self.free_reg();
-                                self.asm_buf.push(Instruction::Mov {
+                                self.asm_buf.push(mov_value(
                                     // Move the value on the right hand side of the `= here`
-                                    src: Location::NumberedOffset { offset: 0, reg: *reg },
+                                    Location::NumberedOffset { offset: 0, reg: *reg },
                                     // to the left hand side of `here =`
-                                    dst: Location::Register(tmp),
-                                    comment: "mov array index for binop",
-                                });
+                                    Location::Register(tmp),
+                                    &operand_ty,
+                                    "mov array index for binop",
+                                ));
                                 *loc = Location::Register(tmp);
                             }
                             _ => {
@@ -1132,6 +1949,8 @@ impl<'ctx> CodeGen<'ctx> {
                         };
                     }
 
+                    lloc = self.load_narrow(lloc, &operand_ty);
+                    rloc = self.load_narrow(rloc, &operand_ty);
                     if let Location::NumberedOffset { .. } = &rloc {
                         let new_reg = self.free_reg();
 
@@ -1147,18 +1966,26 @@ impl<'ctx> CodeGen<'ctx> {
                     let mut spilled_rax = false;
                     let mut spilled_rdx = false;
 
+                    // `imul` only sets the overflow flag for signed multiplication, a checked
+                    // unsigned one uses `mul` which sets the carry flag when `rdx` is not zero
+                    let wide_mul = self.overflow_checks
+                        && matches!(op, BinOp::Mul)
+                        && matches!(&operand_ty, Ty::SizedInt(ity) if ity.size() == 8)
+                        && is_unsigned(&operand_ty);
+
                     let inst = if op.is_cmp() {
                         let cond_reg = self.free_reg();
                         let x = Instruction::from_binop_cmp(
                             lloc.clone(),
                             rloc,
                             op,
+                            !is_unsigned(&operand_ty),
                             Location::Register(cond_reg),
                             self,
                         );
                         rloc = Location::Register(cond_reg);
                         x
-                    } else if matches!(op, BinOp::Div | BinOp::Rem) {
+                    } else if matches!(op, BinOp::Div | BinOp::Rem) || wide_mul {
                         let rax_reg = Register::RAX;
                         let rdx_reg = Register::RDX;
 
@@ -1232,21 +2059,102 @@ impl<'ctx> CodeGen<'ctx> {
                             }
                         }
 
-                        vec![
-                            Instruction::Mov {
-                                src: lloc.clone(),
-                                dst: Location::Register(rax_reg),
-                                comment: "move lhs to dividend `rdx:rax / whatever`",
-                            },
-                            Instruction::Extend,
-                            // lloc is divided by rloc `lloc / rloc`
-                            Instruction::Idiv(rloc.clone()),
-                            Instruction::SizedMov {
-                                src: Location::Register(rax_reg),
-                                dst: rloc.clone(),
+                        if wide_mul {
+                            vec![
+                                Instruction::Mov {
+                                    src: lloc.clone(),
+                                    dst: Location::Register(rax_reg),
+                                    comment: "move lhs to `rax` for `rdx:rax = rax * whatever`",
+                                },
+                                Instruction::Mul(rloc.clone()),
+                                Instruction::SizedMov {
+                                    src: Location::Register(rax_reg),
+                                    dst: rloc.clone(),
+                                    size: 8,
+                                },
+                            ]
+                        } else {
+                            vec![
+                                Instruction::Mov {
+                                    src: lloc.clone(),
+                                    dst: Location::Register(rax_reg),
+                                    comment: "move lhs to dividend `rdx:rax / whatever`",
+                                },
+                                if is_unsigned(&operand_ty) {
+                                    Instruction::Mov {
+                                        src: ZERO,
+                                        dst: Location::Register(rdx_reg),
+                                        comment: "zero extend dividend",
+                                    }
+                                } else {
+                                    Instruction::Extend
+                                },
+                                // lloc is divided by rloc `lloc / rloc`
+                                if is_unsigned(&operand_ty) {
+                                    Instruction::Div(rloc.clone())
+                                } else {
+                                    Instruction::Idiv(rloc.clone())
+                                },
+                                // The quotient is in `rax` and the remainder in `rdx`
+                                Instruction::SizedMov {
+                                    src: Location::Register(if matches!(op, BinOp::Rem) {
+                                        rdx_reg
+                                    } else {
+                                        rax_reg
+                                    }),
+                                    dst: rloc.clone(),
+                                    size: 8,
+                                },
+                            ]
+                        }
+                    } else if matches!(op, BinOp::LeftShift | BinOp::RightShift) {
+                        // Constants are moved to the left so we undo that
+                        let (value, count) = if swapped {
+                            (rloc.clone(), lloc.clone())
+                        } else {
+                            (lloc.clone(), rloc.clone())
+                        };
+
+                        // The count has to be a constant or in `cl`
+                        let dst = self.free_reg_except(Register::RCX);
+                        let mut inst = vec![Instruction::Mov {
+                            src: value,
+                            dst: Location::Register(dst),
+                            comment: "shift value",
+                        }];
+                        let spilled_rcx = !matches!(count, Location::Const { .. } | RCX)
+                            && self.used_regs.contains(&Register::RCX);
+                        if spilled_rcx {
+                            inst.push(Instruction::Push { loc: RCX, size: 8, comment: "rcx used" });
+                        }
+                        let count = if matches!(count, Location::Const { .. }) {
+                            count
+                        } else {
+                            inst.push(Instruction::Mov {
+                                src: count,
+                                dst: RCX,
+                                comment: "shift count",
+                            });
+                            RCX
+                        };
+                        if self.overflow_checks {
+                            inst.extend(self.check_shift(&count, &operand_ty, op, src_loc));
+                        }
+                        inst.push(Instruction::Shift {
+                            src: count,
+                            dst: Location::Register(dst),
+                            op: op.clone(),
+                            signed: !is_unsigned(&operand_ty),
+                        });
+                        if spilled_rcx {
+                            inst.push(Instruction::Pop {
+                                loc: RCX,
                                 size: 8,
-                            },
-                        ]
+                                comment: "move back to rcx",
+                            });
+                        }
+                        rloc = Location::Register(dst);
+                        inst
                     } else {
                         Instruction::from_binop(lloc.clone(), rloc.clone(), op)
                     };
@@ -1254,6 +2162,10 @@ impl<'ctx> CodeGen<'ctx> {
                     // FIXME: don't do it like this, ERROR prone (move this line after and
                     // everything breaks :( )
                     self.asm_buf.extend_from_slice(&inst);
+                    // The flags of the operation are checked before anything clobbers them
+                    if self.overflow_checks && matches!(op, BinOp::Add | BinOp::Sub | BinOp::Mul) {
+                        self.check_overflow(&rloc, &operand_ty, op, src_loc);
+                    }
 
                     // FIXME:
                     // The check for loc == register should be redundant
@@ -1271,6 +2183,9 @@ impl<'ctx> CodeGen<'ctx> {
                             comment: "move back to rdx",
                         });
                     }
+                    if !op.is_cmp() {
+                        self.wrap_narrow(&rloc, &operand_ty);
+                    }
 
                     // TODO: is it ok for any mem_ref ??
                     if let Location::Register(rreg) = rloc {
@@ -1295,6 +2210,7 @@ impl<'ctx> CodeGen<'ctx> {
                     && self.used_regs.contains(&Register::RAX)
                 {
                     spilled = true;
+                    self.total_stack += 8;
                     self.asm_buf.push(Instruction::Push {
                         loc: RAX,
                         size: 8,
@@ -1306,7 +2222,28 @@ impl<'ctx> CodeGen<'ctx> {
                 let ret_loc =
                     self.gen_call_expr(path, def.kind, &def.ret, args, type_args, can_clear);
 
-                if spilled {
+                if spilled && ret_loc.is_stack_offset() {
+                    self.total_stack -= 8;
+                    self.asm_buf.push(Instruction::Pop {
+                        loc: RAX,
+                        size: 8,
+                        comment: "move back to rax",
+                    });
+                    ret_loc
+                } else if spilled
+                    && matches!(abi::classify(&def.ret), Pass::Regs(classes) if classes.len() == 2)
+                {
+                    // The value is in `%rax` and `%rdx`, one register can't hold it
+                    let slot = self.ret_temp(path.span, &def.ret);
+                    self.total_stack -= 8;
+                    self.asm_buf.extend_from_slice(&[
+                        Instruction::SizedMov { src: RAX, dst: slot.clone(), size: 8 },
+                        Instruction::SizedMov { src: RDX, dst: eightbyte(&slot, 1), size: 8 },
+                        Instruction::Pop { loc: RAX, size: 8, comment: "move back to rax" },
+                    ]);
+                    slot
+                } else if spilled {
+                    self.total_stack -= 8;
                     let reg = self.free_reg();
                     self.asm_buf.extend_from_slice(&[
                         Instruction::Mov {
@@ -1321,56 +2258,82 @@ impl<'ctx> CodeGen<'ctx> {
                     ret_loc
                 }
             }
-            Expr::TraitMeth { trait_, args, type_args, def } => self.gen_call_expr(
-                trait_,
+            // The impl method is already named for the types it implements the trait for
+            Expr::TraitMeth { trait_, args, type_args: _, def } => self.gen_call_expr(
+                &ty::Path { span: trait_.span, ..ty::Path::single(def.method.ident) },
                 def.method.kind,
                 &def.method.ret,
                 args,
-                type_args,
+                &[],
                 can_clear,
             ),
             Expr::FieldAccess { lhs, rhs, def } => {
                 let lval = self.vars.get(&lhs.as_ident()).cloned();
-                if let Some(Location::NumberedOffset { offset, reg }) = &lval {
-                    let accessor = construct_field_offset(self, rhs, *offset, *reg, def)?;
+                if let Some(loc @ Location::NumberedOffset { offset, reg }) = &lval {
                     if matches!(lhs.type_of(), Ty::Ptr(..)) {
-                        let register = self.free_reg();
-                        self.asm_buf.extend_from_slice(&[Instruction::Mov {
-                            src: accessor,
-                            dst: Location::Register(register),
-                            comment: "deref of lvalue",
-                        }]);
-                        Location::NumberedOffset { offset: 0, reg: register }
+                        let (end, register) = self.struct_ptr_end(loc.clone(), def);
+                        construct_field_offset(self, rhs, end, register, def)?
                     } else {
-                        accessor
+                        construct_field_offset(self, rhs, *offset, *reg, def)?
                     }
                 } else {
                     panic!("have not resolved field access")
                 }
             }
-            Expr::StructInit { path: _, fields, .. } => {
-                fn flatten_struct_init(f: &FieldInit) -> Vec<&Expr> {
-                    match &f.init {
-                        Expr::StructInit { path, fields, def } => {
-                            fields.iter().flat_map(flatten_struct_init).collect::<Vec<_>>()
+            Expr::StructInit { .. } => {
+                /// The values a struct init is made of and the offset of each from the start of
+                /// the outermost struct.
+                fn flatten_struct_init<'e>(
+                    init: &'e Expr,
+                    at: usize,
+                    out: &mut Vec<(&'e Expr, usize)>,
+                ) {
+                    match init {
+                        Expr::StructInit { path: _, fields, def } => {
+                            for f in fields {
+                                let offset = def.field_offset(f.ident).unwrap();
+                                flatten_struct_init(&f.init, at + offset, out);
+                            }
                         }
-                        Expr::EnumInit { path, variant, items, def } => {
-                            items.iter().collect::<Vec<_>>()
+                        Expr::EnumInit { items, .. } | Expr::ArrayInit { items, .. } => {
+                            let mut at = at;
+                            for item in items {
+                                out.push((item, at));
+                                at += item.type_of().size();
+                            }
                         }
-                        Expr::ArrayInit { items, ty } => items.iter().collect::<Vec<_>>(),
-                        _ => vec![&f.init],
+                        _ => out.push((init, at)),
                     }
                 }
                 let lval: Option<Location> = try { self.vars.get(&assigned?)?.clone() };
                 if let Some(Location::NumberedOffset { offset, reg }) = &lval {
-                    let mut running_offset = *offset;
-                    for expr in fields.iter().flat_map(flatten_struct_init) {
+                    let mut values = vec![];
+                    flatten_struct_init(expr, 0, &mut values);
+                    for (expr, at) in values {
                         let mut rval =
                             self.build_value(expr, assigned, can_clear, is_addr).unwrap();
 
                         let ele_size = expr.type_of().size();
 
-                        if rval.is_stack_offset() {
+                        if let (Ty::Float, Location::Const { .. }) = (expr.type_of(), &rval) {
+                            // A float constant gets to a float register through the stack
+                            let freg = self.free_float_reg();
+                            self.asm_buf.extend_from_slice(&[
+                                Instruction::Push { loc: rval, size: 8, comment: "" },
+                                Instruction::Cvt {
+                                    src: Location::NumberedOffset { offset: 0, reg: Register::RSP },
+                                    dst: Location::FloatReg(freg),
+                                },
+                                Instruction::Math {
+                                    src: Location::Const { val: Val::Int(8) },
+                                    dst: RSP,
+                                    op: BinOp::Add,
+                                    cmt: "fix above push",
+                                },
+                            ]);
+                            self.used_float_regs.remove(&freg);
+                            rval = Location::FloatReg(freg);
+                        } else if rval.is_stack_offset() {
                             let tmp = self.free_reg();
                             self.asm_buf.extend_from_slice(&[Instruction::SizedMov {
                                 src: rval.clone(),
@@ -1384,16 +2347,10 @@ impl<'ctx> CodeGen<'ctx> {
                         self.asm_buf.extend_from_slice(&[Instruction::SizedMov {
                             // Move the value on the right hand side of the `= here`
                             src: rval,
-                            // to the left hand side of `here =`
-                            // The start offset - the current item size + the tag bits which are
-                            // first
-                            dst: Location::NumberedOffset { offset: running_offset, reg: *reg },
+                            // to the left hand side of `here =`, `at` bytes into the struct
+                            dst: Location::NumberedOffset { offset: offset - at, reg: *reg },
                             size: ele_size,
                         }]);
-
-                        // The first field is the start of the struct so don't go to the next field
-                        // until after it's been initialized
-                        running_offset -= ele_size;
                     }
 
                     if let Some(lval @ Location::NumberedOffset { .. }) = lval {
@@ -1431,25 +2388,35 @@ impl<'ctx> CodeGen<'ctx> {
                     todo!()
                 }
 
-                let (mut running_offset, reg) =
-                    if let Some(Location::NumberedOffset { offset, reg }) = lval {
-                        (offset, reg)
-                    } else {
-                        todo!("not sure")
-                    };
-                for item in items.iter() {
-                    let rval = self.build_value(item, None, can_clear, is_addr).unwrap();
+                let mov_to = lval.clone().unwrap_or_else(|| todo!("not sure"));
+                // Each item starts at the offset the padded variant layout gives it
+                for (item, at) in items.iter().zip(def.variants[tag].item_offsets()) {
+                    let mut rval = self.build_value(item, None, can_clear, is_addr).unwrap();
 
                     let ele_size = item.type_of().size();
 
-                    running_offset -= ele_size;
+                    // There is no memory to memory move
+                    if rval.is_stack_offset() {
+                        let tmp = self.free_reg();
+                        self.asm_buf.push(if let Expr::Value(Val::Str(..)) = item {
+                            // A string literal is stored by address
+                            Instruction::Load { src: rval, dst: Location::Register(tmp), size: 8 }
+                        } else {
+                            Instruction::SizedMov {
+                                src: rval,
+                                dst: Location::Register(tmp),
+                                size: ele_size,
+                            }
+                        });
+                        self.used_regs.remove(&tmp);
+                        rval = Location::Register(tmp)
+                    }
+
                     self.asm_buf.extend_from_slice(&[Instruction::SizedMov {
                         // Move the value on the right hand side of the `= here`
                         src: rval,
                         // to the left hand side of `here =`
-                        // The start offset - the current item size + the tag bits which are
-                        // first
-                        dst: Location::NumberedOffset { offset: running_offset, reg },
+                        dst: field_loc(&mov_to, at),
                         size: ele_size,
                     }]);
                 }
@@ -1499,7 +2466,9 @@ impl<'ctx> CodeGen<'ctx> {
                 }
             }
             Expr::Value(val) => match val {
-                Val::Float(_) | Val::Int(_) | Val::Bool(_) => Location::Const { val: val.clone() },
+                Val::Float(_) | Val::Int(_) | Val::SizedInt(..) | Val::Bool(_) => {
+                    Location::Const { val: val.clone() }
+                }
                 Val::Char(c) => Location::Const { val: Val::Int(*c as isize) },
                 Val::Str(s) => {
                     let string = s.name();
@@ -1511,14 +2480,258 @@ impl<'ctx> CodeGen<'ctx> {
                     Location::NamedOffset(x.name().to_string())
                 }
             },
+            Expr::If { cond, blk, els, tmp, ty } => {
+                let live = self.save_live_regs();
+                let loc = self.alloc_stack(*tmp, ty);
+                self.gen_if(cond, blk, Some(els));
+                self.clear_regs_except(None, CanClearRegs::Yes);
+                self.restore_live_regs(live);
+                loc
+            }
+            Expr::Match { expr, arms, match_ty, tmp, ty } => {
+                let live = self.save_live_regs();
+                let loc = self.alloc_stack(*tmp, ty);
+                self.gen_match(expr, arms, match_ty);
+                self.clear_regs_except(None, CanClearRegs::Yes);
+                self.restore_live_regs(live);
+                loc
+            }
+            Expr::Cast { expr, ty } => {
+                match (&**expr, self.build_value(expr, None, can_clear, true)?) {
+                    // Indexing an array gives the address of the element
+                    (Expr::Array { ty: ele_ty, .. }, Location::Register(reg)) => {
+                        self.gen_cast(Location::NumberedOffset { offset: 0, reg }, ele_ty, ty)
+                    }
+                    (expr, loc) => self.gen_cast(loc, &expr.type_of(), ty),
+                }
+            }
+            Expr::InlineAsm { asm, reg, .. } => self.gen_inline_asm(asm, Some(reg))?,
             Expr::Builtin(Builtin::SizeOf(ty)) => {
                 Location::Const { val: Val::Int(ty.size() as isize) }
             }
+            // The length of an array was folded to a value
+            Expr::Builtin(Builtin::Len(slice)) => {
+                let loc = self.build_value(slice, None, can_clear, false)?;
+                let register = self.free_reg();
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Mov {
+                        src: loc,
+                        dst: Location::Register(register),
+                        comment: "address of slice",
+                    },
+                    Instruction::Mov {
+                        src: Location::NumberedOffset { offset: 8, reg: register },
+                        dst: Location::Register(register),
+                        comment: "slice length",
+                    },
+                ]);
+                Location::Register(register)
+            }
             Expr::Builtin(..) => unreachable!("should be something else by now"),
         });
         val
     }
 
+    /// Move the inputs of `asm` into their registers, emit the block then move each output
+    /// register to its variable.
+    ///
+    /// Every input is pushed before any is moved so evaluating one can not overwrite the register
+    /// of another, a register the block uses that holds a live value or is callee saved is saved
+    /// around it. The `result` register of an `asm` expression is moved to a free register before
+    /// any are restored.
+    fn gen_inline_asm(
+        &mut self,
+        asm: &'ctx AsmBlock,
+        result: Option<&ty::Location>,
+    ) -> Option<Location> {
+        let mut saved = vec![];
+        for loc in asm
+            .inputs
+            .iter()
+            .map(|(reg, _)| reg)
+            .chain(asm.outputs.iter().map(|(reg, _)| reg))
+            .chain(&asm.clobbers)
+            .chain(result)
+        {
+            if saved.contains(&loc) {
+                continue;
+            }
+            match loc {
+                ty::Location::Register(reg)
+                    if self.used_regs.contains(reg) || CALLEE_SAVED.contains(reg) =>
+                {
+                    self.asm_buf.push(Instruction::Push {
+                        loc: Location::Register(*reg),
+                        size: 8,
+                        comment: "save register around asm",
+                    });
+                }
+                ty::Location::FloatReg(reg) if self.used_float_regs.contains(reg) => {
+                    self.asm_buf.extend_from_slice(&[
+                        Instruction::Math {
+                            src: Location::Const { val: Val::Int(8) },
+                            dst: RSP,
+                            op: BinOp::Sub,
+                            cmt: "save float register around asm",
+                        },
+                        Instruction::FloatMov { src: Location::FloatReg(*reg), dst: STACK_TOP },
+                    ]);
+                }
+                _ => continue,
+            }
+            saved.push(loc);
+        }
+
+        for (_, expr) in &asm.inputs {
+            let val = self.build_value(expr, None, CanClearRegs::No, false).unwrap();
+            self.push_asm_input(val, &expr.type_of());
+        }
+        for (reg, _) in asm.inputs.iter().rev() {
+            self.pop_asm_reg(reg, "asm input");
+        }
+
+        let mut clobbers = AsmClobbers { at: self.asm_buf.len(), regs: vec![], float_regs: vec![] };
+        for loc in &asm.clobbers {
+            match loc {
+                ty::Location::Register(reg) => clobbers.regs.push(*reg),
+                ty::Location::FloatReg(reg) => clobbers.float_regs.push(*reg),
+                _ => unreachable!("asm clobbers are parsed as registers"),
+            }
+        }
+        self.asm_clobbers.push(clobbers);
+
+        for inst in &asm.assembly {
+            let mut asm_str = format!("    {}  ", inst.inst);
+            if let Some(src) = &inst.src {
+                asm_str.push_str(&self.asm_operand(src));
+                if let Some(dst) = &inst.dst {
+                    asm_str.push_str(",  ");
+                    asm_str.push_str(&self.asm_operand(dst));
+                }
+            }
+            self.asm_buf.push(Instruction::Meta(asm_str));
+        }
+
+        for (reg, lval) in &asm.outputs {
+            let dst = self.get_pointer(lval).unwrap();
+            self.asm_buf.push(match reg {
+                ty::Location::Register(reg) => Instruction::SizedMov {
+                    src: Location::Register(*reg),
+                    dst,
+                    size: lval.type_of().size(),
+                },
+                ty::Location::FloatReg(reg) => {
+                    Instruction::FloatMov { src: Location::FloatReg(*reg), dst }
+                }
+                _ => unreachable!("asm operands are parsed as registers"),
+            });
+        }
+        let value = result.map(|reg| match reg {
+            ty::Location::Register(reg) => {
+                let dst = Location::Register(self.free_reg());
+                self.asm_buf.push(Instruction::Mov {
+                    src: Location::Register(*reg),
+                    dst: dst.clone(),
+                    comment: "asm result",
+                });
+                dst
+            }
+            ty::Location::FloatReg(reg) => {
+                let dst = Location::FloatReg(self.free_float_reg());
+                self.asm_buf.push(Instruction::FloatMov {
+                    src: Location::FloatReg(*reg),
+                    dst: dst.clone(),
+                });
+                dst
+            }
+            _ => unreachable!("the result of an asm expression is a register"),
+        });
+        for reg in saved.into_iter().rev() {
+            self.pop_asm_reg(reg, "restore register after asm");
+        }
+        value
+    }
+
+    /// Push the 8 bytes of an `asm` input at `val`, a float constant is widened to a double.
+    fn push_asm_input(&mut self, val: Location, ty: &Ty) {
+        match (val, ty) {
+            (val @ Location::Const { .. }, Ty::Float) => {
+                let freg = Location::FloatReg(self.free_float_reg());
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Push { loc: val, size: 8, comment: "float asm input" },
+                    Instruction::Cvt { src: STACK_TOP, dst: freg.clone() },
+                    Instruction::FloatMov { src: freg, dst: STACK_TOP },
+                ]);
+            }
+            (freg @ Location::FloatReg(_), _) => {
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Math {
+                        src: Location::Const { val: Val::Int(8) },
+                        dst: RSP,
+                        op: BinOp::Sub,
+                        cmt: "float asm input",
+                    },
+                    Instruction::FloatMov { src: freg, dst: STACK_TOP },
+                ]);
+            }
+            (val, Ty::Float) => {
+                self.asm_buf.push(Instruction::Push { loc: val, size: 8, comment: "asm input" });
+            }
+            (val, ty) => {
+                let reg = Location::Register(self.free_reg());
+                self.asm_buf.extend_from_slice(&[
+                    match val {
+                        // A string literal is its label, the value is the address of the label
+                        val @ (Location::NamedOffset(_) | Location::Label(_))
+                            if matches!(ty, Ty::ConstStr(..)) =>
+                        {
+                            Instruction::Load { src: val, dst: reg.clone(), size: 8 }
+                        }
+                        val => mov_value(val, reg.clone(), ty, "asm input"),
+                    },
+                    Instruction::Push { loc: reg, size: 8, comment: "asm input" },
+                ]);
+            }
+        }
+    }
+
+    /// Pop the top 8 bytes of the stack into the general purpose or float register `reg`.
+    fn pop_asm_reg(&mut self, reg: &ty::Location, comment: &'static str) {
+        match reg {
+            ty::Location::Register(reg) => {
+                self.asm_buf.push(Instruction::Pop {
+                    loc: Location::Register(*reg),
+                    size: 8,
+                    comment,
+                });
+            }
+            ty::Location::FloatReg(reg) => {
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::FloatMov { src: STACK_TOP, dst: Location::FloatReg(*reg) },
+                    Instruction::Math {
+                        src: Location::Const { val: Val::Int(8) },
+                        dst: RSP,
+                        op: BinOp::Add,
+                        cmt: comment,
+                    },
+                ]);
+            }
+            _ => unreachable!("asm operands are parsed as registers"),
+        }
+    }
+
+    /// The text of an operand written in an `asm` block.
+    fn asm_operand(&self, loc: &ty::Location) -> String {
+        match loc {
+            ty::Location::Register(reg) => reg.to_string(),
+            ty::Location::FloatReg(reg) => reg.to_string(),
+            ty::Location::NamedOffset(name) => format!("{}(%rip)", name),
+            ty::Location::Offset { amt, reg } => format!("{}({})", amt, self.asm_operand(reg)),
+            ty::Location::InlineVar(ident) => self.vars.get(ident).unwrap().to_string(),
+            ty::Location::Const(v) => Location::Const { val: Val::lower(v.clone()) }.to_string(),
+        }
+    }
+
     fn gen_statement(&mut self, stmt: &'ctx Stmt) {
         match stmt {
             Stmt::Const(var) => {
@@ -1547,9 +2760,28 @@ impl<'ctx> CodeGen<'ctx> {
                 }
 
                 let ty = lval.type_of();
-                let size = match ty {
-                    Ty::Array { ty, .. } => ty.size(),
-                    t => t.size(),
+                if let (Some(upper), Expr::Call { .. } | Expr::TraitMeth { .. }) =
+                    (ret_upper_size(ty), rval)
+                {
+                    // The call returned the value in `%rax` and `%rdx`, unless they were stored
+                    // to a slot because `%rax` was spilled around the call
+                    if lloc.is_stack_offset() && !rloc.is_stack_offset() {
+                        self.asm_buf.extend_from_slice(&[
+                            Instruction::SizedMov {
+                                src: RDX,
+                                dst: field_loc(&lloc, 8),
+                                size: upper,
+                            },
+                            Instruction::SizedMov { src: rloc, dst: lloc, size: 8 },
+                        ]);
+                        return;
+                    }
+                }
+                let size = match (lval, ty) {
+                    // `*p = 1u8` only writes the byte `p` points to
+                    (LValue::Deref { .. }, Ty::Ptr(t) | Ty::Ref(t)) if t.size() < 8 => t.size(),
+                    (_, Ty::Array { ty, .. }) => ty.size(),
+                    (_, t) => t.size(),
                 };
 
                 if matches!(rval.type_of(), Ty::Array { .. }) {
@@ -1743,6 +2975,15 @@ impl<'ctx> CodeGen<'ctx> {
                         dst: Location::NumberedOffset { offset: 0, reg: *reg },
                         size,
                     }]);
+                } else if let (
+                    true,
+                    Location::NumberedOffset { .. },
+                    Location::NumberedOffset { .. },
+                ) = (size > 8, &lloc, &rloc)
+                {
+                    // A struct returned through memory or copied from another variable
+                    self.clear_regs_except(None, CanClearRegs::Yes);
+                    self.copy_bytes(&rloc, &lloc, size);
                 } else if lloc.is_stack_offset() && rloc.is_stack_offset() {
                     let register = self.free_reg();
                     self.clear_regs_except(None, CanClearRegs::Yes);
@@ -1771,7 +3012,11 @@ impl<'ctx> CodeGen<'ctx> {
                         src: rloc,
                         // to the left hand side of `here =`
                         dst: lloc,
-                        size: 8,
+                        // Only the bytes of a sized integer are stored
+                        size: match (lval, ty) {
+                            (LValue::Deref { .. }, _) | (_, Ty::SizedInt(_)) => size,
+                            _ => 8,
+                        },
                     }]);
                 }
             }
@@ -1783,39 +3028,20 @@ impl<'ctx> CodeGen<'ctx> {
                 self.clear_float_regs_except(Some(&XMM0), CanClearRegs::Yes);
                 self.clear_regs_except(Some(&RAX), CanClearRegs::Yes);
             }
-            Stmt::TraitMeth { expr: _, def: _ } => todo!(),
-            Stmt::If { cond, blk, els } => {
-                let cond_val = self.build_value(cond, None, CanClearRegs::Yes, false).unwrap();
-                // Check if true
-                self.asm_buf.push(Instruction::Cmp {
-                    src: Location::Const { val: Val::Int(1) },
-                    dst: cond_val,
-                });
-
-                let name = format!(".jmpif{}", self.asm_buf.len());
-                let else_or_uncond = Location::Label(name.clone());
-                // Jump over the "then" block
-                self.asm_buf
-                    .push(Instruction::CondJmp { loc: else_or_uncond, cond: JmpCond::NotEq });
-
-                for stmt in &blk.stmts {
-                    self.gen_statement(stmt);
-                }
-
-                // Fall through or "merge" point
-                let merge_label = format!(".mergeif{}", self.asm_buf.len());
-                let merge_loc = Location::Label(merge_label.clone());
-                self.asm_buf.push(Instruction::Jmp(merge_loc));
-
-                self.asm_buf.push(Instruction::Label(name));
-                if let Some(els) = els {
-                    for stmt in &els.stmts {
-                        self.gen_statement(stmt);
-                    }
-                }
+            Stmt::TraitMeth { expr, def } => {
+                self.gen_call_expr(
+                    &ty::Path { span: expr.trait_.span, ..ty::Path::single(def.method.ident) },
+                    def.method.kind,
+                    &def.method.ret,
+                    &expr.args,
+                    &[],
+                    CanClearRegs::No,
+                );
 
-                self.asm_buf.push(Instruction::Label(merge_label));
+                self.clear_float_regs_except(Some(&XMM0), CanClearRegs::Yes);
+                self.clear_regs_except(Some(&RAX), CanClearRegs::Yes);
             }
+            Stmt::If { cond, blk, els } => self.gen_if(cond, blk, els.as_ref()),
             Stmt::While { cond, stmts } => {
                 let uncond_label = format!(".uncondwhile{}", self.asm_buf.len());
                 let uncond_loc = Location::Label(uncond_label.clone());
@@ -1824,10 +3050,13 @@ impl<'ctx> CodeGen<'ctx> {
                 let name = format!(".jmpwhile{}", self.asm_buf.len());
                 let loop_body = Location::Label(name.clone());
                 self.asm_buf.push(Instruction::Label(name));
+                let end_label = format!(".endwhile{}", self.asm_buf.len());
+                self.loop_ends.push(end_label.clone());
                 // Start loop body
                 for stmt in &stmts.stmts {
                     self.gen_statement(stmt);
                 }
+                self.loop_ends.pop();
 
                 self.asm_buf.push(Instruction::Label(uncond_label));
                 if cond.is_const_true() {
@@ -1842,38 +3071,34 @@ impl<'ctx> CodeGen<'ctx> {
                     // Jump back to the loop body
                     self.asm_buf.push(Instruction::CondJmp { loc: loop_body, cond: JmpCond::Eq });
                 }
+                self.asm_buf.push(Instruction::Label(end_label));
             }
-            Stmt::Match { expr, arms, ty } => {
-                let val = self.build_value(expr, None, CanClearRegs::Yes, false).unwrap();
-
-                let match_merge = format!(".matchmerge{}", self.asm_buf.len());
-                let mut jump_stream = vec![];
-                for (idx, arm) in arms.iter().enumerate() {
-                    jump_stream.push(vec![]);
-                    self.gen_match_arm(&arm.pat, &val, None, ty, &mut jump_stream[idx]);
-                    // Add the block instructions (from stmts) to the jump stream
-                    std::mem::swap(&mut self.asm_buf, &mut jump_stream[idx]);
-                    for stmt in &arm.blk.stmts {
-                        self.gen_statement(stmt);
-                    }
-                    self.asm_buf.push(Instruction::Jmp(Location::Label(match_merge.clone())));
-
-                    // Now swap the asm_buf back to where it should be
-                    std::mem::swap(&mut self.asm_buf, &mut jump_stream[idx]);
-                }
-
-                for inst_buf in jump_stream {
-                    self.asm_buf.extend_from_slice(&inst_buf);
-                }
-
-                self.asm_buf.push(Instruction::Label(match_merge));
-            }
+            Stmt::Match { expr, arms, ty } => self.gen_match(expr, arms, ty),
             // TODO: we double the `leave; ret;` instructions for functions that actually return
             Stmt::Ret(expr, ty) => {
                 let expr_ty = expr.type_of();
 
                 let val = self.build_value(expr, None, CanClearRegs::Yes, *ty == expr_ty).unwrap();
-                if matches!(ty, Ty::Float) {
+                if let Some(ptr) = self.sret_ptr.clone() {
+                    // The caller passed the address to copy the value to, it is returned in `%rax`
+                    let reg = self.free_reg();
+                    self.asm_buf.extend_from_slice(&[
+                        Instruction::Mov {
+                            src: ptr.clone(),
+                            dst: Location::Register(reg),
+                            comment: "address to return to",
+                        },
+                        Instruction::Math {
+                            src: Location::Const { val: Val::Int(ty.size() as isize) },
+                            dst: Location::Register(reg),
+                            op: BinOp::Add,
+                            cmt: "end of returned value",
+                        },
+                    ]);
+                    let dst = Location::NumberedOffset { offset: ty.size(), reg };
+                    self.copy_bytes(&val, &dst, ty.size());
+                    self.asm_buf.push(Instruction::Mov { src: ptr, dst: RAX, comment: "" });
+                } else if matches!(ty, Ty::Float) {
                     if !matches!(val, XMM0) {
                         if matches!(val, Location::Const { .. }) {
                             self.asm_buf.extend_from_slice(&[
@@ -1892,153 +3117,609 @@ impl<'ctx> CodeGen<'ctx> {
                             );
                         }
                     }
+                } else if let Some(upper) = ret_upper_size(ty) {
+                    // Enums and structs up to 16 bytes are returned in `%rax` and `%rdx`
+                    match val {
+                        Location::NumberedOffset { .. } => {
+                            self.asm_buf.extend_from_slice(&[
+                                Instruction::SizedMov {
+                                    src: field_loc(&val, 8),
+                                    dst: RDX,
+                                    size: upper,
+                                },
+                                Instruction::SizedMov { src: val, dst: RAX, size: 8 },
+                            ]);
+                        }
+                        // A call already left the value there
+                        RAX => {}
+                        val => {
+                            self.asm_buf.push(Instruction::Mov { src: val, dst: RAX, comment: "" })
+                        }
+                    }
                 } else if !matches!(val, RAX) {
                     // println!("{:?}", ty);
                     if matches!(expr_ty, Ty::Array { .. }) {
                         if let Location::Register(reg) = val {
-                            self.asm_buf.push(Instruction::SizedMov {
-                                src: Location::NumberedOffset { offset: 0, reg },
-                                dst: RAX,
-                                size: ty.size(),
+                            self.asm_buf.push(if let Ty::SizedInt(_) = ty {
+                                mov_value(Location::NumberedOffset { offset: 0, reg }, RAX, ty, "")
+                            } else {
+                                Instruction::SizedMov {
+                                    src: Location::NumberedOffset { offset: 0, reg },
+                                    dst: RAX,
+                                    size: ty.size(),
+                                }
                             });
                         }
+                    } else if let Expr::Value(Val::Str(..)) = expr {
+                        // A string literal is returned by address
+                        self.asm_buf.push(Instruction::Load { src: val, dst: RAX, size: 8 });
                     } else {
+                        let val = self.load_narrow(val, ty);
                         self.asm_buf.push(
                             // return value is stored in %rax
                             Instruction::Mov { src: val, dst: RAX, comment: "" },
                         );
                     }
                 }
+                // The eightbytes of a struct with floats in it move from `%rax` and `%rdx` to
+                // the registers of their class
+                if let (Ty::Struct { .. }, Pass::Regs(classes)) = (ty, abi::classify(ty)) {
+                    for (idx, reg) in abi::ret_regs(&classes).into_iter().enumerate() {
+                        let src = if idx == 0 { RAX } else { RDX };
+                        if reg != src {
+                            self.asm_buf.push(Instruction::SizedMov { src, dst: reg, size: 8 });
+                        }
+                    }
+                }
                 self.asm_buf.extend_from_slice(&[Instruction::Leave, Instruction::Ret]);
             }
             Stmt::Exit => {
                 self.asm_buf.extend_from_slice(&[Instruction::Leave, Instruction::Ret]);
             }
-            Stmt::Block(_) => todo!(),
+            Stmt::Break => {
+                let end = self.loop_ends.last().expect("`break` is checked to be in a loop");
+                self.asm_buf.push(Instruction::Jmp(Location::Label(end.clone())));
+            }
+            Stmt::Block(blk) => {
+                for stmt in &blk.stmts {
+                    self.gen_statement(stmt);
+                }
+            }
             Stmt::InlineAsm(asm) => {
-                for inst in &asm.assembly {
-                    let mut asm_str = format!("    {}  ", inst.inst);
-
-                    if let Some(src) = &inst.src {
-                        match src {
-                            ty::Location::Register(reg) => asm_str.push_str(&reg.to_string()),
-                            ty::Location::FloatReg(reg) => asm_str.push_str(&reg.to_string()),
-                            ty::Location::NamedOffset(_) => todo!(),
-                            ty::Location::Offset { amt, reg } => todo!(),
-                            ty::Location::InlineVar(ident) => {
-                                asm_str.push_str(&format!("{}", self.vars.get(ident).unwrap(),))
-                            }
-                            ty::Location::Const(v) => asm_str.push_str(&format!(
-                                "{}",
-                                Location::Const { val: Val::lower(v.clone()) }
-                            )),
-                        }
-                        asm_str.push_str(",  ");
-                        if let Some(src) = &inst.dst {
-                            match src {
-                                ty::Location::Register(reg) => asm_str.push_str(&reg.to_string()),
-                                ty::Location::FloatReg(reg) => asm_str.push_str(&reg.to_string()),
-                                ty::Location::NamedOffset(_) => todo!(),
-                                ty::Location::Offset { amt, reg } => todo!(),
-                                ty::Location::InlineVar(ident) => {
-                                    asm_str.push_str(&format!("{}", self.vars.get(ident).unwrap(),))
-                                }
-                                ty::Location::Const(v) => asm_str.push_str(&format!(
-                                    "{}",
-                                    Location::Const { val: Val::lower(v.clone()) }
-                                )),
-                            }
-                        }
-                    }
+                self.gen_inline_asm(asm, None);
+            }
+            Stmt::Builtin(ty::Builtin::Bottom, src_loc) => {
+                let label = self.next_label(".unreachable");
+                let msg = format!("{}: entered unreachable code\n", src_loc);
+                self.traps.push(Trap { label: label.clone(), msg, index: None });
+                self.asm_buf.push(Instruction::Jmp(Location::Label(label)));
+            }
+            Stmt::Builtin(b, _) => {
+                self.asm_buf.push(Instruction::Meta(format!(" # a builtin was replaced {}", b)));
+            }
+        }
+    }
+
+    /// Shared by `if` statements and expressions, the blocks of an expression already end by
+    /// storing their value.
+    fn gen_if(&mut self, cond: &'ctx Expr, blk: &'ctx Block, els: Option<&'ctx Block>) {
+        let stack = self.current_stack;
+        let cond_val = self.build_value(cond, None, CanClearRegs::Yes, false).unwrap();
+        // Check if true
+        self.asm_buf
+            .push(Instruction::Cmp { src: Location::Const { val: Val::Int(1) }, dst: cond_val });
+
+        let name = format!(".jmpif{}", self.asm_buf.len());
+        let else_or_uncond = Location::Label(name.clone());
+        // Jump over the "then" block
+        self.asm_buf.push(Instruction::CondJmp { loc: else_or_uncond, cond: JmpCond::NotEq });
+
+        for stmt in &blk.stmts {
+            self.gen_statement(stmt);
+        }
+
+        // Fall through or "merge" point
+        let merge_label = format!(".mergeif{}", self.asm_buf.len());
+        let merge_loc = Location::Label(merge_label.clone());
+        self.asm_buf.push(Instruction::Jmp(merge_loc));
+
+        self.asm_buf.push(Instruction::Label(name));
+        if let Some(els) = els {
+            for stmt in &els.stmts {
+                self.gen_statement(stmt);
+            }
+        }
+
+        self.asm_buf.push(Instruction::Label(merge_label));
+        self.sync_stack(stack);
+    }
+
+    /// Save the registers that hold part of the expression around an `if` or `match` value, like
+    /// the left side of `x * 3 + if c { 1 } else { 2 }`, since the branches use any register.
+    fn save_live_regs(&mut self) -> Vec<(Register, Location)> {
+        let live =
+            USABLE_REGS.iter().copied().filter(|r| self.used_regs.contains(r)).collect::<Vec<_>>();
+        live.into_iter()
+            .map(|reg| {
+                self.current_stack += 8;
+                self.total_stack += 8;
+                self.asm_buf.push(Instruction::Push {
+                    loc: Location::Register(reg),
+                    size: 8,
+                    comment: "save register around branch",
+                });
+                (reg, Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP })
+            })
+            .collect()
+    }
+
+    /// Load the registers `save_live_regs` saved, the branches may have moved `%rsp` so they are
+    /// read from their slots instead of popped.
+    fn restore_live_regs(&mut self, live: Vec<(Register, Location)>) {
+        for (reg, slot) in live {
+            self.use_reg(reg);
+            self.asm_buf.push(Instruction::Mov {
+                src: slot,
+                dst: Location::Register(reg),
+                comment: "restore register after branch",
+            });
+        }
+    }
+
+    /// A variable declared in a branch only moved `%rsp` on that path, after the branches merge
+    /// `%rsp` has to cover every variable so the next one does not end up below it.
+    fn sync_stack(&mut self, before_branch: usize) {
+        if self.current_stack != before_branch {
+            self.asm_buf.push(Instruction::Load {
+                src: Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP },
+                dst: RSP,
+                size: 8,
+            });
+        }
+    }
 
-                    self.asm_buf.push(Instruction::Meta(asm_str));
+    fn gen_match(&mut self, expr: &'ctx Expr, arms: &'ctx [MatchArm], ty: &Ty) {
+        let mut val = self.build_value(expr, None, CanClearRegs::Yes, false).unwrap();
+        // Guards and arm bodies are free to use any register so keep the value we are
+        // matching on somewhere stable
+        if !val.is_stack_offset() {
+            // The upper half of an enum returned in `%rax` and `%rdx` goes at the higher address
+            if ret_upper_size(ty).is_some() {
+                self.current_stack += 8;
+                self.total_stack += 8;
+                self.asm_buf.push(Instruction::Push {
+                    loc: RDX,
+                    size: 8,
+                    comment: "spill match value upper half",
+                });
+            }
+            self.current_stack += 8;
+            self.total_stack += 8;
+            self.asm_buf.push(Instruction::Push {
+                loc: val,
+                size: 8,
+                comment: "spill match value",
+            });
+            val = Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP };
+        } else if !is_frame_loc(&val) {
+            // Memory addressed by a scratch register, like `*ptr`, is copied to a slot since the
+            // register is free to be reused once we start matching
+            let size = abi::eightbytes(ty.size()) * 8;
+            self.current_stack += size;
+            self.total_stack += size;
+            self.asm_buf.push(Instruction::Math {
+                src: Location::Const { val: Val::Int(size as isize) },
+                dst: RSP,
+                op: BinOp::Sub,
+                cmt: "copy match value",
+            });
+            let slot = Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP };
+            self.copy_bytes(&val, &slot, ty.size());
+            val = slot;
+        }
+        self.clear_regs_except(None, CanClearRegs::Yes);
+
+        // Each alternative of an or-pattern can find a variable in a different place so
+        // they get a slot of their own that every alternative copies into
+        let mut or_slots = vec![];
+        for arm in arms {
+            let mut slots = HashMap::default();
+            self.alloc_or_bindings(&arm.pat, ty, false, &mut slots);
+            or_slots.push(slots);
+        }
+
+        let stack = self.current_stack;
+        let match_merge = format!(".matchmerge{}", self.asm_buf.len());
+        let arm_labels = if let Some(table) = jump_table(arms, ty) {
+            let labels = arms.iter().map(|_| self.next_label(".matcharm")).collect::<Vec<_>>();
+            self.gen_jump_table(&val, table, &labels, &match_merge);
+            Some(labels)
+        } else {
+            None
+        };
+
+        for (idx, (arm, slots)) in arms.iter().zip(&or_slots).enumerate() {
+            if let Some(labels) = &arm_labels {
+                self.asm_buf.push(Instruction::Label(labels[idx].clone()));
+            }
+            // Where to go if this arm does not match
+            let next_arm = self.next_label(".matchnext");
+            let next_loc = Location::Label(next_arm.clone());
+
+            self.gen_match_arm(&arm.pat, &val, ty, &next_loc, slots);
+            if let Some(guard) = &arm.guard {
+                let mut cond = self.build_value(guard, None, CanClearRegs::Yes, false).unwrap();
+                if let Location::Const { .. } = cond {
+                    let reg = self.free_reg();
+                    self.asm_buf.push(Instruction::Mov {
+                        src: cond,
+                        dst: Location::Register(reg),
+                        comment: "const match guard",
+                    });
+                    cond = Location::Register(reg);
                 }
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Cmp { src: ONE, dst: cond },
+                    Instruction::CondJmp { loc: next_loc, cond: JmpCond::NotEq },
+                ]);
+                self.clear_regs_except(None, CanClearRegs::Yes);
             }
-            Stmt::Builtin(b) => {
-                self.asm_buf.push(Instruction::Meta(format!(" # a builtin was replaced {}", b)));
+
+            for stmt in &arm.blk.stmts {
+                self.gen_statement(stmt);
             }
+            self.asm_buf.extend_from_slice(&[
+                Instruction::Jmp(Location::Label(match_merge.clone())),
+                Instruction::Label(next_arm),
+            ]);
         }
+
+        self.asm_buf.push(Instruction::Label(match_merge));
+        self.sync_stack(stack);
     }
 
+    /// Jump to the first arm that can match the value at `loc`, the arm still tests the whole
+    /// pattern and falls through to the next arm if it does not match.
+    fn gen_jump_table(
+        &mut self,
+        loc: &Location,
+        table: JumpTable,
+        arm_labels: &[String],
+        match_merge: &str,
+    ) {
+        let label_of = |arm: Option<usize>| {
+            arm.map_or_else(|| match_merge.to_owned(), |i| arm_labels[i].clone())
+        };
+        let name = self.next_label(".Ljumptable");
+
+        let idx = self.free_reg();
+        self.asm_buf.push(Instruction::Mov {
+            src: loc.clone(),
+            dst: Location::Register(idx),
+            comment: "jump table index",
+        });
+        if table.min != 0 {
+            self.asm_buf.push(Instruction::Math {
+                src: Location::Const { val: Val::Int(table.min) },
+                dst: Location::Register(idx),
+                op: BinOp::Sub,
+                cmt: "start jump table at zero",
+            });
+        }
+        // Negative values wrap around so one unsigned compare checks both ends of the table
+        let table_reg = self.free_reg();
+        self.asm_buf.extend_from_slice(&[
+            Instruction::Cmp {
+                src: Location::Const { val: Val::Int(table.entries.len() as isize - 1) },
+                dst: Location::Register(idx),
+            },
+            Instruction::CondJmp {
+                loc: Location::Label(label_of(table.default)),
+                cond: JmpCond::Above,
+            },
+            Instruction::Load {
+                src: Location::NamedOffset(name.clone()),
+                dst: Location::Register(table_reg),
+                size: 8,
+            },
+            Instruction::JmpTable { table: table_reg, idx },
+        ]);
+        self.clear_regs_except(None, CanClearRegs::Yes);
+
+        let labels = table.entries.into_iter().map(label_of).collect();
+        self.globals.insert(Ident::new(ty::DUMMY, &name), Global::JumpTable { name, labels });
+    }
+
+    fn next_label(&mut self, prefix: &str) -> String {
+        self.label_count += 1;
+        format!("{}{}", prefix, self.label_count)
+    }
+
+    /// Make room on the stack for any variables bound inside of an or-pattern.
+    fn alloc_or_bindings(
+        &mut self,
+        pat: &Pat,
+        ty: &Ty,
+        in_or: bool,
+        slots: &mut HashMap<Ident, Location>,
+    ) {
+        match pat {
+            Pat::Enum { idx, items, .. } => {
+                let var = if let Ty::Enum { def, .. } = ty {
+                    &def.variants[*idx]
+                } else {
+                    unreachable!("enum match without enum")
+                };
+                for (item, item_ty) in items.iter().zip(&var.types) {
+                    self.alloc_or_bindings(item, item_ty, in_or, slots);
+                }
+            }
+            Pat::Array { items, .. } => {
+                let ele_ty =
+                    if let Ty::Array { ty, .. } = ty { ty } else { unreachable!("array match") };
+                for item in items {
+                    self.alloc_or_bindings(item, ele_ty, in_or, slots);
+                }
+            }
+            Pat::Struct { fields, .. } => {
+                let def = if let Ty::Struct { def, .. } = ty {
+                    def
+                } else {
+                    unreachable!("struct match")
+                };
+                for (ident, field) in fields {
+                    let field_ty = &def.fields.iter().find(|f| f.ident == *ident).unwrap().ty;
+                    self.alloc_or_bindings(field, field_ty, in_or, slots);
+                }
+            }
+            Pat::Or(alts) => {
+                for alt in alts {
+                    self.alloc_or_bindings(alt, ty, true, slots);
+                }
+            }
+            Pat::At { ident, pat } => {
+                if in_or && !slots.contains_key(ident) {
+                    let slot = self.alloc_stack(*ident, ty);
+                    slots.insert(*ident, slot);
+                }
+                self.alloc_or_bindings(pat, ty, in_or, slots);
+            }
+            Pat::Bind(Binding::Wild(ident)) => {
+                if in_or && !slots.contains_key(ident) {
+                    let slot = self.alloc_stack(*ident, ty);
+                    slots.insert(*ident, slot);
+                }
+            }
+            Pat::Bind(Binding::Value(_)) | Pat::Range { .. } | Pat::Wild => {}
+        }
+    }
+
+    /// Bind `ident` to the value at `loc`, copying it if the binding has a slot of it's own.
+    fn bind_pat(
+        &mut self,
+        ident: Ident,
+        loc: &Location,
+        ty: &Ty,
+        slots: &HashMap<Ident, Location>,
+    ) {
+        if let Some(slot) = slots.get(&ident) {
+            let tmp = self.free_reg();
+            if let Ty::SizedInt(ity) = ty {
+                self.asm_buf.extend_from_slice(&[
+                    mov_value(loc.clone(), Location::Register(tmp), ty, "copy or-pattern binding"),
+                    Instruction::SizedMov {
+                        src: Location::Register(tmp),
+                        dst: slot.clone(),
+                        size: ity.size(),
+                    },
+                ]);
+            } else {
+                for word in 0..((ty.size() + 7) / 8) {
+                    self.asm_buf.extend_from_slice(&[
+                        Instruction::Mov {
+                            src: field_loc(loc, word * 8),
+                            dst: Location::Register(tmp),
+                            comment: "copy or-pattern binding",
+                        },
+                        Instruction::Mov {
+                            src: Location::Register(tmp),
+                            dst: field_loc(slot, word * 8),
+                            comment: "",
+                        },
+                    ]);
+                }
+            }
+            self.clear_regs_except(None, CanClearRegs::Yes);
+            self.vars.insert(ident, slot.clone());
+        } else {
+            self.vars.insert(ident, loc.clone());
+        }
+    }
+
+    /// The operand to compare a literal in a pattern against, `cmp` only takes a 32 bit immediate
+    /// so a larger one is loaded into a register.
+    fn pat_operand(&mut self, val: &Val) -> Location {
+        match pat_const(val) {
+            Location::Const { val: Val::Int(v) | Val::SizedInt(v, _) }
+                if v < i32::MIN as isize || v > i32::MAX as isize =>
+            {
+                let reg = self.free_reg();
+                self.asm_buf.push(Instruction::Mov {
+                    src: pat_const(val),
+                    dst: Location::Register(reg),
+                    comment: "pattern literal is wider than an immediate",
+                });
+                Location::Register(reg)
+            }
+            imm => imm,
+        }
+    }
+
+    /// Test if the value at `loc` matches `pat` jumping to `fail` if it does not.
+    ///
+    /// Falls through when the pattern matches with any variables bound.
     fn gen_match_arm(
         &mut self,
         pat: &Pat,
-        tag_val: &Location,
-        item_idx: Option<usize>,
+        loc: &Location,
         ty: &Ty,
-        jump_stream: &mut Vec<Instruction>,
+        fail: &Location,
+        slots: &HashMap<Ident, Location>,
     ) {
         match pat {
             Pat::Enum { idx, items, .. } => {
                 let var = if let Ty::Enum { def, .. } = ty {
                     &def.variants[*idx]
                 } else {
-                    panic!("enum match without enum")
+                    unreachable!("enum match without enum")
                 };
-                let name = format!(".matcharm{}x{}", self.asm_buf.len(), idx);
-                let match_arm = Location::Label(name.clone());
 
                 // cmp enum tag to variant
                 self.asm_buf.extend_from_slice(&[
                     Instruction::Cmp {
                         src: Location::Const { val: Val::Int(*idx as isize) },
-                        dst: tag_val.clone(),
+                        dst: loc.clone(),
                     },
-                    Instruction::CondJmp { loc: match_arm, cond: JmpCond::Eq },
+                    Instruction::CondJmp { loc: fail.clone(), cond: JmpCond::NotEq },
                 ]);
 
-                jump_stream.push(Instruction::Label(name));
-                // cmp each non wildcard pattern
+                // The items follow the tag, see `Expr::EnumInit` in `build_value`
+                for ((item, item_ty), at) in items.iter().zip(&var.types).zip(var.item_offsets()) {
+                    self.gen_match_arm(item, &field_loc(loc, at), item_ty, fail, slots);
+                }
+            }
+            Pat::Array { items, .. } => {
+                let ele_ty =
+                    if let Ty::Array { ty, .. } = ty { ty } else { unreachable!("array match") };
                 for (idx, item) in items.iter().enumerate() {
-                    self.gen_match_arm(item, tag_val, Some(idx), &var.types[idx], jump_stream)
+                    let ele_loc = field_loc(loc, idx * ele_ty.size());
+                    self.gen_match_arm(item, &ele_loc, ele_ty, fail, slots);
                 }
             }
-            Pat::Array { size: _, items } => {
-                for _item in items {
-                    // TODO: recursively add cmp instructions
+            Pat::Struct { fields, .. } => {
+                let def = if let Ty::Struct { def, .. } = ty {
+                    def
+                } else {
+                    unreachable!("struct match")
+                };
+                for (ident, field) in fields {
+                    let f = def.fields.iter().find(|f| f.ident == *ident).unwrap();
+                    let at = def.field_offset(*ident).unwrap();
+                    self.gen_match_arm(field, &field_loc(loc, at), &f.ty, fail, slots);
                 }
             }
-            Pat::Bind(bind) => match bind {
-                Binding::Wild(ident) => {
-                    if let Location::NumberedOffset { offset, reg } = tag_val {
-                        if let Some(idx) = item_idx {
-                            let ref_loc = Location::NumberedOffset {
-                                // TODO: don't assume the type is a certain size (8 bytes)
-                                // We add 2 to make up for the tag and we assume the type is 8 bytes
-                                offset: offset - ((idx + 1) * 8),
-                                reg: *reg,
-                            };
-
-                            self.vars.insert(*ident, ref_loc);
-                        }
+            Pat::Range { start, end } => {
+                let val = self.load_narrow(loc.clone(), ty);
+                let (below, above) = if is_unsigned(ty) {
+                    (JmpCond::Below, JmpCond::Above)
+                } else {
+                    (JmpCond::Lt, JmpCond::Gt)
+                };
+                let (start, end) = (self.pat_operand(start), self.pat_operand(end));
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Cmp { src: start, dst: val.clone() },
+                    Instruction::CondJmp { loc: fail.clone(), cond: below },
+                    Instruction::Cmp { src: end, dst: val },
+                    Instruction::CondJmp { loc: fail.clone(), cond: above },
+                ]);
+                self.clear_regs_except(None, CanClearRegs::Yes);
+            }
+            Pat::Or(alts) => {
+                let mut matched = None;
+                for (idx, alt) in alts.iter().enumerate() {
+                    if idx + 1 == alts.len() {
+                        self.gen_match_arm(alt, loc, ty, fail, slots);
+                        break;
                     }
-                }
-                Binding::Value(val) => {
-                    let name = format!(".bindval{}", self.asm_buf.len());
-                    let bind_jmp = Location::Label(name.clone());
 
-                    // cmp enum tag to variant
+                    let next_alt = self.next_label(".matchalt");
+                    self.gen_match_arm(alt, loc, ty, &Location::Label(next_alt.clone()), slots);
+
+                    let matched = matched.get_or_insert_with(|| self.next_label(".matchor"));
                     self.asm_buf.extend_from_slice(&[
-                        Instruction::Cmp {
-                            src: Location::Const { val: val.clone() },
-                            dst: tag_val.clone(),
-                        },
-                        Instruction::CondJmp { loc: bind_jmp, cond: JmpCond::Eq },
+                        Instruction::Jmp(Location::Label(matched.clone())),
+                        Instruction::Label(next_alt),
                     ]);
-
-                    jump_stream.push(Instruction::Label(name));
                 }
-            },
+                if let Some(matched) = matched {
+                    self.asm_buf.push(Instruction::Label(matched));
+                }
+            }
+            Pat::At { ident, pat } => {
+                self.gen_match_arm(pat, loc, ty, fail, slots);
+                self.bind_pat(*ident, loc, ty, slots);
+            }
+            Pat::Bind(Binding::Wild(ident)) => self.bind_pat(*ident, loc, ty, slots),
+            Pat::Bind(Binding::Value(val)) => {
+                let loc = self.load_narrow(loc.clone(), ty);
+                let val = self.pat_operand(val);
+                self.asm_buf.extend_from_slice(&[
+                    Instruction::Cmp { src: val, dst: loc },
+                    Instruction::CondJmp { loc: fail.clone(), cond: JmpCond::NotEq },
+                ]);
+                self.clear_regs_except(None, CanClearRegs::Yes);
+            }
+            Pat::Wild => {}
         }
     }
 }
 
+/// The fewest values a `match` needs before it uses a jump table.
+const JUMP_TABLE_MIN: usize = 4;
+/// The most entries a jump table can have.
+const JUMP_TABLE_MAX: usize = 512;
+
+/// Where to start testing the arms of a `match` for each value.
+#[derive(Debug)]
+struct JumpTable {
+    /// The value of the first entry.
+    min: isize,
+    /// The first arm that can match each value from `min` on, `None` if no arm can.
+    entries: Vec<Option<usize>>,
+    /// The first arm that can match values outside of the table.
+    default: Option<usize>,
+}
+
+/// Build a jump table if the integers or enum variants the `arms` test are dense enough.
+fn jump_table(arms: &[MatchArm], ty: &Ty) -> Option<JumpTable> {
+    if !matches!(ty, Ty::Int | Ty::Enum { .. }) {
+        return None;
+    }
+
+    let heads = arms.iter().map(|arm| arm.pat.head_ranges()).collect::<Vec<_>>();
+    let mut values = HashSet::default();
+    for (start, end) in heads.iter().flatten().flatten() {
+        // A range like `isize::MIN..=isize::MAX` does not fit in `isize`
+        match end.checked_sub(*start) {
+            Some(span) if span >= 0 && (span as usize) < JUMP_TABLE_MAX => {}
+            _ => return None,
+        }
+        values.extend(*start..=*end);
+    }
+    let min = *values.iter().min()?;
+    let max = *values.iter().max()?;
+    let len = max.checked_sub(min)? as usize + 1;
+    // Dense means at least half of the table jumps to an arm that tests the value
+    if values.len() < JUMP_TABLE_MIN || len > JUMP_TABLE_MAX || len > values.len() * 2 {
+        return None;
+    }
+
+    let can_match = |head: &Option<Vec<(isize, isize)>>, val: isize| {
+        head.as_ref().map_or(true, |ranges| ranges.iter().any(|(s, e)| (*s..=*e).contains(&val)))
+    };
+    Some(JumpTable {
+        min,
+        entries: (min..=max).map(|val| heads.iter().position(|h| can_match(h, val))).collect(),
+        default: heads.iter().position(Option::is_none),
+    })
+}
+
 impl<'ast> Visit<'ast> for CodeGen<'ast> {
     fn visit_const(&mut self, var: &'ast Const) {
         fn convert_to_const(e: &Expr) -> Val {
             match e {
                 Expr::Urnary { op, expr, ty } => todo!(),
-                Expr::Binary { op, lhs, rhs, ty } => todo!(),
+                Expr::Binary { .. } => todo!(),
                 Expr::Parens(_) => todo!(),
                 Expr::StructInit { path, fields, def } => todo!(),
                 Expr::EnumInit { path, variant, items, def } => todo!(),
@@ -2049,7 +3730,12 @@ impl<'ast> Visit<'ast> for CodeGen<'ast> {
         }
         let name = format!(".Lglobal_{}", var.ident);
         match var.ty {
-            Ty::Generic { .. } | Ty::Struct { .. } | Ty::Enum { .. } | Ty::Ptr(_) | Ty::Ref(_) => {
+            Ty::Generic { .. }
+            | Ty::Struct { .. }
+            | Ty::Enum { .. }
+            | Ty::Ptr(_)
+            | Ty::Ref(_)
+            | Ty::Slice(_) => {
                 todo!()
             }
             Ty::Array { .. } => {
@@ -2108,12 +3794,12 @@ impl<'ast> Visit<'ast> for CodeGen<'ast> {
                     },
                 );
             }
-            Ty::Int => {
+            Ty::Int | Ty::SizedInt(_) => {
                 self.globals.insert(
                     var.ident,
                     Global::Int {
                         name: name.clone(),
-                        content: if let Expr::Value(Val::Int(i)) = var.init {
+                        content: if let Expr::Value(Val::Int(i) | Val::SizedInt(i, _)) = var.init {
                             i as i64
                         } else {
                             unreachable!("non char value used in constant")
@@ -2140,14 +3826,11 @@ impl<'ast> Visit<'ast> for CodeGen<'ast> {
                     },
                 );
             }
-            Ty::Func { .. } | Ty::Void | Ty::Bottom => unreachable!(),
+            Ty::Func { .. } | Ty::Void | Ty::Bottom | Ty::ConstArg(..) => unreachable!(),
         };
         self.vars.insert(var.ident, Location::NamedOffset(name));
     }
 
-    // TODO: we double the `leave; ret;` instructions for functions that actually return
-    // TODO: we double the `leave; ret;` instructions for functions that actually return
-    // TODO: we double the `leave; ret;` instructions for functions that actually return
     fn visit_func(&mut self, func: &'ast Func) {
         let function_name = func.ident.name().to_string();
         self.vars.insert(func.ident, Location::Label(function_name.clone()));
@@ -2157,6 +3840,7 @@ impl<'ast> Visit<'ast> for CodeGen<'ast> {
             return;
         }
 
+        let start = self.asm_buf.len();
         self.asm_buf.extend_from_slice(&[
             Instruction::Meta(format!(
                 ".global {name}\n.type {name},@function\n",
@@ -2169,14 +3853,53 @@ impl<'ast> Visit<'ast> for CodeGen<'ast> {
 
         self.current_fn_params.clear();
         self.current_fn_params = func.params.iter().map(|p| p.ident).collect();
-
-        // TODO: make this better
-        let mut float_count = 0;
-        for (i, arg) in func.params.iter().enumerate() {
-            let alloca = self.alloc_arg(i, &mut float_count, arg.ident, &arg.ty);
+        self.slices.clear();
+        self.stack_slots.clear();
+        self.asm_clobbers.clear();
+
+        let layout = abi::call_layout(func.params.iter().map(|p| &p.ty), &func.ret);
+        self.sret_ptr = if layout.sret {
+            self.current_stack += 8;
+            self.total_stack += 8;
+            self.asm_buf.push(Instruction::Push {
+                loc: Location::Register(Register::RDI),
+                size: 8,
+                comment: "address to return to",
+            });
+            Some(Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP })
+        } else {
+            None
+        };
+        for (arg, loc) in func.params.iter().zip(&layout.args) {
+            let alloca = match &arg.ty {
+                // `main` is called with `argc` and `argv` not a slice
+                Ty::Slice(_) if func.ident.name() == "main" => self.alloc_main_args(arg.ident),
+                ty => self.alloc_arg(loc, arg.ident, ty),
+            };
             self.vars.insert(arg.ident, alloca);
         }
 
+        // Each call that returns through memory or in two registers has space of its own for the
+        // whole function
+        self.ret_temps.clear();
+        let calls = abi::ret_slots(func);
+        let size = calls.iter().map(|(_, size)| size).sum::<usize>();
+        if size != 0 {
+            self.asm_buf.push(Instruction::Math {
+                src: Location::Const { val: Val::Int(size as isize) },
+                dst: RSP,
+                op: BinOp::Sub,
+                cmt: "space for returned values",
+            });
+            self.total_stack += size;
+            for (span, size) in calls {
+                self.current_stack += size;
+                let loc =
+                    Location::NumberedOffset { offset: self.current_stack, reg: Register::RBP };
+                self.ret_temps.insert(span, loc);
+            }
+        }
+
         for stmt in &func.stmts {
             self.gen_statement(stmt);
         }
@@ -2191,6 +3914,125 @@ impl<'ast> Visit<'ast> for CodeGen<'ast> {
             self.asm_buf.push(Instruction::SizedMov { src: ZERO, dst: RAX, size: 8 });
         }
         self.asm_buf.extend_from_slice(&[Instruction::Leave, Instruction::Ret]);
+        self.gen_traps();
+
+        let slots = self
+            .stack_slots
+            .drain(..)
+            .map(|s| StackSlot { offset: s.offset, alloc: s.alloc - start })
+            .collect::<Vec<_>>();
+        let clobbers = self
+            .asm_clobbers
+            .drain(..)
+            .map(|c| AsmClobbers { at: c.at - start, ..c })
+            .collect::<Vec<_>>();
+        let body = self.asm_buf.split_off(start);
+        self.asm_buf.extend(regalloc::allocate(body, &slots, &clobbers));
+    }
+}
+
+/// Move the value of type `ty` at `src` into the register `dst`, integers narrower than a
+/// register are sign or zero extended.
+fn mov_value(src: Location, dst: Location, ty: &Ty, comment: &'static str) -> Instruction {
+    match ty {
+        Ty::SizedInt(ity) if ity.size() < 8 => {
+            Instruction::MovExt { src, dst, size: ity.size(), signed: ity.is_signed() }
+        }
+        _ => Instruction::Mov { src, dst, comment },
+    }
+}
+
+/// A variable of this type fits in a register, its stack slot can be moved to one.
+fn is_scalar(ty: &Ty) -> bool {
+    matches!(
+        ty,
+        Ty::Int
+            | Ty::Float
+            | Ty::Char
+            | Ty::Bool
+            | Ty::Ptr(_)
+            | Ty::Ref(_)
+            | Ty::ConstStr(..)
+            | Ty::Func { .. }
+    )
+}
+
+/// `int` and the signed sized integers are compared, divided and shifted as signed values.
+fn is_unsigned(ty: &Ty) -> bool {
+    matches!(ty, Ty::SizedInt(ity) if !ity.is_signed())
+}
+
+/// The number of bytes returned in `%rdx` for an enum or struct that does not fit in `%rax`, a
+/// larger one is returned through memory.
+fn ret_upper_size(ty: &Ty) -> Option<usize> {
+    match ty {
+        Ty::Enum { .. } | Ty::Struct { .. } if ty.size() > 8 && ty.size() <= 16 => {
+            Some(ty.size() - 8)
+        }
+        _ => None,
+    }
+}
+
+/// The eightbyte `idx` of the struct or enum at `loc`, one a call returned is in `%rax` and
+/// `%rdx`.
+///
+/// A value in two registers is stored to a slot before `%rax` is used for anything else.
+fn eightbyte(loc: &Location, idx: usize) -> Location {
+    match *loc {
+        Location::NumberedOffset { .. } => field_loc(loc, idx * 8),
+        RAX if idx == 1 => RDX,
+        Location::Register(_) if idx == 0 => loc.clone(),
+        _ => unreachable!("eightbyte {} of a value in {:?}", idx, loc),
+    }
+}
+
+/// The location `by` bytes into the value at `loc`.
+///
+/// A value in memory addressed by a register, like `0(%rax)` for `*ptr`, continues above the
+/// register so its fields are at positive offsets.
+fn field_loc(loc: &Location, by: usize) -> Location {
+    match loc {
+        Location::NumberedOffset { offset, reg } if *offset >= by => {
+            Location::NumberedOffset { offset: offset - by, reg: *reg }
+        }
+        Location::NumberedOffset { offset, reg } => {
+            Location::ArgOffset { offset: by - offset, reg: *reg }
+        }
+        Location::Indexable { end, ele_pos, reg } if *ele_pos >= by => {
+            Location::Indexable { end: *end, ele_pos: ele_pos - by, reg: *reg }
+        }
+        Location::Indexable { ele_pos, reg, .. } => {
+            Location::ArgOffset { offset: by - ele_pos, reg: *reg }
+        }
+        Location::ArgOffset { offset, reg } => {
+            Location::ArgOffset { offset: offset + by, reg: *reg }
+        }
+        Location::NamedOffset(name) => Location::NamedOffsetIndex { name: name.clone(), plus: by },
+        Location::NamedOffsetIndex { name, plus } => {
+            Location::NamedOffsetIndex { name: name.clone(), plus: plus + by }
+        }
+        _ if by == 0 => loc.clone(),
+        _ => unreachable!("ICE: a field of a value that is not in memory {:?}", loc),
+    }
+}
+
+/// A location that stays valid while registers are reused, a slot in the stack frame or a global.
+fn is_frame_loc(loc: &Location) -> bool {
+    match loc {
+        Location::NumberedOffset { reg, .. }
+        | Location::ArgOffset { reg, .. }
+        | Location::Indexable { reg, .. } => *reg == Register::RBP,
+        Location::NamedOffset(_) | Location::NamedOffsetIndex { .. } => true,
+        _ => false,
+    }
+}
+
+/// The immediate to compare against for a literal in a pattern.
+fn pat_const(val: &Val) -> Location {
+    match val {
+        Val::Char(c) => Location::Const { val: Val::Int(*c as isize) },
+        Val::Bool(b) => Location::Const { val: Val::Int(*b as isize) },
+        v => Location::Const { val: v.clone() },
     }
 }
 
@@ -2205,53 +4047,22 @@ fn construct_field_offset_lvalue<'a>(
 ) -> Option<Location> {
     match rhs {
         LValue::Ident { ident, ty } => {
-            let mut count = 0;
-            for f in &def.fields {
-                if f.ident == *ident {
-                    return Some(Location::NumberedOffset { offset: offset - count, reg });
-                }
-                // Do this after so first field is the 0th offset
-                count += f.ty.size();
-            }
-
-            // type checking missed this field somehow
-            None
+            // `None` if type checking missed this field somehow
+            let at = def.field_offset(*ident)?;
+            Some(Location::NumberedOffset { offset: offset - at, reg })
         }
         LValue::Deref { indir, expr, ty } => {
             todo!("follow the pointer")
         }
-        LValue::Array { ident, exprs, ty } => {
-            let mut count = 0;
-            for f in &def.fields {
-                if f.ident == *ident {
-                    let arr = Location::NumberedOffset { offset: offset - count, reg };
-                    let ele_size =
-                        if let Ty::Array { ty, .. } = ty { ty.size() } else { ty.size() };
-                    return gen.index_arr(arr, exprs, ele_size, true);
-                }
-                count += f.ty.size();
-            }
-
-            // type checking missed this field somehow
-            None
+        LValue::Array { ident, exprs, ty, len, loc } => {
+            let at = def.field_offset(*ident)?;
+            let arr = Location::NumberedOffset { offset: offset - at, reg };
+            let ele_size = if let Ty::Array { ty, .. } = ty { ty.size() } else { ty.size() };
+            gen.index_arr(arr, exprs, ele_size, true, *len, loc)
         }
         LValue::FieldAccess { lhs, rhs: inner, def: inner_def, field_idx } => {
-            let mut count = 0;
-            for f in &def.fields {
-                if Some(f.ident) == lhs.as_ident() {
-                    return construct_field_offset_lvalue(
-                        gen,
-                        inner,
-                        offset - count,
-                        reg,
-                        inner_def,
-                    );
-                }
-                count += f.ty.size();
-            }
-
-            // type checking missed this field somehow
-            None
+            let at = def.field_offset(lhs.as_ident()?)?;
+            construct_field_offset_lvalue(gen, inner, offset - at, reg, inner_def)
         }
         _ => unreachable!("not a valid struct field accessor"),
     }
@@ -2266,49 +4077,22 @@ fn construct_field_offset<'a>(
 ) -> Option<Location> {
     match rhs {
         Expr::Ident { ident, ty } => {
-            let mut count = 0;
-            for f in &def.fields {
-                // println!("{} = {} - {}", ident, offset, count);
-                if f.ident == *ident {
-                    return Some(Location::NumberedOffset { offset: offset - count, reg });
-                }
-
-                // Do this after so first field is the 0th offset
-                count += f.ty.size();
-            }
-
-            // type checking missed this field somehow
-            None
+            // `None` if type checking missed this field somehow
+            let at = def.field_offset(*ident)?;
+            Some(Location::NumberedOffset { offset: offset - at, reg })
         }
         Expr::Deref { indir, expr, ty } => {
             todo!("follow the pointer")
         }
-        Expr::Array { ident, exprs, ty } => {
-            let mut count = 0;
-            for f in &def.fields {
-                if f.ident == *ident {
-                    let arr = Location::NumberedOffset { offset: offset - count, reg };
-                    let ele_size =
-                        if let Ty::Array { ty, .. } = ty { ty.size() } else { ty.size() };
-                    return gen.index_arr(arr, exprs, ele_size, false);
-                }
-                count += f.ty.size();
-            }
-
-            // type checking missed this field somehow
-            None
+        Expr::Array { ident, exprs, ty, len, loc } => {
+            let at = def.field_offset(*ident)?;
+            let arr = Location::NumberedOffset { offset: offset - at, reg };
+            let ele_size = if let Ty::Array { ty, .. } = ty { ty.size() } else { ty.size() };
+            gen.index_arr(arr, exprs, ele_size, false, *len, loc)
         }
         Expr::FieldAccess { lhs, rhs: inner, def: inner_def } => {
-            let mut count = 0;
-            for f in &def.fields {
-                if f.ident == lhs.as_ident() {
-                    return construct_field_offset(gen, inner, offset - count, reg, inner_def);
-                }
-                count += f.ty.size();
-            }
-
-            // type checking missed this field somehow
-            None
+            let at = def.field_offset(lhs.as_ident())?;
+            construct_field_offset(gen, inner, offset - at, reg, inner_def)
         }
         Expr::AddrOf(_) => todo!(),
         Expr::Call { path, args, type_args, def } => todo!(),
@@ -0,0 +1,241 @@
+//! The System V x86-64 calling convention.
+//!
+//! A value is split into eightbytes, an eightbyte holding nothing but floats is passed in an
+//! `%xmm` register and any other in a general purpose register. A value larger than 16 bytes,
+//! or one there are not enough registers left for, is copied to the stack right above the return
+//! address. A value larger than 16 bytes is returned in memory the caller passes the address of
+//! in `%rdi`.
+
+use crate::{
+    ast::types::Range,
+    gen::asm::inst::{FloatRegister, Location, Register, ARG_FLOAT_REGS, ARG_REGS},
+    lir::{
+        lower::{Expr, Func, LValue, Stmt, Ty},
+        visit::{walk_expr, walk_stmt, Visit},
+    },
+};
+
+/// The class of one eightbyte of a value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+crate enum Class {
+    /// Passed in a general purpose register.
+    Integer,
+    /// Passed in an `%xmm` register.
+    Sse,
+}
+
+/// How a value is passed or returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+crate enum Pass {
+    /// In registers, the class of each eightbyte.
+    Regs(Vec<Class>),
+    /// Copied to memory.
+    Memory,
+}
+
+/// Where one argument of a call goes.
+#[derive(Clone, Debug)]
+crate enum ArgLoc {
+    /// The register of each eightbyte.
+    Regs(Vec<Location>),
+    /// This many bytes above the first argument on the stack.
+    Stack(usize),
+}
+
+/// Where the arguments of a call go.
+#[derive(Clone, Debug)]
+crate struct CallLayout {
+    crate args: Vec<ArgLoc>,
+    /// The value is returned to the memory `%rdi` points to.
+    crate sret: bool,
+    /// The general purpose registers that hold arguments, including the address for `sret`.
+    crate int_regs: usize,
+    crate float_regs: usize,
+    /// The bytes of arguments on the stack, always a multiple of 8.
+    crate stack: usize,
+}
+
+crate fn classify(ty: &Ty) -> Pass {
+    match ty {
+        // The declared return type of a generic function still names its parameters, these
+        // values are kept to `%rax` and `%rdx`
+        _ if !is_sized(ty) => Pass::Regs(vec![Class::Integer]),
+        Ty::Struct { .. } | Ty::Enum { .. } if ty.size() > 16 => Pass::Memory,
+        Ty::Struct { .. } => {
+            let mut classes = vec![None; eightbytes(ty.size())];
+            merge_scalars(ty, 0, &mut classes);
+            Pass::Regs(classes.into_iter().map(|c| c.unwrap_or(Class::Integer)).collect())
+        }
+        // C has nothing like an enum with items, the tag and items go in integer registers
+        Ty::Enum { .. } => Pass::Regs(vec![Class::Integer; eightbytes(ty.size())]),
+        Ty::Float => Pass::Regs(vec![Class::Sse]),
+        Ty::Void => Pass::Regs(vec![]),
+        // An array is passed by address
+        _ => Pass::Regs(vec![Class::Integer]),
+    }
+}
+
+/// Assign registers or stack space to arguments of the types `params` in order.
+crate fn call_layout<'a>(params: impl IntoIterator<Item = &'a Ty>, ret: &Ty) -> CallLayout {
+    let sret = classify(ret) == Pass::Memory;
+    let mut layout =
+        CallLayout { args: vec![], sret, int_regs: sret as usize, float_regs: 0, stack: 0 };
+    for ty in params {
+        let loc = match classify(ty) {
+            Pass::Regs(classes) => {
+                let ints = classes.iter().filter(|c| **c == Class::Integer).count();
+                let floats = classes.len() - ints;
+                // A value is never split between registers and the stack
+                if layout.int_regs + ints <= ARG_REGS.len()
+                    && layout.float_regs + floats <= ARG_FLOAT_REGS.len()
+                {
+                    let regs = classes
+                        .iter()
+                        .map(|class| match class {
+                            Class::Integer => {
+                                layout.int_regs += 1;
+                                Location::Register(ARG_REGS[layout.int_regs - 1])
+                            }
+                            Class::Sse => {
+                                layout.float_regs += 1;
+                                Location::FloatReg(ARG_FLOAT_REGS[layout.float_regs - 1])
+                            }
+                        })
+                        .collect();
+                    Some(ArgLoc::Regs(regs))
+                } else {
+                    None
+                }
+            }
+            Pass::Memory => None,
+        };
+        let loc = loc.unwrap_or_else(|| {
+            let offset = layout.stack;
+            layout.stack += stack_size(ty);
+            ArgLoc::Stack(offset)
+        });
+        layout.args.push(loc);
+    }
+    layout
+}
+
+/// The register each eightbyte of a value is returned in.
+crate fn ret_regs(classes: &[Class]) -> Vec<Location> {
+    let (mut ints, mut floats) = (0, 0);
+    classes
+        .iter()
+        .map(|class| match class {
+            Class::Integer => {
+                ints += 1;
+                Location::Register([Register::RAX, Register::RDX][ints - 1])
+            }
+            Class::Sse => {
+                floats += 1;
+                Location::FloatReg([FloatRegister::XMM0, FloatRegister::XMM1][floats - 1])
+            }
+        })
+        .collect()
+}
+
+/// The bytes an argument of type `ty` takes on the stack.
+crate fn stack_size(ty: &Ty) -> usize {
+    match ty {
+        Ty::Array { .. } => 8,
+        ty => eightbytes(ty.size()) * 8,
+    }
+}
+
+crate fn eightbytes(size: usize) -> usize {
+    match size % 8 {
+        0 => size / 8,
+        _ => size / 8 + 1,
+    }
+}
+
+/// `ty` has no generic parameters left in it.
+fn is_sized(ty: &Ty) -> bool {
+    match ty {
+        Ty::Generic { .. } | Ty::ConstArg(_) => false,
+        Ty::Array { ty, .. } => is_sized(ty),
+        Ty::Struct { def, .. } => def.fields.iter().all(|f| is_sized(&f.ty)),
+        Ty::Enum { def, .. } => def.variants.iter().flat_map(|v| &v.types).all(is_sized),
+        _ => true,
+    }
+}
+
+/// Mark the eightbytes each scalar in `ty` overlaps, an eightbyte with anything but a float in
+/// it is `Integer`.
+fn merge_scalars(ty: &Ty, offset: usize, classes: &mut [Option<Class>]) {
+    match ty {
+        Ty::Struct { def, .. } => {
+            for (field, at) in def.fields.iter().zip(def.field_offsets()) {
+                merge_scalars(&field.ty, offset + at, classes);
+            }
+        }
+        Ty::Array { size, ty } => {
+            for idx in 0..*size {
+                merge_scalars(ty, offset + idx * ty.size(), classes);
+            }
+        }
+        _ if ty.size() == 0 => {}
+        _ => {
+            let float = matches!(ty, Ty::Float);
+            for class in &mut classes[offset / 8..=(offset + ty.size() - 1) / 8] {
+                *class = match class {
+                    Some(Class::Sse) | None if float => Some(Class::Sse),
+                    _ => Some(Class::Integer),
+                };
+            }
+        }
+    }
+}
+
+/// The calls in `func` that return a value through memory or in two registers, the span of each
+/// call and the bytes it needs.
+///
+/// A value in two registers is stored to its space when the call is nested in an expression
+/// that keeps something in `%rax`. Calls of the same function share the return type of its
+/// definition, the span of the path they call tells them apart.
+crate fn ret_slots(func: &Func) -> Vec<(Range, usize)> {
+    struct Calls(Vec<(Range, usize)>);
+
+    impl Calls {
+        fn add(&mut self, span: Range, ret: &Ty) {
+            let needs_slot = match classify(ret) {
+                Pass::Memory => true,
+                Pass::Regs(classes) => classes.len() == 2,
+            };
+            if needs_slot {
+                self.0.push((span, eightbytes(ret.size()) * 8));
+            }
+        }
+    }
+
+    impl<'ast> Visit<'ast> for Calls {
+        fn visit_lval(&mut self, _lval: &LValue) {}
+
+        fn visit_stmt(&mut self, stmt: &'ast Stmt) {
+            match stmt {
+                Stmt::Call { expr, def } => self.add(expr.path.span, &def.ret),
+                Stmt::TraitMeth { expr, def } => self.add(expr.trait_.span, &def.method.ret),
+                _ => {}
+            }
+            walk_stmt(self, stmt);
+        }
+
+        fn visit_expr(&mut self, expr: &'ast Expr) {
+            match expr {
+                Expr::Call { path, def, .. } => self.add(path.span, &def.ret),
+                Expr::TraitMeth { trait_, def, .. } => self.add(trait_.span, &def.method.ret),
+                _ => {}
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    let mut calls = Calls(vec![]);
+    for stmt in &func.stmts {
+        calls.visit_stmt(stmt);
+    }
+    calls.0
+}
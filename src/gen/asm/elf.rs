@@ -0,0 +1,346 @@
+//! Write the code and data of an `Assembler` as an ELF64 relocatable object for x86-64.
+//!
+//! A jump or call within `.text` is resolved here, any other reference to a symbol is left to the
+//! linker as a relocation. A reference to a label of this object is relative to its section so
+//! only functions and globals need a symbol.
+
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::gen::asm::encode::{pad, Assembler, Reloc, Section};
+
+const SECTIONS: [Section; 3] = [Section::Text, Section::Data, Section::Rodata];
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_PC32: u64 = 2;
+const R_X86_64_PLT32: u64 = 4;
+const R_X86_64_32S: u64 = 11;
+
+/// The index of `.symtab` in the section headers, the sections it follows are `.text`, `.data`,
+/// `.rodata` and a `.rela` section for each.
+const SYMTAB: u32 = 7;
+const SHSTRTAB: u16 = 9;
+
+/// A section header and its contents.
+struct Shdr {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+    data: Vec<u8>,
+}
+
+/// The section header index of `section`, the null section is first.
+fn shndx(section: Section) -> usize {
+    section as usize + 1
+}
+
+fn push_sym(symtab: &mut Vec<u8>, name: u32, bind: u8, kind: u8, shndx: u16, value: u64) {
+    symtab.extend_from_slice(&name.to_le_bytes());
+    symtab.push(bind << 4 | kind);
+    symtab.push(0);
+    symtab.extend_from_slice(&shndx.to_le_bytes());
+    symtab.extend_from_slice(&value.to_le_bytes());
+    symtab.extend_from_slice(&0_u64.to_le_bytes());
+}
+
+fn push_str(strtab: &mut Vec<u8>, s: &str) -> u32 {
+    let at = strtab.len() as u32;
+    strtab.extend_from_slice(s.as_bytes());
+    strtab.push(0);
+    at
+}
+
+crate fn object(asm: &Assembler) -> Vec<u8> {
+    let mut contents =
+        SECTIONS.iter().map(|section| asm.bytes(*section).to_vec()).collect::<Vec<_>>();
+
+    // The null symbol and one for each section, then the labels with the local ones first
+    let mut symtab = vec![0; 24];
+    let mut strtab = vec![0];
+    for section in &SECTIONS {
+        push_sym(&mut symtab, 0, STB_LOCAL, STT_SECTION, shndx(*section) as u16, 0);
+    }
+    let mut labels = asm
+        .labels
+        .iter()
+        .map(|(name, (section, at))| (asm.globals.contains(name), *section as usize, *at, name))
+        .collect::<Vec<_>>();
+    labels.sort();
+    let first_global = (symtab.len() / 24 + labels.iter().filter(|l| !l.0).count()) as u32;
+
+    let mut index = HashMap::default();
+    for (global, section, at, name) in labels {
+        index.insert(name.as_str(), symtab.len() / 24);
+        let kind = if asm.funcs.contains(name) {
+            STT_FUNC
+        } else if section == Section::Text as usize {
+            STT_NOTYPE
+        } else {
+            STT_OBJECT
+        };
+        let bind = if global { STB_GLOBAL } else { STB_LOCAL };
+        let name = push_str(&mut strtab, name);
+        push_sym(&mut symtab, name, bind, kind, section as u16 + 1, at as u64);
+    }
+    // Anything used but not defined here is a global of another object, like `printf`
+    let mut undefined = asm
+        .fixups
+        .iter()
+        .map(|fixup| &fixup.sym)
+        .chain(&asm.globals)
+        .filter(|sym| !asm.labels.contains_key(*sym))
+        .collect::<Vec<_>>();
+    undefined.sort();
+    undefined.dedup();
+    for sym in undefined {
+        index.insert(sym.as_str(), symtab.len() / 24);
+        let name = push_str(&mut strtab, sym);
+        push_sym(&mut symtab, name, STB_GLOBAL, STT_NOTYPE, 0, 0);
+    }
+
+    let mut relas = vec![vec![]; SECTIONS.len()];
+    for fixup in &asm.fixups {
+        let (sym, addend) = match asm.labels.get(&fixup.sym) {
+            Some((section, at)) if *section == fixup.section && fixup.kind != Reloc::Abs32S => {
+                let dist = *at as i64 + fixup.addend - fixup.at as i64;
+                contents[fixup.section as usize][fixup.at..fixup.at + 4]
+                    .copy_from_slice(&(dist as i32).to_le_bytes());
+                continue;
+            }
+            Some((section, at)) => (shndx(*section), *at as i64 + fixup.addend),
+            None => (index[fixup.sym.as_str()], fixup.addend),
+        };
+        let kind = match fixup.kind {
+            Reloc::Pc32 => R_X86_64_PC32,
+            Reloc::Plt32 => R_X86_64_PLT32,
+            Reloc::Abs32S => R_X86_64_32S,
+        };
+        let rela = &mut relas[fixup.section as usize];
+        rela.extend_from_slice(&(fixup.at as u64).to_le_bytes());
+        rela.extend_from_slice(&((sym as u64) << 32 | kind).to_le_bytes());
+        rela.extend_from_slice(&addend.to_le_bytes());
+    }
+
+    let mut relas = relas.into_iter();
+    let mut contents = contents.into_iter();
+    let mut shdrs = vec![];
+    for &(name, flags, align) in &[
+        (".text", SHF_ALLOC | SHF_EXECINSTR, 16),
+        (".data", SHF_ALLOC | SHF_WRITE, 32),
+        (".rodata", SHF_ALLOC, 32),
+    ] {
+        shdrs.push(Shdr {
+            name,
+            kind: SHT_PROGBITS,
+            flags,
+            link: 0,
+            info: 0,
+            align,
+            entsize: 0,
+            data: contents.next().unwrap(),
+        });
+    }
+    for (&name, section) in [".rela.text", ".rela.data", ".rela.rodata"].iter().zip(&SECTIONS) {
+        shdrs.push(Shdr {
+            name,
+            kind: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            link: SYMTAB,
+            info: shndx(*section) as u32,
+            align: 8,
+            entsize: 24,
+            data: relas.next().unwrap(),
+        });
+    }
+    shdrs.push(Shdr {
+        name: ".symtab",
+        kind: SHT_SYMTAB,
+        flags: 0,
+        link: SYMTAB + 1,
+        info: first_global,
+        align: 8,
+        entsize: 24,
+        data: symtab,
+    });
+    shdrs.push(Shdr {
+        name: ".strtab",
+        kind: SHT_STRTAB,
+        flags: 0,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+        data: strtab,
+    });
+    let mut shstrtab = vec![0];
+    let mut names = vec![];
+    for name in shdrs.iter().map(|s| s.name).chain(vec![".shstrtab", ".note.GNU-stack"]) {
+        names.push(push_str(&mut shstrtab, name));
+    }
+    shdrs.push(Shdr {
+        name: ".shstrtab",
+        kind: SHT_STRTAB,
+        flags: 0,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+        data: shstrtab,
+    });
+    // The stack is not executable
+    shdrs.push(Shdr {
+        name: ".note.GNU-stack",
+        kind: SHT_PROGBITS,
+        flags: 0,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+        data: vec![],
+    });
+
+    // The ELF header, the contents of each section then the section headers
+    let mut out = vec![0; 64];
+    let mut headers = vec![0; 64];
+    for (shdr, name) in shdrs.iter().zip(names) {
+        pad(&mut out, shdr.align as usize);
+        headers.extend_from_slice(&name.to_le_bytes());
+        headers.extend_from_slice(&shdr.kind.to_le_bytes());
+        headers.extend_from_slice(&shdr.flags.to_le_bytes());
+        headers.extend_from_slice(&0_u64.to_le_bytes());
+        headers.extend_from_slice(&(out.len() as u64).to_le_bytes());
+        headers.extend_from_slice(&(shdr.data.len() as u64).to_le_bytes());
+        headers.extend_from_slice(&shdr.link.to_le_bytes());
+        headers.extend_from_slice(&shdr.info.to_le_bytes());
+        headers.extend_from_slice(&shdr.align.to_le_bytes());
+        headers.extend_from_slice(&shdr.entsize.to_le_bytes());
+        out.extend_from_slice(&shdr.data);
+    }
+    pad(&mut out, 8);
+    let shoff = out.len() as u64;
+    out.extend_from_slice(&headers);
+
+    let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    // A relocatable object for x86-64
+    header.extend_from_slice(&1_u16.to_le_bytes());
+    header.extend_from_slice(&62_u16.to_le_bytes());
+    header.extend_from_slice(&1_u32.to_le_bytes());
+    header.extend_from_slice(&0_u64.to_le_bytes());
+    header.extend_from_slice(&0_u64.to_le_bytes());
+    header.extend_from_slice(&shoff.to_le_bytes());
+    header.extend_from_slice(&0_u32.to_le_bytes());
+    for field in &[64, 0, 0, 64, shdrs.len() as u16 + 1, SHSTRTAB] {
+        header.extend_from_slice(&field.to_le_bytes());
+    }
+    out[..64].copy_from_slice(&header);
+    out
+}
+
+#[test]
+fn symbols_and_relocations() {
+    let mut asm = Assembler::new();
+    asm.text(
+        ".global main
+        .type main,@function
+        main:
+            jmp .l1
+        .l1:
+            leaq .str(%rip), %rdi
+            movq $.l1, %rax
+            call printf
+            ret
+        .section .rodata
+        .str:
+            .string \"hi\"
+        .data
+        .global counter
+        counter:
+            .quad 0",
+    )
+    .unwrap();
+    let obj = object(&asm);
+
+    let u16_at = |at: usize| u16::from_le_bytes([obj[at], obj[at + 1]]) as usize;
+    let u32_at = |at: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&obj[at..at + 4]);
+        u32::from_le_bytes(bytes) as usize
+    };
+    let u64_at = |at: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&obj[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let shoff = u64_at(0x28) as usize;
+    assert_eq!(u16_at(0x3e), SHSTRTAB as usize);
+    // The offset, size and info field of the section header `idx`
+    let shdr = |idx: usize| {
+        let at = shoff + idx * 64;
+        (u64_at(at + 24) as usize, u64_at(at + 32) as usize, u32_at(at + 44))
+    };
+
+    let (symtab, size, first_global) = shdr(SYMTAB as usize);
+    let (strtab, _, _) = shdr(SYMTAB as usize + 1);
+    let syms = (0..size / 24)
+        .map(|idx| {
+            let at = symtab + idx * 24;
+            let name = &obj[strtab + u32_at(at)..];
+            let name = &name[..name.iter().position(|b| *b == 0).unwrap()];
+            (std::str::from_utf8(name).unwrap(), obj[at + 4] >> 4, u16_at(at + 6), u64_at(at + 8))
+        })
+        .collect::<Vec<_>>();
+    // The null symbol, a symbol for each section, the local labels then the globals followed by
+    // the undefined symbols
+    assert_eq!(
+        syms,
+        [
+            ("", STB_LOCAL, 0, 0),
+            ("", STB_LOCAL, 1, 0),
+            ("", STB_LOCAL, 2, 0),
+            ("", STB_LOCAL, 3, 0),
+            (".l1", STB_LOCAL, 1, 5),
+            (".str", STB_LOCAL, 3, 0),
+            ("main", STB_GLOBAL, 1, 0),
+            ("counter", STB_GLOBAL, 2, 0),
+            ("printf", STB_GLOBAL, 0, 0),
+        ]
+    );
+    assert_eq!(first_global, 6);
+
+    // The jump to `.l1` is resolved here and never reaches the linker
+    let (text, _, _) = shdr(1);
+    assert_eq!(&obj[text..text + 5], &[0xe9, 0, 0, 0, 0]);
+
+    let (rela, size, info) = shdr(4);
+    assert_eq!(info, 1);
+    let relas = (0..size / 24)
+        .map(|idx| {
+            let at = rela + idx * 24;
+            (u64_at(at), u64_at(at + 8) >> 32, u64_at(at + 8) & 0xffff_ffff, u64_at(at + 16) as i64)
+        })
+        .collect::<Vec<_>>();
+    // A label is found from its section symbol and `$label` is always left to the linker
+    assert_eq!(
+        relas,
+        [(8, 3, R_X86_64_PC32, -4), (15, 1, R_X86_64_32S, 5), (20, 8, R_X86_64_PLT32, -4)]
+    );
+}
@@ -0,0 +1,1218 @@
+//! An x86-64 encoder for the instructions `CodeGen` generates.
+//!
+//! Each instruction is encoded the way the GNU assembler encodes the text `CodeGen::to_asm` writes
+//! for it. The `asm` blocks, the entry point and the other snippets that only exist as text are
+//! parsed as AT&T syntax. A symbol is resolved or turned into a relocation once the whole program
+//! is encoded, see `elf::object`.
+
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
+
+use crate::{
+    gen::asm::inst::{FloatRegister, Global, Instruction, Location, Register, USABLE_FLOAT_REGS},
+    lir::lower::{BinOp, Val},
+};
+
+use Register::*;
+
+/// Every general purpose register, the variants of `Register` are in the order of their number.
+const REGISTERS: [Register; 16] =
+    [RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8, R9, R10, R11, R12, R13, R14, R15];
+
+/// The sections of an object file that hold code or data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+crate enum Section {
+    Text,
+    Data,
+    Rodata,
+}
+
+/// How the address of a symbol is written to a 32 bit field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+crate enum Reloc {
+    /// The distance from the field to the symbol, `sym(%rip)` or a jump table entry.
+    Pc32,
+    /// The distance from the field to a function, `call sym`.
+    Plt32,
+    /// The address of the symbol, `$sym`.
+    Abs32S,
+}
+
+/// A 32 bit field in a section that refers to `sym`, it is set to `sym + addend - at` or for
+/// `Reloc::Abs32S` to `sym + addend`.
+#[derive(Clone, Debug)]
+crate struct Fixup {
+    crate section: Section,
+    crate at: usize,
+    crate sym: String,
+    crate addend: i64,
+    crate kind: Reloc,
+}
+
+/// The machine code and data of a program.
+#[derive(Debug)]
+crate struct Assembler {
+    crate text: Vec<u8>,
+    crate data: Vec<u8>,
+    crate rodata: Vec<u8>,
+    /// The section and offset of each label.
+    crate labels: HashMap<String, (Section, usize)>,
+    /// The labels other objects can use, `.global name`.
+    crate globals: HashSet<String>,
+    /// The labels that start a function, `.type name,@function`.
+    crate funcs: HashSet<String>,
+    crate fixups: Vec<Fixup>,
+    /// The section labels, data and instructions are added to.
+    section: Section,
+}
+
+impl Assembler {
+    crate fn new() -> Self {
+        Self {
+            text: vec![],
+            data: vec![],
+            rodata: vec![],
+            labels: HashMap::default(),
+            globals: HashSet::default(),
+            funcs: HashSet::default(),
+            fixups: vec![],
+            section: Section::Text,
+        }
+    }
+
+    crate fn bytes(&self, section: Section) -> &[u8] {
+        match section {
+            Section::Text => &self.text,
+            Section::Data => &self.data,
+            Section::Rodata => &self.rodata,
+        }
+    }
+
+    fn buf(&mut self) -> &mut Vec<u8> {
+        match self.section {
+            Section::Text => &mut self.text,
+            Section::Data => &mut self.data,
+            Section::Rodata => &mut self.rodata,
+        }
+    }
+
+    fn label(&mut self, name: &str) {
+        let at = self.buf().len();
+        self.labels.insert(name.to_owned(), (self.section, at));
+    }
+
+    fn align(&mut self, align: usize) {
+        pad(self.buf(), align)
+    }
+
+    /// Encode `mnemonic` in AT&T syntax with `ops` in the current section.
+    fn emit(&mut self, mnemonic: &str, ops: &[Operand]) -> Result<(), String> {
+        let enc = encode(mnemonic, ops).ok_or_else(|| {
+            format!(
+                "can not encode `{} {}`",
+                mnemonic,
+                ops.iter().map(|op| op.to_string()).collect::<Vec<_>>().join(", ")
+            )
+        })?;
+        let section = self.section;
+        let start = self.buf().len();
+        self.buf().extend_from_slice(&enc.bytes);
+        self.fixups.extend(enc.fixups.into_iter().map(|(at, sym, addend, kind)| Fixup {
+            section,
+            at: start + at,
+            sym,
+            addend,
+            kind,
+        }));
+        Ok(())
+    }
+
+    /// Encode `inst`, these are the same instructions `CodeGen::to_asm` writes.
+    crate fn instruction(&mut self, inst: &Instruction) -> Result<(), String> {
+        match inst {
+            Instruction::Label(label) => {
+                self.label(label);
+                Ok(())
+            }
+            Instruction::Meta(meta) => self.text(meta),
+            Instruction::Push { loc, size, .. } => {
+                self.emit(&format!("push{}", suffix(*size)), &[operand(loc, 8)?])
+            }
+            Instruction::Pop { loc, size, .. } => {
+                self.emit(&format!("pop{}", suffix(*size)), &[operand(loc, 8)?])
+            }
+            Instruction::Call { loc, .. } => self.emit("call", &[operand(loc, 8)?]),
+            Instruction::Jmp(loc) => self.emit("jmp", &[operand(loc, 8)?]),
+            Instruction::CondJmp { loc, cond } => {
+                self.emit(&format!("j{}", cond.to_string()), &[operand(loc, 8)?])
+            }
+            Instruction::JmpTable { table, idx } => {
+                let entry = Mem { base: Base::Reg(*table), index: Some((*idx, 4)), disp: 0 };
+                self.emit("movslq", &[Operand::Mem(entry), Operand::Reg(*idx, 8)])?;
+                self.emit("addq", &[Operand::Reg(*table, 8), Operand::Reg(*idx, 8)])?;
+                self.emit("jmp", &[Operand::Indirect(box Operand::Reg(*idx, 8))])
+            }
+            Instruction::Leave => self.emit("leave", &[]),
+            Instruction::Ret => self.emit("ret", &[]),
+            Instruction::Cmp { src, dst } => {
+                self.emit("cmpq", &[operand(src, 8)?, operand(dst, 8)?])
+            }
+            Instruction::Mov { src, dst, .. } => {
+                self.emit("mov", &[operand(src, 8)?, operand(dst, 8)?])
+            }
+            Instruction::FloatMov { src, dst } => {
+                self.emit("movsd", &[operand(src, 8)?, operand(dst, 8)?])
+            }
+            Instruction::SizedMov { src, dst, size } => match size {
+                // Only the bytes of the value are written to memory
+                1 | 2 | 4 if dst.is_stack_offset() => self.emit(
+                    &format!("mov{}", suffix(*size)),
+                    &[operand(src, *size)?, operand(dst, 8)?],
+                ),
+                1 | 2 if src.is_stack_offset() => self.emit(
+                    &format!("movz{}q", suffix(*size)),
+                    &[operand(src, 8)?, operand(dst, 8)?],
+                ),
+                // Writing the 32 bit register clears the top half
+                4 if src.is_stack_offset() => {
+                    self.emit("movl", &[operand(src, 8)?, operand(dst, 4)?])
+                }
+                1 | 2 | 4 => self.emit("movq", &[operand(src, 8)?, operand(dst, 8)?]),
+                _ => self
+                    .emit(&format!("mov{}", suffix(*size)), &[operand(src, 8)?, operand(dst, 8)?]),
+            },
+            Instruction::MovExt { src, dst, size, signed } => {
+                let (mnemonic, dst) = match (size, signed) {
+                    (8, _) => ("movq".to_owned(), operand(dst, 8)?),
+                    // Writing the 32 bit register clears the top half
+                    (4, false) => ("movl".to_owned(), operand(dst, 4)?),
+                    (_, true) => (format!("movs{}q", suffix(*size)), operand(dst, 8)?),
+                    (_, false) => (format!("movz{}q", suffix(*size)), operand(dst, 8)?),
+                };
+                self.emit(&mnemonic, &[operand(src, *size)?, dst])
+            }
+            Instruction::CondMov { src, dst, cond } => self
+                .emit(&format!("cmov{}", cond.to_string()), &[operand(src, 8)?, operand(dst, 8)?]),
+            Instruction::Load { src, dst, .. } => {
+                self.emit("leaq", &[operand(src, 8)?, operand(dst, 8)?])
+            }
+            Instruction::Alloca { amount, reg } => {
+                self.emit("sub", &[Operand::Imm(*amount), Operand::Reg(*reg, 8)])
+            }
+            Instruction::Math { src, dst, op, .. } => self
+                .emit(&format!("{}q", op.as_instruction()), &[operand(src, 8)?, operand(dst, 8)?]),
+            Instruction::FloatMath { src, dst, op } => {
+                let op = match op {
+                    BinOp::Mul => "mul",
+                    op => op.as_instruction(),
+                };
+                self.emit(&format!("{}sd", op), &[operand(src, 8)?, operand(dst, 8)?])
+            }
+            Instruction::Idiv(loc) => self.emit("idiv", &[operand(loc, 8)?]),
+            Instruction::Div(loc) => self.emit("div", &[operand(loc, 8)?]),
+            Instruction::Mul(loc) => self.emit("mul", &[operand(loc, 8)?]),
+            Instruction::Extend => self.emit("cqo", &[]),
+            Instruction::Shift { src, dst, op, signed } => {
+                let mnemonic = match (op, signed) {
+                    (BinOp::LeftShift, _) => "shlq",
+                    (_, true) => "sarq",
+                    (_, false) => "shrq",
+                };
+                self.emit(mnemonic, &[operand(src, 1)?, operand(dst, 8)?])
+            }
+            Instruction::Cvt { src, dst } => {
+                self.emit("cvtss2sd", &[operand(src, 8)?, operand(dst, 8)?])
+            }
+            Instruction::IntToFloat { src, dst } => {
+                self.emit("cvtsi2sdq", &[operand(src, 8)?, operand(dst, 8)?])
+            }
+            Instruction::FloatToInt { src, dst } => {
+                self.emit("cvttsd2siq", &[operand(src, 8)?, operand(dst, 8)?])
+            }
+        }
+    }
+
+    /// Add the data of `glob`, these are the same directives `CodeGen::to_global` writes except
+    /// constants go in `.rodata`.
+    crate fn global(&mut self, glob: &Global) {
+        match glob {
+            Global::Text { name, content, mutable } => {
+                self.data_label(name, *mutable, 1);
+                let buf = self.buf();
+                buf.extend_from_slice(content.as_bytes());
+                buf.push(0);
+            }
+            Global::Int { name, content, mutable } => {
+                self.data_label(name, *mutable, 8);
+                self.buf().extend_from_slice(&content.to_le_bytes());
+            }
+            Global::Char { name, content, mutable } => {
+                self.data_label(name, *mutable, 8);
+                self.buf().extend_from_slice(&(*content as u64).to_le_bytes());
+            }
+            Global::Array { name, content, mutable } => {
+                self.globals.insert(name.clone());
+                self.data_label(name, *mutable, 32);
+                for item in content {
+                    let bytes = match item {
+                        Val::Float(v) => v.to_bits().to_le_bytes().to_vec(),
+                        Val::Int(v) => (*v as i64).to_le_bytes().to_vec(),
+                        Val::SizedInt(v, ity) => (*v as i64).to_le_bytes()[..ity.size()].to_vec(),
+                        Val::Char(v) => (*v as u8 as u64).to_le_bytes().to_vec(),
+                        Val::Bool(v) => (*v as u64).to_le_bytes().to_vec(),
+                        Val::Str(v) => format!("{}\0", v).into_bytes(),
+                    };
+                    self.buf().extend_from_slice(&bytes);
+                }
+            }
+            Global::JumpTable { name, labels } => {
+                self.data_label(name, false, 4);
+                let table = self.rodata.len();
+                for label in labels {
+                    // Each entry is the distance from the table to the label
+                    let at = self.rodata.len();
+                    self.fixups.push(Fixup {
+                        section: Section::Rodata,
+                        at,
+                        sym: label.clone(),
+                        addend: (at - table) as i64,
+                        kind: Reloc::Pc32,
+                    });
+                    self.rodata.extend_from_slice(&[0; 4]);
+                }
+            }
+        }
+        self.section = Section::Text;
+    }
+
+    fn data_label(&mut self, name: &str, mutable: bool, align: usize) {
+        self.section = if mutable { Section::Data } else { Section::Rodata };
+        if mutable {
+            self.globals.insert(name.to_owned());
+        }
+        self.align(align);
+        self.label(name);
+    }
+
+    /// Encode `src` written in AT&T syntax, each line is a label, a directive or an instruction.
+    crate fn text(&mut self, src: &str) -> Result<(), String> {
+        for line in src.lines() {
+            let mut line = strip_comment(line).trim();
+            if let Some(colon) = line.find(':') {
+                let name = &line[..colon];
+                if !name.is_empty()
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || "._$".contains(c))
+                {
+                    self.label(name);
+                    line = line[colon + 1..].trim();
+                }
+            }
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, rest) = match line.find(char::is_whitespace) {
+                Some(idx) => (&line[..idx], line[idx..].trim()),
+                None => (line, ""),
+            };
+            if name.starts_with('.') {
+                self.directive(name, rest)?;
+            } else {
+                let ops = split_operands(rest)
+                    .into_iter()
+                    .map(parse_operand)
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.emit(name, &ops)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, name: &str, args: &str) -> Result<(), String> {
+        match name {
+            ".global" | ".globl" => {
+                self.globals.insert(args.to_owned());
+            }
+            ".type" => {
+                if let Some((sym, "@function")) =
+                    args.split_once(',').map(|(sym, kind)| (sym.trim(), kind.trim()))
+                {
+                    self.funcs.insert(sym.to_owned());
+                }
+            }
+            ".text" => self.section = Section::Text,
+            ".data" => self.section = Section::Data,
+            ".section" if args == ".rodata" => self.section = Section::Rodata,
+            ".string" | ".asciz" => {
+                let mut bytes = unescape(args).ok_or_else(|| format!("invalid string {}", args))?;
+                bytes.push(0);
+                self.buf().extend_from_slice(&bytes);
+            }
+            ".byte" | ".short" | ".long" | ".quad" => {
+                let val = parse_int(args).ok_or_else(|| format!("invalid number {}", args))?;
+                let size = match name {
+                    ".byte" => 1,
+                    ".short" => 2,
+                    ".long" => 4,
+                    _ => 8,
+                };
+                self.buf().extend_from_slice(&val.to_le_bytes()[..size]);
+            }
+            ".align" => self.align(
+                parse_int(args).ok_or_else(|| format!("invalid alignment {}", args))? as usize,
+            ),
+            ".size" => {}
+            _ => return Err(format!("unknown directive `{} {}`", name, args)),
+        }
+        Ok(())
+    }
+}
+
+/// An operand in AT&T syntax.
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    /// A general purpose register `size` bytes wide, `%eax` is `RAX` 4 bytes.
+    Reg(Register, usize),
+    Xmm(FloatRegister),
+    Imm(i64),
+    /// The address of a symbol `$name`.
+    ImmSym(String),
+    Mem(Mem),
+    /// A symbol a jump or call goes to, used as any other operand it is memory at an absolute
+    /// address.
+    Sym(String),
+    /// The target of an indirect jump or call `*%rax`.
+    Indirect(Box<Operand>),
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Reg(reg, size) => reg.sized(*size).fmt(f),
+            Operand::Xmm(reg) => reg.fmt(f),
+            Operand::Imm(val) => write!(f, "${}", val),
+            Operand::ImmSym(sym) => write!(f, "${}", sym),
+            Operand::Mem(Mem { base: Base::Rip(sym), disp, .. }) => {
+                write!(f, "{}+{}(%rip)", sym, disp)
+            }
+            Operand::Mem(Mem { base: Base::Reg(reg), index, disp }) => match index {
+                Some((idx, scale)) => write!(f, "{}({},{},{})", disp, reg, idx, scale),
+                None => write!(f, "{}({})", disp, reg),
+            },
+            Operand::Sym(sym) => sym.fmt(f),
+            Operand::Indirect(op) => write!(f, "*{}", op),
+        }
+    }
+}
+
+/// A memory operand `disp(base,index,scale)`.
+#[derive(Clone, Debug, PartialEq)]
+struct Mem {
+    base: Base,
+    /// The index register and what it is multiplied by.
+    index: Option<(Register, u8)>,
+    disp: i64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Base {
+    Reg(Register),
+    /// Relative to the next instruction `sym(%rip)`.
+    Rip(String),
+}
+
+/// `loc` as an operand, a register is `size` bytes wide.
+fn operand(loc: &Location, size: usize) -> Result<Operand, String> {
+    Ok(match loc {
+        Location::Register(reg) => Operand::Reg(*reg, size),
+        Location::FloatReg(reg) => Operand::Xmm(*reg),
+        Location::Const { val } => match val {
+            // A float constant is the bits of a single precision float, see `Instruction::Cvt`
+            Val::Float(v) => Operand::Imm((*v as f32).to_bits() as i32 as i64),
+            Val::Int(v) | Val::SizedInt(v, _) => Operand::Imm(*v as i64),
+            Val::Char(v) => Operand::Imm(*v as i64),
+            Val::Bool(v) => Operand::Imm(*v as i64),
+            Val::Str(v) => Operand::ImmSym(v.to_string()),
+        },
+        // A label is text like `main`, `$main` or `*%rax`
+        Location::Label(label) => parse_operand(label)?,
+        Location::NamedOffset(name) => {
+            Operand::Mem(Mem { base: Base::Rip(name.clone()), index: None, disp: 0 })
+        }
+        Location::NamedOffsetIndex { name, plus } => {
+            Operand::Mem(Mem { base: Base::Rip(name.clone()), index: None, disp: *plus as i64 })
+        }
+        Location::NumberedOffset { offset, reg }
+        | Location::Indexable { ele_pos: offset, reg, .. } => {
+            Operand::Mem(Mem { base: Base::Reg(*reg), index: None, disp: -(*offset as i64) })
+        }
+        Location::ArgOffset { offset, reg } => {
+            Operand::Mem(Mem { base: Base::Reg(*reg), index: None, disp: *offset as i64 })
+        }
+        Location::RegAddr { .. } => return Err(format!("can not encode {:?}", loc)),
+    })
+}
+
+/// Fill `buf` with zeros up to a multiple of `align`.
+crate fn pad(buf: &mut Vec<u8>, align: usize) {
+    match buf.len() % align {
+        0 => {}
+        rem => buf.resize(buf.len() + align - rem, 0),
+    }
+}
+
+fn suffix(size: usize) -> &'static str {
+    match size {
+        1 => "b",
+        2 => "w",
+        4 => "l",
+        _ => "q",
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..idx],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Split `ops` at each comma outside of parentheses, `(%rax,%rcx,4)` is one operand.
+fn split_operands(ops: &str) -> Vec<&str> {
+    let mut split = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (idx, c) in ops.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(ops[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    if !ops[start..].trim().is_empty() {
+        split.push(ops[start..].trim());
+    }
+    split
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    let text = text.trim();
+    match text.strip_prefix('-') {
+        Some(pos) => pos.parse::<u64>().ok().map(|v| (v as i64).wrapping_neg()),
+        None => text.parse::<u64>().ok().map(|v| v as i64),
+    }
+}
+
+/// The bytes of a quoted string with the escapes of `{:?}`.
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut bytes = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c => c,
+            },
+            c => c,
+        };
+        let mut buf = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+    Some(bytes)
+}
+
+fn register(name: &str) -> Option<Operand> {
+    if let Some(reg) = USABLE_FLOAT_REGS.iter().find(|reg| reg.to_string() == name) {
+        return Some(Operand::Xmm(*reg));
+    }
+    REGISTERS
+        .iter()
+        .flat_map(|reg| [1, 2, 4, 8].iter().map(move |size| (*reg, *size)))
+        .find(|(reg, size)| reg.sized(*size) == name)
+        .map(|(reg, size)| Operand::Reg(reg, size))
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    let invalid = || format!("invalid operand `{}`", text);
+    if let Some(target) = text.strip_prefix('*') {
+        return Ok(Operand::Indirect(box parse_operand(target)?));
+    }
+    if let Some(imm) = text.strip_prefix('$') {
+        return Ok(parse_int(imm).map_or_else(|| Operand::ImmSym(imm.to_owned()), Operand::Imm));
+    }
+    if text.starts_with('%') {
+        return register(text).ok_or_else(invalid);
+    }
+    let open = match text.find('(') {
+        Some(open) => open,
+        None if parse_int(text).is_none() && !text.is_empty() => {
+            return Ok(Operand::Sym(text.to_owned()))
+        }
+        None => return Err(invalid()),
+    };
+
+    // `sym+8(%rip)`, `-8(%rbp)` or `(%rcx,%rax,4)`
+    let (sym, disp) = match &text[..open] {
+        "" => (None, 0),
+        disp => match parse_int(disp) {
+            Some(disp) => (None, disp),
+            None => match disp.find(&['+', '-'][..]) {
+                Some(at) => (
+                    Some(&disp[..at]),
+                    parse_int(disp[at..].trim_start_matches('+')).ok_or_else(invalid)?,
+                ),
+                None => (Some(disp), 0),
+            },
+        },
+    };
+    let inner = text[open + 1..].strip_suffix(')').ok_or_else(invalid)?;
+    let parts = inner.split(',').map(str::trim).collect::<Vec<_>>();
+    let reg64 = |name: &str| match register(name) {
+        Some(Operand::Reg(reg, 8)) => Some(reg),
+        _ => None,
+    };
+    let base = match (parts[0], sym) {
+        ("%rip", Some(sym)) => Base::Rip(sym.to_owned()),
+        (base, None) => Base::Reg(reg64(base).ok_or_else(invalid)?),
+        _ => return Err(invalid()),
+    };
+    let index = match parts.get(1) {
+        Some(idx) => Some((
+            reg64(idx).ok_or_else(invalid)?,
+            parts.get(2).map_or(Some(1), |scale| parse_int(scale)).ok_or_else(invalid)? as u8,
+        )),
+        None => None,
+    };
+    Ok(Operand::Mem(Mem { base, index, disp }))
+}
+
+/// The bytes of one instruction and the symbols it refers to, a fixup is at an offset from the
+/// start of the instruction.
+#[derive(Debug, Default)]
+struct Encoding {
+    bytes: Vec<u8>,
+    fixups: Vec<(usize, String, i64, Reloc)>,
+}
+
+/// The immediate after the ModRM byte and displacement.
+enum Imm {
+    None,
+    Bytes(Vec<u8>),
+    /// The address of a symbol, 4 bytes.
+    Sym(String),
+}
+
+/// A `size` byte immediate, a 64 bit operation sign extends 4 bytes.
+fn imm(size: usize, val: i64) -> Option<Imm> {
+    Some(Imm::Bytes(match size {
+        1 => vec![val as u8],
+        2 => (val as u16).to_le_bytes().to_vec(),
+        4 => (val as u32).to_le_bytes().to_vec(),
+        _ if fits_i32(val) => (val as i32).to_le_bytes().to_vec(),
+        _ => return None,
+    }))
+}
+
+fn fits_i8(val: i64) -> bool {
+    val >= i8::MIN as i64 && val <= i8::MAX as i64
+}
+
+fn fits_i32(val: i64) -> bool {
+    val >= i32::MIN as i64 && val <= i32::MAX as i64
+}
+
+/// `%spl`, `%bpl`, `%sil` and `%dil` can only be named with a REX prefix.
+fn needs_rex(op: &Operand) -> bool {
+    matches!(op, Operand::Reg(reg, 1) if (4..8).contains(&(*reg as u8)))
+}
+
+/// Encode `opcode` with a ModRM byte, `reg` is a register number or an extension of the opcode
+/// and `rm` the register or memory operand.
+///
+/// `prefix` is the operand size or mandatory SSE prefix and `rex` holds the REX.W bit or forces
+/// an empty REX prefix with `0x40`.
+fn with_modrm(
+    prefix: Option<u8>,
+    mut rex: u8,
+    opcode: &[u8],
+    reg: u8,
+    rm: &Operand,
+    imm: Imm,
+) -> Option<Encoding> {
+    rex |= (reg & 8) >> 1;
+    let mut modrm = vec![];
+    // The offset into `modrm`, symbol and displacement of a field that is fixed up
+    let mut fixup = None;
+    match rm {
+        Operand::Reg(r, _) => {
+            rex |= (*r as u8 & 8) >> 3;
+            modrm.push(0xc0 | (reg & 7) << 3 | (*r as u8 & 7));
+        }
+        Operand::Xmm(x) => modrm.push(0xc0 | (reg & 7) << 3 | *x as u8),
+        Operand::Mem(Mem { base: Base::Rip(sym), disp, .. }) => {
+            modrm.push((reg & 7) << 3 | 0b101);
+            fixup = Some((modrm.len(), sym.clone(), *disp, Reloc::Pc32));
+            modrm.extend_from_slice(&[0; 4]);
+        }
+        Operand::Mem(Mem { base: Base::Reg(base), index, disp }) => {
+            let base = *base as u8;
+            rex |= (base & 8) >> 3;
+            let (mode, disp) = if *disp == 0 && base & 7 != 5 {
+                (0, vec![])
+            } else if fits_i8(*disp) {
+                (1, vec![*disp as u8])
+            } else {
+                (2, (*disp as i32).to_le_bytes().to_vec())
+            };
+            match index {
+                Some((idx, scale)) => {
+                    let idx = *idx as u8;
+                    rex |= (idx & 8) >> 2;
+                    let scale = match scale {
+                        1 => 0,
+                        2 => 1,
+                        4 => 2,
+                        8 => 3,
+                        _ => return None,
+                    };
+                    modrm.push(mode << 6 | (reg & 7) << 3 | 0b100);
+                    modrm.push(scale << 6 | (idx & 7) << 3 | (base & 7));
+                }
+                // `%rsp` and `%r12` can only be a base with a SIB byte
+                None if base & 7 == 4 => {
+                    modrm.push(mode << 6 | (reg & 7) << 3 | 0b100);
+                    modrm.push(0b100 << 3 | (base & 7));
+                }
+                None => modrm.push(mode << 6 | (reg & 7) << 3 | (base & 7)),
+            }
+            modrm.extend_from_slice(&disp);
+        }
+        // An absolute address, a SIB byte with no base or index
+        Operand::Sym(sym) => {
+            modrm.extend_from_slice(&[(reg & 7) << 3 | 0b100, 0x25]);
+            fixup = Some((modrm.len(), sym.clone(), 0, Reloc::Abs32S));
+            modrm.extend_from_slice(&[0; 4]);
+        }
+        _ => return None,
+    }
+
+    let mut enc = Encoding::default();
+    enc.bytes.extend(prefix);
+    if rex != 0 {
+        enc.bytes.push(0x40 | rex);
+    }
+    enc.bytes.extend_from_slice(opcode);
+    let start = enc.bytes.len();
+    enc.bytes.extend_from_slice(&modrm);
+    match imm {
+        Imm::None => {}
+        Imm::Bytes(bytes) => enc.bytes.extend_from_slice(&bytes),
+        Imm::Sym(sym) => {
+            enc.fixups.push((enc.bytes.len(), sym, 0, Reloc::Abs32S));
+            enc.bytes.extend_from_slice(&[0; 4]);
+        }
+    }
+    if let Some((at, sym, disp, kind)) = fixup {
+        // `%rip` is the end of the instruction, past any immediate
+        let at = start + at;
+        let addend = if kind == Reloc::Pc32 { disp - (enc.bytes.len() - at) as i64 } else { disp };
+        enc.fixups.push((at, sym, addend, kind));
+    }
+    Some(enc)
+}
+
+/// Encode a `size` byte operation, `byte` is the opcode when `size` is 1.
+fn sized(
+    size: usize,
+    byte: &[u8],
+    opcode: &[u8],
+    reg: u8,
+    rm: &Operand,
+    other: Option<&Operand>,
+    imm: Imm,
+) -> Option<Encoding> {
+    let prefix = if size == 2 { Some(0x66) } else { None };
+    let mut rex = if size == 8 { 0x08 } else { 0 };
+    if size == 1 && (needs_rex(rm) || matches!(other, Some(op) if needs_rex(op))) {
+        rex |= 0x40;
+    }
+    with_modrm(prefix, rex, if size == 1 { byte } else { opcode }, reg, rm, imm)
+}
+
+/// A register operand or the number of the register.
+fn reg_num(op: &Operand) -> Option<u8> {
+    match op {
+        Operand::Reg(reg, _) => Some(*reg as u8),
+        Operand::Xmm(reg) => Some(*reg as u8),
+        _ => None,
+    }
+}
+
+fn is_mem(op: &Operand) -> bool {
+    matches!(op, Operand::Mem(_) | Operand::Sym(_))
+}
+
+fn cond(cc: &str) -> Option<u8> {
+    Some(match cc {
+        "o" => 0,
+        "no" => 1,
+        "b" | "c" | "nae" => 2,
+        "ae" | "nb" | "nc" => 3,
+        "e" | "z" => 4,
+        "ne" | "nz" => 5,
+        "be" | "na" => 6,
+        "a" | "nbe" => 7,
+        "s" => 8,
+        "ns" => 9,
+        "p" | "pe" => 10,
+        "np" | "po" => 11,
+        "l" | "nge" => 12,
+        "ge" | "nl" => 13,
+        "le" | "ng" => 14,
+        "g" | "nle" => 15,
+        _ => return None,
+    })
+}
+
+/// The instructions that take a size suffix, `add` is written `addb`, `addw`, `addl` or `addq`.
+const SIZED: &[&str] = &[
+    "add", "or", "adc", "sbb", "and", "sub", "xor", "cmp", "mov", "lea", "push", "pop", "test",
+    "xchg", "imul", "mul", "div", "idiv", "neg", "not", "inc", "dec", "shl", "sal", "shr", "sar",
+    "rol", "ror",
+];
+
+/// Encode `mnemonic` with the operands `ops` in AT&T order, `None` when there is no such form.
+fn encode(mnemonic: &str, ops: &[Operand]) -> Option<Encoding> {
+    let fixed: &[u8] = match mnemonic {
+        "ret" => &[0xc3],
+        "leave" => &[0xc9],
+        "cqo" | "cqto" => &[0x48, 0x99],
+        "cltq" => &[0x48, 0x98],
+        "syscall" => &[0x0f, 0x05],
+        "ud2" => &[0x0f, 0x0b],
+        "nop" => &[0x90],
+        "hlt" => &[0xf4],
+        "pause" => &[0xf3, 0x90],
+        "cpuid" => &[0x0f, 0xa2],
+        "rdtsc" => &[0x0f, 0x31],
+        "mfence" => &[0x0f, 0xae, 0xf0],
+        "int3" => &[0xcc],
+        _ => &[],
+    };
+    if !fixed.is_empty() {
+        return if ops.is_empty() {
+            Some(Encoding { bytes: fixed.to_vec(), fixups: vec![] })
+        } else {
+            None
+        };
+    }
+
+    if let Some(enc) = encode_special(mnemonic, ops) {
+        return enc;
+    }
+
+    let (name, size) = if SIZED.contains(&mnemonic) {
+        let size = ops.iter().find_map(|op| match op {
+            Operand::Reg(_, size) => Some(*size),
+            _ => None,
+        });
+        (mnemonic, size.unwrap_or(8))
+    } else {
+        let (name, size) = mnemonic.split_at(mnemonic.len() - 1);
+        let size = match size {
+            "b" => 1,
+            "w" => 2,
+            "l" => 4,
+            "q" => 8,
+            _ => return None,
+        };
+        if !SIZED.contains(&name) {
+            return None;
+        }
+        (name, size)
+    };
+
+    match (name, ops) {
+        ("add" | "or" | "adc" | "sbb" | "and" | "sub" | "xor" | "cmp", [src, dst]) => {
+            let ext = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"]
+                .iter()
+                .position(|n| *n == name)? as u8;
+            match (src, dst) {
+                (Operand::Imm(val), rm) if size == 1 => {
+                    sized(size, &[0x80], &[], ext, rm, None, imm(1, *val)?)
+                }
+                (Operand::Imm(val), rm) if fits_i8(*val) => {
+                    sized(size, &[], &[0x83], ext, rm, None, imm(1, *val)?)
+                }
+                (Operand::Imm(val), rm) => {
+                    sized(size, &[], &[0x81], ext, rm, None, imm(size, *val)?)
+                }
+                (Operand::ImmSym(sym), rm) => {
+                    sized(size, &[], &[0x81], ext, rm, None, Imm::Sym(sym.clone()))
+                }
+                (src @ Operand::Reg(..), rm) => {
+                    sized(size, &[ext * 8], &[ext * 8 + 1], reg_num(src)?, rm, Some(src), Imm::None)
+                }
+                (rm, dst @ Operand::Reg(..)) => sized(
+                    size,
+                    &[ext * 8 + 2],
+                    &[ext * 8 + 3],
+                    reg_num(dst)?,
+                    rm,
+                    Some(dst),
+                    Imm::None,
+                ),
+                _ => None,
+            }
+        }
+        ("mov", [src, dst]) => encode_mov(size, src, dst),
+        ("lea", [src, dst @ Operand::Reg(..)]) if is_mem(src) => {
+            sized(size, &[], &[0x8d], reg_num(dst)?, src, None, Imm::None)
+        }
+        // `pushw` and `popw` take the operand size prefix, there is no 4 byte push or pop
+        ("push" | "pop", [op]) if size == 2 => {
+            let (short, opcode, ext) =
+                if name == "push" { (0x50, 0xff, 6) } else { (0x58, 0x8f, 0) };
+            let bytes = match op {
+                Operand::Reg(reg, 2) => short_reg(short, *reg).bytes,
+                Operand::Imm(val) if name == "push" && fits_i8(*val) => vec![0x6a, *val as u8],
+                Operand::Imm(val) if name == "push" => {
+                    let mut bytes = vec![0x68];
+                    bytes.extend_from_slice(&(*val as u16).to_le_bytes());
+                    bytes
+                }
+                rm if is_mem(rm) => {
+                    return with_modrm(Some(0x66), 0, &[opcode], ext, rm, Imm::None);
+                }
+                _ => return None,
+            };
+            Some(Encoding { bytes: [&[0x66], &bytes[..]].concat(), fixups: vec![] })
+        }
+        ("push" | "pop", _) if size != 8 => None,
+        ("push", [op]) => match op {
+            Operand::Reg(reg, 8) => Some(short_reg(0x50, *reg)),
+            Operand::Imm(val) if fits_i8(*val) => {
+                Some(Encoding { bytes: vec![0x6a, *val as u8], fixups: vec![] })
+            }
+            Operand::Imm(val) if fits_i32(*val) => {
+                let mut bytes = vec![0x68];
+                bytes.extend_from_slice(&(*val as i32).to_le_bytes());
+                Some(Encoding { bytes, fixups: vec![] })
+            }
+            Operand::ImmSym(sym) => Some(Encoding {
+                bytes: vec![0x68, 0, 0, 0, 0],
+                fixups: vec![(1, sym.clone(), 0, Reloc::Abs32S)],
+            }),
+            rm if is_mem(rm) => with_modrm(None, 0, &[0xff], 6, rm, Imm::None),
+            _ => None,
+        },
+        ("pop", [op]) => match op {
+            Operand::Reg(reg, 8) => Some(short_reg(0x58, *reg)),
+            rm if is_mem(rm) => with_modrm(None, 0, &[0x8f], 0, rm, Imm::None),
+            _ => None,
+        },
+        ("test", [Operand::Imm(val), rm]) => {
+            sized(size, &[0xf6], &[0xf7], 0, rm, None, imm(size, *val)?)
+        }
+        ("test" | "xchg", [src, dst]) => {
+            let (reg, rm) = if let Operand::Reg(..) = src { (src, dst) } else { (dst, src) };
+            let (byte, opcode) = if name == "test" { (0x84, 0x85) } else { (0x86, 0x87) };
+            sized(size, &[byte], &[opcode], reg_num(reg)?, rm, Some(reg), Imm::None)
+        }
+        ("imul", [rm]) => sized(size, &[0xf6], &[0xf7], 5, rm, None, Imm::None),
+        ("imul", [Operand::Imm(val), dst @ Operand::Reg(..)]) => encode_imul3(size, *val, dst, dst),
+        ("imul", [rm, dst @ Operand::Reg(..)]) => {
+            sized(size, &[], &[0x0f, 0xaf], reg_num(dst)?, rm, None, Imm::None)
+        }
+        ("imul", [Operand::Imm(val), rm, dst @ Operand::Reg(..)]) => {
+            encode_imul3(size, *val, rm, dst)
+        }
+        ("not" | "neg" | "mul" | "div" | "idiv", [rm]) => {
+            let ext = match name {
+                "not" => 2,
+                "neg" => 3,
+                "mul" => 4,
+                "div" => 6,
+                _ => 7,
+            };
+            sized(size, &[0xf6], &[0xf7], ext, rm, None, Imm::None)
+        }
+        ("inc" | "dec", [rm]) => {
+            sized(size, &[0xfe], &[0xff], (name == "dec") as u8, rm, None, Imm::None)
+        }
+        ("shl" | "sal" | "shr" | "sar" | "rol" | "ror", ops) => {
+            let ext = match name {
+                "rol" => 0,
+                "ror" => 1,
+                "shl" | "sal" => 4,
+                "shr" => 5,
+                _ => 7,
+            };
+            match ops {
+                [rm] | [Operand::Imm(1), rm] => {
+                    sized(size, &[0xd0], &[0xd1], ext, rm, None, Imm::None)
+                }
+                [Operand::Imm(val), rm] => {
+                    sized(size, &[0xc0], &[0xc1], ext, rm, None, imm(1, *val)?)
+                }
+                [Operand::Reg(RCX, 1), rm] => {
+                    sized(size, &[0xd2], &[0xd3], ext, rm, None, Imm::None)
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Encode the instructions that are not a `SIZED` one with a suffix, `None` when `mnemonic` is
+/// not one of them.
+fn encode_special(mnemonic: &str, ops: &[Operand]) -> Option<Option<Encoding>> {
+    let xmm_src = |op: &Operand| matches!(op, Operand::Xmm(_)) || is_mem(op);
+    Some(match (mnemonic, ops) {
+        ("call" | "jmp", [Operand::Sym(sym)]) => {
+            let opcode = if mnemonic == "call" { 0xe8 } else { 0xe9 };
+            Some(Encoding {
+                bytes: vec![opcode, 0, 0, 0, 0],
+                fixups: vec![(1, sym.clone(), -4, Reloc::Plt32)],
+            })
+        }
+        ("call" | "jmp", [Operand::Indirect(rm)]) => {
+            with_modrm(None, 0, &[0xff], if mnemonic == "call" { 2 } else { 4 }, rm, Imm::None)
+        }
+        ("int", [Operand::Imm(val)]) => {
+            Some(Encoding { bytes: vec![0xcd, *val as u8], fixups: vec![] })
+        }
+        ("movsd" | "movss", [src, dst]) => {
+            let prefix = Some(if mnemonic == "movsd" { 0xf2 } else { 0xf3 });
+            match (src, dst) {
+                (src, Operand::Xmm(dst)) if xmm_src(src) => {
+                    with_modrm(prefix, 0, &[0x0f, 0x10], *dst as u8, src, Imm::None)
+                }
+                (Operand::Xmm(src), dst) if is_mem(dst) => {
+                    with_modrm(prefix, 0, &[0x0f, 0x11], *src as u8, dst, Imm::None)
+                }
+                _ => None,
+            }
+        }
+        ("movabs" | "movabsq", [Operand::Imm(val), Operand::Reg(reg, 8)]) => {
+            let mut enc = short_reg(0xb8, *reg);
+            enc.bytes.insert(enc.bytes.len() - 1, 0x48 | (*reg as u8 & 8) >> 3);
+            if enc.bytes.len() == 3 {
+                // The `REX.B` of a high register is merged into the one `REX.W` prefix
+                enc.bytes.remove(0);
+            }
+            enc.bytes.extend_from_slice(&val.to_le_bytes());
+            Some(enc)
+        }
+        (m, [src, dst @ Operand::Reg(..)]) if ext_opcode(m).is_some() => {
+            let (prefix, rex, opcode) = ext_opcode(m)?;
+            let rex = if needs_rex(src) { rex | 0x40 } else { rex };
+            with_modrm(prefix, rex, opcode, reg_num(dst)?, src, Imm::None)
+        }
+        (m, [src, Operand::Xmm(dst)]) if sse_opcode(m).is_some() && xmm_src(src) => {
+            let (prefix, opcode) = sse_opcode(m)?;
+            with_modrm(Some(prefix), 0, &[0x0f, opcode], *dst as u8, src, Imm::None)
+        }
+        ("cvtsi2sd" | "cvtsi2sdq" | "cvtsi2sdl", [src, Operand::Xmm(dst)]) => {
+            // Without a suffix a register names the size and memory is 4 bytes
+            let wide = mnemonic.ends_with('q') || matches!(src, Operand::Reg(_, 8));
+            let rex = if wide && mnemonic != "cvtsi2sdl" { 0x08 } else { 0 };
+            with_modrm(Some(0xf2), rex, &[0x0f, 0x2a], *dst as u8, src, Imm::None)
+        }
+        (
+            "cvttsd2si" | "cvttsd2siq" | "cvttsd2sil" | "cvtsd2si" | "cvtsd2siq" | "cvtsd2sil",
+            [src, dst @ Operand::Reg(_, size @ (4 | 8))],
+        ) if xmm_src(src) => {
+            // A suffix has to agree with the size of the register
+            match mnemonic.as_bytes().last() {
+                Some(b'q') if *size != 8 => return Some(None),
+                Some(b'l') if *size != 4 => return Some(None),
+                _ => {}
+            }
+            let opcode = if mnemonic.starts_with("cvtt") { 0x2c } else { 0x2d };
+            let rex = if *size == 8 { 0x08 } else { 0 };
+            with_modrm(Some(0xf2), rex, &[0x0f, opcode], reg_num(dst)?, src, Imm::None)
+        }
+        (m, ops) if m.starts_with("cmov") => {
+            let cc = &m[4..];
+            let cc = cond(cc).or_else(|| cond(cc.strip_suffix(&['w', 'l', 'q'][..])?))?;
+            match ops {
+                [src, dst @ Operand::Reg(_, size)] => {
+                    sized(*size, &[], &[0x0f, 0x40 + cc], reg_num(dst)?, src, None, Imm::None)
+                }
+                _ => None,
+            }
+        }
+        (m, [Operand::Sym(sym)]) if m.starts_with('j') && cond(&m[1..]).is_some() => {
+            Some(Encoding {
+                bytes: vec![0x0f, 0x80 + cond(&m[1..])?, 0, 0, 0, 0],
+                fixups: vec![(2, sym.clone(), -4, Reloc::Plt32)],
+            })
+        }
+        _ => return None,
+    })
+}
+
+/// `mov` between general purpose registers, memory and immediates and `movq` to or from an
+/// `%xmm` register.
+fn encode_mov(size: usize, src: &Operand, dst: &Operand) -> Option<Encoding> {
+    match (src, dst) {
+        (Operand::Xmm(src), Operand::Xmm(dst)) => {
+            with_modrm(Some(0xf3), 0, &[0x0f, 0x7e], *dst as u8, &Operand::Xmm(*src), Imm::None)
+        }
+        (src, Operand::Xmm(dst)) if is_mem(src) => {
+            with_modrm(Some(0xf3), 0, &[0x0f, 0x7e], *dst as u8, src, Imm::None)
+        }
+        (src @ Operand::Reg(..), Operand::Xmm(dst)) => {
+            with_modrm(Some(0x66), 0x08, &[0x0f, 0x6e], *dst as u8, src, Imm::None)
+        }
+        (Operand::Xmm(src), dst @ Operand::Reg(..)) => {
+            with_modrm(Some(0x66), 0x08, &[0x0f, 0x7e], *src as u8, dst, Imm::None)
+        }
+        (Operand::Xmm(src), dst) if is_mem(dst) => {
+            with_modrm(Some(0x66), 0, &[0x0f, 0xd6], *src as u8, dst, Imm::None)
+        }
+        (Operand::Imm(val), Operand::Reg(reg, 8)) if !fits_i32(*val) => {
+            encode_special("movabsq", &[Operand::Imm(*val), Operand::Reg(*reg, 8)])?
+        }
+        (Operand::Imm(val), rm) => sized(size, &[0xc6], &[0xc7], 0, rm, None, imm(size, *val)?),
+        (Operand::ImmSym(sym), rm) => {
+            sized(size, &[0xc6], &[0xc7], 0, rm, None, Imm::Sym(sym.clone()))
+        }
+        (src @ Operand::Reg(..), rm) => {
+            sized(size, &[0x88], &[0x89], reg_num(src)?, rm, Some(src), Imm::None)
+        }
+        (rm, dst @ Operand::Reg(..)) => {
+            sized(size, &[0x8a], &[0x8b], reg_num(dst)?, rm, Some(dst), Imm::None)
+        }
+        _ => None,
+    }
+}
+
+/// `imul $val, rm, dst`
+fn encode_imul3(size: usize, val: i64, rm: &Operand, dst: &Operand) -> Option<Encoding> {
+    if fits_i8(val) {
+        sized(size, &[], &[0x6b], reg_num(dst)?, rm, None, imm(1, val)?)
+    } else {
+        sized(size, &[], &[0x69], reg_num(dst)?, rm, None, imm(size, val)?)
+    }
+}
+
+/// `push`, `pop` and `movabs` have the register in the low bits of the opcode.
+fn short_reg(opcode: u8, reg: Register) -> Encoding {
+    let reg = reg as u8;
+    let mut bytes = vec![];
+    if reg & 8 != 0 {
+        bytes.push(0x41);
+    }
+    bytes.push(opcode + (reg & 7));
+    Encoding { bytes, fixups: vec![] }
+}
+
+/// The prefix, REX.W and opcode of a sign or zero extending move.
+fn ext_opcode(mnemonic: &str) -> Option<(Option<u8>, u8, &'static [u8])> {
+    Some(match mnemonic {
+        "movzbq" => (None, 0x08, &[0x0f, 0xb6]),
+        "movzbl" => (None, 0, &[0x0f, 0xb6]),
+        "movzbw" => (Some(0x66), 0, &[0x0f, 0xb6]),
+        "movzwq" => (None, 0x08, &[0x0f, 0xb7]),
+        "movzwl" => (None, 0, &[0x0f, 0xb7]),
+        "movsbq" => (None, 0x08, &[0x0f, 0xbe]),
+        "movsbl" => (None, 0, &[0x0f, 0xbe]),
+        "movsbw" => (Some(0x66), 0, &[0x0f, 0xbe]),
+        "movswq" => (None, 0x08, &[0x0f, 0xbf]),
+        "movswl" => (None, 0, &[0x0f, 0xbf]),
+        "movslq" => (None, 0x08, &[0x63]),
+        _ => return None,
+    })
+}
+
+/// The mandatory prefix and opcode after `0x0f` of an SSE instruction `op src, %xmm`.
+fn sse_opcode(mnemonic: &str) -> Option<(u8, u8)> {
+    Some(match mnemonic {
+        "addsd" => (0xf2, 0x58),
+        "mulsd" => (0xf2, 0x59),
+        "subsd" => (0xf2, 0x5c),
+        "divsd" => (0xf2, 0x5e),
+        "sqrtsd" => (0xf2, 0x51),
+        "minsd" => (0xf2, 0x5d),
+        "maxsd" => (0xf2, 0x5f),
+        "cvtsd2ss" => (0xf2, 0x5a),
+        "cvtss2sd" => (0xf3, 0x5a),
+        "ucomisd" => (0x66, 0x2e),
+        "comisd" => (0x66, 0x2f),
+        "xorpd" => (0x66, 0x57),
+        "pxor" => (0x66, 0xef),
+        _ => return None,
+    })
+}
+
+#[test]
+fn encode_like_gas() {
+    // The bytes `as` assembles each line to
+    let cases: &[(&str, &[u8])] = &[
+        ("movq %rax, -8(%rbp)", &[0x48, 0x89, 0x45, 0xf8]),
+        ("addq $1, %rax", &[0x48, 0x83, 0xc0, 0x01]),
+        ("subq $4096, %rsp", &[0x48, 0x81, 0xec, 0x00, 0x10, 0x00, 0x00]),
+        ("movzbq (%rax), %rcx", &[0x48, 0x0f, 0xb6, 0x08]),
+        ("movb %dil, -1(%rbp)", &[0x40, 0x88, 0x7d, 0xff]),
+        ("movsd %xmm0, -32(%rbp)", &[0xf2, 0x0f, 0x11, 0x45, 0xe0]),
+        ("pushq %r12", &[0x41, 0x54]),
+        ("movslq (%rcx,%rax,4), %rax", &[0x48, 0x63, 0x04, 0x81]),
+        ("leaq 8(%rsp), %rsi", &[0x48, 0x8d, 0x74, 0x24, 0x08]),
+        ("cvtsi2sdq %rcx, %xmm1", &[0xf2, 0x48, 0x0f, 0x2a, 0xc9]),
+        ("shlq %cl, %rdx", &[0x48, 0xd3, 0xe2]),
+        ("imulq $16, %rcx", &[0x48, 0x6b, 0xc9, 0x10]),
+        ("movq %xmm0, %rax", &[0x66, 0x48, 0x0f, 0x7e, 0xc0]),
+        ("movl -16(%r13), %r8d", &[0x45, 0x8b, 0x45, 0xf0]),
+        ("jmp *%rax", &[0xff, 0xe0]),
+        ("cqo", &[0x48, 0x99]),
+        ("cvttsd2sil %xmm0, %eax", &[0xf2, 0x0f, 0x2c, 0xc0]),
+        ("cvtsd2sil -8(%rbp), %r8d", &[0xf2, 0x44, 0x0f, 0x2d, 0x45, 0xf8]),
+        ("cvttsd2si %xmm1, %ecx", &[0xf2, 0x0f, 0x2c, 0xc9]),
+        ("pushw %ax", &[0x66, 0x50]),
+        ("push %r9w", &[0x66, 0x41, 0x51]),
+        ("pushw $1000", &[0x66, 0x68, 0xe8, 0x03]),
+        ("pushw 8(%rbp)", &[0x66, 0xff, 0x75, 0x08]),
+        ("popw (%rax)", &[0x66, 0x8f, 0x00]),
+    ];
+    for (line, bytes) in cases {
+        let mut asm = Assembler::new();
+        asm.text(line).unwrap();
+        assert_eq!(&asm.text, bytes, "{}", line);
+    }
+}
+
+#[test]
+fn reject_like_gas() {
+    // Lines `as` has no encoding for
+    for line in
+        ["push %eax", "pushl $1", "popw %rax", "cvttsd2siq %xmm0, %eax", "cvtsd2sil %xmm0, %rax"]
+    {
+        let mut asm = Assembler::new();
+        assert!(asm.text(line).is_err(), "{}", line);
+    }
+}
+
+#[test]
+fn rip_relative_fixup() {
+    let mut asm = Assembler::new();
+    asm.text("cmpq $1, .bool_test(%rip)\ncall printf").unwrap();
+    assert_eq!(&asm.text[..3], &[0x48, 0x83, 0x3d]);
+    // The displacement is relative to the end of the instruction after the immediate
+    assert_eq!(asm.fixups[0].at, 3);
+    assert_eq!(asm.fixups[0].addend, -5);
+    assert_eq!((asm.fixups[1].at, asm.fixups[1].kind), (9, Reloc::Plt32));
+}
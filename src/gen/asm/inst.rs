@@ -1,7 +1,5 @@
 use std::fmt;
 
-use rustc_hash::FxHashSet as HashSet;
-
 use crate::{
     gen::asm::{CodeGen, ZERO},
     lir::lower::{BinOp, Val},
@@ -11,11 +9,10 @@ use Register::*;
 
 pub const ARG_REGS: [Register; 6] = [RDI, RSI, RDX, RCX, R8, R9];
 
-lazy_static::lazy_static! { pub static ref USABLE_REGS: HashSet<Register> =
-    vec![RAX, RCX, RDX, RBX, RSI, RDI, R8, R9, R10, R11, R12, R13, R14, R15]
-        .into_iter()
-        .collect();
-}
+/// The registers values can be kept in, the caller saved registers come first so a function only
+/// has to save a callee saved register when it runs out.
+pub const USABLE_REGS: [Register; 14] =
+    [RAX, RCX, RDX, RSI, RDI, R8, R9, R10, R11, RBX, R12, R13, R14, R15];
 
 #[rustfmt::skip]
 #[allow(clippy::upper_case_acronyms)]
@@ -56,15 +53,40 @@ impl fmt::Display for Register {
     }
 }
 
+impl Register {
+    /// The name of the low `size` bytes of the register `%al`, `%ax`, `%eax` or `%rax`.
+    #[rustfmt::skip]
+    crate fn sized(&self, size: usize) -> &'static str {
+        match (self, size) {
+            (RAX, 1) => "%al",   (RAX, 2) => "%ax",   (RAX, 4) => "%eax",
+            (RCX, 1) => "%cl",   (RCX, 2) => "%cx",   (RCX, 4) => "%ecx",
+            (RDX, 1) => "%dl",   (RDX, 2) => "%dx",   (RDX, 4) => "%edx",
+            (RBX, 1) => "%bl",   (RBX, 2) => "%bx",   (RBX, 4) => "%ebx",
+            (RSP, 1) => "%spl",  (RSP, 2) => "%sp",   (RSP, 4) => "%esp",
+            (RBP, 1) => "%bpl",  (RBP, 2) => "%bp",   (RBP, 4) => "%ebp",
+            (RSI, 1) => "%sil",  (RSI, 2) => "%si",   (RSI, 4) => "%esi",
+            (RDI, 1) => "%dil",  (RDI, 2) => "%di",   (RDI, 4) => "%edi",
+            (R8, 1)  => "%r8b",  (R8, 2)  => "%r8w",  (R8, 4)  => "%r8d",
+            (R9, 1)  => "%r9b",  (R9, 2)  => "%r9w",  (R9, 4)  => "%r9d",
+            (R10, 1) => "%r10b", (R10, 2) => "%r10w", (R10, 4) => "%r10d",
+            (R11, 1) => "%r11b", (R11, 2) => "%r11w", (R11, 4) => "%r11d",
+            (R12, 1) => "%r12b", (R12, 2) => "%r12w", (R12, 4) => "%r12d",
+            (R13, 1) => "%r13b", (R13, 2) => "%r13w", (R13, 4) => "%r13d",
+            (R14, 1) => "%r14b", (R14, 2) => "%r14w", (R14, 4) => "%r14d",
+            (R15, 1) => "%r15b", (R15, 2) => "%r15w", (R15, 4) => "%r15d",
+            (RAX, _) => "%rax",  (RCX, _) => "%rcx",  (RDX, _) => "%rdx",  (RBX, _) => "%rbx",
+            (RSP, _) => "%rsp",  (RBP, _) => "%rbp",  (RSI, _) => "%rsi",  (RDI, _) => "%rdi",
+            (R8, _)  => "%r8",   (R9, _)  => "%r9",   (R10, _) => "%r10",  (R11, _) => "%r11",
+            (R12, _) => "%r12",  (R13, _) => "%r13",  (R14, _) => "%r14",  (R15, _) => "%r15",
+        }
+    }
+}
+
 use FloatRegister::*;
 
-pub const ARG_FLOAT_REGS: [FloatRegister; 7] = [XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6];
+pub const ARG_FLOAT_REGS: [FloatRegister; 8] = [XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7];
 
-lazy_static::lazy_static! { pub static ref USABLE_FLOAT_REGS: HashSet<FloatRegister> =
-    vec![XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7]
-        .into_iter()
-        .collect();
-}
+pub const USABLE_FLOAT_REGS: [FloatRegister; 8] = [XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7];
 
 #[rustfmt::skip]
 #[allow(clippy::upper_case_acronyms)]
@@ -121,6 +143,11 @@ pub enum Location {
         offset: usize,
         reg: Register,
     },
+    /// The argument passed on the stack `offset` bytes above `reg`, like `16(%rbp)`.
+    ArgOffset {
+        offset: usize,
+        reg: Register,
+    },
     Indexable {
         end: usize,
         ele_pos: usize,
@@ -136,10 +163,16 @@ impl fmt::Display for Location {
             Location::Register(reg) => write!(f, "{:>width$}", reg, width = width),
             Location::FloatReg(reg) => write!(f, "{:>width$}", reg, width = width),
             Location::Const { val } => match val {
-                Val::Float(v) => {
-                    write!(f, "{:>width$}", format!("${}", (*v as f32).to_bits()), width = width)
+                // Signed so a negative float still fits the 32 bit immediate of `push`
+                Val::Float(v) => write!(
+                    f,
+                    "{:>width$}",
+                    format!("${}", (*v as f32).to_bits() as i32),
+                    width = width
+                ),
+                Val::Int(v) | Val::SizedInt(v, _) => {
+                    write!(f, "{:>width$}", format!("${}", v), width = width)
                 }
-                Val::Int(v) => write!(f, "{:>width$}", format!("${}", v), width = width),
                 Val::Char(v) => write!(f, "{:>width$}", format!("${}", v), width = width),
                 Val::Bool(v) => {
                     write!(f, "{:>width$}", format!("${}", if *v { 1 } else { 0 }), width = width)
@@ -163,6 +196,9 @@ impl fmt::Display for Location {
                 ),
                 width = width
             ),
+            Location::ArgOffset { offset, reg } => {
+                write!(f, "{:>width$}", format!("{}({})", offset, reg), width = width)
+            }
             Location::Indexable { end, ele_pos, reg } => {
                 assert!(ele_pos <= end, "array index is out of bounds");
                 write!(
@@ -182,12 +218,27 @@ impl fmt::Display for Location {
 
 impl Location {
     crate fn is_stack_offset(&self) -> bool {
-        matches!(self, Self::NumberedOffset { .. } | Self::NamedOffset(..) | Self::Indexable { .. })
+        matches!(
+            self,
+            Self::NumberedOffset { .. }
+                | Self::ArgOffset { .. }
+                | Self::NamedOffset(..)
+                | Self::Indexable { .. }
+        )
     }
 
     crate fn is_float_reg(&self) -> bool {
         matches!(self, Self::FloatReg(..))
     }
+
+    /// The location formatted as an operand `size` bytes wide, only registers have a different
+    /// name for each size.
+    crate fn sized(&self, size: usize) -> String {
+        match self {
+            Location::Register(reg) => reg.sized(size).to_owned(),
+            loc => loc.to_string(),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -196,6 +247,7 @@ pub enum Global {
     Int { name: String, content: i64, mutable: bool },
     Char { name: String, content: u8, mutable: bool },
     Array { name: String, content: Vec<Val>, mutable: bool },
+    JumpTable { name: String, labels: Vec<String> },
 }
 
 impl Global {
@@ -205,6 +257,7 @@ impl Global {
             Global::Int { name, .. } => name,
             Global::Char { name, .. } => name,
             Global::Array { name, .. } => name,
+            Global::JumpTable { name, .. } => name,
         }
     }
 }
@@ -215,6 +268,8 @@ pub enum CondFlag {
     Overflow,
     NoOverflow,
     Below,
+    BelowEq,
+    Above,
     Carry,
     NotAboveEq,
     AboveEq,
@@ -236,6 +291,8 @@ impl ToString for CondFlag {
             CondFlag::Overflow => "o".into(),
             CondFlag::NoOverflow => "no".into(),
             CondFlag::Below => "b".into(),
+            CondFlag::BelowEq => "be".into(),
+            CondFlag::Above => "a".into(),
             CondFlag::Carry => "c".into(),
             CondFlag::NotAboveEq => "nae".into(),
             CondFlag::AboveEq => "ae".into(),
@@ -262,6 +319,14 @@ pub enum JmpCond {
     Ge,
     Lt,
     Le,
+    /// Unsigned greater than.
+    Above,
+    /// Unsigned greater than or equal.
+    AboveEq,
+    /// Unsigned less than, also a carry out of unsigned arithmetic.
+    Below,
+    /// Signed arithmetic overflowed.
+    Overflow,
 }
 
 impl ToString for JmpCond {
@@ -273,6 +338,10 @@ impl ToString for JmpCond {
             JmpCond::Ge => "ge".into(),
             JmpCond::Lt => "l".into(),
             JmpCond::Le => "le".into(),
+            JmpCond::Above => "a".into(),
+            JmpCond::AboveEq => "ae".into(),
+            JmpCond::Below => "b".into(),
+            JmpCond::Overflow => "o".into(),
         }
     }
 }
@@ -305,8 +374,15 @@ pub enum Instruction {
     },
     /// Jump to the address saving stack info.
     ///
-    /// the `Location` is most often a label but can be an address.
-    Call(Location),
+    /// the `Location` is most often a label but can be an address, `args` and `float_args` are how
+    /// many argument registers of each kind the call reads and `stack` is the bytes of arguments
+    /// pushed right before it.
+    Call {
+        loc: Location,
+        args: usize,
+        float_args: usize,
+        stack: usize,
+    },
     /// Jump to the specified `Location`.
     Jmp(Location),
     /// Conditionally jump to the specified `Location`.
@@ -314,6 +390,13 @@ pub enum Instruction {
         loc: Location,
         cond: JmpCond,
     },
+    /// Jump to the label at entry `idx` of the jump table whose address is in `table`.
+    ///
+    /// This clobbers `idx`.
+    JmpTable {
+        table: Register,
+        idx: Register,
+    },
     /// Clean up stack before returning from a call.
     Leave,
     /// Return from a call.
@@ -337,11 +420,25 @@ pub enum Instruction {
         src: Location,
         dst: Location,
     },
+    /// Move `size` bytes from source to destination.
+    ///
+    /// A register destination always gets all 64 bits, loading a smaller value from memory zero
+    /// extends it.
     SizedMov {
         src: Location,
         dst: Location,
         size: usize,
     },
+    /// Move the low `size` bytes of `src` into the 64 bit register `dst` sign or zero extending
+    /// them.
+    ///
+    /// `src` and `dst` can be the same register.
+    MovExt {
+        src: Location,
+        dst: Location,
+        size: usize,
+        signed: bool,
+    },
     /// Load from the address `src` to `dst`.
     Load {
         src: Location,
@@ -369,13 +466,36 @@ pub enum Instruction {
     },
     /// A `idiv` instruction, since they are funky just special case it.
     Idiv(Location),
+    /// The unsigned `div` instruction, `rdx` must be zeroed first.
+    Div(Location),
+    /// The unsigned `mul` instruction, `rdx:rax = rax * loc`.
+    Mul(Location),
     /// Sign extend `rax` into `rdx` `rdx:rax`.
     Extend,
+    /// Shift `dst` by `src` bits, `src` must be a constant or `%rcx` (`%cl`).
+    ///
+    /// A right shift of a `signed` value keeps the sign bit (`sar`).
+    Shift {
+        src: Location,
+        dst: Location,
+        op: BinOp,
+        signed: bool,
+    },
     /// Convert single precision float to double for printf.
     Cvt {
         src: Location,
         dst: Location,
     },
+    /// Convert a 64 bit integer to a double.
+    IntToFloat {
+        src: Location,
+        dst: Location,
+    },
+    /// Convert a double to a 64 bit integer, rounding toward zero.
+    FloatToInt {
+        src: Location,
+        dst: Location,
+    },
     /// Compare `src` to `dst`.
     Cmp {
         src: Location,
@@ -391,10 +511,13 @@ impl Instruction {
     }
 
     /// The `rhs` is where the value will end up for most operations.
+    ///
+    /// Values that are not `signed` are compared using the above/below flags.
     crate fn from_binop_cmp(
         mut lhs: Location,
         mut rhs: Location,
         op: &BinOp,
+        signed: bool,
         cond_reg: Location,
         ctxt: &mut CodeGen,
     ) -> Vec<Self> {
@@ -431,16 +554,32 @@ impl Instruction {
 
         match op {
             BinOp::Lt => {
-                inst.extend_from_slice(&op_instructions(CondFlag::Less));
+                inst.extend_from_slice(&op_instructions(if signed {
+                    CondFlag::Less
+                } else {
+                    CondFlag::Below
+                }));
             }
             BinOp::Le => {
-                inst.extend_from_slice(&op_instructions(CondFlag::LessEq));
+                inst.extend_from_slice(&op_instructions(if signed {
+                    CondFlag::LessEq
+                } else {
+                    CondFlag::BelowEq
+                }));
             }
             BinOp::Ge => {
-                inst.extend_from_slice(&op_instructions(CondFlag::GreaterEq));
+                inst.extend_from_slice(&op_instructions(if signed {
+                    CondFlag::GreaterEq
+                } else {
+                    CondFlag::AboveEq
+                }));
             }
             BinOp::Gt => {
-                inst.extend_from_slice(&op_instructions(CondFlag::Greater));
+                inst.extend_from_slice(&op_instructions(if signed {
+                    CondFlag::Greater
+                } else {
+                    CondFlag::Above
+                }));
             }
             BinOp::Eq => {
                 inst.extend_from_slice(&op_instructions(CondFlag::Eq));
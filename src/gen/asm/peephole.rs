@@ -0,0 +1,406 @@
+//! A peephole optimizer over the instructions of the whole program.
+//!
+//! `CodeGen` moves most values through `%rax` and the stack, these rules clean up what that
+//! leaves behind. Each rule looks at one or two instructions next to each other and they run
+//! until none of them change anything.
+
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
+
+use crate::gen::asm::inst::{Instruction, Location, Register};
+
+/// Optimize `insts`, `tables` are the labels in jump tables. Returns how many instructions were
+/// removed.
+crate fn optimize(insts: &mut Vec<Instruction>, tables: &[&str]) -> usize {
+    let before = insts.len();
+    let mut changed = true;
+    while changed {
+        changed = forward_stores(insts);
+        changed |= remove_moves(insts);
+        changed |= thread_jumps(insts);
+        changed |= remove_unreachable(insts);
+        changed |= remove_dead_labels(insts, tables);
+    }
+    before - insts.len()
+}
+
+/// A value stored to memory and loaded right back is taken from the register it was stored
+/// from, a `push` followed by a `pop` is a `mov`.
+fn forward_stores(insts: &mut Vec<Instruction>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < insts.len() {
+        let (first, rest) = insts.split_at_mut(i + 1);
+        match (&first[i], &mut rest[0]) {
+            (
+                Instruction::Mov { src: src @ Location::Register(_), dst: mem, .. }
+                | Instruction::SizedMov { src: src @ Location::Register(_), dst: mem, size: 8 },
+                Instruction::Mov { src: load, .. }
+                | Instruction::SizedMov { src: load, size: 8, .. }
+                | Instruction::MovExt { src: load, .. },
+            )
+            | (
+                Instruction::FloatMov { src: src @ Location::FloatReg(_), dst: mem },
+                Instruction::FloatMov { src: load, .. },
+            ) if mem.is_stack_offset() && mem == load => {
+                *load = src.clone();
+                changed = true;
+            }
+            (
+                Instruction::Push { loc: src, size: 8, .. },
+                Instruction::Pop { loc: dst, size: 8, .. },
+            ) if !(src.is_stack_offset() && dst.is_stack_offset())
+                && !uses_reg(src, Register::RSP)
+                && !uses_reg(dst, Register::RSP)
+                && matches!(
+                    src,
+                    Location::Register(_)
+                        | Location::Const { .. }
+                        | Location::NumberedOffset { .. }
+                ) =>
+            {
+                let mov = Instruction::SizedMov { src: src.clone(), dst: dst.clone(), size: 8 };
+                insts.splice(i..i + 2, Some(mov));
+                changed = true;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    changed
+}
+
+/// Remove a move to itself, a move straight back to where the value came from and a move to a
+/// register that is written again before it is read.
+fn remove_moves(insts: &mut Vec<Instruction>) -> bool {
+    let before = insts.len();
+    insts.retain(|inst| !matches!(mov_operands(inst), Some((src, dst)) if src == dst));
+
+    let mut i = 0;
+    while i + 1 < insts.len() {
+        match (mov_operands(&insts[i]), mov_operands(&insts[i + 1])) {
+            // `mov %rax, %rcx; mov %rcx, %rax`
+            (Some((a, b)), Some((c, d)))
+                if a == d
+                    && b == c
+                    && !(a.is_stack_offset() && b.is_stack_offset())
+                    && !depends_on(a, b)
+                    && !depends_on(b, a)
+                    && same_kind(&insts[i], &insts[i + 1]) =>
+            {
+                insts.remove(i + 1);
+            }
+            // `mov $1, %rax; mov %rcx, %rax`
+            (Some((_, Location::Register(r))), Some((src, Location::Register(dst))))
+                if r == dst
+                    && !uses_reg(src, *r)
+                    && matches!(insts[i + 1], Instruction::Mov { .. }) =>
+            {
+                insts.remove(i);
+            }
+            _ => i += 1,
+        }
+    }
+    insts.len() != before
+}
+
+/// A jump to a label that only jumps again goes straight to the last label, a jump to the
+/// instruction right after it is removed.
+fn thread_jumps(insts: &mut Vec<Instruction>) -> bool {
+    let before = insts.len();
+    let labels = label_indices(insts);
+
+    let mut retargeted = false;
+    for i in 0..insts.len() {
+        let target = match jump_target(&insts[i]) {
+            Some(l) => l.to_string(),
+            None => continue,
+        };
+        let mut to = target.clone();
+        let mut seen = HashSet::default();
+        while let Some(next) = labels.get(&to).and_then(|at| jump_after(insts, *at)) {
+            // A loop of jumps that never ends
+            if !seen.insert(next.clone()) || next == target {
+                break;
+            }
+            to = next;
+        }
+        if to != target {
+            match &mut insts[i] {
+                Instruction::Jmp(loc) | Instruction::CondJmp { loc, .. } => {
+                    *loc = Location::Label(to)
+                }
+                _ => unreachable!(),
+            }
+            retargeted = true;
+        }
+    }
+
+    let mut i = 0;
+    while i < insts.len() {
+        let to_next = match jump_target(&insts[i]) {
+            Some(l) => insts[i + 1..]
+                .iter()
+                .take_while(|inst| matches!(inst, Instruction::Label(_)))
+                .any(|inst| matches!(inst, Instruction::Label(next) if next == l)),
+            None => false,
+        };
+        if to_next {
+            insts.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    retargeted || insts.len() != before
+}
+
+/// Nothing after an unconditional jump or `ret` runs until the next label.
+fn remove_unreachable(insts: &mut Vec<Instruction>) -> bool {
+    let before = insts.len();
+    let mut reachable = true;
+    insts.retain(|inst| {
+        match inst {
+            // Directives like `.global` start the next function
+            Instruction::Label(_) | Instruction::Meta(_) => reachable = true,
+            _ if !reachable => return false,
+            _ => {}
+        }
+        if matches!(inst, Instruction::Jmp(_) | Instruction::JmpTable { .. } | Instruction::Ret) {
+            reachable = false;
+        }
+        true
+    });
+    insts.len() != before
+}
+
+/// Remove each local label `.name` that is never jumped to.
+fn remove_dead_labels(insts: &mut Vec<Instruction>, tables: &[&str]) -> bool {
+    let mut used = tables.iter().map(|l| l.to_string()).collect::<HashSet<_>>();
+    let mut text = String::new();
+    for inst in insts.iter() {
+        match inst {
+            Instruction::Jmp(Location::Label(l))
+            | Instruction::CondJmp { loc: Location::Label(l), .. }
+            | Instruction::Call { loc: Location::Label(l), .. } => {
+                used.insert(l.clone());
+            }
+            Instruction::Meta(meta) => text.push_str(meta),
+            _ => {}
+        }
+    }
+
+    let before = insts.len();
+    insts.retain(|inst| match inst {
+        Instruction::Label(l) => {
+            !l.starts_with('.') || used.contains(l) || text.contains(l.as_str())
+        }
+        _ => true,
+    });
+    insts.len() != before
+}
+
+fn label_indices(insts: &[Instruction]) -> HashMap<String, usize> {
+    insts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, inst)| match inst {
+            Instruction::Label(l) => Some((l.clone(), i)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The label a `jmp` or `jcc` goes to.
+fn jump_target(inst: &Instruction) -> Option<&str> {
+    match inst {
+        Instruction::Jmp(Location::Label(l))
+        | Instruction::CondJmp { loc: Location::Label(l), .. } => Some(l),
+        _ => None,
+    }
+}
+
+/// The label of the `jmp` that is the first instruction after the label at `at`.
+fn jump_after(insts: &[Instruction], at: usize) -> Option<String> {
+    match insts[at..].iter().find(|inst| !matches!(inst, Instruction::Label(_)))? {
+        Instruction::Jmp(Location::Label(l)) => Some(l.clone()),
+        _ => None,
+    }
+}
+
+/// The source and destination of a plain 8 byte move.
+fn mov_operands(inst: &Instruction) -> Option<(&Location, &Location)> {
+    match inst {
+        Instruction::Mov { src, dst, .. }
+        | Instruction::FloatMov { src, dst }
+        | Instruction::SizedMov { src, dst, size: 8 } => Some((src, dst)),
+        _ => None,
+    }
+}
+
+/// `movsd` and `mov` can not replace each other.
+fn same_kind(a: &Instruction, b: &Instruction) -> bool {
+    matches!(a, Instruction::FloatMov { .. }) == matches!(b, Instruction::FloatMov { .. })
+}
+
+/// The address of `loc` is computed from the register `of`.
+fn depends_on(loc: &Location, of: &Location) -> bool {
+    match of {
+        Location::Register(reg) => loc.is_stack_offset() && uses_reg(loc, *reg),
+        _ => false,
+    }
+}
+
+/// `loc` is `reg` or addressed from it.
+fn uses_reg(loc: &Location, reg: Register) -> bool {
+    match loc {
+        Location::Register(r)
+        | Location::NumberedOffset { reg: r, .. }
+        | Location::ArgOffset { reg: r, .. }
+        | Location::Indexable { reg: r, .. }
+        | Location::RegAddr { reg: r, .. } => *r == reg,
+        _ => false,
+    }
+}
+
+#[test]
+fn forward_stores_rules() {
+    let rax = Location::Register(Register::RAX);
+    let rcx = Location::Register(Register::RCX);
+    let slot = Location::NumberedOffset { offset: 8, reg: Register::RBP };
+    let store = Instruction::Mov { src: rax.clone(), dst: slot.clone(), comment: "" };
+    let load = Instruction::Mov { src: slot.clone(), dst: rcx.clone(), comment: "" };
+
+    let mut insts = vec![store.clone(), load.clone()];
+    assert!(forward_stores(&mut insts));
+    assert!(matches!(&insts[1], Instruction::Mov { src, .. } if *src == rax));
+
+    // Another path can reach the load through a label and a call can change the slot
+    let call = Instruction::Call {
+        loc: Location::Label("f".to_owned()),
+        args: 0,
+        float_args: 0,
+        stack: 0,
+    };
+    for between in [Instruction::Label(".l".to_owned()), call] {
+        let mut insts = vec![store.clone(), between, load.clone()];
+        assert!(!forward_stores(&mut insts));
+        assert!(matches!(&insts[2], Instruction::Mov { src, .. } if *src == slot));
+    }
+
+    let mut insts = vec![
+        Instruction::Push { loc: rax.clone(), size: 8, comment: "" },
+        Instruction::Pop { loc: rcx, size: 8, comment: "" },
+    ];
+    assert!(forward_stores(&mut insts));
+    assert!(matches!(&insts[..], [Instruction::SizedMov { src, .. }] if *src == rax));
+
+    // A `mov` can only address memory once
+    let other = Location::NumberedOffset { offset: 16, reg: Register::RBP };
+    let mut insts = vec![
+        Instruction::Push { loc: slot, size: 8, comment: "" },
+        Instruction::Pop { loc: other, size: 8, comment: "" },
+    ];
+    assert!(!forward_stores(&mut insts));
+    assert_eq!(insts.len(), 2);
+}
+
+#[test]
+fn remove_moves_rules() {
+    let rax = Location::Register(Register::RAX);
+    let rcx = Location::Register(Register::RCX);
+    let mov = |src: &Location, dst: &Location| Instruction::Mov {
+        src: src.clone(),
+        dst: dst.clone(),
+        comment: "",
+    };
+
+    let mut insts = vec![mov(&rax, &rax), mov(&rax, &rcx), mov(&rcx, &rax)];
+    assert!(remove_moves(&mut insts));
+    assert_eq!(insts.len(), 1);
+
+    // The first move is overwritten before it is read
+    let one = Location::Const { val: crate::lir::lower::Val::Int(1) };
+    let mut insts = vec![mov(&one, &rax), mov(&rcx, &rax)];
+    assert!(remove_moves(&mut insts));
+    assert!(matches!(&insts[..], [Instruction::Mov { src, .. }] if *src == rcx));
+
+    // The second move reads what the first wrote
+    let field = Location::NumberedOffset { offset: 8, reg: Register::RAX };
+    let mut insts = vec![mov(&one, &rax), mov(&field, &rax)];
+    assert!(!remove_moves(&mut insts));
+    assert_eq!(insts.len(), 2);
+
+    // Moving back through the register the address came from is not a no-op
+    let mut insts = vec![mov(&field, &rax), mov(&rax, &field)];
+    assert!(!remove_moves(&mut insts));
+    assert_eq!(insts.len(), 2);
+}
+
+#[test]
+fn thread_jumps_rules() {
+    let label = |l: &str| Instruction::Label(l.to_owned());
+    let jmp = |l: &str| Instruction::Jmp(Location::Label(l.to_owned()));
+    let ret = || Instruction::Ret;
+
+    let mut insts = vec![jmp(".a"), ret(), label(".a"), jmp(".b"), ret(), label(".b"), ret()];
+    assert!(thread_jumps(&mut insts));
+    assert!(matches!(&insts[0], Instruction::Jmp(Location::Label(l)) if l == ".b"));
+
+    // A jump to the next instruction does nothing
+    let mut insts = vec![jmp(".a"), label(".a"), ret()];
+    assert!(thread_jumps(&mut insts));
+    assert!(matches!(&insts[..], [Instruction::Label(_), Instruction::Ret]));
+
+    // A jump to itself has no last label to thread to
+    let mut insts = vec![label(".a"), jmp(".a"), ret()];
+    assert!(!thread_jumps(&mut insts));
+    assert_eq!(insts.len(), 3);
+
+    // A label that does more than jump is not skipped
+    let mut insts = vec![jmp(".a"), ret(), label(".a"), Instruction::Leave, jmp(".b"), label(".b")];
+    assert!(thread_jumps(&mut insts));
+    assert!(matches!(&insts[0], Instruction::Jmp(Location::Label(l)) if l == ".a"));
+}
+
+#[test]
+fn remove_unreachable_rules() {
+    let rax = Location::Register(Register::RAX);
+    let pop = || Instruction::Pop { loc: rax.clone(), size: 8, comment: "" };
+
+    let mut insts = vec![Instruction::Ret, pop(), Instruction::Label(".a".to_owned()), pop()];
+    assert!(remove_unreachable(&mut insts));
+    assert!(matches!(
+        &insts[..],
+        [Instruction::Ret, Instruction::Label(_), Instruction::Pop { .. }]
+    ));
+
+    // A conditional jump falls through and a directive starts the next function
+    let cond = Instruction::CondJmp {
+        loc: Location::Label(".a".to_owned()),
+        cond: crate::gen::asm::inst::JmpCond::Eq,
+    };
+    let meta = Instruction::Meta(".global f".to_owned());
+    let mut insts = vec![cond, pop(), Instruction::Ret, meta, pop()];
+    assert!(!remove_unreachable(&mut insts));
+    assert_eq!(insts.len(), 5);
+}
+
+#[test]
+fn remove_dead_labels_rules() {
+    let label = |l: &str| Instruction::Label(l.to_owned());
+    let mut insts = vec![
+        label("main"),
+        label(".dead"),
+        Instruction::Jmp(Location::Label(".jumped".to_owned())),
+        label(".jumped"),
+        label(".table"),
+        Instruction::Meta("    jmp .in_asm".to_owned()),
+        label(".in_asm"),
+    ];
+    assert!(remove_dead_labels(&mut insts, &[".table"]));
+    assert_eq!(insts.len(), 6);
+    assert!(!insts.iter().any(|inst| matches!(inst, Instruction::Label(l) if l == ".dead")));
+
+    // Every label left is used
+    assert!(!remove_dead_labels(&mut insts, &[".table"]));
+}
@@ -0,0 +1,717 @@
+//! Linear-scan register allocation for the variables of a function.
+//!
+//! `CodeGen` keeps every variable in a stack slot and borrows registers for the values of each
+//! expression, once a function is generated the slots of 8 byte scalars are moved to registers
+//! the rest of the function leaves free. Liveness is found for the slots and the registers
+//! `CodeGen` used together, the registers it used are fixed and a slot only gets a register that
+//! is not live anywhere in the slot's interval. A `call` clobbers the caller saved registers so a
+//! slot live across one can only get a callee saved register, those are saved by the function.
+
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
+
+use crate::{
+    ast::mnemonic::Mnemonic,
+    gen::asm::{
+        inst::{
+            FloatRegister::{self, *},
+            Instruction, Location,
+            Register::{self, *},
+            ARG_FLOAT_REGS, ARG_REGS, USABLE_FLOAT_REGS, USABLE_REGS,
+        },
+        RBP, RSP, STACK_TOP,
+    },
+    lir::lower::{BinOp, Val},
+};
+
+/// The return value registers come first.
+const CALLER_SAVED: [Register; 9] = [RAX, RDX, RCX, RSI, RDI, R8, R9, R10, R11];
+crate const CALLEE_SAVED: [Register; 5] = [RBX, R12, R13, R14, R15];
+
+const EIGHT: Location = Location::Const { val: Val::Int(8) };
+
+/// An 8 byte scalar variable on the stack, the only kind of variable that can be moved to a
+/// register.
+#[derive(Clone, Copy, Debug)]
+crate struct StackSlot {
+    /// How far below `%rbp` the variable is.
+    crate offset: usize,
+    /// The index of the `push` that allocates the variable.
+    crate alloc: usize,
+}
+
+/// The registers an `asm` block names in its `clobber(..)` list, the block's text does not have
+/// to name them.
+#[derive(Clone, Debug)]
+crate struct AsmClobbers {
+    /// The index of the first instruction of the block.
+    crate at: usize,
+    crate regs: Vec<Register>,
+    crate float_regs: Vec<FloatRegister>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Var {
+    Reg(Register),
+    Float(FloatRegister),
+    /// The stack slot at this offset from `%rbp`.
+    Slot(usize),
+}
+
+/// The register file that can replace a stack slot used as an operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Class {
+    Int,
+    Float,
+    /// `movq` moves between any two registers.
+    Either,
+    /// The operand has to stay in memory, the slot is never moved to a register.
+    Memory,
+}
+
+/// What an instruction reads and writes.
+#[derive(Debug, Default)]
+struct Effects {
+    uses: Vec<Var>,
+    defs: Vec<Var>,
+    /// Each offset from `%rbp` the instruction touches and how it uses it.
+    slots: Vec<(usize, Class)>,
+}
+
+impl Effects {
+    fn read(&mut self, loc: &Location, class: Class) {
+        match loc {
+            Location::Register(reg) => self.uses.push(Var::Reg(*reg)),
+            Location::FloatReg(reg) => self.uses.push(Var::Float(*reg)),
+            Location::NumberedOffset { offset, reg: Register::RBP } => {
+                self.uses.push(Var::Slot(*offset));
+                self.slots.push((*offset, class));
+            }
+            loc => self.address(loc),
+        }
+    }
+
+    fn write(&mut self, loc: &Location, class: Class) {
+        match loc {
+            Location::Register(reg) => self.defs.push(Var::Reg(*reg)),
+            Location::FloatReg(reg) => self.defs.push(Var::Float(*reg)),
+            Location::NumberedOffset { offset, reg: Register::RBP } => {
+                self.defs.push(Var::Slot(*offset));
+                self.slots.push((*offset, class));
+            }
+            loc => self.address(loc),
+        }
+    }
+
+    fn read_write(&mut self, loc: &Location, class: Class) {
+        self.read(loc, class);
+        self.write(loc, class);
+    }
+
+    /// The register a memory operand is addressed from is read even when the operand is written.
+    fn address(&mut self, loc: &Location) {
+        match loc {
+            Location::Indexable { ele_pos, reg: Register::RBP, .. } => {
+                self.slots.push((*ele_pos, Class::Memory));
+            }
+            Location::NumberedOffset { reg, .. }
+            | Location::ArgOffset { reg, .. }
+            | Location::Indexable { reg, .. }
+            | Location::RegAddr { reg, .. } => self.uses.push(Var::Reg(*reg)),
+            _ => {}
+        }
+    }
+}
+
+/// Move the variables in `slots` of the function `body` to registers.
+///
+/// The registers a `call` would clobber that are still used after it are saved around it, the
+/// callee saved registers the function writes are saved in it's prologue. Each `asm` block
+/// writes the registers in its `clobbers`.
+crate fn allocate(
+    body: Vec<Instruction>,
+    slots: &[StackSlot],
+    clobbers: &[AsmClobbers],
+) -> Vec<Instruction> {
+    let labels = body
+        .iter()
+        .enumerate()
+        .filter_map(|(i, inst)| match inst {
+            Instruction::Label(label) => Some((label.as_str(), i)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+    let succs = (0..body.len()).map(|i| successors(&body, &labels, i)).collect::<Vec<_>>();
+    let allocs = slots.iter().map(|s| (s.alloc, s.offset)).collect::<HashMap<_, _>>();
+
+    let mut effects = body.iter().map(effects).collect::<Vec<_>>();
+    for (idx, offset) in &allocs {
+        effects[*idx].defs.push(Var::Slot(*offset));
+    }
+    for asm in clobbers {
+        let fx = &mut effects[asm.at];
+        fx.defs.extend(asm.regs.iter().map(|r| Var::Reg(*r)));
+        fx.defs.extend(asm.float_regs.iter().map(|r| Var::Float(*r)));
+    }
+    let candidates = candidates(slots, &effects);
+    for fx in &mut effects {
+        fx.uses.retain(|var| tracked(var, &candidates));
+        fx.defs.retain(|var| tracked(var, &candidates));
+    }
+
+    // A value `CodeGen` left in a caller saved register that is used after a call has to live
+    // through the call
+    let (live_in, live_out) = liveness(&effects, &succs);
+    let mut preserved = HashMap::default();
+    for (i, inst) in body.iter().enumerate() {
+        if !matches!(inst, Instruction::Call { .. }) {
+            continue;
+        }
+        // The return value is in `%rax` and `%rdx` or `%xmm0` and `%xmm1`
+        let keep = CALLER_SAVED[2..]
+            .iter()
+            .map(|r| Var::Reg(*r))
+            .chain(USABLE_FLOAT_REGS[2..].iter().map(|r| Var::Float(*r)))
+            .filter(|var| live_out[i].contains(var))
+            .collect::<Vec<_>>();
+        if !keep.is_empty() {
+            effects[i].defs.retain(|var| !keep.contains(var));
+            preserved.insert(i, keep);
+        }
+    }
+    let (live_in, live_out) =
+        if preserved.is_empty() { (live_in, live_out) } else { liveness(&effects, &succs) };
+
+    let mut intervals = candidates
+        .iter()
+        .filter_map(|(offset, class)| {
+            let var = Var::Slot(*offset);
+            let mut live = (0..body.len()).filter(|i| {
+                live_in[*i].contains(&var)
+                    || live_out[*i].contains(&var)
+                    || effects[*i].defs.contains(&var)
+            });
+            let start = live.next()?;
+            let end = live.next_back().unwrap_or(start);
+            Some(Interval { offset: *offset, start, end, class: *class })
+        })
+        .collect::<Vec<_>>();
+    intervals.sort_by_key(|iv| (iv.start, iv.offset));
+
+    let fixed = |var: &Var, iv: &Interval| {
+        (iv.start..=iv.end).any(|i| {
+            live_in[i].contains(var) || live_out[i].contains(var) || effects[i].defs.contains(var)
+        })
+    };
+
+    let mut assigned = HashMap::default();
+    let mut active: Vec<(usize, Var, usize)> = vec![];
+    for iv in &intervals {
+        active.retain(|(end, ..)| *end >= iv.start);
+
+        let regs = match iv.class {
+            Class::Float => USABLE_FLOAT_REGS.iter().map(|r| Var::Float(*r)).collect::<Vec<_>>(),
+            _ => USABLE_REGS.iter().map(|r| Var::Reg(*r)).collect(),
+        }
+        .into_iter()
+        .filter(|reg| !fixed(reg, iv))
+        .collect::<Vec<_>>();
+
+        if let Some(reg) = regs.iter().find(|reg| !active.iter().any(|(_, r, _)| r == *reg)) {
+            assigned.insert(iv.offset, *reg);
+            active.push((iv.end, *reg, iv.offset));
+            continue;
+        }
+        // Out of registers, the slot that is live the longest stays on the stack
+        let furthest = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, reg, _))| regs.contains(reg))
+            .max_by_key(|(_, (end, ..))| *end)
+            .map(|(pos, _)| pos);
+        if let Some(pos) = furthest.filter(|pos| active[*pos].0 > iv.end) {
+            let (_, reg, spilled) = active.remove(pos);
+            assigned.remove(&spilled);
+            assigned.insert(iv.offset, reg);
+            active.push((iv.end, reg, iv.offset));
+        }
+    }
+
+    let mut out = Vec::with_capacity(body.len());
+    for (i, mut inst) in body.into_iter().enumerate() {
+        // The address of the slot is still on the stack
+        let lea = matches!(inst, Instruction::Load { .. });
+        for loc in locations_mut(&mut inst).into_iter().filter(|_| !lea) {
+            if let Location::NumberedOffset { offset, reg: Register::RBP } = loc {
+                match assigned.get(offset) {
+                    Some(Var::Reg(reg)) => *loc = Location::Register(*reg),
+                    Some(Var::Float(reg)) => *loc = Location::FloatReg(*reg),
+                    _ => {}
+                }
+            }
+        }
+        if is_self_move(&inst) {
+            continue;
+        }
+
+        // An argument is pushed from the register it was passed in
+        let pushed = match &inst {
+            Instruction::Push { loc: reg @ Location::Register(_), .. } => Some(reg.clone()),
+            _ => None,
+        };
+        let saved = preserved.get(&i).map_or(&[][..], |regs| regs.as_slice());
+        let stack = if let Instruction::Call { stack, .. } = &inst { *stack } else { 0 };
+        out.extend(save_around_call(saved, stack, true));
+        out.push(inst);
+        out.extend(save_around_call(saved, stack, false));
+
+        // The variable was initialized by the `push` that allocated it
+        if let Some(offset) = allocs.get(&i) {
+            let mem = Location::NumberedOffset { offset: *offset, reg: Register::RBP };
+            match assigned.get(offset) {
+                Some(Var::Reg(reg)) if live_out[i].contains(&Var::Slot(*offset)) => {
+                    out.push(Instruction::Mov {
+                        src: pushed.unwrap_or(mem),
+                        dst: Location::Register(*reg),
+                        comment: "load variable to register",
+                    });
+                }
+                Some(Var::Float(reg)) if live_out[i].contains(&Var::Slot(*offset)) => {
+                    out.push(Instruction::FloatMov { src: mem, dst: Location::FloatReg(*reg) });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    save_callee_saved(out)
+}
+
+/// A stack slot and the instructions it is live for.
+#[derive(Debug)]
+struct Interval {
+    offset: usize,
+    start: usize,
+    end: usize,
+    class: Class,
+}
+
+/// The slots that can be moved to a register and the register file they go in.
+///
+/// A slot whose address is taken, that is written in an `asm` block or that is only partly
+/// read or written stays on the stack.
+fn candidates(slots: &[StackSlot], effects: &[Effects]) -> Vec<(usize, Class)> {
+    slots
+        .iter()
+        .map(|s| s.offset)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .filter_map(|offset| {
+            let mut used = false;
+            let mut int = true;
+            let mut float = true;
+            for (at, class) in effects.iter().flat_map(|fx| &fx.slots) {
+                if *at != offset {
+                    if (*at as isize - offset as isize).abs() < 8 {
+                        return None;
+                    }
+                    continue;
+                }
+                used = true;
+                match class {
+                    Class::Int => float = false,
+                    Class::Float => int = false,
+                    Class::Either => {}
+                    Class::Memory => return None,
+                }
+            }
+            match (used, int, float) {
+                (true, true, _) => Some((offset, Class::Int)),
+                (true, false, true) => Some((offset, Class::Float)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Registers are always tracked, slots only when they can be moved to a register.
+fn tracked(var: &Var, candidates: &[(usize, Class)]) -> bool {
+    match var {
+        Var::Reg(Register::RSP | Register::RBP) => false,
+        Var::Slot(offset) => candidates.iter().any(|(at, _)| at == offset),
+        _ => true,
+    }
+}
+
+/// The vars live into and out of each instruction.
+fn liveness(effects: &[Effects], succs: &[Vec<usize>]) -> (Vec<HashSet<Var>>, Vec<HashSet<Var>>) {
+    let mut live_in = vec![HashSet::default(); effects.len()];
+    let mut live_out = vec![HashSet::<Var>::default(); effects.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..effects.len()).rev() {
+            let out = succs[i]
+                .iter()
+                .flat_map(|s| live_in[*s].iter().copied())
+                .collect::<HashSet<_>>();
+            let mut inn = out
+                .iter()
+                .filter(|var| !effects[i].defs.contains(var))
+                .copied()
+                .collect::<HashSet<_>>();
+            inn.extend(effects[i].uses.iter().copied());
+            if out != live_out[i] || inn != live_in[i] {
+                changed = true;
+                live_out[i] = out;
+                live_in[i] = inn;
+            }
+        }
+    }
+    (live_in, live_out)
+}
+
+/// The instructions that can run after `body[i]`.
+fn successors(body: &[Instruction], labels: &HashMap<&str, usize>, i: usize) -> Vec<usize> {
+    let next = Some(i + 1).filter(|n| *n < body.len());
+    match &body[i] {
+        // A label outside of the function is a trap or `.bounds_fail`, neither return
+        Instruction::Jmp(Location::Label(label)) => {
+            labels.get(label.as_str()).copied().into_iter().collect()
+        }
+        Instruction::CondJmp { loc: Location::Label(label), .. } => {
+            labels.get(label.as_str()).copied().into_iter().chain(next).collect()
+        }
+        // A jump table can go to any label
+        Instruction::Jmp(_) | Instruction::JmpTable { .. } => labels.values().copied().collect(),
+        Instruction::Ret => vec![],
+        Instruction::Meta(text) if text.trim() == "ud2" => vec![],
+        _ => next.into_iter().collect(),
+    }
+}
+
+/// The registers and slots `inst` reads and writes.
+fn effects(inst: &Instruction) -> Effects {
+    let mut fx = Effects::default();
+    let sized = |size: usize| if size == 8 { Class::Int } else { Class::Memory };
+    match inst {
+        Instruction::Label(_) | Instruction::Leave => {}
+        Instruction::Meta(text) => meta_effects(text, &mut fx),
+        Instruction::Push { loc, size, .. } => fx.read(loc, sized(*size)),
+        Instruction::Pop { loc, size, .. } => fx.write(loc, sized(*size)),
+        Instruction::Alloca { reg, .. } => fx.read_write(&Location::Register(*reg), Class::Int),
+        Instruction::Call { loc, args, float_args, .. } => {
+            // A call through a function pointer `call *%rax`
+            if let Location::Label(label) = loc {
+                if let Some(reg) = USABLE_REGS.iter().find(|r| label == &format!("*{}", r)) {
+                    fx.uses.push(Var::Reg(*reg));
+                }
+            }
+            // `%rax` tells a variadic function if any argument is a float
+            let int_args = ARG_REGS.iter().take(*args).chain(&[RAX]);
+            fx.uses.extend(int_args.map(|r| Var::Reg(*r)));
+            fx.uses.extend(ARG_FLOAT_REGS.iter().take(*float_args).map(|r| Var::Float(*r)));
+            fx.defs.extend(CALLER_SAVED.iter().map(|r| Var::Reg(*r)));
+            fx.defs.extend(USABLE_FLOAT_REGS.iter().map(|r| Var::Float(*r)));
+        }
+        Instruction::Jmp(loc) | Instruction::CondJmp { loc, .. } => fx.read(loc, Class::Memory),
+        Instruction::JmpTable { table, idx } => {
+            fx.read(&Location::Register(*table), Class::Int);
+            fx.read_write(&Location::Register(*idx), Class::Int);
+        }
+        // The return value is in `%rax` and `%rdx` or `%xmm0` and `%xmm1`
+        Instruction::Ret => {
+            fx.uses.extend(&[Var::Reg(RAX), Var::Reg(RDX), Var::Float(XMM0), Var::Float(XMM1)])
+        }
+        Instruction::Cmp { src, dst } => {
+            fx.read(src, Class::Int);
+            fx.read(dst, Class::Int);
+        }
+        Instruction::Mov { src, dst, .. } | Instruction::MovExt { src, dst, .. } => {
+            fx.read(src, Class::Int);
+            fx.write(dst, Class::Int);
+        }
+        Instruction::CondMov { src, dst, .. } => {
+            fx.read(src, Class::Int);
+            fx.read_write(dst, Class::Int);
+        }
+        Instruction::FloatMov { src, dst } | Instruction::Cvt { src, dst } => {
+            fx.read(src, Class::Float);
+            fx.write(dst, Class::Float);
+        }
+        Instruction::SizedMov { src, dst, size } => {
+            let class = match (size, src) {
+                (8, Location::Const { .. }) => Class::Int,
+                (8, _) => Class::Either,
+                _ => Class::Memory,
+            };
+            fx.read(src, class);
+            fx.write(dst, class);
+        }
+        // `lea` only reads the address but whatever it is stored to can read the slot
+        Instruction::Load { src, dst, .. } => {
+            // Moving `%rsp` below the variables of a branch
+            if dst != &RSP {
+                fx.read(src, Class::Memory);
+            }
+            fx.write(dst, Class::Int);
+        }
+        Instruction::Math { src, dst, .. } | Instruction::Shift { src, dst, .. } => {
+            fx.read(src, Class::Int);
+            fx.read_write(dst, Class::Int);
+        }
+        Instruction::FloatMath { src, dst, .. } => {
+            fx.read(src, Class::Float);
+            fx.read_write(dst, Class::Float);
+        }
+        Instruction::Idiv(loc) | Instruction::Div(loc) | Instruction::Mul(loc) => {
+            fx.read(loc, Class::Int);
+            fx.uses.extend(&[Var::Reg(RAX), Var::Reg(RDX)]);
+            fx.defs.extend(&[Var::Reg(RAX), Var::Reg(RDX)]);
+        }
+        Instruction::Extend => {
+            fx.uses.push(Var::Reg(RAX));
+            fx.defs.push(Var::Reg(RDX));
+        }
+        Instruction::IntToFloat { src, dst } => {
+            fx.read(src, Class::Int);
+            fx.write(dst, Class::Float);
+        }
+        Instruction::FloatToInt { src, dst } => {
+            fx.read(src, Class::Float);
+            fx.write(dst, Class::Int);
+        }
+    }
+    fx
+}
+
+/// The text of an `asm` block is searched for the registers it names, a variable used as an
+/// operand `(x)` is written as it's stack slot `-8(%rbp)`. The registers an instruction uses
+/// without naming them are found in the mnemonic table.
+fn meta_effects(text: &str, fx: &mut Effects) {
+    for reg in &USABLE_REGS {
+        if [1, 2, 4, 8].iter().any(|size| text.contains(reg.sized(*size))) {
+            fx.uses.push(Var::Reg(*reg));
+            fx.defs.push(Var::Reg(*reg));
+        }
+    }
+    for reg in &USABLE_FLOAT_REGS {
+        if text.contains(&reg.to_string()) {
+            fx.uses.push(Var::Float(*reg));
+            fx.defs.push(Var::Float(*reg));
+        }
+    }
+    let mut words = text.trim().splitn(2, char::is_whitespace);
+    if let Some(mnemonic) = words.next().and_then(Mnemonic::lookup) {
+        let operands = words.next().map_or(0, |ops| ops.split(',').count());
+        for name in mnemonic.implicit(operands) {
+            let reg = USABLE_REGS
+                .iter()
+                .find(|reg| reg.sized(8) == *name)
+                .expect("implicit registers are general purpose registers");
+            fx.uses.push(Var::Reg(*reg));
+            fx.defs.push(Var::Reg(*reg));
+        }
+    }
+    if text.contains("syscall") {
+        fx.uses.extend([RAX, RDI, RSI, RDX, R10, R8, R9].iter().map(|r| Var::Reg(*r)));
+        fx.defs.extend([RAX, RCX, R11].iter().map(|r| Var::Reg(*r)));
+    }
+    for (idx, _) in text.match_indices("(%rbp)") {
+        let head = &text[..idx];
+        let rest = head.trim_end_matches(|c: char| c.is_ascii_digit());
+        if let (true, Ok(offset)) = (rest.ends_with('-'), head[rest.len()..].parse()) {
+            fx.slots.push((offset, Class::Memory));
+        }
+    }
+}
+
+/// Every location operand of `inst`.
+fn locations_mut(inst: &mut Instruction) -> Vec<&mut Location> {
+    match inst {
+        Instruction::Push { loc, .. }
+        | Instruction::Pop { loc, .. }
+        | Instruction::Call { loc, .. }
+        | Instruction::Jmp(loc)
+        | Instruction::CondJmp { loc, .. }
+        | Instruction::Idiv(loc)
+        | Instruction::Div(loc)
+        | Instruction::Mul(loc) => vec![loc],
+        Instruction::Mov { src, dst, .. }
+        | Instruction::CondMov { src, dst, .. }
+        | Instruction::FloatMov { src, dst }
+        | Instruction::SizedMov { src, dst, .. }
+        | Instruction::MovExt { src, dst, .. }
+        | Instruction::Load { src, dst, .. }
+        | Instruction::Math { src, dst, .. }
+        | Instruction::FloatMath { src, dst, .. }
+        | Instruction::Shift { src, dst, .. }
+        | Instruction::Cvt { src, dst }
+        | Instruction::IntToFloat { src, dst }
+        | Instruction::FloatToInt { src, dst }
+        | Instruction::Cmp { src, dst } => vec![src, dst],
+        Instruction::Label(_)
+        | Instruction::Meta(_)
+        | Instruction::Alloca { .. }
+        | Instruction::JmpTable { .. }
+        | Instruction::Leave
+        | Instruction::Ret
+        | Instruction::Extend => vec![],
+    }
+}
+
+/// A move from a register to itself, left behind when both sides got the same register.
+fn is_self_move(inst: &Instruction) -> bool {
+    match inst {
+        Instruction::Mov { src, dst, .. }
+        | Instruction::FloatMov { src, dst }
+        | Instruction::SizedMov { src, dst, size: 8 } => {
+            src == dst && matches!(src, Location::Register(_) | Location::FloatReg(_))
+        }
+        _ => false,
+    }
+}
+
+/// Push the registers in `regs` before a call or pop them after it, an odd number of registers
+/// is padded so the stack stays 16 byte aligned.
+///
+/// The `stack` bytes of arguments the call takes on the stack are pushed again below the saved
+/// registers so they are still right above the return address.
+fn save_around_call(regs: &[Var], stack: usize, before: bool) -> Vec<Instruction> {
+    if regs.is_empty() {
+        return vec![];
+    }
+    let pad = regs.len() % 2 == 1;
+    let saved = (regs.len() + pad as usize) * 8;
+    let mut insts = vec![];
+    if before {
+        if pad {
+            insts.push(Instruction::Math { src: EIGHT, dst: RSP, op: BinOp::Sub, cmt: "align" });
+        }
+        for var in regs {
+            match var {
+                Var::Reg(reg) => insts.push(Instruction::Push {
+                    loc: Location::Register(*reg),
+                    size: 8,
+                    comment: "save register across call",
+                }),
+                Var::Float(reg) => insts.extend_from_slice(&[
+                    Instruction::Math {
+                        src: EIGHT,
+                        dst: RSP,
+                        op: BinOp::Sub,
+                        cmt: "save float register across call",
+                    },
+                    Instruction::FloatMov { src: Location::FloatReg(*reg), dst: STACK_TOP },
+                ]),
+                Var::Slot(_) => unreachable!("only registers are saved"),
+            }
+        }
+        // Each push moves the next argument down to the same offset
+        for _ in 0..stack / 8 {
+            insts.push(Instruction::Push {
+                loc: Location::ArgOffset { offset: saved + stack - 8, reg: Register::RSP },
+                size: 8,
+                comment: "copy stack argument",
+            });
+        }
+    } else {
+        if stack > 0 {
+            insts.push(Instruction::Math {
+                src: Location::Const { val: Val::Int(stack as isize) },
+                dst: RSP,
+                op: BinOp::Add,
+                cmt: "remove copied stack arguments",
+            });
+        }
+        for var in regs.iter().rev() {
+            match var {
+                Var::Reg(reg) => insts.push(Instruction::Pop {
+                    loc: Location::Register(*reg),
+                    size: 8,
+                    comment: "restore register after call",
+                }),
+                Var::Float(reg) => insts.extend_from_slice(&[
+                    Instruction::FloatMov { src: STACK_TOP, dst: Location::FloatReg(*reg) },
+                    Instruction::Math {
+                        src: EIGHT,
+                        dst: RSP,
+                        op: BinOp::Add,
+                        cmt: "restore float register after call",
+                    },
+                ]),
+                Var::Slot(_) => unreachable!("only registers are saved"),
+            }
+        }
+        if pad {
+            insts.push(Instruction::Math { src: EIGHT, dst: RSP, op: BinOp::Add, cmt: "align" });
+        }
+    }
+    insts
+}
+
+/// Save the callee saved registers the function uses before the frame is made and restore them
+/// after each `leave`.
+fn save_callee_saved(body: Vec<Instruction>) -> Vec<Instruction> {
+    let mut used = HashSet::default();
+    for inst in &body {
+        let fx = effects(inst);
+        used.extend(fx.uses.into_iter().chain(fx.defs));
+    }
+    let saved = CALLEE_SAVED.iter().filter(|r| used.contains(&Var::Reg(**r))).collect::<Vec<_>>();
+    if saved.is_empty() {
+        return body;
+    }
+    let pad = saved.len() % 2 == 1;
+    let pushed = (saved.len() + pad as usize) * 8;
+
+    let mut out = Vec::with_capacity(body.len() + saved.len() * 2);
+    for mut inst in body {
+        // The arguments on the stack are further from `%rbp` by what was pushed before it
+        for loc in locations_mut(&mut inst) {
+            if let Location::ArgOffset { offset, reg: Register::RBP } = loc {
+                *offset += pushed;
+            }
+        }
+        match inst {
+            // The frame pointer is the first push of a function
+            Instruction::Push { loc: RBP, .. } if !out.iter().any(is_frame_push) => {
+                out.extend(saved.iter().map(|reg| Instruction::Push {
+                    loc: Location::Register(**reg),
+                    size: 8,
+                    comment: "save callee saved register",
+                }));
+                if pad {
+                    out.push(Instruction::Math {
+                        src: EIGHT,
+                        dst: RSP,
+                        op: BinOp::Sub,
+                        cmt: "align callee saved registers",
+                    });
+                }
+                out.push(inst);
+            }
+            Instruction::Leave => {
+                out.push(inst);
+                if pad {
+                    out.push(Instruction::Math {
+                        src: EIGHT,
+                        dst: RSP,
+                        op: BinOp::Add,
+                        cmt: "align callee saved registers",
+                    });
+                }
+                out.extend(saved.iter().rev().map(|reg| Instruction::Pop {
+                    loc: Location::Register(**reg),
+                    size: 8,
+                    comment: "restore callee saved register",
+                }));
+            }
+            inst => out.push(inst),
+        }
+    }
+    out
+}
+
+fn is_frame_push(inst: &Instruction) -> bool {
+    matches!(inst, Instruction::Push { loc: RBP, .. })
+}
@@ -1,9 +1,15 @@
 #![allow(dead_code)]
 
-use std::{collections::HashMap, convert::TryInto, path::Path, vec};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::{TryFrom, TryInto},
+    path::Path,
+    vec,
+};
 
 use either::Either;
 use inkwell::{
+    basic_block::BasicBlock,
     builder::Builder,
     context::Context,
     execution_engine::ExecutionEngine,
@@ -11,14 +17,17 @@ use inkwell::{
     passes::PassManager,
     targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine},
     types::{BasicType, BasicTypeEnum},
-    values::{BasicValue, BasicValueEnum, FunctionValue, PointerValue},
+    values::{BasicValue, BasicValueEnum, CallableValue, FunctionValue, IntValue, PointerValue},
     AddressSpace, IntPredicate, OptimizationLevel,
 };
 
 use crate::{
-    ast::parse::symbol::Ident,
+    ast::{parse::symbol::Ident, types as ty},
     lir::{
-        lower::{BinOp, CallExpr, Const, Expr, Func, LValue, Stmt, Ty, Val},
+        lower::{
+            AsmBlock, BinOp, Binding, Block, CallExpr, Const, Expr, Func, LValue, MatchArm, Pat,
+            SrcLoc, Stmt, Ty, Val,
+        },
         visit::Visit,
     },
 };
@@ -33,9 +42,11 @@ impl Ty {
                     false,
                 )
                 .into(),
-            Ty::Enum { ident, gen: _, def: _ } => context.opaque_struct_type(ident.name()).into(),
+            // The tag and items are placed by `Variant::item_offsets`, words keep it aligned
+            Ty::Enum { .. } => context.i64_type().array_type((self.size() / 8) as u32).into(),
             Ty::ConstStr(..) => context.i16_type().array_type(0).into(),
             Ty::Int => context.i64_type().into(),
+            Ty::SizedInt(ity) => context.custom_width_int_type(ity.size() as u32 * 8).into(),
             Ty::Char => context.i8_type().into(),
             Ty::Float => context.f64_type().into(),
             Ty::Bool => context.bool_type().into(),
@@ -55,11 +66,14 @@ impl Ty {
                 )
                 .const_zero()
                 .into(),
-            Ty::Enum { ident, gen: _, def: _ } => {
-                context.opaque_struct_type(ident.name()).const_zero().into()
+            Ty::Enum { .. } => {
+                context.i64_type().array_type((self.size() / 8) as u32).const_zero().into()
             }
             Ty::ConstStr(..) => context.i16_type().array_type(0).const_zero().into(),
             Ty::Int => context.i64_type().const_zero().into(),
+            Ty::SizedInt(ity) => {
+                context.custom_width_int_type(ity.size() as u32 * 8).const_zero().into()
+            }
             Ty::Char => context.i8_type().const_zero().into(),
             Ty::Float => context.f64_type().const_zero().into(),
             Ty::Bool => context.bool_type().const_zero().into(),
@@ -80,11 +94,21 @@ crate struct LLVMGen<'ctx> {
     pass: PassManager<FunctionValue<'ctx>>,
     machine: TargetMachine,
     vars: HashMap<Ident, BasicValueEnum<'ctx>>,
+    /// Check integer arithmetic for overflow, `--overflow-checks`.
+    overflow_checks: bool,
+    /// Check dynamic array indexes against the length of the array, `--unchecked-indexing`
+    /// turns this off.
+    bounds_checks: bool,
     path: &'ctx Path,
 }
 
 impl<'ctx> LLVMGen<'ctx> {
-    crate fn new(ctxt: &'ctx Context, path: &'ctx Path) -> LLVMGen<'ctx> {
+    crate fn new(
+        ctxt: &'ctx Context,
+        path: &'ctx Path,
+        overflow_checks: bool,
+        bounds_checks: bool,
+    ) -> LLVMGen<'ctx> {
         Target::initialize_native(&InitializationConfig::default())
             .expect("Failed to initialize native target");
 
@@ -125,6 +149,8 @@ impl<'ctx> LLVMGen<'ctx> {
             pass,
             machine,
             vars: HashMap::new(),
+            overflow_checks,
+            bounds_checks,
             path,
         };
 
@@ -186,6 +212,7 @@ impl<'ctx> LLVMGen<'ctx> {
             | Ty::Enum { .. }
             | Ty::ConstStr(..)
             | Ty::Int
+            | Ty::SizedInt(_)
             | Ty::Char
             | Ty::Float
             | Ty::Bool => {
@@ -199,16 +226,40 @@ impl<'ctx> LLVMGen<'ctx> {
         }
     }
 
+    /// Constant indexes are checked by typeck, a dynamic index into an array of length `len` is
+    /// checked at runtime unless `--unchecked-indexing` was passed.
     fn index_arr(
-        &self,
+        &mut self,
         arr_ptr: PointerValue<'ctx>,
         idx_exprs: &'ctx [Expr],
+        len: Option<usize>,
+        loc: &SrcLoc,
     ) -> Option<PointerValue<'ctx>> {
         let mut indexes = idx_exprs
             .iter()
             .map(|e| Some(self.build_value(e, None)?.into_int_value()))
             .collect::<Option<Vec<_>>>()?;
 
+        match len {
+            // Constant indexes were proven to be in bounds by typeck
+            Some(len) if self.bounds_checks && !matches!(idx_exprs[0], Expr::Value(_)) => {
+                let idx = indexes[0];
+                // A negative index is a huge unsigned number so it fails too
+                let out_of_bounds = self.builder.build_int_compare(
+                    IntPredicate::UGE,
+                    idx,
+                    idx.get_type().const_int(len as u64, false),
+                    "out_of_bounds",
+                );
+                let msg = format!(
+                    "{}: index out of bounds: the length is {} but the index is ",
+                    loc, len
+                );
+                self.build_trap_if(out_of_bounds, &msg, Some(idx));
+            }
+            _ => {}
+        }
+
         // Always index with i64 types
         //
         // This is to access the value as a pointer (we do `address + 0` first, llvm oddity
@@ -218,6 +269,139 @@ impl<'ctx> LLVMGen<'ctx> {
         unsafe { self.builder.build_in_bounds_gep(arr_ptr, &indexes, "lhs_arr_index").into() }
     }
 
+    /// Use the `llvm.*.with.overflow` intrinsic for `op`, when it overflows `loc` is written to
+    /// stderr and the program traps.
+    fn build_checked_int_op(
+        &self,
+        op: &BinOp,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+        signed: bool,
+        loc: &SrcLoc,
+    ) -> IntValue<'ctx> {
+        let int_ty = lhs.get_type();
+        let name = format!(
+            "llvm.{}{}.with.overflow.i{}",
+            if signed { "s" } else { "u" },
+            match op {
+                BinOp::Add => "add",
+                BinOp::Sub => "sub",
+                BinOp::Mul => "mul",
+                _ => unreachable!("ICE only add, sub and mul have overflow intrinsics"),
+            },
+            int_ty.get_bit_width()
+        );
+        let checked = self.module.get_function(&name).unwrap_or_else(|| {
+            let ret =
+                self.context.struct_type(&[int_ty.into(), self.context.bool_type().into()], false);
+            self.module.add_function(
+                &name,
+                ret.fn_type(&[int_ty.into(), int_ty.into()], false),
+                None,
+            )
+        });
+        let res = self
+            .builder
+            .build_call(checked, &[lhs.into(), rhs.into()], "checked")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_struct_value();
+        let val = self.builder.build_extract_value(res, 0, "value").unwrap().into_int_value();
+        let overflowed =
+            self.builder.build_extract_value(res, 1, "overflowed").unwrap().into_int_value();
+
+        let msg = format!("{}: attempt to {} with overflow\n", loc, op.overflow_verb());
+        self.build_trap_if(overflowed, &msg, None);
+        val
+    }
+
+    /// Branch to a block that writes `msg` to stderr and traps when `failed` is true, an
+    /// out of bounds `index` is written after the message.
+    fn build_trap_if(&self, failed: IntValue<'ctx>, msg: &str, index: Option<IntValue<'ctx>>) {
+        let fnval = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+        let trap_bb = self.context.append_basic_block(fnval, "trap");
+        let cont_bb = self.context.append_basic_block(fnval, "checked");
+        self.builder.build_conditional_branch(failed, trap_bb, cont_bb);
+
+        self.builder.position_at_end(trap_bb);
+        let stderr = self.context.i32_type().const_int(2, false).as_basic_value_enum();
+        let msg_ptr = self.builder.build_global_string_ptr(msg, "trap_msg").as_basic_value_enum();
+        if let Some(idx) = index {
+            let dprintf = self.module.get_function("dprintf").unwrap_or_else(|| {
+                self.linked_func(
+                    "dprintf",
+                    vec![
+                        self.context.i32_type().into(),
+                        self.context.i8_type().ptr_type(AddressSpace::Generic).into(),
+                    ],
+                    self.context.i32_type().into(),
+                )
+                .unwrap()
+            });
+            let fmt =
+                self.builder.build_global_string_ptr("%s%ld\n", "trap_fmt").as_basic_value_enum();
+            self.builder.build_call(
+                dprintf,
+                &[stderr, fmt, msg_ptr, idx.as_basic_value_enum()],
+                "report_trap",
+            );
+        } else {
+            let write = self.module.get_function("write").unwrap_or_else(|| {
+                self.linked_func(
+                    "write",
+                    vec![
+                        self.context.i32_type().into(),
+                        self.context.i8_type().ptr_type(AddressSpace::Generic).into(),
+                        self.context.i64_type().into(),
+                    ],
+                    self.context.i64_type().into(),
+                )
+                .unwrap()
+            });
+            let len =
+                self.context.i64_type().const_int(msg.len() as u64, false).as_basic_value_enum();
+            self.builder.build_call(write, &[stderr, msg_ptr, len], "report_trap");
+        }
+        let trap = self.module.get_function("llvm.trap").unwrap_or_else(|| {
+            self.module.add_function(
+                "llvm.trap",
+                self.context.void_type().fn_type(&[], false),
+                None,
+            )
+        });
+        self.builder.build_call(trap, &[], "trap");
+        self.builder.build_unreachable();
+
+        self.builder.position_at_end(cont_bb);
+    }
+
+    /// The address of the enum item `at` bytes from the start of `enum_ptr`.
+    fn enum_item_ptr(
+        &self,
+        enum_ptr: PointerValue<'ctx>,
+        at: usize,
+        ty: &Ty,
+    ) -> PointerValue<'ctx> {
+        let bytes = self.builder.build_pointer_cast(
+            enum_ptr,
+            self.context.i8_type().ptr_type(AddressSpace::Generic),
+            "enum_bytes",
+        );
+        let item = unsafe {
+            self.builder.build_in_bounds_gep(
+                bytes,
+                &[self.context.i64_type().const_int(at as u64, false)],
+                "enum_item",
+            )
+        };
+        self.builder.build_pointer_cast(
+            item,
+            ty.as_llvm_type(self.context).ptr_type(AddressSpace::Generic),
+            "enum_item_ptr",
+        )
+    }
+
     fn create_entry_block_alloca(
         &self,
         name: &str,
@@ -238,16 +422,46 @@ impl<'ctx> LLVMGen<'ctx> {
         Some(match expr {
             LValue::Ident { ident, ty: _ } => self.vars.get(ident).copied()?,
             LValue::Deref { indir: _, expr, .. } => self.get_pointer(expr)?,
-            LValue::Array { ident, exprs, ty: _ } => {
+            LValue::Array { ident, exprs, len, loc, .. } => {
                 let arr_ptr = self.vars.get(ident).copied()?;
-                self.index_arr(arr_ptr.into_pointer_value(), exprs)?.into()
+                self.index_arr(arr_ptr.into_pointer_value(), exprs, *len, loc)?.into()
             }
             LValue::FieldAccess { lhs: _, def: _, rhs: _, field_idx: _ } => todo!(),
         })
     }
 
+    /// The constant for a literal value.
+    fn const_value(&self, val: &Val) -> BasicValueEnum<'ctx> {
+        match val {
+            Val::Float(f) => BasicValueEnum::FloatValue(self.context.f64_type().const_float(*f)),
+            Val::Int(i) => {
+                BasicValueEnum::IntValue(self.context.i64_type().const_int(*i as u64, true))
+            }
+            Val::SizedInt(i, ity) => BasicValueEnum::IntValue(
+                self.context
+                    .custom_width_int_type(ity.size() as u32 * 8)
+                    .const_int(*i as u64, ity.is_signed()),
+            ),
+            Val::Char(c) => {
+                BasicValueEnum::IntValue(self.context.i8_type().const_int(*c as u64, false))
+            }
+            Val::Bool(b) => {
+                BasicValueEnum::IntValue(self.context.bool_type().const_int((*b) as u64, false))
+            }
+            Val::Str(s) => BasicValueEnum::ArrayValue(
+                self.context.i8_type().const_array(
+                    &s.name()
+                        .as_bytes()
+                        .iter()
+                        .map(|b| self.context.i8_type().const_int(*b as u64, false))
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+        }
+    }
+
     fn build_value(
-        &self,
+        &mut self,
         expr: &'ctx Expr,
         assigned: Option<Ident>,
     ) -> Option<BasicValueEnum<'ctx>> {
@@ -266,22 +480,36 @@ impl<'ctx> LLVMGen<'ctx> {
                 let val = self.build_value(expr, None)?;
                 val
             }
-            Expr::Array { ident, exprs, ty: _ } => {
+            Expr::Array { ident, exprs, len, loc, .. } => {
                 let arr_ptr = self.vars.get(ident).copied()?;
-                let idx_ptr = self.index_arr(arr_ptr.into_pointer_value(), exprs)?;
+                let idx_ptr = self.index_arr(arr_ptr.into_pointer_value(), exprs, *len, loc)?;
                 self.builder.build_load(idx_ptr, "arr_ele")
             }
             Expr::Urnary { op: _, expr: _, ty: _ } => todo!(),
-            Expr::Binary { op, lhs, rhs, ty } => {
-                let lval = self.deref_to_value(self.build_value(lhs, None)?, ty);
-                let rval = self.deref_to_value(self.build_value(rhs, None)?, ty);
+            Expr::Binary { op, lhs, rhs, ty, loc } => {
+                let (lval, rval) = (self.build_value(lhs, None)?, self.build_value(rhs, None)?);
+                let (lval, rval) = (self.deref_to_value(lval, ty), self.deref_to_value(rval, ty));
                 match op {
-                    BinOp::Mul => match ty {
-                        Ty::Int => BasicValueEnum::IntValue(self.builder.build_int_mul(
+                    BinOp::Mul | BinOp::Add | BinOp::Sub
+                        if self.overflow_checks && matches!(ty, Ty::Int | Ty::SizedInt(_)) =>
+                    {
+                        let signed = !matches!(ty, Ty::SizedInt(ity) if !ity.is_signed());
+                        BasicValueEnum::IntValue(self.build_checked_int_op(
+                            op,
                             lval.into_int_value(),
                             rval.into_int_value(),
-                            "intmul",
-                        )),
+                            signed,
+                            loc,
+                        ))
+                    }
+                    BinOp::Mul => match ty {
+                        Ty::Int | Ty::SizedInt(_) => {
+                            BasicValueEnum::IntValue(self.builder.build_int_mul(
+                                lval.into_int_value(),
+                                rval.into_int_value(),
+                                "intmul",
+                            ))
+                        }
                         Ty::Float => BasicValueEnum::FloatValue(self.builder.build_float_mul(
                             lval.into_float_value(),
                             rval.into_float_value(),
@@ -292,11 +520,13 @@ impl<'ctx> LLVMGen<'ctx> {
                     BinOp::Div => todo!(),
                     BinOp::Rem => todo!(),
                     BinOp::Add => match ty {
-                        Ty::Int => BasicValueEnum::IntValue(self.builder.build_int_add(
-                            lval.into_int_value(),
-                            rval.into_int_value(),
-                            "intadd",
-                        )),
+                        Ty::Int | Ty::SizedInt(_) => {
+                            BasicValueEnum::IntValue(self.builder.build_int_add(
+                                lval.into_int_value(),
+                                rval.into_int_value(),
+                                "intadd",
+                            ))
+                        }
                         Ty::Float => BasicValueEnum::FloatValue(self.builder.build_float_add(
                             lval.into_float_value(),
                             rval.into_float_value(),
@@ -354,7 +584,7 @@ impl<'ctx> LLVMGen<'ctx> {
                     }
                     Expr::Deref { indir: _, expr: _, ty: _ } => todo!(),
                     Expr::AddrOf(_) => todo!(),
-                    Expr::Array { ident, exprs, ty: _ } => {
+                    Expr::Array { ident, exprs, len, loc, .. } => {
                         let idx = def
                             .fields
                             .iter()
@@ -366,7 +596,7 @@ impl<'ctx> LLVMGen<'ctx> {
                             .build_struct_gep(struct_ptr, idx.try_into().unwrap(), def.ident.name())
                             .unwrap();
 
-                        let elptr = self.index_arr(field, exprs)?;
+                        let elptr = self.index_arr(field, exprs, *len, loc)?;
                         self.builder.build_load(elptr, &format!("{}.{}[]", def.ident, ident))
                     }
                     Expr::FieldAccess { lhs, def: _, rhs } => {
@@ -377,7 +607,12 @@ impl<'ctx> LLVMGen<'ctx> {
                 }
             }
             Expr::StructInit { path, fields, def: _ } => {
-                let struct_ptr = self.vars.get(&assigned?).copied()?.into_pointer_value();
+                let struct_ptr = match assigned.and_then(|id| self.vars.get(&id)) {
+                    Some(ptr) => ptr.into_pointer_value(),
+                    None => self
+                        .builder
+                        .build_alloca(expr.type_of().as_llvm_type(self.context), "structinit"),
+                };
                 for (idx, field) in fields.iter().enumerate() {
                     let val = self.build_value(&field.init, None).unwrap();
                     let ptr = self
@@ -399,7 +634,31 @@ impl<'ctx> LLVMGen<'ctx> {
                 }
                 struct_ptr.into()
             }
-            Expr::EnumInit { .. } => todo!(),
+            Expr::EnumInit { path, variant, items, def } => {
+                // Build the enum in the variable it is assigned to or a temporary
+                let enum_ptr = match assigned.and_then(|id| self.vars.get(&id)) {
+                    Some(ptr) => ptr.into_pointer_value(),
+                    None => self
+                        .builder
+                        .build_alloca(expr.type_of().as_llvm_type(self.context), "enuminit"),
+                };
+                let tag = def.variants.iter().position(|v| variant == &v.ident).unwrap();
+                let tag_ptr = self.builder.build_pointer_cast(
+                    enum_ptr,
+                    self.context.i64_type().ptr_type(AddressSpace::Generic),
+                    &format!("{}::{}.tag", path, variant),
+                );
+                self.builder
+                    .build_store(tag_ptr, self.context.i64_type().const_int(tag as u64, false));
+
+                let var = &def.variants[tag];
+                for ((item, ty), at) in items.iter().zip(&var.types).zip(var.item_offsets()) {
+                    let val = self.build_value(item, None)?;
+                    let val = self.deref_to_value(val, ty);
+                    self.builder.build_store(self.enum_item_ptr(enum_ptr, at, ty), val);
+                }
+                enum_ptr.into()
+            }
             Expr::ArrayInit { items, ty } => {
                 let memptr = self.builder.build_alloca(ty.as_llvm_type(self.context), "arrinit");
                 for (idx, expr) in items.iter().enumerate() {
@@ -420,39 +679,195 @@ impl<'ctx> LLVMGen<'ctx> {
                 }
                 memptr.into()
             }
-            Expr::Value(v) => match v {
-                Val::Float(f) => {
-                    BasicValueEnum::FloatValue(self.context.f64_type().const_float(*f))
-                }
-                Val::Int(i) => {
-                    BasicValueEnum::IntValue(self.context.i64_type().const_int(*i as u64, true))
-                }
-                Val::Char(c) => {
-                    BasicValueEnum::IntValue(self.context.i8_type().const_int(*c as u64, false))
-                }
-                Val::Bool(b) => {
-                    BasicValueEnum::IntValue(self.context.bool_type().const_int((*b) as u64, false))
+            Expr::Value(v) => self.const_value(v),
+            Expr::Cast { expr, ty } => {
+                let from = match expr.type_of() {
+                    Ty::Array { ty, .. } => *ty,
+                    t => t,
+                };
+                let val = self.build_value(expr, None)?;
+                let val = self.deref_to_value(val, &from);
+                let signed = match from {
+                    Ty::SizedInt(ity) => ity.is_signed(),
+                    Ty::Char | Ty::Bool => false,
+                    _ => true,
+                };
+                let llvm_ty = ty.as_llvm_type(self.context);
+                match (val, ty) {
+                    (BasicValueEnum::FloatValue(_), Ty::Float) => val,
+                    (BasicValueEnum::FloatValue(f), _) => self
+                        .builder
+                        .build_float_to_signed_int(f, llvm_ty.into_int_type(), "ftoi")
+                        .into(),
+                    (BasicValueEnum::PointerValue(p), Ty::Ptr(_)) => self
+                        .builder
+                        .build_pointer_cast(p, llvm_ty.into_pointer_type(), "ptrcast")
+                        .into(),
+                    (BasicValueEnum::PointerValue(p), _) => {
+                        self.builder.build_ptr_to_int(p, llvm_ty.into_int_type(), "ptoi").into()
+                    }
+                    (BasicValueEnum::IntValue(i), Ty::Float) if signed => self
+                        .builder
+                        .build_signed_int_to_float(i, llvm_ty.into_float_type(), "itof")
+                        .into(),
+                    (BasicValueEnum::IntValue(i), Ty::Float) => self
+                        .builder
+                        .build_unsigned_int_to_float(i, llvm_ty.into_float_type(), "itof")
+                        .into(),
+                    (BasicValueEnum::IntValue(i), Ty::Ptr(_)) => {
+                        self.builder.build_int_to_ptr(i, llvm_ty.into_pointer_type(), "itop").into()
+                    }
+                    (BasicValueEnum::IntValue(i), Ty::Bool) => self
+                        .builder
+                        .build_int_compare(IntPredicate::NE, i, i.get_type().const_zero(), "itob")
+                        .into(),
+                    (BasicValueEnum::IntValue(i), _) => {
+                        let to = llvm_ty.into_int_type();
+                        let (from_bits, to_bits) =
+                            (i.get_type().get_bit_width(), to.get_bit_width());
+                        if from_bits > to_bits {
+                            self.builder.build_int_truncate(i, to, "itrunc").into()
+                        } else if from_bits == to_bits {
+                            val
+                        } else if signed {
+                            self.builder.build_int_s_extend(i, to, "isext").into()
+                        } else {
+                            self.builder.build_int_z_extend(i, to, "izext").into()
+                        }
+                    }
+                    (val, ty) => unreachable!("typeck allowed cast of {:?} to {:?}", val, ty),
                 }
-                Val::Str(s) => BasicValueEnum::ArrayValue(
-                    self.context.i8_type().const_array(
-                        &s.name()
-                            .as_bytes()
-                            .iter()
-                            .map(|b| self.context.i8_type().const_int(*b as u64, false))
-                            .collect::<Vec<_>>(),
-                    ),
-                ),
-            },
+            }
+            Expr::InlineAsm { asm, reg, ty } => self.gen_inline_asm(asm, Some((reg, ty)))?,
+            // Each block assigns the value to `tmp`, see `Block::lower_value`
+            Expr::If { cond, blk, els, tmp, ty } => {
+                let fnval = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+                let alloca = self.create_entry_block_alloca(tmp.name(), ty, fnval);
+                self.vars.insert(*tmp, alloca.as_basic_value_enum());
+                self.gen_if(fnval, cond, blk, Some(els));
+                self.builder.build_load(alloca, tmp.name())
+            }
+            Expr::Match { expr, arms, match_ty, tmp, ty } => {
+                let fnval = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+                let alloca = self.create_entry_block_alloca(tmp.name(), ty, fnval);
+                self.vars.insert(*tmp, alloca.as_basic_value_enum());
+                self.gen_match(fnval, expr, arms, match_ty);
+                self.builder.build_load(alloca, tmp.name())
+            }
             Expr::Builtin(b) => todo!(),
         })
     }
 
+    /// Map an `asm` block to an LLVM inline asm call, outputs are the return value and inputs
+    /// are arguments bound to registers by the constraint string.
+    ///
+    /// Variables used in the instructions are passed by pointer as memory operands. The `result`
+    /// of an `asm` expression is the first output.
+    fn gen_inline_asm(
+        &mut self,
+        asm: &'ctx AsmBlock,
+        result: Option<(&ty::Location, &Ty)>,
+    ) -> Option<BasicValueEnum<'ctx>> {
+        let mut constraints = vec![];
+        let mut ret_tys = vec![];
+        if let Some((reg, ty)) = result {
+            constraints.push(format!("={}", asm_constraint(reg)));
+            ret_tys.push(ty.as_llvm_type(self.context));
+        }
+        for (reg, lval) in &asm.outputs {
+            constraints.push(format!("={}", asm_constraint(reg)));
+            ret_tys.push(lval.type_of().as_llvm_type(self.context));
+        }
+
+        let mut args = vec![];
+        for (reg, expr) in &asm.inputs {
+            let val = self.build_value(expr, None).unwrap();
+            args.push(self.deref_to_value(val, &expr.type_of()));
+            constraints.push(asm_constraint(reg));
+        }
+
+        // Operands after the outputs are numbered by their argument position
+        let mut mem_vars = HashMap::new();
+        let mut text = vec![];
+        for inst in &asm.assembly {
+            let mut ops = vec![];
+            for loc in inst.src.iter().chain(&inst.dst) {
+                ops.push(match loc {
+                    ty::Location::Register(reg) => reg.to_string(),
+                    ty::Location::FloatReg(reg) => reg.to_string(),
+                    ty::Location::NamedOffset(name) => format!("{}(%rip)", name),
+                    ty::Location::Offset { amt, reg: box ty::Location::Register(reg) } => {
+                        format!("{}({})", amt, reg)
+                    }
+                    ty::Location::Offset { .. } => unreachable!("parsed relative to a register"),
+                    ty::Location::InlineVar(ident) => {
+                        let idx = match mem_vars.get(ident) {
+                            Some(idx) => *idx,
+                            None => {
+                                args.push(*self.vars.get(ident).unwrap());
+                                constraints.push("*m".to_owned());
+                                mem_vars.insert(*ident, args.len() - 1);
+                                args.len() - 1
+                            }
+                        };
+                        format!("${}", ret_tys.len() + idx)
+                    }
+                    ty::Location::Const(ty::Val::Str(s)) => {
+                        let s = self.builder.build_global_string_ptr(s.name(), "asmstr");
+                        args.push(s.as_pointer_value().into());
+                        constraints.push("r".to_owned());
+                        format!("${}", ret_tys.len() + args.len() - 1)
+                    }
+                    ty::Location::Const(val) => format!("$${}", asm_const(val)),
+                });
+            }
+            text.push(format!("{} {}", inst.inst, ops.join(", ")));
+        }
+        for reg in &asm.clobbers {
+            constraints.push(format!("~{}", asm_constraint(reg)));
+        }
+        constraints.push("~{memory},~{dirflag},~{fpsr},~{flags}".to_owned());
+
+        let arg_tys = args.iter().map(|a| a.get_type()).collect::<Vec<_>>();
+        let fn_ty = match ret_tys.as_slice() {
+            [] => self.context.void_type().fn_type(&arg_tys, false),
+            [ty] => ty.fn_type(&arg_tys, false),
+            tys => self.context.struct_type(tys, false).fn_type(&arg_tys, false),
+        };
+        let asm_ptr = self.context.create_inline_asm(
+            fn_ty,
+            text.join("\n"),
+            constraints.join(","),
+            true,
+            false,
+            None,
+        );
+        let callable = CallableValue::try_from(asm_ptr).unwrap();
+        let ret = self.builder.build_call(callable, &args, "asm").try_as_basic_value().left();
+
+        let output = |builder: &Builder<'ctx>, idx: usize| {
+            if ret_tys.len() == 1 {
+                ret.unwrap()
+            } else {
+                let ret = ret.unwrap().into_struct_value();
+                builder.build_extract_value(ret, idx as u32, "asmout").unwrap()
+            }
+        };
+        let skip = result.is_some() as usize;
+        for (idx, (_, lval)) in asm.outputs.iter().enumerate() {
+            let ptr = self.get_pointer(lval).unwrap();
+            self.builder.build_store(ptr.into_pointer_value(), output(&self.builder, idx + skip));
+        }
+        result.map(|_| output(&self.builder, 0))
+    }
+
     fn gen_statement(&mut self, fnval: FunctionValue<'ctx>, stmt: &'ctx Stmt) {
         match stmt {
             Stmt::Const(var) => {
                 let alloca = self.create_entry_block_alloca(var.ident.name(), &var.ty, fnval);
-                self.builder
-                    .build_store(alloca, self.build_value(&var.init, Some(var.ident)).unwrap());
+                // A struct or enum is built in a temporary and copied in
+                let init = self.build_value(&var.init, Some(var.ident)).unwrap();
+                self.builder.build_store(alloca, self.deref_to_value(init, &var.ty));
                 self.vars.insert(var.ident, alloca.as_basic_value_enum());
             }
             Stmt::Assign { lval, rval, .. } => {
@@ -463,8 +878,8 @@ impl<'ctx> LLVMGen<'ctx> {
             Stmt::Call { expr: CallExpr { path, args, .. }, def } => {
                 if "write" == &path.segs[0] {
                     let function = self.module.get_function("printf").unwrap();
-                    let val =
-                        self.deref_to_value(self.build_value(&args[0], None).unwrap(), &def.ret);
+                    let val = self.build_value(&args[0], None).unwrap();
+                    let val = self.deref_to_value(val, &def.ret);
 
                     let fmtstr = self
                         .builder
@@ -487,60 +902,304 @@ impl<'ctx> LLVMGen<'ctx> {
                 }
             }
             Stmt::TraitMeth { expr: _, def: _ } => todo!(),
-            Stmt::If { cond, blk, els } => {
-                let cond_expr = self.build_value(cond, None).unwrap();
-                let cmp = self.builder.build_int_compare(
-                    IntPredicate::EQ,
-                    cond_expr.into_int_value(),
-                    self.context.i8_type().const_int(1, false),
-                    "ifcond",
-                );
-
-                let then_bb = self.context.append_basic_block(fnval, "then");
-                let else_bb = self.context.append_basic_block(fnval, "else");
-                let cont_bb = self.context.append_basic_block(fnval, "ifcont");
-
-                self.builder.build_conditional_branch(cmp, then_bb, else_bb);
-
-                self.builder.position_at_end(then_bb);
+            Stmt::If { cond, blk, els } => self.gen_if(fnval, cond, blk, els.as_ref()),
+            Stmt::While { .. } => todo!(),
+            Stmt::Match { expr, arms, ty } => self.gen_match(fnval, expr, arms, ty),
+            Stmt::Ret(expr, ty) => {
+                let value = self.build_value(expr, None).unwrap();
+                let value = self.deref_to_value(value, ty);
+                self.builder.build_return(Some(&value));
+            }
+            Stmt::Exit => {
+                self.builder.build_return(None);
+            }
+            // There are no loops yet
+            Stmt::Break => todo!(),
+            Stmt::Block(blk) => {
                 for stmt in &blk.stmts {
                     self.gen_statement(fnval, stmt);
                 }
-                self.builder.build_unconditional_branch(cont_bb);
-                let _then_block = self.builder.get_insert_block().unwrap();
+            }
+            Stmt::InlineAsm(asm) => {
+                self.gen_inline_asm(asm, None);
+            }
+            Stmt::Builtin(ty::Builtin::Bottom, loc) => {
+                let always = self.context.bool_type().const_int(1, false);
+                self.build_trap_if(always, &format!("{}: entered unreachable code\n", loc), None);
+            }
+            Stmt::Builtin(bin, _) => {}
+        }
+    }
+
+    /// The value of an `if` expression is assigned to its temporary at the end of each block.
+    fn gen_if(
+        &mut self,
+        fnval: FunctionValue<'ctx>,
+        cond: &'ctx Expr,
+        blk: &'ctx Block,
+        els: Option<&'ctx Block>,
+    ) {
+        let cond_expr = self.build_value(cond, None).unwrap();
+        let cmp = self.builder.build_int_compare(
+            IntPredicate::EQ,
+            cond_expr.into_int_value(),
+            self.context.i8_type().const_int(1, false),
+            "ifcond",
+        );
+
+        let then_bb = self.context.append_basic_block(fnval, "then");
+        let else_bb = self.context.append_basic_block(fnval, "else");
+        let cont_bb = self.context.append_basic_block(fnval, "ifcont");
+
+        self.builder.build_conditional_branch(cmp, then_bb, else_bb);
+
+        self.builder.position_at_end(then_bb);
+        self.gen_block_to(fnval, blk, cont_bb);
+
+        self.builder.position_at_end(else_bb);
+        if let Some(els) = els {
+            self.gen_block_to(fnval, els, cont_bb);
+        } else {
+            self.builder.build_unconditional_branch(cont_bb);
+        }
+
+        // Program execution comes back together, merge block
+        self.builder.position_at_end(cont_bb);
+    }
 
-                self.builder.position_at_end(else_bb);
-                if let Some(els) = els {
-                    for stmt in &els.stmts {
-                        self.gen_statement(fnval, stmt);
+    /// A match on integers is a switch when every arm is a single value, anything else tests
+    /// the arms in order.
+    fn gen_match(
+        &mut self,
+        fnval: FunctionValue<'ctx>,
+        expr: &'ctx Expr,
+        arms: &'ctx [MatchArm],
+        ty: &Ty,
+    ) {
+        let cont_bb = self.context.append_basic_block(fnval, "matchcont");
+        let single_values = matches!(ty, Ty::Int | Ty::SizedInt(_) | Ty::Char | Ty::Bool)
+            && arms.iter().all(|arm| {
+                arm.guard.is_none()
+                    && arm.pat.head_ranges().map_or(true, |r| r.iter().all(|(s, e)| s == e))
+            });
+        if single_values {
+            let val = self.build_value(expr, None).unwrap();
+            let val = self.deref_to_value(val, ty);
+            let int_val = val.into_int_value();
+            let int_ty = int_val.get_type();
+
+            let mut default = None;
+            let mut seen = HashSet::new();
+            let mut cases = vec![];
+            let mut arm_bbs = vec![];
+            for arm in arms {
+                let arm_bb = self.context.append_basic_block(fnval, "matcharm");
+                arm_bbs.push((arm, arm_bb));
+
+                if let Some(ranges) = arm.pat.head_ranges() {
+                    for (v, _) in ranges {
+                        // An earlier arm already covers the value
+                        if seen.insert(v) {
+                            cases.push((int_ty.const_int(v as u64, true), arm_bb));
+                        }
                     }
+                } else {
+                    // Any arm after this can never be reached
+                    default = Some(arm_bb);
+                    break;
                 }
-                self.builder.build_unconditional_branch(cont_bb);
-                let _else_block = self.builder.get_insert_block().unwrap();
+            }
+            self.builder.build_switch(int_val, default.unwrap_or(cont_bb), &cases);
 
-                // Program execution comes back together, merge block
-                self.builder.position_at_end(cont_bb);
+            for (arm, arm_bb) in arm_bbs {
+                self.builder.position_at_end(arm_bb);
+                let mut binds = vec![];
+                collect_bindings(&arm.pat, &mut binds);
+                for ident in binds {
+                    let alloca = self.create_entry_block_alloca(ident.name(), ty, fnval);
+                    self.builder.build_store(alloca, val);
+                    self.vars.insert(ident, alloca.as_basic_value_enum());
+                }
+                self.gen_block_to(fnval, &arm.blk, cont_bb);
+            }
+        } else {
+            // The patterns are tested against the value in memory, a value that is not
+            // in a variable is stored to a temporary first
+            let mut scrutinee = match self.build_value(expr, None).unwrap() {
+                BasicValueEnum::PointerValue(ptr) => ptr,
+                val => {
+                    let tmp = self.create_entry_block_alloca("matchval", ty, fnval);
+                    self.builder.build_store(tmp, val);
+                    tmp
+                }
+            };
+            while scrutinee.get_type().get_element_type().is_pointer_type() {
+                scrutinee = self.builder.build_load(scrutinee, "deref").into_pointer_value();
+            }
 
-                // TODO: this is complicated and seem like an optimization?
-                //
-                // let phi = self.builder.build_phi(type_, "iftmp");
-                // phi.add_incoming(&[(&then_val, then_bb), (&else_val, else_bb)]);
+            // Each arm falls through to the next when its pattern or guard fails
+            for arm in arms {
+                let next_bb = self.context.append_basic_block(fnval, "matchnext");
+                let mut binds = HashMap::new();
+                self.gen_pat_test(fnval, &arm.pat, scrutinee, ty, next_bb, &mut binds);
+                self.vars.extend(binds.into_iter().map(|(id, ptr)| (id, ptr.into())));
+                if let Some(guard) = &arm.guard {
+                    let body_bb = self.context.append_basic_block(fnval, "matchbody");
+                    let cond = self.build_value(guard, None).unwrap();
+                    let cond = self.deref_to_value(cond, &Ty::Bool).into_int_value();
+                    self.builder.build_conditional_branch(cond, body_bb, next_bb);
+                    self.builder.position_at_end(body_bb);
+                }
+                self.gen_block_to(fnval, &arm.blk, cont_bb);
+                self.builder.position_at_end(next_bb);
             }
-            Stmt::While { .. } => todo!(),
-            Stmt::Match { .. } => todo!(),
-            Stmt::Ret(expr, ty) => {
-                let value = self.deref_to_value(self.build_value(expr, None).unwrap(), ty);
-                self.builder.build_return(Some(&value));
+            self.builder.build_unconditional_branch(cont_bb);
+        }
+
+        self.builder.position_at_end(cont_bb);
+    }
+    /// The statements of a block, then on to `cont_bb` unless the block returned.
+    fn gen_block_to(
+        &mut self,
+        fnval: FunctionValue<'ctx>,
+        blk: &'ctx Block,
+        cont_bb: BasicBlock<'ctx>,
+    ) {
+        for stmt in &blk.stmts {
+            self.gen_statement(fnval, stmt);
+        }
+        if self.builder.get_insert_block().unwrap().get_terminator().is_none() {
+            self.builder.build_unconditional_branch(cont_bb);
+        }
+    }
+
+    /// Test if the value `ptr` points to matches `pat` branching to `fail` if it does not.
+    ///
+    /// Continues in a new block when the pattern matches, each variable it binds is stored to
+    /// the alloca in `binds`.
+    fn gen_pat_test(
+        &mut self,
+        fnval: FunctionValue<'ctx>,
+        pat: &Pat,
+        ptr: PointerValue<'ctx>,
+        ty: &Ty,
+        fail: BasicBlock<'ctx>,
+        binds: &mut HashMap<Ident, PointerValue<'ctx>>,
+    ) {
+        match pat {
+            Pat::Enum { idx, items, .. } => {
+                let var = if let Ty::Enum { def, .. } = ty {
+                    &def.variants[*idx]
+                } else {
+                    unreachable!("enum match without enum")
+                };
+                let tag_ptr = self.builder.build_pointer_cast(
+                    ptr,
+                    self.context.i64_type().ptr_type(AddressSpace::Generic),
+                    "tag_ptr",
+                );
+                let tag = self.builder.build_load(tag_ptr, "tag").into_int_value();
+                let idx = self.context.i64_type().const_int(*idx as u64, false);
+                let same = self.builder.build_int_compare(IntPredicate::EQ, tag, idx, "variant");
+                self.branch_or_fail(fnval, same, fail);
+
+                for ((item, item_ty), at) in items.iter().zip(&var.types).zip(var.item_offsets()) {
+                    let item_ptr = self.enum_item_ptr(ptr, at, item_ty);
+                    self.gen_pat_test(fnval, item, item_ptr, item_ty, fail, binds);
+                }
             }
-            Stmt::Exit => {
-                self.builder.build_return(None);
+            Pat::Array { items, .. } => {
+                let ele_ty =
+                    if let Ty::Array { ty, .. } = ty { ty } else { unreachable!("array match") };
+                for (idx, item) in items.iter().enumerate() {
+                    let ele_ptr = unsafe {
+                        self.builder.build_in_bounds_gep(
+                            ptr,
+                            &[
+                                self.context.i64_type().const_zero(),
+                                self.context.i64_type().const_int(idx as u64, false),
+                            ],
+                            "match_ele",
+                        )
+                    };
+                    self.gen_pat_test(fnval, item, ele_ptr, ele_ty, fail, binds);
+                }
+            }
+            Pat::Struct { fields, .. } => {
+                let def = if let Ty::Struct { def, .. } = ty {
+                    def
+                } else {
+                    unreachable!("struct match")
+                };
+                for (ident, field) in fields {
+                    let idx = def.fields.iter().position(|f| f.ident == *ident).unwrap();
+                    let field_ptr = self
+                        .builder
+                        .build_struct_gep(ptr, idx as u32, &format!("{}.{}", def.ident, ident))
+                        .unwrap();
+                    self.gen_pat_test(fnval, field, field_ptr, &def.fields[idx].ty, fail, binds);
+                }
             }
-            Stmt::Block(_) => todo!(),
-            Stmt::InlineAsm(asm) => {}
-            Stmt::Builtin(bin) => {}
+            Pat::Range { start, end } => {
+                let (ge, le) = if is_signed(ty) {
+                    (IntPredicate::SGE, IntPredicate::SLE)
+                } else {
+                    (IntPredicate::UGE, IntPredicate::ULE)
+                };
+                let val = self.builder.build_load(ptr, "matchval").into_int_value();
+                let start = self.const_value(start).into_int_value();
+                let end = self.const_value(end).into_int_value();
+                let above = self.builder.build_int_compare(ge, val, start, "above");
+                let below = self.builder.build_int_compare(le, val, end, "below");
+                let in_range = self.builder.build_and(above, below, "matchrange");
+                self.branch_or_fail(fnval, in_range, fail);
+            }
+            Pat::Or(alts) => {
+                let matched_bb = self.context.append_basic_block(fnval, "matchor");
+                for alt in alts {
+                    let next_alt = self.context.append_basic_block(fnval, "matchalt");
+                    self.gen_pat_test(fnval, alt, ptr, ty, next_alt, binds);
+                    self.builder.build_unconditional_branch(matched_bb);
+                    self.builder.position_at_end(next_alt);
+                }
+                self.builder.build_unconditional_branch(fail);
+                self.builder.position_at_end(matched_bb);
+            }
+            Pat::At { ident, pat } => {
+                self.gen_pat_test(fnval, pat, ptr, ty, fail, binds);
+                self.gen_pat_test(fnval, &Pat::Bind(Binding::Wild(*ident)), ptr, ty, fail, binds);
+            }
+            Pat::Bind(Binding::Wild(ident)) => {
+                // Every alternative of an or-pattern binds to the same alloca
+                let slot = *binds
+                    .entry(*ident)
+                    .or_insert_with(|| self.create_entry_block_alloca(ident.name(), ty, fnval));
+                let val = self.builder.build_load(ptr, ident.name());
+                self.builder.build_store(slot, val);
+            }
+            Pat::Bind(Binding::Value(val)) => {
+                let loaded = self.builder.build_load(ptr, "matchval").into_int_value();
+                let val = self.const_value(val).into_int_value();
+                let same =
+                    self.builder.build_int_compare(IntPredicate::EQ, loaded, val, "matchval");
+                self.branch_or_fail(fnval, same, fail);
+            }
+            Pat::Wild => {}
         }
     }
 
+    /// Continue in a new block when `cond` is true otherwise branch to `fail`.
+    fn branch_or_fail(
+        &self,
+        fnval: FunctionValue<'ctx>,
+        cond: IntValue<'ctx>,
+        fail: BasicBlock<'ctx>,
+    ) {
+        let pass = self.context.append_basic_block(fnval, "matchpass");
+        self.builder.build_conditional_branch(cond, pass, fail);
+        self.builder.position_at_end(pass);
+    }
+
     /// Add a function header (signature) to LLVM
     fn linked_func(
         &self,
@@ -583,6 +1242,59 @@ impl<'ctx> LLVMGen<'ctx> {
     }
 }
 
+/// The constraint naming a register in LLVM inline asm, `%rax` is `{rax}`.
+fn asm_constraint(loc: &ty::Location) -> String {
+    let reg = match loc {
+        ty::Location::Register(reg) => reg.to_string(),
+        ty::Location::FloatReg(reg) => reg.to_string(),
+        _ => unreachable!("ICE: asm operands are always registers {:?}", loc),
+    };
+    format!("{{{}}}", reg.trim_start_matches('%'))
+}
+
+/// An immediate in inline asm text, `$` is escaped since LLVM uses it for operands.
+fn asm_const(val: &ty::Val) -> String {
+    match val {
+        ty::Val::Int(v) => v.to_string(),
+        ty::Val::SizedInt(v, ity) => ity.widen(*v).to_string(),
+        ty::Val::Char(c) => (*c as u32).to_string(),
+        ty::Val::Bool(b) => (*b as u8).to_string(),
+        ty::Val::Float(_) | ty::Val::Str(_) => unreachable!("ICE: invalid asm constant {}", val),
+    }
+}
+
+/// If the integer type `ty` is compared as signed, chars and bools are unsigned.
+fn is_signed(ty: &Ty) -> bool {
+    match ty {
+        Ty::SizedInt(ity) => ity.is_signed(),
+        Ty::Char | Ty::Bool => false,
+        _ => true,
+    }
+}
+
+/// The variables a pattern binds, an or-pattern binds the same ones in every alternative.
+fn collect_bindings(pat: &Pat, binds: &mut Vec<Ident>) {
+    match pat {
+        Pat::Bind(Binding::Wild(ident)) => binds.push(*ident),
+        Pat::At { ident, pat } => {
+            binds.push(*ident);
+            collect_bindings(pat, binds);
+        }
+        Pat::Or(alts) => collect_bindings(&alts[0], binds),
+        Pat::Enum { items, .. } | Pat::Array { items, .. } => {
+            for item in items {
+                collect_bindings(item, binds);
+            }
+        }
+        Pat::Struct { fields, .. } => {
+            for (_, field) in fields {
+                collect_bindings(field, binds);
+            }
+        }
+        Pat::Bind(Binding::Value(_)) | Pat::Range { .. } | Pat::Wild => {}
+    }
+}
+
 impl<'ast> Visit<'ast> for LLVMGen<'ast> {
     fn visit_const(&mut self, var: &'ast Const) {
         let global = self.module.add_global(
@@ -1,6 +1,12 @@
+use std::convert::TryFrom;
+
 use crate::{
-    ast::parse::symbol::Ident,
-    lir::lower::{BinOp, Expr, UnOp, Val},
+    ast::{
+        parse::symbol::Ident,
+        types::{IntTy, Range, DUMMY},
+    },
+    error::Error,
+    lir::lower::{BinOp, Builtin, Expr, Ty, UnOp, Val},
     typeck::TyCheckRes,
 };
 
@@ -8,7 +14,8 @@ use crate::{
 crate struct Folder;
 
 impl Expr {
-    crate fn const_fold(&mut self, tcxt: &TyCheckRes<'_, '_>) {
+    /// Fold constant sub expressions, overflow is reported as an error at `span`.
+    crate fn const_fold(&mut self, tcxt: &TyCheckRes<'_, '_>, span: Range) {
         match self {
             Expr::Ident { ident: _, .. } => {
                 // TODO: damn, this needs to track mutations to work
@@ -21,10 +28,10 @@ impl Expr {
                 // }
             }
             Expr::AddrOf(expr) | Expr::Deref { expr, .. } => {
-                expr.const_fold(tcxt);
+                expr.const_fold(tcxt, span);
             }
             Expr::Parens(expr) => {
-                expr.const_fold(tcxt);
+                expr.const_fold(tcxt, span);
                 if let ex @ Expr::Value(_) = &**expr {
                     *self = ex.clone();
                 }
@@ -33,7 +40,7 @@ impl Expr {
                 if let box Expr::Value(val) = expr {
                     match op {
                         UnOp::Not => match val {
-                            Val::Int(i) => {
+                            Val::Int(i) | Val::SizedInt(i, _) => {
                                 // TODO: hmmm is this right ??
                                 *val = Val::Bool(*i == 0);
                             }
@@ -47,6 +54,9 @@ impl Expr {
                                 // TODO: hmmm is this right ??
                                 *val = Val::Int(!(*i));
                             }
+                            Val::SizedInt(i, ity) => {
+                                *val = Val::SizedInt(ity.wrap(!(*i as i128)), *ity);
+                            }
                             Val::Float(f) => {
                                 // TODO: hmmm is this right ??
                                 *val = Val::Float(f64::from_bits(!f.to_bits()));
@@ -61,49 +71,80 @@ impl Expr {
                 }
             }
             Expr::Binary { op, lhs, rhs, .. } => {
-                lhs.const_fold(tcxt);
-                rhs.const_fold(tcxt);
-                if let Some(folded) = eval_binop(op, lhs, rhs) {
+                lhs.const_fold(tcxt, span);
+                rhs.const_fold(tcxt, span);
+                if let Some(folded) = eval_binop(op, lhs, rhs, tcxt, span) {
                     *self = folded;
                 }
             }
             Expr::Array { exprs, .. } => {
                 for expr in exprs {
-                    expr.const_fold(tcxt);
+                    expr.const_fold(tcxt, span);
                 }
             }
             Expr::Call { args, .. } => {
                 for expr in args {
-                    expr.const_fold(tcxt);
+                    expr.const_fold(tcxt, span);
                 }
             }
             Expr::TraitMeth { trait_: _, args, type_args: _, .. } => {
                 for expr in args {
-                    expr.const_fold(tcxt);
+                    expr.const_fold(tcxt, span);
                 }
             }
             Expr::StructInit { fields, .. } => {
                 for expr in fields {
-                    expr.init.const_fold(tcxt);
+                    expr.init.const_fold(tcxt, span);
                 }
             }
             Expr::EnumInit { items, .. } => {
                 for expr in items {
-                    expr.const_fold(tcxt);
+                    expr.const_fold(tcxt, span);
                 }
             }
             Expr::ArrayInit { items, .. } => {
                 for expr in items {
-                    expr.const_fold(tcxt);
+                    expr.const_fold(tcxt, span);
+                }
+            }
+            // A cast of a constant is folded when it is lowered
+            Expr::Cast { .. } => {}
+            // The condition and branches are folded as they are lowered, as are asm operands
+            Expr::If { .. } | Expr::Match { .. } | Expr::InlineAsm { .. } => {}
+            // Monomorphization has already replaced any generic types
+            Expr::Builtin(Builtin::SizeOf(ty)) => {
+                *self = Expr::Value(Val::Int(ty.size() as isize));
+            }
+            Expr::Builtin(Builtin::AlignOf(ty)) => {
+                *self = Expr::Value(Val::Int(ty.align() as isize));
+            }
+            Expr::Builtin(Builtin::OffsetOf(ty, field)) => match ty.field_offset(*field) {
+                Some(offset) => *self = Expr::Value(Val::Int(offset as isize)),
+                None => tcxt.errors.push_error(Error::error_with_span(
+                    tcxt,
+                    span,
+                    &format!("[E0ce] no field `{}` found for `{}`", field, ty),
+                )),
+            },
+            // The length of a slice is only known at runtime
+            Expr::Builtin(Builtin::Len(expr)) => {
+                if let Some(len) = expr.type_of().array_len() {
+                    *self = Expr::Value(Val::Int(len as isize));
                 }
             }
-            Expr::Value(_) | Expr::Builtin(..) | Expr::FieldAccess { .. } => {}
+            Expr::Value(_) | Expr::Builtin(Builtin::Bottom) | Expr::FieldAccess { .. } => {}
         }
     }
 }
 
 // TODO: identity folding `x & 0` or `x * 0` is always `0`
-fn eval_binop(op: &BinOp, lhs: &Expr, rhs: &Expr) -> Option<Expr> {
+fn eval_binop(
+    op: &BinOp,
+    lhs: &Expr,
+    rhs: &Expr,
+    tcxt: &TyCheckRes<'_, '_>,
+    span: Range,
+) -> Option<Expr> {
     let lval = if let Expr::Value(val) = lhs {
         val
     } else {
@@ -114,17 +155,32 @@ fn eval_binop(op: &BinOp, lhs: &Expr, rhs: &Expr) -> Option<Expr> {
     } else {
         return None;
     };
-    Some(Expr::Value(lval.evaluate(op, rval)))
+    // Leave it for the program to blow up at runtime
+    if matches!(op, BinOp::Div | BinOp::Rem) && rval.as_int() == Some(0) {
+        return None;
+    }
+    let (val, overflowed) = lval.evaluate(op, rval);
+    // The wrapped value is still folded so enclosing expressions don't report it again
+    if overflowed {
+        tcxt.errors.push_error(Error::error_with_span(
+            tcxt,
+            span,
+            &format!("[E0ce] attempt to {} with overflow", op.overflow_verb()),
+        ));
+    }
+    Some(Expr::Value(val))
 }
 
 impl Val {
-    fn evaluate(&self, op: &BinOp, other: &Val) -> Val {
+    /// The result of `self op other` and whether the integer operation overflowed.
+    fn evaluate(&self, op: &BinOp, other: &Val) -> (Val, bool) {
         match (self, other) {
-            (Val::Float(f1), Val::Float(f2)) => float_op(*f1, *f2, op),
+            (Val::Float(f1), Val::Float(f2)) => (float_op(*f1, *f2, op), false),
             (Val::Int(i1), Val::Int(i2)) => int_op(*i1, *i2, op),
-            (Val::Char(c1), Val::Char(c2)) => char_op(*c1, *c2, op),
-            (Val::Bool(b1), Val::Bool(b2)) => bool_op(*b1, *b2, op),
-            (Val::Str(s1), Val::Str(s2)) => str_op(s1, s2, op),
+            (Val::SizedInt(i1, ity), Val::SizedInt(i2, _)) => sized_int_op(*i1, *i2, *ity, op),
+            (Val::Char(c1), Val::Char(c2)) => (char_op(*c1, *c2, op), false),
+            (Val::Bool(b1), Val::Bool(b2)) => (bool_op(*b1, *b2, op), false),
+            (Val::Str(s1), Val::Str(s2)) => (str_op(s1, s2, op), false),
             _ => todo!("coercion and const fold"),
         }
     }
@@ -153,26 +209,61 @@ fn float_op(a: f64, b: f64, op: &BinOp) -> Val {
     }
 }
 
-fn int_op(a: isize, b: isize, op: &BinOp) -> Val {
+/// The wrapped result of the operation and whether it overflowed.
+fn int_op(a: isize, b: isize, op: &BinOp) -> (Val, bool) {
+    let int = |(v, overflowed): (isize, bool)| (Val::Int(v), overflowed);
+    let shift = u32::try_from(b).unwrap_or(u32::MAX);
     match op {
-        BinOp::Mul => Val::Int(a * b),
-        BinOp::Div => Val::Int(a / b),
-        BinOp::Rem => Val::Int(a % b),
-        BinOp::Add => Val::Int(a + b),
-        BinOp::Sub => Val::Int(a - b),
-        BinOp::LeftShift => Val::Int(a << b),
-        BinOp::RightShift => Val::Int(a >> b),
-        BinOp::Lt => Val::Bool(a < b),
-        BinOp::Le => Val::Bool(a <= b),
-        BinOp::Ge => Val::Bool(a >= b),
-        BinOp::Gt => Val::Bool(a > b),
-        BinOp::Eq => Val::Bool(a == b),
-        BinOp::Ne => Val::Bool(a != b),
-        BinOp::BitAnd => Val::Int(a & b),
-        BinOp::BitXor => Val::Int(a ^ b),
-        BinOp::BitOr => Val::Int(a | b),
-        BinOp::And => Val::Bool((a != 0) && (b != 0)),
-        BinOp::Or => Val::Bool((a != 0) || (b != 0)),
+        BinOp::Mul => int(a.overflowing_mul(b)),
+        BinOp::Div => int(a.overflowing_div(b)),
+        BinOp::Rem => int(a.overflowing_rem(b)),
+        BinOp::Add => int(a.overflowing_add(b)),
+        BinOp::Sub => int(a.overflowing_sub(b)),
+        BinOp::LeftShift => int(a.overflowing_shl(shift)),
+        BinOp::RightShift => int(a.overflowing_shr(shift)),
+        BinOp::Lt => (Val::Bool(a < b), false),
+        BinOp::Le => (Val::Bool(a <= b), false),
+        BinOp::Ge => (Val::Bool(a >= b), false),
+        BinOp::Gt => (Val::Bool(a > b), false),
+        BinOp::Eq => (Val::Bool(a == b), false),
+        BinOp::Ne => (Val::Bool(a != b), false),
+        BinOp::BitAnd => (Val::Int(a & b), false),
+        BinOp::BitXor => (Val::Int(a ^ b), false),
+        BinOp::BitOr => (Val::Int(a | b), false),
+        BinOp::And => (Val::Bool((a != 0) && (b != 0)), false),
+        BinOp::Or => (Val::Bool((a != 0) || (b != 0)), false),
+        BinOp::AddAssign | BinOp::SubAssign => {
+            unreachable!("ICE assign operations should be lowered by const folding")
+        }
+    }
+}
+
+/// Fold the same way the generated code would, the operation wraps around at the width of `ity`
+/// and overflowed if the exact result does not fit.
+fn sized_int_op(a: isize, b: isize, ity: IntTy, op: &BinOp) -> (Val, bool) {
+    let (a, b) = (ity.widen(a), ity.widen(b));
+    let sized = |v: i128| (Val::SizedInt(ity.wrap(v), ity), !ity.fits(v));
+    let bits = (ity.size() * 8) as i128;
+    match op {
+        BinOp::Mul => a.checked_mul(b).map_or_else(|| (sized(a.wrapping_mul(b)).0, true), sized),
+        BinOp::Div => sized(a / b),
+        BinOp::Rem => sized(a % b),
+        BinOp::Add => sized(a + b),
+        BinOp::Sub => sized(a - b),
+        // Like `shl` and `shr` the shift amount is masked to 6 bits
+        BinOp::LeftShift => (sized(a << (b & 63)).0, !(0..bits).contains(&b)),
+        BinOp::RightShift => (sized(a >> (b & 63)).0, !(0..bits).contains(&b)),
+        BinOp::Lt => (Val::Bool(a < b), false),
+        BinOp::Le => (Val::Bool(a <= b), false),
+        BinOp::Ge => (Val::Bool(a >= b), false),
+        BinOp::Gt => (Val::Bool(a > b), false),
+        BinOp::Eq => (Val::Bool(a == b), false),
+        BinOp::Ne => (Val::Bool(a != b), false),
+        BinOp::BitAnd => (sized(a & b).0, false),
+        BinOp::BitXor => (sized(a ^ b).0, false),
+        BinOp::BitOr => (sized(a | b).0, false),
+        BinOp::And => (Val::Bool((a != 0) && (b != 0)), false),
+        BinOp::Or => (Val::Bool((a != 0) || (b != 0)), false),
         BinOp::AddAssign | BinOp::SubAssign => {
             unreachable!("ICE assign operations should be lowered by const folding")
         }
@@ -248,7 +339,8 @@ fn fold_expr() {
                 op: BinOp::Add,
                 lhs: box Expr::Value(Val::Int($ex)),
                 rhs: box expr!($($rest)*),
-                ty: crate::lir::lower::Ty::Void
+                ty: crate::lir::lower::Ty::Void,
+                loc: crate::lir::lower::SrcLoc { file: Ident::new(DUMMY, "test"), line: 0 },
             }
         };
         ($ex:tt * $($rest:tt)*) => {
@@ -256,7 +348,8 @@ fn fold_expr() {
                 op: BinOp::Mul,
                 lhs: box Expr::Value(Val::Int($ex)),
                 rhs: box expr!($($rest)*),
-                ty: crate::lir::lower::Ty::Void
+                ty: crate::lir::lower::Ty::Void,
+                loc: crate::lir::lower::SrcLoc { file: Ident::new(DUMMY, "test"), line: 0 },
             }
         };
         ($ex:tt - $($rest:tt)*) => {
@@ -264,7 +357,8 @@ fn fold_expr() {
                 op: BinOp::Sub,
                 lhs: box Expr::Value(Val::Int($ex)),
                 rhs: box expr!($($rest)*),
-                ty: crate::lir::lower::Ty::Void
+                ty: crate::lir::lower::Ty::Void,
+                loc: crate::lir::lower::SrcLoc { file: Ident::new(DUMMY, "test"), line: 0 },
             }
         };
         ($ex:expr) => {
@@ -273,7 +367,7 @@ fn fold_expr() {
     }
 
     let mut ex = expr!(5 + 9 * 9 - 3);
-    ex.const_fold(&TyCheckRes::default());
+    ex.const_fold(&TyCheckRes::default(), DUMMY);
 
     assert!(matches!(
         ex,
@@ -3,11 +3,11 @@ use std::fmt;
 use crate::{
     ast::{
         parse::symbol::Ident,
-        types::{self as ty, FuncKind, Path, Spanned, DUMMY},
+        types::{self as ty, FuncKind, IntTy, Path, Spanned, DUMMY},
     },
     error::Error,
     lir::{const_fold::Folder, mono::TraitRes},
-    typeck::TyCheckRes,
+    typeck::{const_eval, TyCheckRes},
     visit::VisitMut,
 };
 
@@ -15,6 +15,8 @@ use crate::{
 pub enum Val {
     Float(f64),
     Int(isize),
+    /// The value is stored wrapped to the width of the type, see `IntTy::wrap`.
+    SizedInt(isize, IntTy),
     Char(char),
     Bool(bool),
     Str(Ident),
@@ -25,27 +27,52 @@ impl Val {
         match val {
             ty::Val::Float(v) => Val::Float(v),
             ty::Val::Int(v) => Val::Int(v),
+            ty::Val::SizedInt(v, ity) => Val::SizedInt(v, ity),
             ty::Val::Char(v) => Val::Char(v),
             ty::Val::Bool(v) => Val::Bool(v),
             ty::Val::Str(v) => Val::Str(v),
         }
     }
 
+    /// The inverse of `Val::lower`.
+    fn to_ast(&self) -> ty::Val {
+        match self {
+            Val::Float(v) => ty::Val::Float(*v),
+            Val::Int(v) => ty::Val::Int(*v),
+            Val::SizedInt(v, ity) => ty::Val::SizedInt(*v, *ity),
+            Val::Char(v) => ty::Val::Char(*v),
+            Val::Bool(v) => ty::Val::Bool(*v),
+            Val::Str(v) => ty::Val::Str(*v),
+        }
+    }
+
     fn type_of(&self) -> Ty {
         match self {
             Val::Float(_) => Ty::Float,
             Val::Int(_) => Ty::Int,
+            Val::SizedInt(_, ity) => Ty::SizedInt(*ity),
             Val::Char(_) => Ty::Char,
             Val::Bool(_) => Ty::Bool,
             Val::Str(s) => Ty::ConstStr(s.name().len()),
         }
     }
 
+    /// The value as an integer, if it is one or can be treated like one.
+    crate fn as_int(&self) -> Option<isize> {
+        match self {
+            Val::Int(v) | Val::SizedInt(v, _) => Some(*v),
+            Val::Char(v) => Some(*v as isize),
+            Val::Bool(v) => Some(*v as isize),
+            Val::Float(_) | Val::Str(_) => None,
+        }
+    }
+
     #[allow(dead_code)]
     crate fn size_of(&self) -> usize {
         match self {
             Val::Float(_) => 8,
             Val::Int(_) => 8,
+            Val::SizedInt(_, ity) => ity.size(),
             Val::Char(_) => 4,
             Val::Bool(_) => 1,
             Val::Str(_) => 8,
@@ -58,6 +85,7 @@ impl fmt::Display for Val {
         match self {
             Val::Float(v) => v.fmt(f),
             Val::Int(v) => v.fmt(f),
+            Val::SizedInt(v, ity) => ity.widen(*v).fmt(f),
             Val::Char(v) => v.fmt(f),
             Val::Bool(v) => v.fmt(f),
             Val::Str(v) => v.fmt(f),
@@ -72,6 +100,8 @@ impl PartialEq for Val {
             (Val::Float(_), _) => false,
             (Val::Int(a), Val::Int(b)) => a.eq(b),
             (Val::Int(_), _) => false,
+            (Val::SizedInt(a, t1), Val::SizedInt(b, t2)) => a.eq(b) && t1.eq(t2),
+            (Val::SizedInt(..), _) => false,
             (Val::Char(a), Val::Char(b)) => a.eq(b),
             (Val::Char(_), _) => false,
             (Val::Str(a), Val::Str(b)) => a.eq(b),
@@ -198,6 +228,20 @@ impl BinOp {
     crate fn is_cmp(&self) -> bool {
         matches!(self, Self::Lt | Self::Le | Self::Ge | Self::Gt | Self::Eq | Self::Ne)
     }
+
+    /// The verb for an overflow message `attempt to add with overflow`.
+    crate fn overflow_verb(&self) -> &'static str {
+        match self {
+            BinOp::Mul => "multiply",
+            BinOp::Div => "divide",
+            BinOp::Rem => "calculate the remainder",
+            BinOp::Add => "add",
+            BinOp::Sub => "subtract",
+            BinOp::LeftShift => "shift left",
+            BinOp::RightShift => "shift right",
+            _ => unreachable!("ICE only arithmetic can overflow"),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -224,6 +268,33 @@ pub enum Builtin {
     // TODO: these will have to be expr's also then
     /// The type of operator
     SizeOf(Ty),
+    /// The alignment of a type, folded to a value by `const_fold`.
+    AlignOf(Ty),
+    /// The offset of a struct field, folded to a value by `const_fold`.
+    OffsetOf(Ty, Ident),
+    /// The number of items in an array or slice, only the length of an array is folded to a value
+    /// by `const_fold`.
+    Len(Box<Expr>),
+}
+
+/// The line of source code an operation that can fail at runtime came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrcLoc {
+    pub file: Ident,
+    pub line: usize,
+}
+
+impl SrcLoc {
+    fn lower(tyctx: &TyCheckRes<'_, '_>, span: ty::Range) -> Self {
+        let (file, line) = tyctx.file_and_line(span);
+        SrcLoc { file: Ident::new(DUMMY, file), line }
+    }
+}
+
+impl fmt::Display for SrcLoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
 }
 
 #[derive(Clone, derive_help::Debug, PartialEq, Eq)]
@@ -236,12 +307,15 @@ pub enum Expr {
     AddrOf(Box<Expr>),
     /// Access an array by index `[expr][expr]`.
     ///
-    /// Each `exprs` represents an access of a dimension of the array.
-    Array { ident: Ident, exprs: Vec<Expr>, ty: Ty },
+    /// Each `exprs` represents an access of a dimension of the array. `len` is the length of the
+    /// array when it is known and `loc` is reported when the index is out of bounds.
+    Array { ident: Ident, exprs: Vec<Expr>, ty: Ty, len: Option<usize>, loc: SrcLoc },
     /// A urnary operation `!expr`.
     Urnary { op: UnOp, expr: Box<Expr>, ty: Ty },
     /// A binary operation `1 + 1`.
-    Binary { op: BinOp, lhs: Box<Expr>, rhs: Box<Expr>, ty: Ty },
+    ///
+    /// `loc` is reported when the operation overflows with `--overflow-checks`.
+    Binary { op: BinOp, lhs: Box<Expr>, rhs: Box<Expr>, ty: Ty, loc: SrcLoc },
     /// An expression wrapped in parantheses (expr).
     Parens(Box<Expr>),
     /// A function call with possible expression arguments `call(expr)`.
@@ -284,6 +358,16 @@ pub enum Expr {
     },
     /// An array initializer `{0, 1, 2}`
     ArrayInit { items: Vec<Expr>, ty: Ty },
+    /// An `if` used as a value, each block ends by assigning its value to `tmp`.
+    If { cond: Box<Expr>, blk: Block, els: Block, tmp: Ident, ty: Ty },
+    /// A `match` used as a value, each arm ends by assigning its value to `tmp`.
+    ///
+    /// `match_ty` is the type of the scrutinee, `ty` is the type of the whole expression.
+    Match { expr: Box<Expr>, arms: Vec<MatchArm>, match_ty: Ty, tmp: Ident, ty: Ty },
+    /// Convert a value to another primitive type `expr as ty`.
+    Cast { expr: Box<Expr>, ty: Ty },
+    /// An `asm` block used as a value, the value is read from `reg` after the block runs.
+    InlineAsm { asm: AsmBlock, reg: ty::Location, ty: Ty },
     /// A literal value `1, "hello", true`
     Value(Val),
     /// A builtin used in expression position.
@@ -291,14 +375,68 @@ pub enum Expr {
 }
 
 impl Expr {
+    /// Lower the evaluated value of a global const of type `ty`.
+    fn lower_const(tyctx: &TyCheckRes<'_, '_>, val: &const_eval::ConstVal, ty: &Ty) -> Self {
+        match val {
+            const_eval::ConstVal::Val(v) => Expr::Value(Val::lower(v.clone())),
+            const_eval::ConstVal::Array(items) => {
+                let elem = if let Ty::Array { ty, .. } = ty { &**ty } else { ty };
+                Expr::ArrayInit {
+                    items: items.iter().map(|v| Expr::lower_const(tyctx, v, elem)).collect(),
+                    ty: ty.clone(),
+                }
+            }
+            const_eval::ConstVal::Struct { path, fields } => {
+                let ident = path.segs.last().unwrap();
+                let def = Struct::lower(tyctx, (*tyctx.name_struct[ident]).clone());
+                Expr::StructInit {
+                    path: path.clone(),
+                    fields: fields
+                        .iter()
+                        .zip(&def.fields)
+                        .map(|((ident, v), fdef)| FieldInit {
+                            ident: *ident,
+                            init: Expr::lower_const(tyctx, v, &fdef.ty),
+                            ty: fdef.ty.clone(),
+                        })
+                        .collect(),
+                    def,
+                }
+            }
+            const_eval::ConstVal::Enum { path, variant, items } => {
+                let ident = path.segs.last().unwrap();
+                let def = Enum::lower(tyctx, (*tyctx.name_enum[ident]).clone());
+                let types = &def.variants.iter().find(|v| v.ident == *variant).unwrap().types;
+                Expr::EnumInit {
+                    path: path.clone(),
+                    variant: *variant,
+                    items: items
+                        .iter()
+                        .zip(types)
+                        .map(|(v, ty)| Expr::lower_const(tyctx, v, ty))
+                        .collect(),
+                    def,
+                }
+            }
+            const_eval::ConstVal::Void => unreachable!("void consts are rejected by type checking"),
+        }
+    }
+
     fn lower(tyctx: &TyCheckRes<'_, '_>, fold: &Folder, mut ex: ty::Expression) -> Self {
-        let mut typ = tyctx.expr_ty.get(&ex).cloned().unwrap_or_else(|| match &mut ex.val {
+        // Substituting a const generic changes the expressions that use it, only the types of the
+        // specialization know them
+        let known = tyctx
+            .expr_ty
+            .get(&ex)
+            .cloned()
+            .or_else(|| tyctx.mono_expr_ty.borrow().get(&ex).cloned());
+        let mut typ = known.unwrap_or_else(|| match &mut ex.val {
             // HACK: pass the return value to lower via `type_args` see `TraitRes::visit_expr`
             ty::Expr::Call { path: _, args: _, type_args } => type_args.remove(0).val,
             ty::Expr::TraitMeth { trait_: _, args: _, type_args } => type_args.remove(0).val,
             // TODO: HACK: DANGER: ok so, when we mutate the inner type of `size_of::<T>` we
             // invalidate it's entry in the hashmap without removing it
-            ty::Expr::Builtin(ty::Builtin::SizeOf(..)) => ty::Ty::Int,
+            ty::Expr::Builtin(b) if b.type_arg().is_some() => b.type_of(),
             ex => unreachable!("only trait impl calls and function calls are replaced {:?}", ex),
         });
 
@@ -310,6 +448,7 @@ impl Expr {
         }
         let ty = Ty::lower(tyctx, &typ);
 
+        let span = ex.span;
         let mut lowered = match ex.val {
             ty::Expr::Ident(ident) => Expr::Ident { ident, ty },
             ty::Expr::AddrOf(expr) => Expr::AddrOf(box Expr::lower(tyctx, fold, *expr)),
@@ -320,12 +459,16 @@ impl Expr {
                 ident,
                 ty,
                 exprs: exprs.into_iter().map(|e| Expr::lower(tyctx, fold, e)).collect(),
+                len: tyctx
+                    .type_of_ident(ident, span)
+                    .and_then(|arr| Ty::lower(tyctx, &arr).array_len()),
+                loc: SrcLoc::lower(tyctx, span),
             },
             ty::Expr::FieldAccess { lhs, rhs } => {
                 let original_lhs_span = lhs.span;
 
                 let left = Expr::lower(tyctx, fold, *lhs);
-                let right = Expr::lower(tyctx, fold, *rhs);
+                let mut right = Expr::lower(tyctx, fold, *rhs);
 
                 fn get_type_of_struct_ident(
                     left: &Expr,
@@ -333,11 +476,9 @@ impl Expr {
                     tyctx: &TyCheckRes<'_, '_>,
                 ) -> Struct {
                     match left {
-                        Expr::Ident { ident, .. } => tyctx
-                            .type_of_ident(*ident, orig_span)
-                            .map(|t| deref_field(&Ty::lower(tyctx, &t), None))
-                            .expect("field access of non struct"),
-                        Expr::Array { ident: _, exprs: _, ty: inner } => {
+                        // The lowered type is the specialized one in a generic function
+                        Expr::Ident { ty, .. } => deref_field(ty, None),
+                        Expr::Array { ty: inner, .. } => {
                             get_type_of_struct_ident(left, orig_span, tyctx)
                         }
                         _ => unreachable!("lhs of field access must be struct {:?}", left),
@@ -345,6 +486,14 @@ impl Expr {
                 }
 
                 let def = get_type_of_struct_ident(&left, original_lhs_span, tyctx);
+                // The array is a field so the length comes from the struct not a variable in scope
+                if let Expr::Array { ident, len, .. } = &mut right {
+                    *len = def
+                        .fields
+                        .iter()
+                        .find(|f| f.ident == *ident)
+                        .and_then(|f| f.ty.array_len());
+                }
 
                 Expr::FieldAccess { lhs: box left, def, rhs: box right }
             }
@@ -356,6 +505,7 @@ impl Expr {
                 lhs: box Expr::lower(tyctx, fold, *lhs),
                 rhs: box Expr::lower(tyctx, fold, *rhs),
                 ty,
+                loc: SrcLoc::lower(tyctx, span),
             },
             ty::Expr::Parens(expr) => Expr::Parens(box Expr::lower(tyctx, fold, *expr)),
             ty::Expr::Call { path, args, type_args } => {
@@ -385,7 +535,11 @@ impl Expr {
                                         .collect(),
                                     ret: crate::rawptr!(ret.into_spanned(DUMMY)),
                                     generics: vec![],
-                                    stmts: ty::Block { stmts: crate::raw_vec![], span: DUMMY },
+                                    stmts: ty::Block {
+                                        stmts: crate::raw_vec![],
+                                        tail: None,
+                                        span: DUMMY,
+                                    },
                                     // TODO: confirm if we are here it can only be a fn ptr
                                     kind: ty::FuncKind::Pointer,
                                     span: DUMMY,
@@ -397,10 +551,10 @@ impl Expr {
                     })
                     .expect("a declared function or a function pointer as a parameter");
                 Expr::Call {
+                    args: lower_args(tyctx, fold, &func, args, span),
+                    def: Func::lower_called(tyctx, fold, &func, type_args.slice()),
                     path,
-                    args: args.into_iter().map(|a| Expr::lower(tyctx, fold, a)).collect(),
                     type_args: type_args.into_iter().map(|t| Ty::lower(tyctx, &t.val)).collect(),
-                    def: Func::lower_minus_body(tyctx, fold, &func),
                 }
             }
             ty::Expr::TraitMeth { trait_, args, type_args } => {
@@ -415,11 +569,7 @@ impl Expr {
                 let ident = trait_.segs.last().unwrap();
                 let func = tyctx
                     .trait_solve
-                    .impls
-                    .get(&trait_)
-                    .expect("function is defined")
-                    .get(&type_args.iter().map(|t| &t.val).collect::<Vec<_>>())
-                    .cloned()
+                    .impl_for(&trait_, &type_args.iter().map(|t| &t.val).collect::<Vec<_>>())
                     // TODO: what was I THINKING hmmm in what way is this ok...
                     .unwrap_or(&f);
                 // .expect(&format!("types have impl {:?}", tyctx.trait_solve));
@@ -445,28 +595,111 @@ impl Expr {
                 }
             }
             ty::Expr::EnumInit { path, variant, items } => {
-                let ident = path.segs.last().unwrap();
-                let enu = tyctx.name_enum.get(ident).expect("struct is defined");
-                Expr::EnumInit {
-                    path,
-                    variant,
-                    items: items.into_iter().map(|f| Expr::lower(tyctx, fold, f)).collect(),
-                    def: Enum::lower(tyctx, (*enu).clone()),
+                // The type of the expression has the items of a generic enum substituted
+                let mut def = if let Ty::Enum { def, .. } = ty {
+                    def
+                } else {
+                    let ident = path.segs.last().unwrap();
+                    let enu = tyctx.name_enum.get(ident).expect("struct is defined");
+                    Enum::lower(tyctx, (*enu).clone())
+                };
+                let items =
+                    items.into_iter().map(|f| Expr::lower(tyctx, fold, f)).collect::<Vec<_>>();
+                // Inference can leave the other half of `result::err(e)` generic, the items of
+                // the variant are known though
+                if let Some(var) = def.variants.iter_mut().find(|v| v.ident == variant) {
+                    for (item_ty, item) in var.types.iter_mut().zip(&items) {
+                        if let Ty::Generic { .. } = item_ty {
+                            *item_ty = item.type_of();
+                        }
+                    }
                 }
+                Expr::EnumInit { path, variant, items, def }
             }
             ty::Expr::ArrayInit { items } => Expr::ArrayInit {
                 items: items.into_iter().map(|f| Expr::lower(tyctx, fold, f)).collect(),
                 ty,
             },
+            ty::Expr::If { cond, blk, els } => {
+                let tmp = Ident::new(ex.span, &format!("__value{}", ex.span.start));
+                Expr::If {
+                    cond: box Expr::lower(tyctx, fold, *cond),
+                    blk: Block::lower_value(tyctx, fold, blk, tmp, &ty),
+                    els: Block::lower_value(tyctx, fold, els, tmp, &ty),
+                    tmp,
+                    ty,
+                }
+            }
+            ty::Expr::Match { expr, arms } => {
+                let tmp = Ident::new(ex.span, &format!("__value{}", ex.span.start));
+                let expr = Expr::lower(tyctx, fold, *expr);
+                let match_ty = expr.type_of();
+                Expr::Match {
+                    expr: box expr,
+                    arms: arms
+                        .into_iter()
+                        .map(|a| MatchArm {
+                            pat: Pat::lower(tyctx, fold, a.pat.val),
+                            guard: a.guard.map(|g| Expr::lower(tyctx, fold, g)),
+                            blk: Block::lower_value(tyctx, fold, a.blk, tmp, &ty),
+                        })
+                        .collect(),
+                    match_ty,
+                    tmp,
+                    ty,
+                }
+            }
+            ty::Expr::Cast { expr, ty: _ } => {
+                // The inner expression is already folded, a constant is cast the same way the
+                // generated code would
+                let expr = Expr::lower(tyctx, fold, *expr);
+                match &expr {
+                    Expr::Value(val) => match val.to_ast().cast(&typ) {
+                        Some(val) => Expr::Value(Val::lower(val)),
+                        None => Expr::Cast { expr: box expr, ty },
+                    },
+                    _ => Expr::Cast { expr: box expr, ty },
+                }
+            }
+            ty::Expr::Try(_) => unreachable!("`?` is desugared before type checking"),
+            ty::Expr::InlineAsm { asm, ty: asm_ty } => Expr::InlineAsm {
+                asm: AsmBlock::lower(tyctx, fold, asm),
+                reg: ty::AsmBlock::result_reg(&asm_ty.val),
+                ty,
+            },
             ty::Expr::Value(v) => Expr::Value(Val::lower(v.val)),
-            ty::Expr::Builtin(b) => Expr::Builtin(match b {
-                ty::Builtin::Bottom => Builtin::Bottom,
-                ty::Builtin::SizeOf(t) => Builtin::SizeOf(Ty::lower(tyctx, &t.get().val)),
-            }),
+            ty::Expr::Builtin(b) => match b {
+                ty::Builtin::Bottom => Expr::Builtin(Builtin::Bottom),
+                ty::Builtin::SizeOf(t) => {
+                    Expr::Builtin(Builtin::SizeOf(Ty::lower(tyctx, &t.get().val)))
+                }
+                ty::Builtin::AlignOf(t) => {
+                    Expr::Builtin(Builtin::AlignOf(Ty::lower(tyctx, &t.get().val)))
+                }
+                ty::Builtin::OffsetOf(t, field) => {
+                    Expr::Builtin(Builtin::OffsetOf(Ty::lower(tyctx, &t.get().val), field))
+                }
+                ty::Builtin::Len(expr) => {
+                    Expr::Builtin(Builtin::Len(box Expr::lower(tyctx, fold, *expr)))
+                }
+                // Generic functions are lowered once they are monomorphized so this is the name
+                // of the concrete type
+                ty::Builtin::TypeName(t) => {
+                    Expr::Value(Val::Str(Ident::new(DUMMY, &t.get().val.to_string())))
+                }
+                ty::Builtin::StaticAssert(..) => {
+                    unreachable!("@static_assert is only parsed as a statement")
+                }
+                ty::Builtin::Line => {
+                    Expr::Value(Val::Int(SrcLoc::lower(tyctx, span).line as isize))
+                }
+                ty::Builtin::File => Expr::Value(Val::Str(SrcLoc::lower(tyctx, span).file)),
+                ty::Builtin::CallerLocation => caller_location(tyctx, span),
+            },
         };
         // Evaluate any constant expressions, since this is the lowered Expr we don't have to worry
         // about destroying spans or hashes since we gather types for everything
-        lowered.const_fold(tyctx);
+        lowered.const_fold(tyctx, span);
         lowered
     }
 
@@ -475,11 +708,11 @@ impl Expr {
             Expr::Ident { ident: _, ty } => ty.clone(),
             Expr::Deref { indir: _, expr: _, ty } => ty.clone(),
             Expr::AddrOf(expr) => Ty::Ptr(box expr.type_of()),
-            Expr::Array { ident: _, exprs, ty } => {
+            Expr::Array { exprs, ty, .. } => {
                 Ty::Array { size: exprs.len(), ty: box ty.clone() }
             }
             Expr::Urnary { op: _, expr: _, ty } => ty.clone(),
-            Expr::Binary { op: _, lhs: _, rhs: _, ty } => ty.clone(),
+            Expr::Binary { ty, .. } => ty.clone(),
             Expr::Parens(expr) => expr.type_of(),
             Expr::Call { def, .. } => def.ret.clone(),
             Expr::TraitMeth { trait_: _, args: _, type_args: _, def } => def.method.ret.clone(),
@@ -495,10 +728,17 @@ impl Expr {
                 def: def.clone(),
             },
             Expr::ArrayInit { items: _, ty } => ty.clone(),
+            Expr::If { ty, .. }
+            | Expr::Match { ty, .. }
+            | Expr::Cast { ty, .. }
+            | Expr::InlineAsm { ty, .. } => ty.clone(),
             Expr::Value(v) => v.type_of(),
             Expr::Builtin(b) => match b {
                 Builtin::Bottom => Ty::Bottom,
-                Builtin::SizeOf(..) => Ty::Int,
+                Builtin::SizeOf(..)
+                | Builtin::AlignOf(..)
+                | Builtin::OffsetOf(..)
+                | Builtin::Len(..) => Ty::Int,
             },
         }
     }
@@ -508,7 +748,7 @@ impl Expr {
             Expr::Ident { ident, ty } => *ident,
             Expr::Deref { indir, expr, ty } => expr.as_ident(),
             Expr::AddrOf(expr) => expr.as_ident(),
-            Expr::Array { ident, exprs, ty } => *ident,
+            Expr::Array { ident, .. } => *ident,
             Expr::Parens(expr) => expr.as_ident(),
             Expr::FieldAccess { lhs, def, rhs } => todo!(),
             Expr::StructInit { path, fields, def } => todo!(),
@@ -548,8 +788,9 @@ pub enum LValue {
     Deref { indir: usize, expr: Box<LValue>, ty: Ty },
     /// Access an array by index `[expr][expr]`.
     ///
-    /// Each `exprs` represents an access of a dimension of the array.
-    Array { ident: Ident, exprs: Vec<Expr>, ty: Ty },
+    /// Each `exprs` represents an access of a dimension of the array. `len` is the length of the
+    /// array when it is known and `loc` is reported when the index is out of bounds.
+    Array { ident: Ident, exprs: Vec<Expr>, ty: Ty, len: Option<usize>, loc: SrcLoc },
     /// Access the fields of a struct `expr.expr.expr;`.
     FieldAccess {
         lhs: Box<LValue>,
@@ -564,15 +805,19 @@ impl LValue {
     fn lower(tyctx: &TyCheckRes<'_, '_>, fold: &Folder, ex: ty::Expression) -> Self {
         match ex.val {
             ty::Expr::Ident(ident) => {
-                let ty = Ty::lower(
-                    tyctx,
-                    &tyctx.type_of_ident(ident, ex.span).unwrap_or_else(|| {
-                        panic!(
-                            "type checking missed ident {}",
-                            Error::error_with_span(tyctx, ex.span, "foolio")
-                        )
-                    }),
-                );
+                let mut typ = tyctx.type_of_ident(ident, ex.span).unwrap_or_else(|| {
+                    panic!(
+                        "type checking missed ident {}",
+                        Error::error_with_span(tyctx, ex.span, "foolio")
+                    )
+                });
+                // `let x = *p;` in a specialization of a generic function has `x: T`
+                if typ.has_generics() {
+                    if let Some(ty) = tyctx.mono_expr_ty.borrow().get(&ex) {
+                        typ = ty.resolve().unwrap_or_else(|| ty.clone());
+                    }
+                }
+                let ty = Ty::lower(tyctx, &typ);
                 LValue::Ident { ident, ty }
             }
             ty::Expr::Deref { indir, expr } => {
@@ -588,7 +833,9 @@ impl LValue {
                 LValue::Array {
                     ident,
                     exprs: exprs.into_iter().map(|expr| Expr::lower(tyctx, fold, expr)).collect(),
+                    len: ty.array_len(),
                     ty,
+                    loc: SrcLoc::lower(tyctx, ex.span),
                 }
             }
             ty::Expr::FieldAccess { lhs, rhs } => {
@@ -597,7 +844,7 @@ impl LValue {
                 let def = match &left {
                     LValue::Ident { ty, .. } => deref_field(ty, Some(&left)),
                     LValue::Deref { indir: _, expr: _, ty } => deref_field(ty, Some(&left)),
-                    LValue::Array { ident: _, exprs: _, ty: _ } => todo!(),
+                    LValue::Array { .. } => todo!(),
                     _ => unreachable!("lhs of field access must be struct {:?}", left),
                 };
 
@@ -642,7 +889,9 @@ impl LValue {
                         ty::Expr::Array { ident, exprs } => LValue::Array {
                             ident,
                             exprs: exprs.into_iter().map(|e| Expr::lower(tyctx, fold, e)).collect(),
+                            len: right_ty.array_len(),
                             ty: right_ty,
+                            loc: SrcLoc::lower(tyctx, rhs.span),
                         },
                         _ => unreachable!("lhs of field access must be struct {:?}", rhs),
                     },
@@ -679,9 +928,13 @@ impl LValue {
             LValue::Deref { indir, expr, ty } => {
                 Expr::Deref { indir: *indir, expr: box expr.as_expr(), ty: ty.clone() }
             }
-            LValue::Array { ident, exprs, ty } => {
-                Expr::Array { ident: *ident, exprs: exprs.to_vec(), ty: ty.clone() }
-            }
+            LValue::Array { ident, exprs, ty, len, loc } => Expr::Array {
+                ident: *ident,
+                exprs: exprs.to_vec(),
+                ty: ty.clone(),
+                len: *len,
+                loc: loc.clone(),
+            },
             LValue::FieldAccess { lhs, rhs, def, .. } => Expr::FieldAccess {
                 lhs: box lhs.as_expr(),
                 rhs: box rhs.as_expr(),
@@ -696,7 +949,7 @@ pub enum Ty {
     /// A generic type parameter `<T>`.
     ///
     /// N.B. This may be used as a type argument but should not be.
-    Generic { ident: Ident, bound: Option<Path> },
+    Generic { ident: Ident, bounds: Vec<Path> },
     /// A static array of `size` containing item of `ty`.
     Array { size: usize, ty: Box<Ty> },
     /// A struct defined by the user.
@@ -730,12 +983,18 @@ pub enum Ty {
     ///
     /// The number of dereferences represented as layers.
     Ref(Box<Ty>),
+    /// A view of a run of `ty` whose length is only known at runtime.
+    ///
+    /// The value is the address of a pointer to the first item followed by the length.
+    Slice(Box<Ty>),
     /// A const array of `char`'s, the size is known at compile time.
     ///
     /// `"hello, world"`
     ConstStr(usize),
     /// A positive or negative number.
     Int,
+    /// An integer with a fixed width and signedness `u8`, `i32`.
+    SizedInt(IntTy),
     /// An ascii character.
     ///
     /// todo: Could be bound to between 0-255
@@ -748,37 +1007,60 @@ pub enum Ty {
     Void,
     /// The never/uninhabited type.
     Bottom,
+    /// The value given to a const generic parameter, only used as a type argument.
+    ConstArg(usize),
 }
 
 impl Ty {
-    fn lower(tyctx: &TyCheckRes<'_, '_>, ty: &ty::Ty) -> Self {
+    crate fn lower(tyctx: &TyCheckRes<'_, '_>, ty: &ty::Ty) -> Self {
         match ty {
             ty::Ty::Array { size, ty: t } => {
                 Ty::Array { ty: box Ty::lower(tyctx, &t.val), size: *size }
             }
-            ty::Ty::Struct { ident, gen } => Ty::Struct {
-                ident: *ident,
-                gen: gen.iter().map(|t| Ty::lower(tyctx, &t.val)).collect(),
-                def: tyctx
-                    .name_struct
-                    .get(ident)
-                    .map(|e| Struct::lower(tyctx, (*e).clone()))
-                    .unwrap(),
-            },
-            ty::Ty::Enum { ident, gen } => Ty::Enum {
-                ident: *ident,
-                gen: gen.iter().map(|t| Ty::lower(tyctx, &t.val)).collect(),
-                def: tyctx.name_enum.get(ident).map(|e| Enum::lower(tyctx, (*e).clone())).unwrap(),
+            ty::Ty::ConstArray { size, ty: t } => match &size.val {
+                // Like any other generic the size is only known in a monomorphized function
+                ty::Expr::Ident(gen) if !tyctx.name_const.contains_key(gen) => {
+                    Ty::Generic { ident: *gen, bounds: vec![] }
+                }
+                _ => Ty::Array {
+                    ty: box Ty::lower(tyctx, &t.val),
+                    size: tyctx.array_size(size).expect("array size is evaluated in type checking"),
+                },
             },
+            ty::Ty::ConstArg(val) => Ty::ConstArg(*val),
+            ty::Ty::Struct { ident, gen } => {
+                let struc = tyctx.name_struct[ident];
+                let mut def = Struct::lower(tyctx, struc.clone());
+                for (field, fdef) in def.fields.iter_mut().zip(&struc.fields) {
+                    field.ty = subst_generics(tyctx, &fdef.ty.get().val, &struc.generics, gen);
+                }
+                let gen = gen.iter().map(|t| Ty::lower(tyctx, &t.val)).collect();
+                Ty::Struct { ident: *ident, gen, def }
+            }
+            ty::Ty::Enum { ident, gen } => {
+                let enu = tyctx.name_enum[ident];
+                let mut def = Enum::lower(tyctx, enu.clone());
+                for (variant, vdef) in def.variants.iter_mut().zip(&enu.variants) {
+                    for (ty, tdef) in variant.types.iter_mut().zip(vdef.types.iter()) {
+                        *ty = subst_generics(tyctx, &tdef.val, &enu.generics, gen);
+                    }
+                }
+                let gen = gen.iter().map(|t| Ty::lower(tyctx, &t.val)).collect();
+                Ty::Enum { ident: *ident, gen, def }
+            }
             ty::Ty::Ptr(t) => Ty::Ptr(box Ty::lower(tyctx, &t.val)),
             ty::Ty::Ref(t) => Ty::Ref(box Ty::lower(tyctx, &t.val)),
+            ty::Ty::Slice(t) => Ty::Slice(box Ty::lower(tyctx, &t.val)),
             ty::Ty::ConstStr(size) => Ty::ConstStr(*size),
             ty::Ty::Int => Ty::Int,
+            ty::Ty::SizedInt(ity) => Ty::SizedInt(*ity),
             ty::Ty::Char => Ty::Char,
             ty::Ty::Float => Ty::Float,
             ty::Ty::Bool => Ty::Bool,
             ty::Ty::Void => Ty::Void,
-            ty::Ty::Generic { ident, bound } => Ty::Generic { ident: *ident, bound: bound.clone() },
+            ty::Ty::Generic { ident, bounds } => {
+                Ty::Generic { ident: *ident, bounds: bounds.clone() }
+            }
             ty::Ty::Path(_) => {
                 println!("lowering path: should not happen");
                 Ty::lower(tyctx, &tyctx.name_res.resolve_name(ty, tyctx).unwrap())
@@ -792,38 +1074,82 @@ impl Ty {
         }
     }
 
+    /// The number of elements when `self` is an array.
+    crate fn array_len(&self) -> Option<usize> {
+        if let Ty::Array { size, .. } = self {
+            Some(*size)
+        } else {
+            None
+        }
+    }
+
     crate fn size(&self) -> usize {
         match self {
             Ty::Array { size, ty } => ty.size() * size,
-            Ty::Struct { ident: _, gen: _, def } => def.fields.iter().map(|f| f.ty.size()).sum(),
+            Ty::Struct { ident: _, gen: _, def } => def.size(),
+            // TODO: tag size
             Ty::Enum { ident: _, gen: _, def } => {
-                let variants = def
-                    .variants
-                    .iter()
-                    .map(|v| v.types.iter().map(|t| t.size()).sum::<usize>())
-                    .max()
-                    .unwrap_or(0);
-                // TODO: tag size
-                let tag = 8_usize;
-                tag + variants
+                round_up(def.variants.iter().map(|v| v.end()).max().unwrap_or(ENUM_TAG_SIZE), 8)
             }
             Ty::Ptr(_)         // A pointer is 8 bytes
             | Ty::Func { .. }  // A function pointer is 8 bytes
             | Ty::Ref(_)       // this is just a pointer
+            | Ty::Slice(_)     // the address of the pointer and length
             | Ty::ConstStr(..) // same, pointer
             | Ty::Int
             | Ty::Char
             | Ty::Float
             | Ty::Bool => 8,
+            Ty::SizedInt(ity) => ity.size(),
             Ty::Void => 0,
             _ => unreachable!("generic type should be monomorphized {:?}", self),
         }
     }
 
+    /// The alignment of the type in bytes, a struct is aligned like its most aligned field.
+    crate fn align(&self) -> usize {
+        match self {
+            Ty::Array { ty, .. } => ty.align(),
+            Ty::Struct { def, .. } => def.align(),
+            // The tag
+            Ty::Enum { .. } => 8,
+            Ty::Void => 1,
+            ty => ty.size(),
+        }
+    }
+
+    /// The offset of `field` from the start of the struct, see `Struct::field_offsets`.
+    crate fn field_offset(&self, field: Ident) -> Option<usize> {
+        let def = if let Ty::Struct { def, .. } = self { def } else { return None };
+        def.field_offset(field)
+    }
+
+    /// The type of `*ptr` is recorded as a reference to the pointer, peel a pointer off for
+    /// each reference to get the type of the value.
+    crate fn resolve(&self) -> Ty {
+        let mut ty = self;
+        let mut deref = 0;
+        while let Ty::Ref(t) = ty {
+            deref += 1;
+            ty = t;
+        }
+        while deref > 0 {
+            match ty {
+                Ty::Ptr(t) => ty = t,
+                _ => break,
+            }
+            deref -= 1;
+        }
+        ty.clone()
+    }
+
     crate fn null_val(&self) -> Val {
         match self {
-            Ty::Ptr(_) | Ty::Ref(_) | Ty::ConstStr(..) | Ty::Int | Ty::Float => Val::Int(0),
+            Ty::Ptr(_) | Ty::Ref(_) | Ty::Slice(_) | Ty::ConstStr(..) | Ty::Int | Ty::Float => {
+                Val::Int(0)
+            }
             Ty::Char | Ty::Bool => Val::Int(0),
+            Ty::SizedInt(ity) => Val::SizedInt(0, *ity),
             _ => unreachable!("generic type should be monomorphized cannot create null value"),
         }
     }
@@ -882,13 +1208,16 @@ impl fmt::Display for Ty {
             ),
             Ty::Ptr(t) => write!(f, "&{}", t),
             Ty::Ref(t) => write!(f, "*{}", t),
+            Ty::Slice(t) => write!(f, "[{}]", t),
             Ty::ConstStr(..) => write!(f, "string"),
             Ty::Int => write!(f, "int"),
+            Ty::SizedInt(ity) => write!(f, "{}", ity),
             Ty::Char => write!(f, "char"),
             Ty::Float => write!(f, "float"),
             Ty::Bool => write!(f, "bool"),
             Ty::Void => write!(f, "void"),
             Ty::Bottom => write!(f, "!"),
+            Ty::ConstArg(val) => write!(f, "{}", val),
         }
     }
 }
@@ -903,6 +1232,16 @@ impl Param {
     fn lower(tyctx: &TyCheckRes<'_, '_>, v: ty::Param) -> Self {
         Param { ident: v.ident, ty: Ty::lower(tyctx, &v.ty.get().val) }
     }
+
+    /// The parameters of `func` followed by the hidden `@caller_location` if it has one.
+    fn lower_all(tyctx: &TyCheckRes<'_, '_>, func: &ty::Func) -> Vec<Self> {
+        let mut params: Vec<_> =
+            func.params.iter().map(|p| Param::lower(tyctx, p.clone())).collect();
+        if tyctx.caller_location_fns.contains(&func.span) {
+            params.push(Param { ident: caller_location_param(), ty: Ty::ConstStr(0) });
+        }
+        params
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -912,8 +1251,101 @@ pub struct Block {
 
 impl Block {
     fn lower(tyctx: &TyCheckRes<'_, '_>, fold: &Folder, b: ty::Block) -> Self {
-        Block { stmts: b.stmts.into_iter().map(|s| Stmt::lower(tyctx, fold, s)).collect() }
+        Block::lower_scope(tyctx, fold, b.stmts.into_iter(), false, || None)
+    }
+
+    /// Lower the block of an `if` or `match` expression, the tail becomes an assignment to `tmp`.
+    fn lower_value(
+        tyctx: &TyCheckRes<'_, '_>,
+        fold: &Folder,
+        b: ty::Block,
+        tmp: Ident,
+        ty: &Ty,
+    ) -> Self {
+        let tail = b.tail;
+        Block::lower_scope(tyctx, fold, b.stmts.into_iter(), false, || {
+            let tail = tail?;
+            Some(if tail.val.has_bottom_type() {
+                Stmt::Builtin(ty::Builtin::Bottom, SrcLoc::lower(tyctx, tail.span))
+            } else {
+                Stmt::Assign {
+                    lval: LValue::Ident { ident: tmp, ty: ty.clone() },
+                    rval: Expr::lower(tyctx, fold, *tail),
+                    is_let: false,
+                }
+            })
+        })
+    }
+
+    /// Lower the statements of a block followed by it's `tail`, the deferred statements run in
+    /// reverse order when the end of the block is reached.
+    fn lower_scope(
+        tyctx: &TyCheckRes<'_, '_>,
+        fold: &Folder,
+        stmts: impl IntoIterator<Item = ty::Statement>,
+        is_loop: bool,
+        tail: impl FnOnce() -> Option<Stmt>,
+    ) -> Self {
+        tyctx.defer_scopes.borrow_mut().push(DeferScope { is_loop, stmts: vec![] });
+
+        let mut lowered = vec![];
+        let mut diverges = false;
+        for stmt in stmts {
+            if let ty::Stmt::Defer(deferred) = stmt.val {
+                tyctx.defer_scopes.borrow_mut().last_mut().unwrap().stmts.push(*deferred);
+                continue;
+            }
+            diverges = stmt.val.has_bottom_type()
+                || matches!(stmt.val, ty::Stmt::Ret(_) | ty::Stmt::Exit | ty::Stmt::Break);
+            lowered.push(Stmt::lower(tyctx, fold, stmt));
+        }
+        lowered.extend(tail());
+
+        let scope = tyctx.defer_scopes.borrow_mut().pop().unwrap();
+        // The `return` or `break` that ends the block already ran them
+        if !diverges {
+            lowered.extend(scope.stmts.into_iter().rev().map(|s| Stmt::lower(tyctx, fold, s)));
+        }
+        Block { stmts: lowered }
+    }
+}
+
+/// Replace the generic parameters of an enum or struct definition in `ty` with the types it was
+/// used with, the fields of `option<int>` are the size of an `int` not `T`. The arguments are
+/// substituted before lowering so a const argument can size an array `[N; int]`.
+fn subst_generics(
+    tyctx: &TyCheckRes<'_, '_>,
+    ty: &ty::Ty,
+    generics: &[ty::Generic],
+    gen: &[ty::Type],
+) -> Ty {
+    let mut ty = ty.clone();
+    for (generic, arg) in generics.iter().zip(gen) {
+        ty.subst_generic(generic.ident, &arg.val);
+    }
+    Ty::lower(tyctx, &ty)
+}
+
+/// The statements deferred in a block that is being lowered.
+#[derive(Debug, Default)]
+crate struct DeferScope {
+    /// Is this the body of a loop, `break` stops running deferred statements after this block.
+    is_loop: bool,
+    stmts: Vec<ty::Statement>,
+}
+
+/// The deferred statements of the enclosing blocks in the order they run, innermost block first.
+///
+/// When `to_loop` is set this stops at the body of the innermost loop.
+fn deferred_stmts(tyctx: &TyCheckRes<'_, '_>, to_loop: bool) -> Vec<ty::Statement> {
+    let mut stmts = vec![];
+    for scope in tyctx.defer_scopes.borrow().iter().rev() {
+        stmts.extend(scope.stmts.iter().rev().cloned());
+        if to_loop && scope.is_loop {
+            break;
+        }
     }
+    stmts
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -935,7 +1367,23 @@ pub enum Pat {
         size: usize,
         items: Vec<Pat>,
     },
+    /// Match a struct by it's fields, fields that are not mentioned match anything.
+    Struct {
+        path: Path,
+        fields: Vec<(Ident, Pat)>,
+    },
+    /// An inclusive range `1..=9`.
+    Range {
+        start: Val,
+        end: Val,
+    },
+    Or(Vec<Pat>),
+    At {
+        ident: Ident,
+        pat: Box<Pat>,
+    },
     Bind(Binding),
+    Wild,
 }
 
 impl Pat {
@@ -959,10 +1407,53 @@ impl Pat {
                 size,
                 items: items.into_iter().map(|p| Pat::lower(tyctx, fold, p.val)).collect(),
             },
+            ty::Pat::Struct { path, fields, rest: _ } => Pat::Struct {
+                path,
+                fields: fields
+                    .into_iter()
+                    .map(|f| (f.ident, Pat::lower(tyctx, fold, f.pat.val)))
+                    .collect(),
+            },
+            ty::Pat::Range { start, end } => {
+                Pat::Range { start: Val::lower(start.val), end: Val::lower(end.val) }
+            }
+            ty::Pat::Or(alts) => {
+                Pat::Or(alts.into_iter().map(|p| Pat::lower(tyctx, fold, p.val)).collect())
+            }
+            ty::Pat::At { ident, pat } => {
+                Pat::At { ident, pat: box Pat::lower(tyctx, fold, pat.val) }
+            }
             ty::Pat::Bind(b) => Pat::Bind(match b {
                 ty::Binding::Wild(w) => Binding::Wild(w),
                 ty::Binding::Value(v) => Binding::Value(Val::lower(v.val)),
             }),
+            ty::Pat::Wild => Pat::Wild,
+        }
+    }
+
+    /// The inclusive ranges of values the outermost part of this pattern can match, for enums
+    /// this is the variant index.
+    ///
+    /// `None` when the pattern may match any value.
+    crate fn head_ranges(&self) -> Option<Vec<(isize, isize)>> {
+        match self {
+            Pat::Enum { idx, .. } => Some(vec![(*idx as isize, *idx as isize)]),
+            Pat::Range { start, end } => Some(vec![(start.as_int()?, end.as_int()?)]),
+            Pat::Bind(Binding::Value(val)) => {
+                let v = val.as_int()?;
+                Some(vec![(v, v)])
+            }
+            Pat::Or(alts) => {
+                let mut ranges = vec![];
+                for alt in alts {
+                    ranges.extend(alt.head_ranges()?);
+                }
+                Some(ranges)
+            }
+            Pat::At { pat, .. } => pat.head_ranges(),
+            Pat::Array { .. } | Pat::Struct { .. } | Pat::Bind(Binding::Wild(_)) | Pat::Wild => {
+                None
+            }
         }
     }
 }
@@ -970,6 +1461,7 @@ impl Pat {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MatchArm {
     pub pat: Pat,
+    pub guard: Option<Expr>,
     pub blk: Block,
 }
 
@@ -977,6 +1469,7 @@ impl MatchArm {
     fn lower(tyctx: &TyCheckRes<'_, '_>, fold: &Folder, arm: ty::MatchArm) -> Self {
         MatchArm {
             pat: Pat::lower(tyctx, fold, arm.pat.val),
+            guard: arm.guard.map(|g| Expr::lower(tyctx, fold, g)),
             blk: Block::lower(tyctx, fold, arm.blk),
         }
     }
@@ -996,6 +1489,37 @@ pub struct TraitMethExpr {
     pub type_args: Vec<Ty>,
 }
 
+/// A block of inline assembly with the values of its operands lowered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmBlock {
+    pub assembly: Vec<ty::Instruction>,
+    /// Each value is moved into its register before the block runs.
+    pub inputs: Vec<(ty::Location, Expr)>,
+    /// Each register is moved into its variable after the block runs.
+    pub outputs: Vec<(ty::Location, LValue)>,
+    /// The registers the block changes that are not outputs.
+    pub clobbers: Vec<ty::Location>,
+}
+
+impl AsmBlock {
+    fn lower(tyctx: &TyCheckRes<'_, '_>, fold: &Folder, asm: ty::AsmBlock) -> Self {
+        AsmBlock {
+            assembly: asm.assembly,
+            inputs: asm
+                .inputs
+                .into_iter()
+                .map(|op| (op.reg, Expr::lower(tyctx, fold, op.expr)))
+                .collect(),
+            outputs: asm
+                .outputs
+                .into_iter()
+                .map(|op| (op.reg, LValue::lower(tyctx, fold, op.expr)))
+                .collect(),
+            clobbers: asm.clobbers,
+        }
+    }
+}
+
 #[derive(Clone, derive_help::Debug, PartialEq, Eq)]
 pub enum Stmt {
     /// Variable declaration `int x;`
@@ -1026,18 +1550,20 @@ pub enum Stmt {
     ///
     /// A void return.
     Exit,
+    /// Jump past the end of the enclosing loop `break`.
+    Break,
     /// A block of statements `{ stmts }`
     Block(Block),
     /// A block of inline assembly.
-    InlineAsm(ty::AsmBlock),
-    /// A builtin used in statement position.
-    Builtin(ty::Builtin),
+    InlineAsm(AsmBlock),
+    /// A builtin used in statement position, reaching `@bottom` reports `loc`.
+    Builtin(ty::Builtin, SrcLoc),
 }
 
 impl Stmt {
     fn lower(tyctx: &TyCheckRes<'_, '_>, fold: &Folder, mut statement: ty::Statement) -> Self {
         if statement.val.has_bottom_type() {
-            return Stmt::Builtin(ty::Builtin::Bottom);
+            return Stmt::Builtin(ty::Builtin::Bottom, SrcLoc::lower(tyctx, statement.span));
         }
         match statement.val.clone() {
             ty::Stmt::Const(var) => Stmt::Const(Const {
@@ -1047,11 +1573,19 @@ impl Stmt {
                 mutable: var.mutable,
                 is_global: false,
             }),
-            ty::Stmt::Assign { lval, rval, ty, is_let } => Stmt::Assign {
-                lval: LValue::lower(tyctx, fold, lval),
-                rval: Expr::lower(tyctx, fold, rval),
-                is_let,
-            },
+            ty::Stmt::Assign { lval, rval, ty, is_let } => {
+                let lval = LValue::lower(tyctx, fold, lval);
+                let mut rval = Expr::lower(tyctx, fold, rval);
+                // `let b: buf<2> = buf { .. }`, only the variable knows the struct's type arguments
+                if let (
+                    Expr::StructInit { def, .. },
+                    LValue::Ident { ty: Ty::Struct { def: var_def, .. }, .. },
+                ) = (&mut rval, &lval)
+                {
+                    *def = var_def.clone();
+                }
+                Stmt::Assign { lval, rval, is_let }
+            }
             ty::Stmt::Call(ty::Spanned {
                 val: ty::Expr::Call { path, args, mut type_args },
                 ..
@@ -1062,16 +1596,17 @@ impl Stmt {
                         .visit_stmt(&mut statement);
                 }
                 let func = tyctx.var_func.name_func.get(ident).expect("function is defined");
+                let def = Func::lower_called(tyctx, fold, func, type_args.slice());
                 Stmt::Call {
                     expr: CallExpr {
+                        args: lower_args(tyctx, fold, func, args, statement.span),
                         path,
-                        args: args.into_iter().map(|a| Expr::lower(tyctx, fold, a)).collect(),
                         type_args: type_args
                             .into_iter()
                             .map(|a| Ty::lower(tyctx, &a.val))
                             .collect(),
                     },
-                    def: Func::lower(tyctx, fold, func),
+                    def,
                 }
             }
             ty::Stmt::Call(_) => unreachable!("call statement without call expression"),
@@ -1093,11 +1628,7 @@ impl Stmt {
                 };
                 let func = tyctx
                     .trait_solve
-                    .impls
-                    .get(&trait_)
-                    .expect("function is defined")
-                    .get(&type_args.iter().map(|t| &t.val).collect::<Vec<_>>())
-                    .cloned()
+                    .impl_for(&trait_, &type_args.iter().map(|t| &t.val).collect::<Vec<_>>())
                     .unwrap_or(&f);
 
                 Stmt::TraitMeth {
@@ -1119,11 +1650,11 @@ impl Stmt {
             },
             ty::Stmt::While { cond, blk: stmts } => Stmt::While {
                 cond: Expr::lower(tyctx, fold, cond),
-                stmts: Block::lower(tyctx, fold, stmts),
+                stmts: Block::lower_scope(tyctx, fold, stmts.stmts.into_iter(), true, || None),
             },
             ty::Stmt::Match { expr, arms } => {
                 let expr = Expr::lower(tyctx, fold, expr);
-                let ty = expr.type_of();
+                let ty = expr.type_of().resolve();
                 Stmt::Match {
                     expr,
                     arms: arms.into_iter().map(|a| MatchArm::lower(tyctx, fold, a)).collect(),
@@ -1136,14 +1667,52 @@ impl Stmt {
                     .get_fn_by_span(ex.span)
                     .and_then(|f| tyctx.var_func.name_func.get(&f).map(|f| &f.ret.get().val))
                     .unwrap();
+                // The declared return type of a generic function is still generic
+                let ty = match tyctx.mono_ret.borrow().as_ref() {
+                    Some(ret) if ty.has_generics() => Ty::lower(tyctx, ret),
+                    _ => Ty::lower(tyctx, ty),
+                };
+                let span = ex.span;
                 let expr = Expr::lower(tyctx, fold, ex);
 
-                Stmt::Ret(expr, Ty::lower(tyctx, ty))
+                let deferred = deferred_stmts(tyctx, false);
+                // An enum or struct has to be built somewhere before it can be returned
+                let is_adt = matches!(expr, Expr::EnumInit { .. } | Expr::StructInit { .. });
+                if deferred.is_empty() && !is_adt {
+                    return Stmt::Ret(expr, ty);
+                }
+                // The value is returned as it was before the deferred statements ran
+                let mut stmts = vec![];
+                let expr = if let Expr::Value(_) = expr {
+                    expr
+                } else {
+                    let tmp = Ident::new(span, &format!("__ret{}", span.start));
+                    stmts.push(Stmt::Assign {
+                        lval: LValue::Ident { ident: tmp, ty: ty.clone() },
+                        rval: expr,
+                        is_let: true,
+                    });
+                    Expr::Ident { ident: tmp, ty: ty.clone() }
+                };
+                stmts.extend(deferred.into_iter().map(|s| Stmt::lower(tyctx, fold, s)));
+                stmts.push(Stmt::Ret(expr, ty));
+                Stmt::Block(Block { stmts })
             }
-            ty::Stmt::Exit => Stmt::Exit,
-            ty::Stmt::Block(ty::Block { stmts, .. }) => Stmt::Block(Block {
-                stmts: stmts.into_iter().map(|s| Stmt::lower(tyctx, fold, s)).collect(),
-            }),
+            ty::Stmt::Exit | ty::Stmt::Break => {
+                let is_break = matches!(statement.val, ty::Stmt::Break);
+                let mut stmts: Vec<_> = deferred_stmts(tyctx, is_break)
+                    .into_iter()
+                    .map(|s| Stmt::lower(tyctx, fold, s))
+                    .collect();
+                let exit = if is_break { Stmt::Break } else { Stmt::Exit };
+                if stmts.is_empty() {
+                    return exit;
+                }
+                stmts.push(exit);
+                Stmt::Block(Block { stmts })
+            }
+            ty::Stmt::Block(blk) => Stmt::Block(Block::lower(tyctx, fold, blk)),
+            ty::Stmt::Defer(_) => unreachable!("deferred statements are collected by their block"),
             ty::Stmt::AssignOp { lval, rval, op } => {
                 let ty = tyctx.expr_ty.get(&lval).unwrap();
                 Stmt::Assign {
@@ -1153,12 +1722,20 @@ impl Stmt {
                         rhs: box Expr::lower(tyctx, fold, rval),
                         op: BinOp::lower(op),
                         ty: Ty::lower(tyctx, ty),
+                        loc: SrcLoc::lower(tyctx, statement.span),
                     },
                     is_let: false,
                 }
             }
-            ty::Stmt::InlineAsm(asm) => Stmt::InlineAsm(asm),
-            ty::Stmt::Builtin(btin) => Stmt::Builtin(btin),
+            ty::Stmt::InlineAsm(asm) => Stmt::InlineAsm(AsmBlock::lower(tyctx, fold, asm)),
+            ty::Stmt::Builtin(btin) => {
+                // Generic functions are lowered once they are monomorphized so the condition only
+                // uses concrete types
+                if let ty::Builtin::StaticAssert(cond, msg) = &btin {
+                    const_eval::eval_static_assert(tyctx, cond, *msg);
+                }
+                Stmt::Builtin(btin, SrcLoc::lower(tyctx, statement.span))
+            }
         }
     }
 }
@@ -1191,6 +1768,47 @@ impl Struct {
             generics: s.generics.into_iter().map(|t| Generic::lower(tyctx, t)).collect(),
         }
     }
+
+    /// The offset of each field from the start of the struct, like C each field starts at a
+    /// multiple of its alignment so `{ a: u8, b: int }` puts `b` at 8.
+    crate fn field_offsets(&self) -> Vec<usize> {
+        let mut at = 0;
+        self.fields
+            .iter()
+            .map(|f| {
+                let offset = round_up(at, f.ty.align());
+                at = offset + f.ty.size();
+                offset
+            })
+            .collect()
+    }
+
+    crate fn field_offset(&self, field: Ident) -> Option<usize> {
+        let idx = self.fields.iter().position(|f| f.ident == field)?;
+        Some(self.field_offsets()[idx])
+    }
+
+    crate fn align(&self) -> usize {
+        self.fields.iter().map(|f| f.ty.align()).max().unwrap_or(1)
+    }
+
+    /// The size including the padding after the last field, an array of the struct keeps every
+    /// element aligned.
+    crate fn size(&self) -> usize {
+        let end = match (self.field_offsets().last(), self.fields.last()) {
+            (Some(at), Some(f)) => at + f.ty.size(),
+            _ => 0,
+        };
+        round_up(end, self.align())
+    }
+}
+
+/// The bytes of the tag at the start of every enum.
+const ENUM_TAG_SIZE: usize = 8;
+
+/// Round `at` up to the next multiple of `align`.
+fn round_up(at: usize, align: usize) -> usize {
+    at + (align - at % align) % align
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1208,6 +1826,28 @@ impl Variant {
             types: v.types.into_iter().map(|t| Ty::lower(tyctx, &t.val)).collect(),
         }
     }
+
+    /// The offset of each item from the start of the enum, the items follow the tag and like the
+    /// fields of a struct each starts at a multiple of its alignment.
+    crate fn item_offsets(&self) -> Vec<usize> {
+        let mut at = ENUM_TAG_SIZE;
+        self.types
+            .iter()
+            .map(|t| {
+                let offset = round_up(at, t.align());
+                at = offset + t.size();
+                offset
+            })
+            .collect()
+    }
+
+    /// The end of the last item, the tag alone for a variant without items.
+    fn end(&self) -> usize {
+        match (self.item_offsets().last(), self.types.last()) {
+            (Some(at), Some(t)) => at + t.size(),
+            _ => ENUM_TAG_SIZE,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1240,16 +1880,16 @@ pub enum Adt {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Generic {
     pub ident: Ident,
-    pub bound: Option<Path>,
+    pub bounds: Vec<Path>,
 }
 
 impl Generic {
     fn lower(tyctx: &TyCheckRes<'_, '_>, g: ty::Generic) -> Self {
-        Generic { ident: g.ident, bound: g.bound }
+        Generic { ident: g.ident, bounds: g.bounds }
     }
 
     crate fn to_type(&self) -> Ty {
-        Ty::Generic { ident: self.ident, bound: self.bound.clone() }
+        Ty::Generic { ident: self.ident, bounds: self.bounds.clone() }
     }
 }
 
@@ -1271,26 +1911,84 @@ pub struct Func {
 
 impl Func {
     fn lower(tyctx: &TyCheckRes<'_, '_>, fold: &Folder, func: &ty::Func) -> Self {
+        // A trait method call lowers the method in the middle of the caller
+        let outer = tyctx.defer_scopes.take();
+        let stmts =
+            Block::lower_scope(tyctx, fold, func.stmts.stmts.iter().cloned(), false, || None);
+        *tyctx.defer_scopes.borrow_mut() = outer;
         Func {
             ret: Ty::lower(tyctx, &func.ret.get().val),
             ident: func.ident,
-            params: func.params.iter().map(|p| Param::lower(tyctx, p.clone())).collect(),
+            params: Param::lower_all(tyctx, func),
             generics: func.generics.iter().map(|g| Ty::lower(tyctx, &g.to_type())).collect(),
             kind: func.kind,
-            stmts: func.stmts.stmts.iter().map(|s| Stmt::lower(tyctx, fold, s.clone())).collect(),
+            stmts: stmts.stmts,
         }
     }
 
+    /// The signature of `func` for a call to it. The body is lowered with the declaration, at a
+    /// call the body of a generic function still has its type parameters.
     fn lower_minus_body(tyctx: &TyCheckRes<'_, '_>, fold: &Folder, func: &ty::Func) -> Self {
         Func {
             ret: Ty::lower(tyctx, &func.ret.get().val),
             ident: func.ident,
-            params: func.params.iter().map(|p| Param::lower(tyctx, p.clone())).collect(),
+            params: Param::lower_all(tyctx, func),
             generics: func.generics.iter().map(|g| Ty::lower(tyctx, &g.to_type())).collect(),
             kind: func.kind,
             stmts: vec![],
         }
     }
+
+    /// The definition of `func` as it is called with `type_args`, the return type is the one of
+    /// the specialized function so a call to `map_get::<int, int>` returns an `option<int>`.
+    fn lower_called(
+        tyctx: &TyCheckRes<'_, '_>,
+        fold: &Folder,
+        func: &ty::Func,
+        type_args: &[ty::Type],
+    ) -> Self {
+        let mut def = Func::lower_minus_body(tyctx, fold, func);
+        if !func.generics.is_empty() {
+            def.ret = subst_generics(tyctx, &func.ret.get().val, &func.generics, type_args);
+        }
+        def
+    }
+}
+
+/// The hidden parameter of a function that uses `@caller_location`, it is not a valid
+/// identifier so it can not clash with the other parameters.
+fn caller_location_param() -> Ident {
+    Ident::new(DUMMY, "@caller_location")
+}
+
+/// The value of `@caller_location` at `span`. In a function with the hidden parameter it is
+/// passed along, so nested calls report the outermost caller, otherwise it is `span` itself.
+fn caller_location(tyctx: &TyCheckRes<'_, '_>, span: ty::Range) -> Expr {
+    let in_tracked_fn = tyctx
+        .caller_location_fns
+        .iter()
+        .any(|f| f.file_id == span.file_id && f.start <= span.start && span.end <= f.end);
+    if in_tracked_fn {
+        Expr::Ident { ident: caller_location_param(), ty: Ty::ConstStr(0) }
+    } else {
+        Expr::Value(Val::Str(Ident::new(DUMMY, &SrcLoc::lower(tyctx, span).to_string())))
+    }
+}
+
+/// Lower the arguments of a call to `func` at `span`, adding the hidden argument of a function
+/// that uses `@caller_location`.
+fn lower_args(
+    tyctx: &TyCheckRes<'_, '_>,
+    fold: &Folder,
+    func: &ty::Func,
+    args: Vec<ty::Expression>,
+    span: ty::Range,
+) -> Vec<Expr> {
+    let mut args: Vec<_> = args.into_iter().map(|a| Expr::lower(tyctx, fold, a)).collect();
+    if tyctx.caller_location_fns.contains(&func.span) {
+        args.push(caller_location(tyctx, span));
+    }
+    args
 }
 
 #[allow(dead_code)]
@@ -1325,7 +2023,15 @@ impl Impl {
     fn lower(tyctx: &TyCheckRes<'_, '_>, fold: &Folder, imp: &ty::Impl) -> Self {
         Impl {
             ident: imp.path.clone(),
-            type_arguments: imp.type_arguments.iter().map(|t| Ty::lower(tyctx, &t.val)).collect(),
+            // The type arguments of an impl are never resolved during type checking
+            type_arguments: imp
+                .type_arguments
+                .iter()
+                .map(|t| {
+                    let ty = tyctx.name_res.resolve_name(&t.val, tyctx);
+                    Ty::lower(tyctx, ty.as_ref().unwrap_or(&t.val))
+                })
+                .collect(),
             method: Func::lower(tyctx, fold, &imp.method),
         }
     }
@@ -1366,44 +2072,65 @@ fn lower_item(
                 lowered.push(Item::Func(Func::lower(tyctx, fold, func)));
             } else {
                 // Monomorphize
-                for mono in tyctx.mono_func(func) {
+                for (mono, expr_tys) in tyctx.mono_func(func) {
+                    // Calls from other generic functions may have specialized it already
+                    if lowered.iter().any(|it| matches!(it, Item::Func(f) if f.ident == mono.ident))
+                    {
+                        continue;
+                    }
+                    *tyctx.mono_expr_ty.borrow_mut() = expr_tys;
+                    *tyctx.mono_ret.borrow_mut() = Some(mono.ret.get().val.clone());
                     lowered.push(Item::Func(Func::lower(tyctx, fold, &mono)));
                 }
+                tyctx.mono_expr_ty.borrow_mut().clear();
+                tyctx.mono_ret.borrow_mut().take();
             }
         }
         ty::Decl::Impl(i) => {
-            let mut specialized = i.method.clone();
-            // TODO: @name-cleanup
-            specialized.ident = Ident::new(
-                i.method.ident.span(),
-                &format!(
-                    "{}{}",
-                    i.method.ident,
-                    i.type_arguments.iter().map(|t| t.val.to_string()).collect::<String>()
-                ),
-            );
-            lowered.push(Item::Func(Func::lower(tyctx, fold, &specialized)));
+            // The method was named after the type arguments when it was parsed
+            lowered.push(Item::Func(Func::lower(tyctx, fold, &i.method)));
+        }
+        ty::Decl::Const(var) => {
+            let ty = Ty::lower(tyctx, &var.ty.val);
+            // Use the evaluated value if the const had to be evaluated
+            let init = match tyctx.const_vals.get(&var.ident) {
+                Some(val) => Expr::lower_const(tyctx, val, &ty),
+                None => Expr::lower(tyctx, fold, var.init.clone()),
+            };
+            lowered.push(Item::Const(Const {
+                ty,
+                ident: var.ident,
+                init,
+                mutable: var.mutable,
+                is_global: true,
+            }))
         }
-        ty::Decl::Const(var) => lowered.push(Item::Const(Const {
-            ty: Ty::lower(tyctx, &var.ty.val),
-            ident: var.ident,
-            init: Expr::lower(tyctx, fold, var.init.clone()),
-            mutable: var.mutable,
-            is_global: true,
-        })),
         _ => {}
     }
 }
 
-crate fn lower_items(items: &[ty::Declaration], tyctx: TyCheckRes<'_, '_>) -> Vec<Item> {
+/// Lower the type checked items, errors are only found by constant folding.
+crate fn lower_items(
+    items: &[ty::Declaration],
+    tyctx: TyCheckRes<'_, '_>,
+) -> Result<Vec<Item>, usize> {
     let fold = Folder::default();
     let mut lowered = vec![];
 
+    tyctx.errors.poisoned(false);
     for item in tyctx.imported_items.iter() {
         lower_item(item, &tyctx, &fold, &mut lowered)
     }
     for item in items.iter() {
         lower_item(item, &tyctx, &fold, &mut lowered);
     }
-    lowered
+
+    if !tyctx.errors.is_empty() {
+        for e in tyctx.errors.errors().iter() {
+            eprintln!("{}", e)
+        }
+        // TODO: see ./src/main.rs for comment
+        return Err(tyctx.errors.errors().len());
+    }
+    Ok(lowered)
 }
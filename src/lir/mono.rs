@@ -1,14 +1,14 @@
 use crate::{
     ast::{
         parse::symbol::Ident,
-        types::{self as ty, Generic, Path, Spany, Ty, DUMMY},
+        types::{self as ty, mangle_ty, Generic, Path, Spany, Ty, DUMMY},
     },
     error::Error,
     typeck::{
         generic::{GenericArgument, Node},
         TyCheckRes,
     },
-    visit::VisitMut,
+    visit::{Visit, VisitMut},
 };
 
 use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
@@ -17,16 +17,19 @@ struct GenSubstitution<'a, 'b> {
     generic: &'a Generic,
     ty: &'a Ty,
     tcxt: &'a TyCheckRes<'a, 'b>,
+    /// The types of the expressions in this specialization, see `TyCheckRes::mono_expr_ty`.
+    expr_tys: &'a mut HashMap<ty::Expression, Ty>,
 }
 
-impl<'ast, 'b> VisitMut<'ast> for GenSubstitution<'ast, 'b> {
+// Any borrow of the AST will do, `visit_expr` walks a reborrow of `expr` so it can use it after
+impl<'ast> VisitMut<'ast> for GenSubstitution<'_, '_> {
     fn visit_func(&mut self, func: &'ast mut ty::Func) {
         // TODO: this is REALLY bad since we lock anytime we do something like this, and making a
         // bunch of thrown away allocations to the interner isn't ideal
         func.ident = Ident::new(
             func.ident.span(),
             // TODO: @name-cleanup
-            &format!("{}{}", func.ident.name(), self.ty.to_string().replace(' ', "")),
+            &format!("{}{}", func.ident.name(), mangle_ty(self.ty)),
         );
         crate::visit::walk_mut_func(self, func);
     }
@@ -37,14 +40,44 @@ impl<'ast, 'b> VisitMut<'ast> for GenSubstitution<'ast, 'b> {
     }
 
     fn visit_expr(&mut self, expr: &'ast mut ty::Expression) {
-        if let Some(t) = self.tcxt.expr_ty.get(expr) {
-            if t.has_generics() && t.generics().contains(&&self.generic.ident) {
-                self.tcxt.mono_expr_ty.borrow_mut().insert(expr.clone(), self.ty.clone());
-            } else if let ty::Expr::Builtin(ty::Builtin::SizeOf(t)) = &mut expr.val {
-                t.set(self.ty.clone().into_spanned(DUMMY));
+        // An earlier generic of this specialization may have already changed the type
+        let ty = self.expr_tys.get(expr).or_else(|| self.tcxt.expr_ty.get(expr)).cloned();
+        // A child like `@size_of::<T>` can change even when the type of `expr` does not
+        let before = ty.is_some().then(|| expr.clone());
+
+        crate::visit::walk_mut_expr(self, &mut *expr);
+
+        // `show::<T>(x)` in a generic function calls the specialization of `show` for this one
+        let type_args = match &mut expr.val {
+            ty::Expr::Call { type_args, .. } => type_args.slice_mut(),
+            ty::Expr::TraitMeth { type_args, .. } => &mut type_args[..],
+            _ => &mut [],
+        };
+        for ty_arg in type_args {
+            self.visit_ty(ty_arg);
+        }
+
+        // A const generic used as a value becomes the value it was given
+        let is_const_arg = if let Ty::ConstArg(val) = self.ty {
+            if matches!(expr.val, ty::Expr::Ident(id) if id == self.generic.ident) {
+                expr.val = ty::Expr::Value(ty::Val::Int(*val as isize).into_spanned(expr.span));
+            }
+            true
+        } else {
+            false
+        };
+
+        // When a const generic or a type argument is substituted any expression could have
+        // changed, which also changes where it hashes to
+        if let Some(mut ty) = ty {
+            if is_const_arg
+                || ty.generics().contains(&&self.generic.ident)
+                || before.as_ref() != Some(expr)
+            {
+                ty.subst_generic(self.generic.ident, self.ty);
+                self.expr_tys.insert(expr.clone(), ty);
             }
         }
-        crate::visit::walk_mut_expr(self, expr);
     }
 }
 
@@ -57,36 +90,39 @@ impl<'ast, 'b> TraitRes<'ast, 'b> {
     crate fn new(tcxt: &'ast TyCheckRes<'ast, 'b>, type_args: Vec<&'ast Ty>) -> Self {
         Self { tcxt, type_args }
     }
+
+    /// The types a trait method call is solved for, `<<heap>::alloc>` keeps its own type
+    /// arguments and `<<T>::alloc>` takes the ones of the specialization.
+    fn trait_args<'t>(&'t self, type_args: &'t [ty::Type]) -> Vec<&'t Ty> {
+        if type_args.is_empty() || type_args.iter().any(|t| t.val.has_generics()) {
+            self.type_args.clone()
+        } else {
+            type_args.iter().map(|t| &t.val).collect()
+        }
+    }
 }
 
 impl<'ast, 'a> VisitMut<'ast> for TraitRes<'ast, 'a> {
     fn visit_stmt(&mut self, stmt: &'ast mut ty::Statement) {
         let mut x = None;
         if let ty::Stmt::TraitMeth(ty::Spanned {
-            val: ty::Expr::TraitMeth { trait_, type_args: _, args },
+            val: ty::Expr::TraitMeth { trait_, type_args, args },
             ..
         }) = &mut stmt.val
         {
-            let ident = trait_.segs.last().unwrap();
-            if let Some(i) =
-                self.tcxt.trait_solve.impls.get(trait_).and_then(|imp| imp.get(&self.type_args))
-            {
+            let trait_args = self.trait_args(type_args);
+            if let Some(i) = self.tcxt.trait_solve.impl_for(trait_, &trait_args) {
                 // TODO: fucking terrible
                 let args2: &'static mut [_] = args.clone().leak();
                 for arg in args2 {
                     self.visit_expr(arg);
                 }
-                // TODO: @name-cleanup
-                let ident = format!(
-                    "{}{}",
-                    trait_,
-                    self.type_args.iter().map(|t| t.to_string()).collect::<Vec<_>>().join("0"),
-                );
+                // The impl method is named for the types it implements the trait for
                 x = Some(ty::Stmt::Call(
                     ty::Expr::Call {
-                        path: trait_.clone(),
+                        path: Path::single(i.method.ident),
                         args: args.to_vec(),
-                        type_args: crate::raw_vec![i.method.ret.get().clone()],
+                        type_args: crate::raw_vec![],
                     }
                     .into_spanned(DUMMY),
                 ));
@@ -99,11 +135,7 @@ impl<'ast, 'a> VisitMut<'ast> for TraitRes<'ast, 'a> {
                         &format!(
                             "`{}` is not implemented for `<{}>`",
                             trait_,
-                            self.type_args
-                                .iter()
-                                .map(|t| t.to_string())
-                                .collect::<Vec<_>>()
-                                .join(", "),
+                            trait_args.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", "),
                         )
                     )
                 )
@@ -118,32 +150,18 @@ impl<'ast, 'a> VisitMut<'ast> for TraitRes<'ast, 'a> {
 
     fn visit_expr(&mut self, expr: &'ast mut ty::Expression) {
         let mut x = None;
-        if let ty::Expr::TraitMeth { trait_, type_args: _, args } = &expr.val {
-            let ident = trait_.segs.last().unwrap();
-            if let Some(i) =
-                self.tcxt.trait_solve.impls.get(trait_).and_then(|imp| imp.get(&self.type_args))
-            {
+        if let ty::Expr::TraitMeth { trait_, type_args, args } = &expr.val {
+            let trait_args = self.trait_args(type_args);
+            if let Some(i) = self.tcxt.trait_solve.impl_for(trait_, &trait_args) {
                 // TODO: fucking terrible
                 let args2: &'static mut [_] = args.clone().leak();
                 for arg in args2 {
                     // Incase there is a function/trait method call as an argument
                     self.visit_expr(arg);
                 }
-                // TODO: @name-cleanup
-                let ident = Ident::new(
-                    DUMMY,
-                    &format!(
-                        "{}{}",
-                        trait_,
-                        self.type_args
-                            .iter()
-                            .map(|t| t.to_string().replace(' ', ""))
-                            .collect::<Vec<_>>()
-                            .join("0"),
-                    ),
-                );
+                // The impl method is named for the types it implements the trait for
                 x = Some(ty::Expr::Call {
-                    path: Path::single(ident),
+                    path: Path::single(i.method.ident),
                     args: args.to_vec(),
                     type_args: crate::raw_vec![i.method.ret.get().clone()],
                 });
@@ -156,11 +174,7 @@ impl<'ast, 'a> VisitMut<'ast> for TraitRes<'ast, 'a> {
                         &format!(
                             "`{}` is not implemented for `<{}>`",
                             trait_,
-                            self.type_args
-                                .iter()
-                                .map(|t| t.to_string())
-                                .collect::<Vec<_>>()
-                                .join(", "),
+                            trait_args.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", "),
                         )
                     )
                 )
@@ -174,7 +188,9 @@ impl<'ast, 'a> VisitMut<'ast> for TraitRes<'ast, 'a> {
 }
 
 impl TyCheckRes<'_, '_> {
-    crate fn mono_func(&self, func: &ty::Func) -> Vec<ty::Func> {
+    /// Every specialization of `func` and of the generic functions it calls, each with the types
+    /// of it's expressions that changed.
+    crate fn mono_func(&self, func: &ty::Func) -> Vec<(ty::Func, HashMap<ty::Expression, Ty>)> {
         let node = Node::Func(func.ident);
         let mut mono_items = vec![];
         // Resolved type mono's so `T` -> `int` for function `foo`
@@ -182,26 +198,88 @@ impl TyCheckRes<'_, '_> {
             // Mono the original function
             let mono_funcs = sub_mono_generic(func, res_list, self);
             mono_items.extend_from_slice(&mono_funcs);
-            // If `foo` was generic itself then any calls to generic functions inside of `foo`
-            // are dependent on the mono of `foo`
-            let relations = self.generic_res.generic_dag().get(&node).unwrap();
-            for node in relations.child_iter().filter(|n| matches!(n, Node::Func(_))) {
-                let dep_func = self.var_func.name_func.get(&node.name()).unwrap();
-                let mono_dep_funcs = sub_mono_generic(dep_func, res_list, self);
-                mono_items.extend_from_slice(&mono_dep_funcs)
+        }
+        // Calls with the same type arguments, inferred or not, share one specialization
+        let mut seen = HashSet::default();
+        mono_items.retain(|(f, _)| seen.insert(f.ident));
+
+        // If `foo` was generic itself then any calls to generic functions inside of `foo` are
+        // specialized with the type arguments of the call, `alloc::<vec<T>>(1)` in `foo::<int>`
+        // needs `alloc::<vec<int>>`
+        let mut idx = 0;
+        while idx < mono_items.len() {
+            let mut calls = GenericCalls { tcxt: self, calls: vec![] };
+            calls.visit_func(&mono_items[idx].0);
+            for (callee, type_args) in calls.calls {
+                let dep = specialize(callee, &type_args, self);
+                if seen.insert(dep.0.ident) {
+                    mono_items.push(dep);
+                }
             }
+            idx += 1;
         }
         // println!("{:#?}", mono_items);
         mono_items
     }
 }
 
+/// Collects the calls to generic functions with known type arguments.
+struct GenericCalls<'t, 'ast, 'a> {
+    tcxt: &'t TyCheckRes<'ast, 'a>,
+    calls: Vec<(&'t ty::Func, Vec<Ty>)>,
+}
+
+impl<'v> Visit<'v> for GenericCalls<'_, '_, '_> {
+    fn visit_expr(&mut self, expr: &'v ty::Expression) {
+        if let ty::Expr::Call { path, type_args, .. } = &expr.val {
+            let callee = self.tcxt.var_func.name_func.get(path.segs.last().unwrap());
+            if let Some(callee) =
+                callee.filter(|f| !f.generics.is_empty() && matches!(f.kind, ty::FuncKind::Normal))
+            {
+                // A generic struct argument `vec<int>` is still fully known
+                let known = |t: &ty::Type| {
+                    !matches!(t.val, Ty::Path(..)) && t.val.generics().is_empty()
+                };
+                if !type_args.is_empty() && type_args.iter().all(known) {
+                    self.calls.push((callee, type_args.iter().map(|t| t.val.clone()).collect()));
+                }
+            }
+        }
+        crate::visit::walk_expr(self, expr);
+    }
+}
+
+/// Monomorphize `func` for the type arguments `type_args` of a call.
+fn specialize(
+    func: &ty::Func,
+    type_args: &[Ty],
+    tcxt: &TyCheckRes<'_, '_>,
+) -> (ty::Func, HashMap<ty::Expression, Ty>) {
+    let mut mono = func.clone();
+    let mut expr_tys = HashMap::default();
+    for (i, (gen_param, ty)) in func.generics.iter().zip(type_args).enumerate() {
+        let mut subs = GenSubstitution { generic: gen_param, ty, tcxt, expr_tys: &mut expr_tys };
+        subs.visit_func(&mut mono);
+
+        // TODO: @name-cleanup
+        if i != func.generics.len() - 1 {
+            mono.ident = Ident::new(DUMMY, &format!("{}0", mono.ident.name()));
+        }
+    }
+
+    let mut trait_res = TraitRes { type_args: type_args.iter().collect(), tcxt };
+    trait_res.visit_func(&mut mono);
+    mono.generics.clear();
+
+    (mono, expr_tys)
+}
+
 /// Monomorphize `foo` and dependent functions with the known types `res_list`.
 fn sub_mono_generic(
     func: &ty::Func,
     res_list: &HashMap<usize, HashSet<GenericArgument<'_>>>,
     tcxt: &TyCheckRes<'_, '_>,
-) -> Vec<ty::Func> {
+) -> Vec<(ty::Func, HashMap<ty::Expression, Ty>)> {
     // We know that there is at least one generic so getting the zeroth value should be fine
     let number_of_specializations = res_list.get(&0).map_or(0, |m| m.len());
     let mut map: HashMap<_, Vec<_>> = HashMap::default();
@@ -210,6 +288,7 @@ fn sub_mono_generic(
     }
 
     let mut functions = vec![func.clone(); number_of_specializations];
+    let mut expr_tys = vec![HashMap::default(); number_of_specializations];
     for (idx, mut generics) in map.into_values().enumerate() {
         generics.sort_by(|a, b| a.gen_idx.cmp(&b.gen_idx));
 
@@ -223,7 +302,12 @@ fn sub_mono_generic(
                 .unwrap_or_else(|| panic!("{:?} {}", functions[idx], gen.gen_idx))
                 .clone();
             // Replace ALL uses of this generic and remove the generic parameters
-            let mut subs = GenSubstitution { generic: &gen_param, ty: &gen.ty, tcxt };
+            let mut subs = GenSubstitution {
+                generic: &gen_param,
+                ty: &gen.ty,
+                tcxt,
+                expr_tys: &mut expr_tys[idx],
+            };
             subs.visit_func(&mut functions[idx]);
 
             // TODO: @name-cleanup
@@ -246,5 +330,5 @@ fn sub_mono_generic(
         }
     }
 
-    functions
+    functions.into_iter().zip(expr_tys).collect()
 }
@@ -101,13 +101,16 @@ crate fn walk_lval<'ast, V: Visit<'ast>>(_visit: &mut V, lval: &LValue) {
     match lval {
         LValue::Ident { ident: _, ty: _ } => todo!(),
         LValue::Deref { indir: _, expr: _, ty: _ } => todo!(),
-        LValue::Array { ident: _, exprs: _, ty: _ } => todo!(),
+        LValue::Array { .. } => todo!(),
         LValue::FieldAccess { lhs: _, def: _, rhs: _, field_idx: _ } => todo!(),
     }
 }
 
 crate fn walk_match_arm<'ast, V: Visit<'ast>>(visit: &mut V, arms: &'ast [MatchArm]) {
-    for MatchArm { pat: _, blk: Block { stmts, .. }, .. } in arms {
+    for MatchArm { pat: _, guard, blk: Block { stmts, .. } } in arms {
+        if let Some(guard) = guard {
+            visit.visit_expr(guard);
+        }
         for stmt in stmts {
             visit.visit_stmt(stmt);
         }
@@ -154,13 +157,20 @@ crate fn walk_stmt<'ast, V: Visit<'ast>>(visit: &mut V, stmt: &'ast Stmt) {
             visit.visit_match_arm(arms);
         }
         Stmt::Ret(expr, _ty) => visit.visit_expr(expr),
-        Stmt::Exit => {}
+        Stmt::Exit | Stmt::Break => {}
         Stmt::Block(Block { stmts, .. }) => {
             for stmt in stmts {
                 visit.visit_stmt(stmt);
             }
         }
-        Stmt::InlineAsm(..) => {}
+        Stmt::InlineAsm(asm) => {
+            for (_, expr) in &asm.inputs {
+                visit.visit_expr(expr);
+            }
+            for (_, lval) in &asm.outputs {
+                visit.visit_lval(lval);
+            }
+        }
         Stmt::Builtin(..) => {}
     }
 }
@@ -219,6 +229,25 @@ crate fn walk_expr<'ast, V: Visit<'ast>>(visit: &mut V, expr: &'ast Expr) {
                 visit.visit_expr(expr);
             }
         }
+        Expr::If { cond, blk, els, .. } => {
+            visit.visit_expr(cond);
+            for stmt in blk.stmts.iter().chain(&els.stmts) {
+                visit.visit_stmt(stmt);
+            }
+        }
+        Expr::Match { expr, arms, .. } => {
+            visit.visit_expr(expr);
+            visit.visit_match_arm(arms);
+        }
+        Expr::Cast { expr, .. } => visit.visit_expr(expr),
+        Expr::InlineAsm { asm, .. } => {
+            for (_, expr) in &asm.inputs {
+                visit.visit_expr(expr);
+            }
+            for (_, lval) in &asm.outputs {
+                visit.visit_lval(lval);
+            }
+        }
         Expr::Value(_) => {
             // visit.visit_value(val);
         }
@@ -26,7 +26,7 @@ use std::{
     alloc::System,
     env,
     fs::{self},
-    path::Path,
+    path::{Path, PathBuf},
     time::Instant,
 };
 
@@ -64,8 +64,13 @@ fn process_file<'a>(
         false
     };
     let backend = args.value_of("backend");
-    let assemble = args.is_present("assemble");
+    let emit = args.value_of("emit");
+    let assemble = args.is_present("assemble") || emit.is_some();
+    let overflow_checks = args.is_present("overflow-checks");
+    let bounds_checks = !args.is_present("unchecked-indexing");
+    let freestanding = args.is_present("freestanding");
     let output = args.value_of("output");
+    let stdlib = args.value_of("stdlib").map_or_else(find_stdlib, PathBuf::from);
 
     // Tabs mess up our error messages
     let input = std::fs::read_to_string(path)?.replace('\t', "");
@@ -75,6 +80,7 @@ fn process_file<'a>(
 
     let (snd, rcv) = std::sync::mpsc::channel();
     let mut parser = AstBuilder::new(&input, path, snd);
+    parser.set_stdlib(stdlib);
     parser.parse().map_err(|e| PrettyError::from_parse(path, &input, e))?;
     let mut items = parser.into_items();
 
@@ -103,7 +109,7 @@ fn process_file<'a>(
 
     let mut lower_mem = Region::new(GLOBAL);
     let lower_time = Instant::now();
-    let lowered = lir::lower::lower_items(&items, tyck);
+    let lowered = lir::lower::lower_items(&items, tyck).map_err(|e| e.to_string())?;
 
     if need_stats {
         println!("    lowering:          {}s", lower_time.elapsed().as_secs_f64());
@@ -120,24 +126,42 @@ fn process_file<'a>(
 
     if backend == Some("llvm") {
         // let ctxt = inkwell::context::Context::create();
-        // let mut gen = gen::llvm::LLVMGen::new(&ctxt, Path::new(path));
+        // let mut gen =
+        //     gen::llvm::LLVMGen::new(&ctxt, Path::new(path), overflow_checks, bounds_checks);
     }
 
     let out = if let Some(out) = output { Path::new(out) } else { Path::new(path) };
 
     let mut gen_mem = Region::new(GLOBAL);
     let gen_time = Instant::now();
-    let mut gen = gen::asm::CodeGen::new(out);
+    let mut gen = gen::asm::CodeGen::new(out, overflow_checks, bounds_checks, freestanding);
     gen.visit_prog(&lowered);
-    gen.dump_asm()?;
+    let removed = gen.optimize();
+    if emit == Some("obj") {
+        gen.dump_obj()?;
+    } else {
+        gen.dump_asm()?;
+    }
 
     if need_stats {
         println!("    code generation:   {}s", gen_time.elapsed().as_secs_f64());
         println!("    code generation:   {}", gen_mem.change_and_reset());
+        println!("    peephole:          {} instructions removed", removed);
     }
     Ok(())
 }
 
+/// The `stdlib` directory next to the compiler or in a directory above it, so a compiler built
+/// in `target/debug` finds the one in the source tree.
+fn find_stdlib() -> PathBuf {
+    let exe = env::current_exe().unwrap_or_default();
+    exe.ancestors()
+        .skip(1)
+        .map(|dir| dir.join("stdlib"))
+        .find(|dir| dir.is_dir())
+        .unwrap_or_else(|| PathBuf::from("stdlib"))
+}
+
 /// Run it!
 fn main() {
     // std::panic::set_hook(Box::new(|panic_info| {
@@ -198,11 +222,41 @@ fn main() {
                 .short("a")
                 .help("enumc will produce assembly output"),
         )
+        .arg(
+            Arg::with_name("emit")
+                .long("emit")
+                .takes_value(true)
+                .possible_values(&["asm", "obj"])
+                .help("write assembly or an ELF object file that needs no assembler"),
+        )
+        .arg(
+            Arg::with_name("overflow-checks")
+                .long("overflow-checks")
+                .short("c")
+                .help("integer arithmetic that overflows aborts the program"),
+        )
+        .arg(
+            Arg::with_name("unchecked-indexing")
+                .long("unchecked-indexing")
+                .help("do not check array indexes against the length of the array"),
+        )
+        .arg(
+            Arg::with_name("freestanding")
+                .long("freestanding")
+                .short("f")
+                .help("emit a `_start` entry point so the program links without libc"),
+        )
         .arg(
             Arg::with_name("output")
                 .long("output")
                 .short("o")
                 .help("specify the assembly file name"),
+        )
+        .arg(
+            Arg::with_name("stdlib")
+                .long("stdlib")
+                .takes_value(true)
+                .help("the directory `import ::std::item` is read from"),
         );
 
     let matches = app.get_matches();
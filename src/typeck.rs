@@ -33,7 +33,10 @@
 //!     - checks declarations and assignments are valid
 //!     - checks the conditions of if, while and match statements
 //!       - checks matches have arm patterns that match the expression
+//!       - checks matches are exhaustive and warns about arms that can never match
 //!       - collects the bound variables for new identifiers live in each arm's block
+//! - Global consts that are not literals are checked once every function is, then evaluated at
+//!   compile time along with any `const fn` they call (see [const_eval])
 //!
 //! Without further ado type checking 🖖
 
@@ -59,8 +62,9 @@ use crate::{
         },
     },
     error::{Error, ErrorReport},
+    lir::lower::DeferScope,
     typeck::{
-        check::{is_truthy, resolve_ty, StmtCheck},
+        check::{block_ty, is_truthy, is_valid_cast, resolve_ty, StmtCheck},
         generic::TyRegion,
         infer::TypeInfer,
     },
@@ -68,6 +72,8 @@ use crate::{
 };
 
 crate mod check;
+crate mod const_eval;
+crate mod exhaustive;
 crate mod generic;
 crate mod infer;
 crate mod scope;
@@ -98,7 +104,7 @@ crate struct VarInFunction<'ast> {
 impl VarInFunction<'_> {
     crate fn get_fn_by_span(&self, span: Range) -> Option<Ident> {
         self.func_spans.iter().find_map(|(k, v)| {
-            if k.start <= span.start && k.end >= span.end {
+            if k.file_id == span.file_id && k.start <= span.start && k.end >= span.end {
                 Some(*v)
             } else {
                 None
@@ -113,12 +119,19 @@ impl VarInFunction<'_> {
 
 pub type AstReceiver = Receiver<ParseResult<ParsedBlob>>;
 
+/// The items of the standard library a file that imports anything from `::std` can use without
+/// naming them, `import ::std::option;` makes `result` usable too.
+const PRELUDE: &[&str] = &["option", "result"];
+
 #[derive(Default, derive_help::Debug)]
 crate struct TyCheckRes<'ast, 'input> {
     /// The name of the file being checked.
     crate file_names: HashMap<u64, &'input str>,
     /// The content of the file as a string.
     crate inputs: HashMap<u64, &'input str>,
+    /// The offset each line of a file starts at, see `file_and_line`.
+    #[dbg_ignore]
+    line_starts: HashMap<u64, Vec<usize>>,
 
     /// The name of the function currently in or `None` if global.
     #[dbg_ignore]
@@ -141,9 +154,16 @@ crate struct TyCheckRes<'ast, 'input> {
     ///
     /// Types reflect specializations that happens to the expressions. This
     /// only effects expressions where parameters are used (as far as I can tell) since
-    /// `GenSubstitution` removes all the typed statements and expressions.
+    /// `GenSubstitution` removes all the typed statements and expressions. It only holds the
+    /// specialization currently being lowered, each has it's own types.
     #[dbg_ignore]
     crate mono_expr_ty: RefCell<HashMap<Expression, Ty>>,
+    /// The return type of the specialization currently being lowered.
+    #[dbg_ignore]
+    crate mono_ret: RefCell<Option<Ty>>,
+    /// The deferred statements of each block around the statement being lowered, innermost last.
+    #[dbg_ignore]
+    crate defer_scopes: RefCell<Vec<DeferScope>>,
 
     // TODO: const folding could fold "const" idents but it would have to track between stmts which
     // we do not
@@ -161,6 +181,21 @@ crate struct TyCheckRes<'ast, 'input> {
     /// A mapping of enum name to enum def.
     #[dbg_ignore]
     crate name_enum: HashMap<Ident, &'ast Enum>,
+    /// A mapping of global const name to const def.
+    #[dbg_ignore]
+    crate name_const: HashMap<Ident, &'ast Const>,
+    /// The evaluated value of any global const that was not already a literal.
+    #[dbg_ignore]
+    crate const_vals: HashMap<Ident, const_eval::ConstVal>,
+    /// The spans of functions that use `@caller_location`, each call passes the location of the
+    /// call as a hidden last argument.
+    crate caller_location_fns: HashSet<Range>,
+    /// The evaluated size of each `[expr; ty]` array type, keyed by the span of the size.
+    #[dbg_ignore]
+    crate array_sizes: RefCell<HashMap<Range, usize>>,
+    /// The spans of the `match` expressions and statements that an `expr?` was desugared into.
+    #[dbg_ignore]
+    crate try_matches: RefCell<HashSet<Range>>,
 
     /// Resolve generic types at the end of type checking.
     #[dbg_ignore]
@@ -175,6 +210,15 @@ crate struct TyCheckRes<'ast, 'input> {
     /// Do we record uses of this variable during the following expr tree walk.
     #[dbg_ignore]
     record_used: bool,
+    /// Are we checking the body of a trait method.
+    #[dbg_ignore]
+    in_impl: bool,
+    /// The number of loops around the statement being checked, a deferred statement starts over.
+    #[dbg_ignore]
+    loop_depth: usize,
+    /// Are we checking a deferred statement.
+    #[dbg_ignore]
+    in_defer: bool,
 
     #[dbg_ignore]
     uniq_generic_instance_id: Cell<usize>,
@@ -188,12 +232,13 @@ crate struct TyCheckRes<'ast, 'input> {
     crate imported_items: Vec<&'static Declaration>,
 }
 
-impl<'input> TyCheckRes<'_, 'input> {
+impl<'ast, 'input> TyCheckRes<'ast, 'input> {
     crate fn new(input: &'input str, name: &'input str, rcv: AstReceiver) -> Self {
         let file_id = hash_file(name);
         Self {
             file_names: HashMap::from_iter([(file_id, name)]),
             inputs: HashMap::from_iter([(file_id, input)]),
+            line_starts: HashMap::from_iter([(file_id, line_starts(input))]),
             rcv: Some(rcv),
             record_used: true,
             ..Self::default()
@@ -201,6 +246,9 @@ impl<'input> TyCheckRes<'_, 'input> {
     }
 
     crate fn report_errors(&self) -> Result<(), usize> {
+        for w in self.errors.warnings().iter() {
+            eprintln!("{}", w)
+        }
         if !self.errors.is_empty() {
             for e in self.errors.errors().iter() {
                 eprintln!("{}", e)
@@ -211,6 +259,17 @@ impl<'input> TyCheckRes<'_, 'input> {
         Ok(())
     }
 
+    /// The file name and line number of `span`, used by `@line`, `@file` and anything that
+    /// reports where it failed at runtime.
+    crate fn file_and_line(&self, span: Range) -> (&'input str, usize) {
+        let file = self.file_names.get(&span.file_id).copied().unwrap_or("<unknown>");
+        let line = self
+            .line_starts
+            .get(&span.file_id)
+            .map_or(0, |starts| starts.partition_point(|at| *at <= span.start));
+        (file, line)
+    }
+
     crate fn unique_id(&self) -> usize {
         let x = self.uniq_generic_instance_id.get();
         self.uniq_generic_instance_id.set(x + 1);
@@ -223,6 +282,77 @@ impl<'input> TyCheckRes<'_, 'input> {
         old
     }
 
+    /// Make the item `import path;` names in the file `from` usable in the file `into`, an import
+    /// from `::std` also brings in the `PRELUDE`.
+    fn import_names(&mut self, path: &Path, from: u64, into: u64) {
+        let prelude = if path.segs[0] == "std" { PRELUDE } else { &[] };
+        let names = prelude.iter().map(|name| Ident::new(DUMMY, name));
+        for name in path.segs.last().copied().into_iter().chain(names) {
+            self.name_res.import_decl(from, into, name);
+        }
+    }
+
+    /// Type check the initializer of a global const that is not a literal, the initializer is
+    /// evaluated later by [`const_eval::eval_const`].
+    fn check_const_init(&mut self, var: &'ast Const) {
+        if const_eval::is_literal(&var.init.val) {
+            return;
+        }
+
+        self.errors.poisoned(false);
+        TypeInfer { tcxt: self }.visit_expr(&var.init);
+        self.visit_expr(&var.init);
+        if self.errors.is_poisoned() {
+            return;
+        }
+
+        let init_ty = resolve_ty(self, &var.init, self.expr_ty.get(&var.init));
+        let var_ty = self.eval_array_sizes(&var.ty, &[]).unwrap_or_else(|| var.ty.clone());
+        if !init_ty.as_ref().is_ty_eq(&Some(&var_ty.val)) {
+            self.errors.push_error(Error::error_with_span(
+                self,
+                var.init.span,
+                &format!(
+                    "[E0ty] const initializer has the wrong type\nexpected `{}` found `{}`",
+                    var_ty.val,
+                    init_ty.map_or("<unknown>".to_owned(), |t| t.to_string())
+                ),
+            ));
+        }
+    }
+
+    /// Make sure every branch of an `if` or `match` expression produces a value of the same
+    /// type, branches that never finish are skipped.
+    fn check_branch_tys(&mut self, blks: &[&Block], msg: &str) {
+        let mut expected: Option<Ty> = None;
+        for blk in blks {
+            match block_ty(self, blk) {
+                Some(Ty::Bottom) => {}
+                Some(Ty::Void) => {
+                    self.errors.push_error(Error::error_with_span(
+                        self,
+                        blk.span,
+                        "[E0ty] branch of `if` or `match` expression does not produce a value",
+                    ));
+                    self.errors.poisoned(true);
+                }
+                Some(ty) => match &expected {
+                    Some(exp) if !exp.is_ty_eq(&ty) => {
+                        self.errors.push_error(Error::error_with_span(
+                            self,
+                            blk.tail.as_ref().map_or(blk.span, |t| t.span),
+                            &format!("[E0ty] {}\nexpected `{}` found `{}`", msg, exp, ty),
+                        ));
+                        self.errors.poisoned(true);
+                    }
+                    Some(_) => {}
+                    None => expected = Some(ty),
+                },
+                None => {}
+            }
+        }
+    }
+
     // TODO: this should use the stuff from scope not a mix of `var_func`, `globals` etc.
     /// Find the `Type` of this identifier AND mark it as used.
     crate fn type_of_ident(&self, id: Ident, span: Range) -> Option<Ty> {
@@ -251,6 +381,123 @@ impl<'input> TyCheckRes<'_, 'input> {
             .cloned()
     }
 
+    /// The arms of the `match` that `expr?` is desugared into in a function returning `ret`.
+    ///
+    /// For an `option` this is `option::some(x) -> x, option::none -> { return option::none; }`
+    /// and for a `result` `result::ok(x) -> x, result::err(e) -> { return result::err(e); }`,
+    /// when the unwrapped value is not used the first arm is empty. Returns `None` if the
+    /// function returns anything else.
+    crate fn try_arms(&self, span: Range, ret: &Ty, is_value: bool) -> Option<Vec<MatchArm>> {
+        let (enm, found, early) = match ret {
+            Ty::Enum { ident, .. } if *ident == "option" => (*ident, "some", "none"),
+            Ty::Enum { ident, .. } if *ident == "result" => (*ident, "ok", "err"),
+            _ => return None,
+        };
+        let path = Path { segs: vec![enm], gen: vec![], span };
+        // Each `?` binds its own locals since they are scoped to the function
+        let val = Ident::new(span, &format!("__try{}", span.start));
+        let err = Ident::new(span, &format!("__err{}", span.start));
+        let (err_pats, err_items) = if early == "err" {
+            (
+                vec![Pat::Bind(Binding::Wild(err)).into_spanned(span)],
+                vec![Expr::Ident(err).into_spanned(span)],
+            )
+        } else {
+            (vec![], vec![])
+        };
+
+        let ret = Expr::EnumInit {
+            path: path.clone(),
+            variant: Ident::new(span, early),
+            items: err_items,
+        };
+        Some(vec![
+            MatchArm {
+                pat: Pat::Enum {
+                    path: path.clone(),
+                    variant: Ident::new(span, found),
+                    items: vec![Pat::Bind(Binding::Wild(val)).into_spanned(span)],
+                }
+                .into_spanned(span),
+                guard: None,
+                blk: Block {
+                    stmts: crate::raw_vec![],
+                    tail: is_value.then(|| box Expr::Ident(val).into_spanned(span)),
+                    span,
+                },
+                span,
+            },
+            MatchArm {
+                pat: Pat::Enum { path, variant: Ident::new(span, early), items: err_pats }
+                    .into_spanned(span),
+                guard: None,
+                blk: Block {
+                    stmts: crate::raw_vec![Stmt::Ret(ret.into_spanned(span)).into_spanned(span)],
+                    tail: None,
+                    span,
+                },
+                span,
+            },
+        ])
+    }
+
+    /// Evaluate the size of every `[expr; ty]` array in `ty`, a size that is one of the const
+    /// `generics` is left for monomorphization.
+    ///
+    /// Returns `None` if there was nothing to evaluate or a size could not be evaluated.
+    crate fn eval_array_sizes(&self, ty: &Type, generics: &[Generic]) -> Option<Type> {
+        Some(match &ty.val {
+            Ty::ConstArray { size, ty: arrty } => {
+                let elem =
+                    box self.eval_array_sizes(arrty, generics).unwrap_or_else(|| (**arrty).clone());
+                if matches!(
+                    &size.val,
+                    Expr::Ident(id) if generics.iter().any(|g| g.is_const && g.ident == *id)
+                ) {
+                    Ty::ConstArray { size: size.clone(), ty: elem }.into_spanned(ty.span)
+                } else {
+                    Ty::Array { size: self.array_size(size)?, ty: elem }.into_spanned(ty.span)
+                }
+            }
+            Ty::Array { size, ty: arrty } => {
+                Ty::Array { size: *size, ty: box self.eval_array_sizes(arrty, generics)? }
+                    .into_spanned(ty.span)
+            }
+            Ty::Ptr(inner) => {
+                Ty::Ptr(box self.eval_array_sizes(inner, generics)?).into_spanned(ty.span)
+            }
+            Ty::Ref(inner) => {
+                Ty::Ref(box self.eval_array_sizes(inner, generics)?).into_spanned(ty.span)
+            }
+            _ => return None,
+        })
+    }
+
+    /// The value of the const expression `size` in an array type `[size; ty]`.
+    crate fn array_size(&self, size: &Expression) -> Option<usize> {
+        if let Some(val) = self.array_sizes.borrow().get(&size.span) {
+            return Some(*val);
+        }
+        let val = const_eval::eval_size(self, size)?;
+        self.array_sizes.borrow_mut().insert(size.span, val);
+        Some(val)
+    }
+
+    /// Can `ty` be the argument of a generic bound by the trait `bound`.
+    ///
+    /// A generic has to name the bound itself, any other type needs an impl of the trait. A type
+    /// that is only generic inside like `vec<T>` is checked when it is monomorphized.
+    fn satisfies_bound(&self, ty: &Ty, bound: &Path) -> bool {
+        match ty {
+            Ty::Generic { bounds, .. } => bounds.contains(bound),
+            ty if ty.has_generics() => true,
+            ty => {
+                !self.trait_solve.traits.contains_key(bound)
+                    || self.trait_solve.impl_for(bound, &[ty]).is_some()
+            }
+        }
+    }
+
     crate fn patch_generic_from_path(&self, ty: &Type, gens_in_scope: &[Generic]) -> Option<Type> {
         // Then have another struct that walks all types and can check if there
         // is a matching generic in the scope and convert Path(T) -> Generic {T}
@@ -260,6 +507,21 @@ impl<'input> TyCheckRes<'_, 'input> {
                 ty: box self.patch_generic_from_path(arrty, gens_in_scope)?,
             }
             .into_spanned(ty.span),
+            Ty::ConstArray { size, ty: arrty } => {
+                let patched = self.patch_generic_from_path(arrty, gens_in_scope);
+                let const_gen = matches!(
+                    &size.val,
+                    Expr::Ident(id) if gens_in_scope.iter().any(|g| g.is_const && g.ident == *id)
+                );
+                if patched.is_none() && !const_gen {
+                    return None;
+                }
+                Ty::ConstArray {
+                    size: size.clone(),
+                    ty: box patched.unwrap_or_else(|| (**arrty).clone()),
+                }
+                .into_spanned(ty.span)
+            }
             Ty::Struct { ident, gen } => Ty::Struct {
                 ident: *ident,
                 gen: gen
@@ -278,9 +540,24 @@ impl<'input> TyCheckRes<'_, 'input> {
             .into_spanned(ty.span),
             Ty::Path(p) => {
                 if let Some(gen) = gens_in_scope.iter().find(|gty| gty.ident == p.segs[0]) {
-                    Ty::Generic { ident: gen.ident, bound: gen.bound.clone() }.into_spanned(ty.span)
+                    Ty::Generic { ident: gen.ident, bounds: gen.bounds.clone() }
+                        .into_spanned(ty.span)
                 } else {
-                    return None;
+                    // The type arguments can name the generics `option<T>`
+                    let patched = p
+                        .gen
+                        .iter()
+                        .map(|g| self.patch_generic_from_path(g, gens_in_scope))
+                        .collect::<Vec<_>>();
+                    if patched.iter().all(Option::is_none) {
+                        return None;
+                    }
+                    let gen = patched
+                        .into_iter()
+                        .zip(&p.gen)
+                        .map(|(patched, g)| patched.unwrap_or_else(|| g.clone()))
+                        .collect();
+                    Ty::Path(Path { gen, ..p.clone() }).into_spanned(ty.span)
                 }
             }
             Ty::Ptr(inner) => Ty::Ptr(box self.patch_generic_from_path(&**inner, gens_in_scope)?)
@@ -293,6 +570,57 @@ impl<'input> TyCheckRes<'_, 'input> {
             }
         })
     }
+
+    /// A `break` has to be in a loop and a deferred statement can not leave the function or
+    /// defer more statements.
+    fn check_control_flow(&self, stmt: &Statement) {
+        let msg = match &stmt.val {
+            Stmt::Ret(_) | Stmt::Exit if self.in_defer => {
+                "[E0ty] can not return from a deferred statement"
+            }
+            Stmt::Defer(_) if self.in_defer => {
+                "[E0ty] `defer` can not be used in a deferred statement"
+            }
+            Stmt::Break if self.loop_depth == 0 && self.in_defer => {
+                "[E0ty] `break` can not leave a deferred statement"
+            }
+            Stmt::Break if self.loop_depth == 0 => "[E0ty] `break` outside of a loop",
+            _ => return,
+        };
+        self.errors.push_error(Error::error_with_span(self, stmt.span, msg));
+        self.errors.poisoned(true);
+    }
+
+    /// `main` is called with the arguments of the program and returns its exit status, it can take
+    /// nothing or a `[str]` and return nothing or an `int`.
+    fn check_main_sig(&self, func: &Func) {
+        let params_ok = match &func.params[..] {
+            [] => true,
+            [args] => {
+                matches!(&args.ty.get().val, Ty::Slice(t) if matches!(t.val, Ty::ConstStr(..)))
+            }
+            _ => false,
+        };
+        let ret_ok = matches!(func.ret.get().val, Ty::Void | Ty::Int);
+        if params_ok && ret_ok && func.generics.is_empty() {
+            return;
+        }
+        let params = func
+            .params
+            .iter()
+            .map(|p| format!("{}: {}", p.ident, p.ty.get().val))
+            .collect::<Vec<_>>();
+        self.errors.push_error(Error::error_with_span(
+            self,
+            func.span,
+            &format!(
+                "[E0ty] invalid signature for `main`, expected `fn main(args: [str]): int`\nfound \
+                 `fn main({}): {}`",
+                params.join(", "),
+                func.ret.get().val,
+            ),
+        ));
+    }
 }
 
 // @cleanup: my guess is this will somewhat go away, stmt and smaller will be handled by TypeInferer
@@ -304,8 +632,16 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
         // we will do the same thing when we see an import too
         self.name_res.add_file_scopes(&self.file_names);
 
+        // Array sizes in the signature of any item can name a const declared after it
+        for item in items {
+            if let Decl::Const(var) = &item.val {
+                self.name_const.insert(var.ident, var);
+            }
+        }
+
         let mut funcs = vec![];
         let mut impls = vec![];
+        let mut consts = vec![];
         for item in items {
             match &item.val {
                 Decl::Func(func) => {
@@ -314,6 +650,7 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                 }
                 Decl::Const(var) => {
                     self.visit_const(var);
+                    consts.push(var);
                 }
                 Decl::Trait(trait_) => self.visit_trait(trait_),
                 Decl::Impl(imp) => {
@@ -323,20 +660,22 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                 }
                 Decl::Adt(adt) => self.visit_adt(adt),
                 Decl::Const(co) => {}
-                Decl::Import(_) => {
+                Decl::Import(path) => {
                     let mut items = vec![];
-                    let mut added_file = false;
+                    let mut imported = None;
                     // TODO: handle errors
                     while let Ok(Ok(blob)) = self.rcv.as_ref().unwrap().recv() {
-                        if !added_file {
-                            let file_id = hash_file(blob.file);
-
+                        let file_id = hash_file(blob.file);
+                        // A file imported again was already added
+                        if !self.file_names.contains_key(&file_id) {
                             self.name_res.add_file_scope(file_id);
                             self.file_names.insert(file_id, blob.file);
                             self.inputs.insert(file_id, blob.input);
+                            self.line_starts.insert(file_id, line_starts(blob.input));
                         }
+                        imported = Some(file_id);
 
-                        items.push(blob.decl);
+                        items.extend(blob.decl);
 
                         if blob.count == 0 {
                             break;
@@ -346,12 +685,45 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
 
                     self.visit_prog(imports);
                     self.imported_items.extend(imports);
+                    if let Some(file) = imported {
+                        self.import_names(path, file, item.span.file_id);
+                    }
+                }
+            }
+        }
+
+        // A field can name a struct or enum declared after the struct it is in
+        for item in items {
+            if let Decl::Adt(Adt::Struct(struc)) = &item.val {
+                for ty in struc.fields.iter().map(|f| &f.ty) {
+                    if let Some(res) = self.name_res.resolve_name(&ty.get().val, self) {
+                        ty.set(res.into_spanned(ty.get().span));
+                    }
                 }
             }
         }
 
         // Stabilize order which I'm not sure how it gets unordered
         funcs.sort_by(|a, b| a.span.start.cmp(&b.span.start));
+
+        // If the parameters or return value are struct or enum names resolve them (eventually
+        // type aliases??), a call can come before the function it calls
+        for func in &funcs {
+            self.name_res
+                .add_to_scope_stack(Scope::Func { file: func.span.file_id, func: func.ident });
+            for ty in func.params.iter().map(|p| &p.ty).chain(Some(&func.ret)) {
+                if let Some(res) = self.name_res.resolve_name(&ty.get().val, self) {
+                    ty.set(res.into_spanned(ty.get().span));
+                }
+            }
+            // The type of the function as a value was saved before its signature was resolved
+            if let Some(Ty::Func { params, ret, .. }) = self.global.get_mut(&func.ident) {
+                *params = func.params.iter().map(|p| p.ty.get().val.clone()).collect();
+                **ret = func.ret.get().val.clone();
+            }
+            self.name_res.pop_scope_stack();
+        }
+
         for func in funcs {
             // Storage space for function pointers passed as params
             //
@@ -361,13 +733,7 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
             self.name_res
                 .add_to_scope_stack(Scope::Func { file: func.span.file_id, func: func.ident });
 
-            // If the parameters are struct or enum names resolve them (eventually type aliases??)
             for param in &func.params {
-                let resolved = self.name_res.resolve_name(&param.ty.get().val, self);
-                if let Some(res) = resolved {
-                    param.ty.set(res.into_spanned(param.ty.get().span));
-                }
-
                 // Add this to the global function scope for the duration of this functions scope
                 // (the one we are in now)
                 if let Ty::Func { ident, params, ret } = &param.ty.get().val {
@@ -388,7 +754,7 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                             .collect(),
                         ret: crate::rawptr!(ret.clone().into_spanned(DUMMY)),
                         generics: vec![],
-                        stmts: Block { stmts: crate::raw_vec![], span: DUMMY },
+                        stmts: Block { stmts: crate::raw_vec![], tail: None, span: DUMMY },
                         kind: FuncKind::Normal,
                         span: DUMMY,
                     });
@@ -398,11 +764,6 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                         .insert(param.ident, unsafe { std::mem::transmute(&fn_ptr_storage[idx]) });
                 }
             }
-            // Resolve the return value (if struct or enum or type)
-            let resolved = self.name_res.resolve_name(&func.ret.get().val, self);
-            if let Some(res) = resolved {
-                func.ret.set(res.into_spanned(func.ret.get().span));
-            }
 
             struct NameResUserTypes<'ast, 'b> {
                 res: &'ast ScopeWalker,
@@ -410,7 +771,33 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                 func: &'ast Func,
             }
             impl<'ast, 'b> VisitMut<'ast> for NameResUserTypes<'ast, 'b> {
+                fn visit_stmt(&mut self, stmt: &'ast mut Statement) {
+                    if let Stmt::Assign { ty: Some(ty), .. } = &mut stmt.val {
+                        if let Some(res) = self.tcxt.eval_array_sizes(ty, &self.func.generics) {
+                            *ty = res;
+                        }
+                    }
+                    // `call()?;` is a `match` statement that ignores the unwrapped value
+                    if let Stmt::Call(Expression { val: Expr::Try(call), span }) = &stmt.val {
+                        let ret = &self.func.ret.get().val;
+                        if let Some(arms) = self.tcxt.try_arms(*span, ret, false) {
+                            self.tcxt.try_matches.borrow_mut().insert(stmt.span);
+                            stmt.val = Stmt::Match { expr: (**call).clone(), arms };
+                        }
+                    }
+                    crate::visit::walk_mut_stmt(self, stmt);
+                }
+
                 fn visit_expr(&mut self, expr: &'ast mut Expression) {
+                    // Desugar `expr?` to a match on the `option` or `result` this function
+                    // returns, anything left is an error
+                    if let Expr::Try(inner) = &expr.val {
+                        let ret = &self.func.ret.get().val;
+                        if let Some(arms) = self.tcxt.try_arms(expr.span, ret, true) {
+                            self.tcxt.try_matches.borrow_mut().insert(expr.span);
+                            expr.val = Expr::Match { expr: inner.clone(), arms };
+                        }
+                    }
                     if let Expr::Call { path, args, type_args } = &expr.val {
                         if let Some(Ty::Enum { ident, .. }) =
                             self.res.type_from_path(path, self.tcxt)
@@ -432,17 +819,45 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                             if let Some(res) = resolved {
                                 *ty_arg = res;
                             }
+                            // `alloc::<vec<T>>(1)` names a struct
+                            if let Some(res) = self.res.resolve_name(&ty_arg.val, self.tcxt) {
+                                *ty_arg = res.into_spanned(ty_arg.span);
+                            }
                         }
-                    } else if let Expr::Builtin(Builtin::SizeOf(ty)) = &expr.val {
-                        if !matches!(ty.get().val, Ty::Path(..)) {
-                            return;
-                        }
-                        let resolved =
-                            self.tcxt.patch_generic_from_path(ty.get(), &self.func.generics);
+                    } else if let Some(ty) =
+                        if let Expr::Builtin(b) = &expr.val { b.type_arg() } else { None }
+                    {
+                        let resolved = if matches!(ty.get().val, Ty::Path(..)) {
+                            self.tcxt.patch_generic_from_path(ty.get(), &self.func.generics)
+                        } else {
+                            self.tcxt.eval_array_sizes(ty.get(), &self.func.generics)
+                        };
                         if let Some(res) = resolved {
                             ty.set(res);
                         }
+                    } else if let Expr::TraitMeth { type_args, .. } = &mut expr.val {
+                        // `<<T>::meth>` in a generic function or `<<heap>::meth>` for a struct
+                        for ty_arg in type_args {
+                            let resolved =
+                                self.tcxt.patch_generic_from_path(ty_arg, &self.func.generics);
+                            if let Some(res) = resolved {
+                                *ty_arg = res;
+                            } else if let Some(res) = self.res.resolve_name(&ty_arg.val, self.tcxt)
+                            {
+                                *ty_arg = res.into_spanned(ty_arg.span);
+                            }
+                        }
+                    } else if let Expr::Cast { ty, .. } | Expr::InlineAsm { ty, .. } =
+                        &mut expr.val
+                    {
+                        // `addr as *T` or `asm -> T` is the generic of this function
+                        if let Some(res) =
+                            self.tcxt.patch_generic_from_path(ty, &self.func.generics)
+                        {
+                            *ty = res;
+                        }
                     }
+                    crate::visit::walk_mut_expr(self, expr);
                 }
             }
 
@@ -451,9 +866,14 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                 NameResUserTypes { res: &self.name_res, tcxt: self, func }.visit_stmt(stmt);
             }
 
+            // Const generic parameters are `int` values in the body of the function
+            for gen in func.generics.iter().filter(|g| g.is_const) {
+                self.var_func.func_refs.entry(func.ident).or_default().insert(gen.ident, Ty::Int);
+            }
+
             crate::visit::walk_func(self, func);
 
-            if matches!(func.kind, FuncKind::Normal)
+            if matches!(func.kind, FuncKind::Normal | FuncKind::Const)
                 && !matches!(func.ret.get().val, Ty::Void)
                 && !self.var_func.func_return.contains(&func.ident)
             {
@@ -488,14 +908,33 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
             self.name_res
                 .add_to_scope_stack(Scope::Impl { file: imp.span.file_id, imp: imp.method.ident });
 
+            // An impl for a struct `impl allocator<heap>` takes it as a parameter `a: *heap`
+            for ty in imp.method.params.iter().map(|p| &p.ty).chain(Some(&imp.method.ret)) {
+                if let Some(res) = self.name_res.resolve_name(&ty.get().val, self) {
+                    ty.set(res.into_spanned(ty.get().span));
+                }
+            }
+
             self.curr_fn = Some(imp.method.ident);
+            self.in_impl = true;
             crate::visit::walk_func(self, &imp.method);
+            self.in_impl = false;
 
             self.name_res.pop_scope_stack();
             // TODO: check return just like above
             self.curr_fn.take();
         }
 
+        // Const initializers can call any `const fn` so they wait until every body is checked
+        for var in &consts {
+            self.check_const_init(var);
+        }
+        if self.errors.is_empty() {
+            for var in consts {
+                const_eval::eval_const(self, var);
+            }
+        }
+
         let mut unused = self
             .var_func
             .unsed_vars
@@ -568,6 +1007,12 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
 
     fn visit_func(&mut self, func: &'ast Func) {
         if self.curr_fn.is_none() {
+            for ty in func.params.iter().map(|p| &p.ty).chain(Some(&func.ret)) {
+                if let Some(t) = self.eval_array_sizes(ty.get(), &func.generics) {
+                    ty.set(t);
+                }
+            }
+
             // If there are generics anytime they are referenced it's a `Ty::Path` so we
             // need to convert them to `Ty::Generic {..}` if they match i.e.
             // `Ty::Path(T) == Ty::Genereic {T, bound}`
@@ -622,7 +1067,7 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                         params: func
                             .generics
                             .iter()
-                            .map(|t| Ty::Generic { ident: t.ident, bound: t.bound.clone() })
+                            .map(|t| Ty::Generic { ident: t.ident, bounds: t.bounds.clone() })
                             .collect(),
                     },
                 );
@@ -651,6 +1096,10 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                 }
             };
 
+            if func.ident.name() == "main" && !self.in_impl {
+                self.check_main_sig(func);
+            }
+
             if self.var_func.name_func.insert(func.ident.to_owned(), func).is_some() {
                 self.errors.push_error(Error::error_with_span(
                     self,
@@ -762,6 +1211,11 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
     fn visit_adt(&mut self, adt: &'ast Adt) {
         match adt {
             Adt::Struct(struc) => {
+                for ty in struc.fields.iter().map(|f| &f.ty) {
+                    if let Some(t) = self.eval_array_sizes(ty.get(), &struc.generics) {
+                        ty.set(t);
+                    }
+                }
                 if !struc.generics.is_empty() {
                     for ty in struc.fields.iter().map(|f| &f.ty) {
                         let patched = self.patch_generic_from_path(ty.get(), &struc.generics);
@@ -803,6 +1257,11 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                 }
             }
             Adt::Enum(en) => {
+                for ty in en.variants.iter().flat_map(|v| unsafe { v.types.iter_mut_shared() }) {
+                    if let Some(t) = self.eval_array_sizes(ty, &en.generics) {
+                        *ty = t;
+                    }
+                }
                 if !en.generics.is_empty() {
                     for ty in en.variants.iter().flat_map(|v| unsafe { v.types.iter_mut_shared() })
                     {
@@ -848,6 +1307,13 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
     }
 
     fn visit_const(&mut self, var: &'ast Const) {
+        let generics = self
+            .curr_fn
+            .and_then(|f| self.var_func.name_func.get(&f))
+            .map_or(&[][..], |f| &f.generics);
+        let ty =
+            self.eval_array_sizes(&var.ty, generics).map_or_else(|| var.ty.val.clone(), |t| t.val);
+
         if let Some(fn_id) = self.curr_fn {
             if self.global.contains_key(&var.ident) {
                 self.errors.push_error(Error::error_with_span(
@@ -860,21 +1326,14 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
             let node = Node::Func(fn_id);
             let mut stack = if self.generic_res.has_generics(&node) { vec![node] } else { vec![] };
             self.generic_res.collect_generic_usage(
-                &var.ty.val,
+                &ty,
                 self.unique_id(),
                 0,
                 &[TyRegion::Const(var)],
                 &mut stack,
             );
 
-            if self
-                .var_func
-                .func_refs
-                .entry(fn_id)
-                .or_default()
-                .insert(var.ident, var.ty.val.clone())
-                .is_some()
-            {
+            if self.var_func.func_refs.entry(fn_id).or_default().insert(var.ident, ty).is_some() {
                 self.errors.push_error(Error::error_with_span(
                     self,
                     var.span,
@@ -896,31 +1355,25 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
             // bail out before we set the scope as global
             return;
         } else {
-            if self.global.insert(var.ident, var.ty.val.clone()).is_some() {
+            if self.global.insert(var.ident, ty).is_some() {
                 self.errors.push_error(Error::error_with_span(
                     self,
                     var.span,
                     &format!("global variable `{}` is already declared", var.ident),
                 ));
             }
-            self.expr_ty.insert(&var.init, var.init.val.type_of().unwrap());
+            self.name_const.insert(var.ident, var);
         }
 
         // TODO: if we don't record global exprs (so far only for arrays) we panic in lowering
         // because we don't have a type in the expr_ty map
         match &var.init.val {
-            Expr::Ident(_) => todo!(),
-            Expr::Array { ident, exprs } => todo!(),
-            Expr::Urnary { op, expr } => todo!(),
-            Expr::Binary { op, lhs, rhs } => todo!(),
-            Expr::Parens(_) => todo!(),
-            Expr::StructInit { path, fields } => todo!(),
-            Expr::EnumInit { path, variant, items } => todo!(),
-            Expr::ArrayInit { items } => {
+            Expr::ArrayInit { items } if const_eval::is_literal(&var.init.val) => {
+                self.expr_ty.insert(&var.init, var.init.val.type_of().unwrap());
                 for ex in items {
                     self.expr_ty.insert(
                         ex,
-                        if let Ty::Array { ty, .. } = &var.ty.val {
+                        if let Ty::Array { ty, .. } | Ty::ConstArray { ty, .. } = &var.ty.val {
                             ty.val.clone()
                         } else {
                             self.errors.push_error(Error::error_with_span(
@@ -933,14 +1386,12 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                     );
                 }
             }
-            Expr::Value(..) => {}
-            _ => {
-                self.errors.push_error(Error::error_with_span(
-                    self,
-                    var.init.span,
-                    &"invalid const expression".to_string(),
-                ));
+            Expr::Value(..) => {
+                self.expr_ty.insert(&var.init, var.init.val.type_of().unwrap());
             }
+            // Anything else is checked and evaluated after all the functions are, see
+            // `TyCheckRes::check_const_init`
+            _ => {}
         }
 
         // This const is declared in the file scope
@@ -970,7 +1421,19 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
         let mut infer = TypeInfer { tcxt: self };
         infer.visit_stmt(stmt);
 
+        self.check_control_flow(stmt);
+        let (loop_depth, in_defer) = (self.loop_depth, self.in_defer);
+        match &stmt.val {
+            Stmt::While { .. } => self.loop_depth += 1,
+            Stmt::Defer(_) => {
+                self.loop_depth = 0;
+                self.in_defer = true;
+            }
+            _ => {}
+        }
         crate::visit::walk_stmt(self, stmt);
+        self.loop_depth = loop_depth;
+        self.in_defer = in_defer;
 
         self.set_record_used_vars(true);
 
@@ -1051,6 +1514,8 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                         // TODO: think about pointer maths
                         if let Some(Ty::Int | Ty::Ptr(_)) = ty {
                             self.expr_ty.insert(expr, Ty::Int);
+                        } else if let Some(ty @ Ty::SizedInt(_)) = ty {
+                            self.expr_ty.insert(expr, ty.clone());
                         } else {
                             self.errors.push_error(Error::error_with_span(
                                 self,
@@ -1132,6 +1597,15 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                             self.errors.poisoned(true);
                         }
                     }
+                } else if let (Some(l @ Ty::SizedInt(_)), Some(r @ (Ty::Int | Ty::SizedInt(_))))
+                | (Some(l @ Ty::Int), Some(r @ Ty::SizedInt(_))) = (lhs_ty, rhs_ty)
+                {
+                    self.errors.push_error(Error::error_with_span(
+                        self,
+                        expr.span,
+                        &format!("[E0ty] mismatched integer types\nfound `{}` and `{}`", l, r),
+                    ));
+                    self.errors.poisoned(true);
                 } else {
                     self.errors.push_error(Error::error_with_span(
                         self,
@@ -1182,8 +1656,14 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                     self.visit_expr(arg);
                 }
 
-                // Check type_args agrees
-                let stack = build_stack(self, Node::Func(*ident));
+                // Check type_args agrees, `alloc::<bool>` in a generic function does not
+                // specialize the function it is called from
+                let partly_generic = type_args.iter().any(|t| t.val.has_generics());
+                let stack = if partly_generic {
+                    build_stack(self, Node::Func(*ident))
+                } else {
+                    vec![Node::Func(*ident)]
+                };
 
                 let gen_arg_set_id = self.unique_id();
                 let mut gen_arg_map = HashMap::default();
@@ -1204,6 +1684,34 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                     let mut stack = stack.clone();
 
                     let gen = &func.generics[gen_arg_idx];
+                    if gen.is_const != matches!(ty_arg.val, Ty::ConstArg(..)) {
+                        self.errors.push_error(Error::error_with_span(
+                            self,
+                            ty_arg.span,
+                            &format!(
+                                "[E0ty] mismatched generic argument for `{}`\nfound `{}` expected {}",
+                                gen.ident,
+                                ty_arg.val,
+                                if gen.is_const { "a const `int`" } else { "a type" },
+                            ),
+                        ));
+                        self.errors.poisoned(true);
+                        return;
+                    }
+                    for bound in &gen.bounds {
+                        if !self.satisfies_bound(&ty_arg.val, bound) {
+                            // An inferred type argument has no span of its own
+                            self.errors.push_error(Error::error_with_span(
+                                self,
+                                expr.span,
+                                &format!(
+                                    "[E0ty] `{}` does not implement `{}` required by `{}`",
+                                    ty_arg.val, bound, gen.ident,
+                                ),
+                            ));
+                            self.errors.poisoned(true);
+                        }
+                    }
                     // Find the param that is the "generic" and check against type argument
                     let mut arguments = vec![];
                     for (i, p) in func.params.iter().enumerate() {
@@ -1212,13 +1720,17 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                         }
                     }
 
-                    self.generic_res.collect_generic_usage(
-                        &ty_arg.val,
-                        gen_arg_set_id,
-                        gen_arg_idx,
-                        &arguments,
-                        &mut stack,
-                    );
+                    // A known argument next to a generic one, `alloc::<T, heap>`, is not a
+                    // specialization yet, the call is specialized with the function it is in
+                    if !partly_generic || ty_arg.val.has_generics() {
+                        self.generic_res.collect_generic_usage(
+                            &ty_arg.val,
+                            gen_arg_set_id,
+                            gen_arg_idx,
+                            &arguments,
+                            &mut stack,
+                        );
+                    }
 
                     gen_arg_map.insert(gen.ident, ty_arg.val.clone());
                 }
@@ -1250,6 +1762,10 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                     {
                         param_ty = Some(ty_arg);
                     }
+                    // `f(*p)` passes the type behind the pointer
+                    let resolve = |t: Ty| t.resolve().unwrap_or(t);
+                    param_ty = param_ty.map(resolve);
+                    arg_ty = arg_ty.map(resolve);
 
                     if !param_ty.as_ref().is_ty_eq(&arg_ty.as_ref()) {
                         self.errors.push_error(Error::error_with_span(
@@ -1282,7 +1798,37 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                 let trait_def =
                     self.trait_solve.traits.get(trait_).cloned().expect("trait is defined");
 
-                let mut stack = build_stack(self, Node::Trait(ident));
+                // `<<T>::eq>` in a generic function needs `T: eq`
+                if let [Ty::Generic { ident: gen, bounds }] =
+                    &type_args.iter().map(|t| &t.val).collect::<Vec<_>>()[..]
+                {
+                    if !bounds.contains(trait_) {
+                        self.errors.push_error(Error::error_with_span(
+                            self,
+                            expr.span,
+                            &format!(
+                                "[E0ty] `{}` is not bound by `{}`\nadd it to the bounds `{}: {}`",
+                                gen,
+                                trait_,
+                                gen,
+                                bounds
+                                    .iter()
+                                    .chain([trait_])
+                                    .map(|b| b.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join(" + "),
+                            ),
+                        ));
+                        self.errors.poisoned(true);
+                    }
+                }
+
+                // A call like `<<heap>::meth>` in a generic function does not specialize it
+                let mut stack = if type_args.iter().any(|t| t.val.has_generics()) {
+                    build_stack(self, Node::Trait(ident))
+                } else {
+                    vec![Node::Trait(ident)]
+                };
 
                 let gen_arg_set_id = self.unique_id();
                 let mut gen_arg_map = HashMap::default();
@@ -1314,13 +1860,19 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                 let func_params = &trait_def.method.function().params;
                 for (idx, arg) in args.iter().enumerate() {
                     let mut param_ty = func_params.get(idx).map(|p| p.ty.get().val.clone());
-                    let arg_ty = self.expr_ty.get(arg).cloned();
+                    let arg_ty =
+                        self.expr_ty.get(arg).map(|t| t.resolve().unwrap_or_else(|| t.clone()));
 
                     if let Some(Ty::Generic { ident, .. }) = &param_ty {
                         has_generic = true;
                         if let Some(ty_arg) = gen_arg_map.get(ident).cloned() {
                             param_ty = Some(ty_arg);
                         }
+                    } else if let Some(t) = param_ty.as_ref().filter(|t| t.has_generics()) {
+                        // A generic behind a pointer `a: *A`, the type argument can name a struct
+                        has_generic = true;
+                        let subs = subs_type_args(t, type_args, &trait_def.generics);
+                        param_ty = Some(self.name_res.resolve_name(&subs, self).unwrap_or(subs));
                     }
 
                     if !param_ty.as_ref().is_ty_eq(&arg_ty.as_ref()) {
@@ -1485,8 +2037,10 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                     // Visit inner expressions
                     self.visit_expr(item);
 
-                    // Gather expression and expected (declared) type
-                    let exprty = self.expr_ty.get(&*item).cloned();
+                    // Gather expression and expected (declared) type, `option::some(*ptr)` holds
+                    // the type behind the pointer
+                    let exprty =
+                        self.expr_ty.get(&*item).map(|t| t.resolve().unwrap_or_else(|| t.clone()));
 
                     let _stack = build_stack(self, Node::Enum(ident));
 
@@ -1589,30 +2143,140 @@ impl<'ast, 'input> Visit<'ast> for TyCheckRes<'ast, 'input> {
                 );
                 // no is_some check: because of `x[0] += 1;` being lowered to `x[0] = w[0] + 1;`
             }
+            Expr::If { cond, blk, els } => {
+                self.visit_expr(cond);
+
+                let cond_ty = self.expr_ty.get(&**cond).and_then(|t| resolve_ty(self, cond, Some(t)));
+                if !is_truthy(cond_ty.as_ref()) {
+                    self.errors.push_error(Error::error_with_span(
+                        self,
+                        cond.span,
+                        "[E0ty] condition of if must be of type bool",
+                    ));
+                    self.errors.poisoned(true);
+                    return;
+                }
+                self.check_branch_tys(&[blk, els], "`if` and `else` have incompatible types");
+            }
+            Expr::Match { expr: ex, arms } => {
+                // The arms are checked during inference
+                self.visit_expr(ex);
+                let blks = arms.iter().map(|a| &a.blk).collect::<Vec<_>>();
+                self.check_branch_tys(&blks, "match arms have incompatible types");
+            }
+            Expr::Cast { expr: ex, ty } => {
+                self.visit_expr(ex);
+
+                let from = self.expr_ty.get(&**ex).and_then(|t| resolve_ty(self, ex, Some(t)));
+                if let Some(from) = from {
+                    if !is_valid_cast(&from, &ty.val) {
+                        self.errors.push_error(Error::error_with_span(
+                            self,
+                            expr.span,
+                            &format!("[E0ty] invalid cast\nfound `{}` as `{}`", from, ty.val),
+                        ));
+                        self.errors.poisoned(true);
+                    }
+                }
+                self.expr_ty.insert(expr, ty.val.clone());
+            }
+            Expr::InlineAsm { asm, ty } => {
+                for op in asm.inputs.iter().chain(&asm.outputs) {
+                    self.visit_expr(&op.expr);
+                }
+                StmtCheck { tcxt: self }.check_inline_asm(asm, Some(ty));
+                self.expr_ty.insert(expr, ty.val.clone());
+            }
             Expr::Value(val) => {
                 // inference collects these
             }
-            Expr::Builtin(bin) => {
-                if let Builtin::SizeOf(t) = bin {
-                    if t.get().val.has_generics() {
-                        let mut stack =
-                            build_stack(self, Node::Builtin(Ident::new(expr.span, "size_of")));
-                        self.generic_res.collect_generic_usage(
-                            &t.get().val,
-                            self.unique_id(),
-                            0,
-                            &[TyRegion::Expr(&expr.val)],
-                            &mut stack,
-                        );
-                    }
+            Expr::Builtin(Builtin::CallerLocation) => match self.curr_fn {
+                // `main` has no caller so it gets its own location
+                Some(f) if f.name() == "main" => {}
+                // Trait methods are called through the trait so there is no call to add the
+                // hidden argument to
+                Some(_) if self.in_impl => {
+                    self.errors.push_error(Error::error_with_span(
+                        self,
+                        expr.span,
+                        "[E0ty] `@caller_location` can not be used in a trait method",
+                    ));
+                    self.errors.poisoned(true);
+                }
+                Some(f) => {
+                    let span = self.var_func.name_func.get(&f).map_or(expr.span, |func| func.span);
+                    self.caller_location_fns.insert(span);
+                }
+                None => {
+                    self.errors.push_error(Error::error_with_span(
+                        self,
+                        expr.span,
+                        "[E0ty] `@caller_location` can only be used in a function",
+                    ));
+                    self.errors.poisoned(true);
                 }
+            },
+            Expr::Try(_) => {
+                self.errors.push_error(Error::error_with_span(
+                    self,
+                    expr.span,
+                    "[E0ty] `?` can only be used in a function that returns `option` or `result`",
+                ));
+                self.errors.poisoned(true);
             }
+            Expr::Builtin(Builtin::Len(arr)) => self.visit_expr(arr),
+            Expr::Builtin(bin) => match bin.type_arg() {
+                Some(t) if t.get().val.has_generics() => {
+                    let name = match bin {
+                        Builtin::SizeOf(..) => "size_of",
+                        Builtin::AlignOf(..) => "align_of",
+                        Builtin::OffsetOf(..) => "offset_of",
+                        _ => "type_name",
+                    };
+                    let mut stack = build_stack(self, Node::Builtin(Ident::new(expr.span, name)));
+                    self.generic_res.collect_generic_usage(
+                        &t.get().val,
+                        self.unique_id(),
+                        0,
+                        &[TyRegion::Expr(&expr.val)],
+                        &mut stack,
+                    );
+                }
+                Some(t) => {
+                    if let Builtin::OffsetOf(_, field) = bin {
+                        let has_field = match &t.get().val {
+                            Ty::Struct { ident, .. } => self
+                                .name_struct
+                                .get(ident)
+                                .map_or(true, |s| s.fields.iter().any(|f| f.ident == *field)),
+                            _ => false,
+                        };
+                        if !has_field {
+                            self.errors.push_error(Error::error_with_span(
+                                self,
+                                expr.span,
+                                &format!("[E0ty] no field `{}` found for `{}`", field, t.get().val),
+                            ));
+                            self.errors.poisoned(true);
+                        }
+                    }
+                }
+                None => {}
+            },
         }
         // We do NOT call walk_expr here since we recursively walk the exprs
         // when ever found so we have folded the expr types depth first
     }
 }
 
+/// The offset of the start of each line of `input`, a line number is found by a binary search
+/// instead of counting newlines for every span.
+fn line_starts(input: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(input.match_indices('\n').map(|(at, _)| at + 1));
+    starts
+}
+
 /// Return a type that has been substituted as much as possible at this stage.
 ///
 /// If any generics are left it is because the variable/call they come from has a
@@ -1786,6 +2450,11 @@ fn check_dereference(tcxt: &mut TyCheckRes<'_, '_>, expr: &Expression) {
         | Expr::EnumInit { .. }
         | Expr::ArrayInit { .. }
         | Expr::Builtin(..)
+        | Expr::If { .. }
+        | Expr::Match { .. }
+        | Expr::Cast { .. }
+        | Expr::Try(..)
+        | Expr::InlineAsm { .. }
         | Expr::Value(_) => todo!(),
     }
 }
@@ -1811,6 +2480,18 @@ fn replace_with_concrete_ty(
             ty: box replace_with_concrete_ty(tcxt, Some(&arrty.val), gen_arg_map)?
                 .into_spanned(DUMMY),
         },
+        Ty::ConstArray { size, ty: arrty } => Ty::Array {
+            size: match &size.val {
+                Expr::Ident(gen) => match gen_arg_map.get(gen)? {
+                    Ty::ConstArg(size) => *size,
+                    _ => return None,
+                },
+                _ => return None,
+            },
+            ty: box replace_with_concrete_ty(tcxt, Some(&arrty.val), gen_arg_map)
+                .unwrap_or_else(|| arrty.val.clone())
+                .into_spanned(DUMMY),
+        },
         Ty::Struct { ident, gen } => Ty::Struct {
             ident: *ident,
             gen: gen
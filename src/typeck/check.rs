@@ -8,16 +8,21 @@ use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
 
 use crate::{
     ast::{
+        mnemonic::{Mnemonic, Operand},
         parse::{symbol::Ident, ParseResult},
         types::{
-            to_rng, Adt, BinOp, Binding, Block, Const, Decl, Declaration, Enum, Expr, Expression,
-            Field, FieldInit, Func, Generic, Impl, MatchArm, Param, Pat, Path, Range, Spany,
-            Statement, Stmt, Struct, Trait, Ty, Type, TypeEquality, UnOp, Val, Variant, DUMMY,
+            to_rng, Adt, AsmBlock, BinOp, Binding, Block, Builtin, Const, Decl, Declaration, Enum,
+            Expr, Expression, Field, FieldInit, Func, Generic, Impl, IntTy, Location, MatchArm,
+            Param, Pat, Path, Pattern, Range, Spany, Statement, Stmt, Struct, Trait, Ty, Type,
+            TypeEquality, UnOp, Val, Variant, DUMMY,
         },
     },
     error::Error,
+    gen::asm::inst::Register,
     typeck::{
+        exhaustive,
         generic::{Node, TyRegion},
+        infer::TypeInfer,
         TyCheckRes,
     },
     visit::Visit,
@@ -93,13 +98,373 @@ impl<'ast> StmtCheck<'_, 'ast, '_> {
             }
         }
     }
+
+    /// Check that the `option` or `result` an `expr?` unwraps can be returned early from the
+    /// enclosing function, a `result` must have the same error type.
+    fn check_try(&mut self, ty: Option<&Ty>, span: Range) -> bool {
+        let ret = self
+            .tcxt
+            .var_func
+            .get_fn_by_span(span)
+            .and_then(|f| self.tcxt.var_func.name_func.get(&f))
+            .map(|f| f.ret.get().val.clone());
+        let compatible = match (ty, ret.as_ref()) {
+            (Some(Ty::Enum { ident, gen }), Some(Ty::Enum { ident: ret, gen: ret_gen })) => {
+                ident == ret
+                    && match (gen.get(1), ret_gen.get(1)) {
+                        (Some(err), Some(ret_err)) => err.val == ret_err.val,
+                        _ => true,
+                    }
+            }
+            // The type of the unwrapped expression is already an error
+            (None, _) => true,
+            _ => false,
+        };
+        if !compatible {
+            self.tcxt.errors.push_error(Error::error_with_span(
+                self.tcxt,
+                span,
+                &format!(
+                    "[E0tc] `?` on `{}` can not return early from a function that returns `{}`",
+                    ty.map_or("<unknown>".to_owned(), |t| t.to_string()),
+                    ret.map_or("<unknown>".to_owned(), |t| t.to_string()),
+                ),
+            ));
+            self.tcxt.errors.poisoned(true);
+        }
+        compatible
+    }
+
+    /// Add the variables bound by a match arm to the function the match is in. A match in the
+    /// initializer of a global const binds them as globals, the globals they shadow are returned
+    /// so they can be put back when the arm ends.
+    fn bind_arm_vars(
+        &mut self,
+        span: Range,
+        bound_vars: &HashMap<Ident, Ty>,
+    ) -> Vec<(Ident, Option<Ty>)> {
+        if let Some(fn_name) = self.tcxt.var_func.get_fn_by_span(span) {
+            let locals = self.tcxt.var_func.func_refs.entry(fn_name).or_default();
+            for (variable, ty) in bound_vars {
+                locals.insert(*variable, ty.clone());
+            }
+            return vec![];
+        }
+        bound_vars
+            .iter()
+            .map(|(variable, ty)| (*variable, self.tcxt.global.insert(*variable, ty.clone())))
+            .collect()
+    }
+
+    fn unbind_global_arm_vars(&mut self, shadowed: Vec<(Ident, Option<Ty>)>) {
+        for (variable, ty) in shadowed {
+            match ty {
+                Some(ty) => self.tcxt.global.insert(variable, ty),
+                None => self.tcxt.global.remove(&variable),
+            };
+        }
+    }
+
+    /// Check the patterns, guards and blocks of each arm, this is shared by `match` statements
+    /// and expressions.
+    ///
+    /// Returns the type of each arm's block.
+    crate fn check_match(
+        &mut self,
+        expr: &'ast Expression,
+        arms: &'ast [MatchArm],
+        span: Range,
+    ) -> Vec<Option<Ty>> {
+        let match_ty = resolve_ty(self.tcxt, expr, self.tcxt.expr_ty.get(expr));
+        if self.tcxt.try_matches.borrow().contains(&span)
+            && !self.check_try(match_ty.as_ref(), span)
+        {
+            return vec![];
+        }
+        // Visiting the arm statements resets the poisoned flag, remember if any of the
+        // patterns were bad so we don't check exhaustiveness of nonsense
+        let mut pat_errors = false;
+        let mut arm_tys = vec![];
+
+        match match_ty.as_ref().unwrap() {
+            // The elements of an array pattern are checked against the element type
+            Ty::Array { .. } | Ty::Enum { .. } | Ty::Struct { .. } => {
+                let mut bound_vars = HashMap::default();
+                for arm in arms {
+                    check_pattern_type(
+                        self.tcxt,
+                        &arm.pat.val,
+                        match_ty.as_ref(),
+                        arm.span,
+                        &mut bound_vars,
+                    );
+                    pat_errors |= self.tcxt.errors.is_poisoned();
+
+                    let shadowed = self.bind_arm_vars(span, &bound_vars);
+
+                    if let Some(guard) = &arm.guard {
+                        self.check_guard(guard);
+                    }
+
+                    arm_tys.push(check_value_block(self.tcxt, &arm.blk));
+                    self.unbind_global_arm_vars(shadowed);
+
+                    // TODO: I need to deal with this some way or I will have ghost vars
+                    //
+
+                    // // Remove the bound locals after the arm leaves scope
+                    // for (id, _) in bound_vars.drain_filter(|_, _| true) {
+                    //     self.tcxt
+                    //         .var_func
+                    //         .func_refs
+                    //         .get_mut(&fn_name)
+                    //         .map(|map| map.remove(&id));
+                    // }
+                }
+            }
+            Ty::Int | Ty::SizedInt(_) | Ty::Char | Ty::Bool => {
+                let mut bound_vars = HashMap::default();
+                for arm in arms {
+                    check_pattern_type(
+                        self.tcxt,
+                        &arm.pat.val,
+                        match_ty.as_ref(),
+                        arm.span,
+                        &mut bound_vars,
+                    );
+                    pat_errors |= self.tcxt.errors.is_poisoned();
+                    let fn_name = self.tcxt.var_func.get_fn_by_span(span);
+                    let shadowed = self.bind_arm_vars(span, &bound_vars);
+
+                    if let Some(guard) = &arm.guard {
+                        self.check_guard(guard);
+                    }
+
+                    arm_tys.push(check_value_block(self.tcxt, &arm.blk));
+                    self.unbind_global_arm_vars(shadowed);
+
+                    // Remove the bound locals after the arm leaves scope
+                    let mut locals = fn_name.and_then(|f| self.tcxt.var_func.func_refs.get_mut(&f));
+                    for (id, _) in bound_vars.drain_filter(|_, _| true) {
+                        locals.as_mut().map(|map| map.remove(&id));
+                    }
+                }
+            }
+            Ty::Float => {
+                self.tcxt.errors.push_error(Error::error_with_span(
+                    self.tcxt,
+                    expr.span,
+                    "[E0ty] can not match on a `float`\ncompare it with `if` instead",
+                ));
+                self.tcxt.errors.poisoned(true);
+                return vec![];
+            }
+            _ => panic!(
+                "{}",
+                Error::error_with_span(
+                    self.tcxt,
+                    span,
+                    &format!(
+                        "[E0tc] not a valid match type found: `{}`",
+                        match_ty.map_or("<unknown>".to_owned(), |t| t.to_string())
+                    ),
+                )
+            ),
+        }
+
+        if !pat_errors {
+            if let Some(ty) = match_ty.as_ref() {
+                exhaustive::check_match(self.tcxt, ty, arms, expr.span);
+            }
+        }
+        arm_tys
+    }
+
+    /// Infer and check the `if expr` of a match arm, the arms bindings must already be in scope.
+    fn check_guard(&mut self, guard: &'ast Expression) {
+        let mut infer = TypeInfer { tcxt: self.tcxt };
+        infer.visit_expr(guard);
+        self.tcxt.visit_expr(guard);
+
+        let guard_ty =
+            self.tcxt.expr_ty.get(guard).and_then(|t| resolve_ty(self.tcxt, guard, Some(t)));
+        if !is_truthy(guard_ty.as_ref()) {
+            self.tcxt.errors.push_error(Error::error_with_span(
+                self.tcxt,
+                guard.span,
+                &format!(
+                    "[E0tc] match guard must be truthy, got `{}`",
+                    guard_ty.map_or("<unknown>".to_owned(), |t| t.to_string())
+                ),
+            ));
+            self.tcxt.errors.poisoned(true);
+        }
+    }
+
+    /// Each instruction has to be one enumc knows with operands it accepts and each variable it
+    /// uses has to be declared before the block.
+    ///
+    /// Each operand has to fit in its register and an output has to be a variable, the stack
+    /// registers are never bound or clobbered since every local lives behind them.
+    ///
+    /// The result type `ret` of an `asm` expression has to fit in the register it is read from.
+    crate fn check_inline_asm(&mut self, asm: &'ast AsmBlock, ret: Option<&Type>) {
+        for inst in &asm.assembly {
+            let ops = inst.src.iter().chain(&inst.dst).collect::<Vec<_>>();
+            for loc in &ops {
+                // A variable only read or written by the assembly is still used
+                if let Location::InlineVar(id) = loc {
+                    if self.tcxt.type_of_ident(*id, inst.span).is_none() {
+                        self.tcxt.errors.push_error(Error::error_with_span(
+                            self.tcxt,
+                            inst.span,
+                            &format!("[E0tc] no variable `{}` in scope of asm block", id),
+                        ));
+                    }
+                }
+                // A generic function has no address until it is monomorphized
+                if let Location::NamedOffset(id) = loc {
+                    let func = self.tcxt.var_func.name_func.get(id);
+                    if !matches!(func, Some(f) if f.generics.is_empty()) {
+                        self.tcxt.errors.push_error(Error::error_with_span(
+                            self.tcxt,
+                            inst.span,
+                            &format!("[E0tc] no function `{}` for asm block to address", id),
+                        ));
+                    }
+                }
+            }
+
+            let kinds = ops.iter().map(|loc| Operand::of(loc)).collect::<Option<Vec<_>>>();
+            let in_memory = ops.iter().filter(|loc| Operand::of(loc) == Some(Operand::Mem)).count();
+            let msg = match (Mnemonic::lookup(inst.inst.name()), kinds) {
+                // x86 can only address memory once per instruction
+                (Some(_), _) if in_memory > 1 => {
+                    format!("[E0tc] `{}` can only have one memory operand", inst.inst)
+                }
+                (Some(mnemonic), Some(kinds)) if mnemonic.accepts(&kinds) => continue,
+                (Some(mnemonic), Some(_)) => format!(
+                    "[E0tc] invalid operands for `{}`\nexpected {}",
+                    inst.inst,
+                    mnemonic.describe()
+                ),
+                (Some(_), None) => format!("[E0tc] `{}` can not take a float constant", inst.inst),
+                (None, _) => format!("[E0tc] unknown asm instruction `{}`", inst.inst),
+            };
+            self.tcxt.errors.push_error(Error::error_with_span(self.tcxt, inst.span, &msg));
+        }
+
+        let mut bound: Vec<(&Location, bool)> = vec![];
+        for (op, is_out) in
+            asm.inputs.iter().map(|op| (op, false)).chain(asm.outputs.iter().map(|op| (op, true)))
+        {
+            let msg = if is_out && !matches!(op.expr.val, Expr::Ident(_)) {
+                "[E0tc] an asm output must be a variable".to_owned()
+            } else if bound.contains(&(&op.reg, is_out)) {
+                format!("[E0tc] register `{}` is bound twice", asm_reg_name(&op.reg))
+            } else {
+                let ty = self
+                    .tcxt
+                    .expr_ty
+                    .get(&op.expr)
+                    .and_then(|t| resolve_ty(self.tcxt, &op.expr, Some(t)));
+                let fits = match &ty {
+                    Some(ty) => fits_register(&op.reg, ty),
+                    None => true,
+                };
+                if fits {
+                    bound.push((&op.reg, is_out));
+                    continue;
+                }
+                format!(
+                    "[E0tc] `{}` does not fit in the register `{}`",
+                    ty.map_or("<unknown>".to_owned(), |t| t.to_string()),
+                    asm_reg_name(&op.reg)
+                )
+            };
+            self.tcxt.errors.push_error(Error::error_with_span(self.tcxt, op.expr.span, &msg));
+        }
+
+        if let Some(ret) = ret {
+            let reg = AsmBlock::result_reg(&ret.val);
+            if !fits_register(&reg, &ret.val) {
+                self.tcxt.errors.push_error(Error::error_with_span(
+                    self.tcxt,
+                    ret.span,
+                    &format!(
+                        "[E0tc] `{}` does not fit in the register `{}`",
+                        ret.val,
+                        asm_reg_name(&reg)
+                    ),
+                ));
+            }
+        }
+
+        let stack_reg = asm
+            .inputs
+            .iter()
+            .chain(&asm.outputs)
+            .map(|op| &op.reg)
+            .chain(&asm.clobbers)
+            .find(|reg| matches!(reg, Location::Register(Register::RSP | Register::RBP)));
+        if let Some(reg) = stack_reg {
+            self.tcxt.errors.push_error(Error::error_with_span(
+                self.tcxt,
+                asm.span,
+                &format!("[E0tc] `{}` can not be an asm operand or clobbered", asm_reg_name(reg)),
+            ));
+        }
+    }
+}
+
+/// A float register only holds a `float`, a general purpose register holds any scalar value.
+fn fits_register(reg: &Location, ty: &Ty) -> bool {
+    match reg {
+        Location::FloatReg(_) => matches!(ty, Ty::Float),
+        _ => matches!(
+            ty,
+            Ty::Int
+                | Ty::SizedInt(_)
+                | Ty::Char
+                | Ty::Bool
+                | Ty::Ptr(_)
+                | Ty::Ref(_)
+                | Ty::ConstStr(..)
+                | Ty::Slice(_)
+                | Ty::Func { .. }
+        ),
+    }
+}
+
+/// The name of a register used by an `asm` block `%rax`.
+fn asm_reg_name(loc: &Location) -> String {
+    match loc {
+        Location::Register(reg) => reg.to_string(),
+        Location::FloatReg(reg) => reg.to_string(),
+        _ => unreachable!("asm operands are parsed as registers"),
+    }
 }
 
 impl<'ast> Visit<'ast> for StmtCheck<'_, 'ast, '_> {
     fn visit_stmt(&mut self, stmt: &'ast Statement) {
         match &stmt.val {
             Stmt::Const(_) => {}
-            Stmt::Assign { lval, rval, is_let, ty: _ty } => {
+            Stmt::Assign { lval, rval, is_let, ty } => {
+                // Inference uses the type of `rval` so a given sized integer type is checked here
+                if let (Some(given), Some(rty)) = (ty, self.tcxt.expr_ty.get(rval)) {
+                    if matches!((&given.val, rty), (Ty::SizedInt(_), _) | (_, Ty::SizedInt(_)))
+                        && !given.val.is_ty_eq(rty)
+                    {
+                        self.tcxt.errors.push_error(Error::error_with_span(
+                            self.tcxt,
+                            stmt.span,
+                            &format!(
+                                "[E0tc] assign to expression of wrong type\nfound `{}` expected `{}`",
+                                rty, given.val
+                            ),
+                        ));
+                    }
+                }
                 self.check_assignment(lval, rval, stmt.span, *is_let)
             }
             Stmt::AssignOp { lval, rval, .. } => {
@@ -153,112 +518,7 @@ impl<'ast> Visit<'ast> for StmtCheck<'_, 'ast, '_> {
                 }
             }
             Stmt::Match { expr, arms } => {
-                let match_ty = resolve_ty(self.tcxt, expr, self.tcxt.expr_ty.get(expr));
-
-                // TODO: handle array
-                match match_ty.as_ref().unwrap() {
-                    Ty::Array { size: _, ty: _ } => todo!(),
-                    Ty::Enum { ident, gen: _ } => {
-                        let mut bound_vars = HashMap::default();
-                        for arm in arms {
-                            check_pattern_type(
-                                self.tcxt,
-                                &arm.pat.val,
-                                match_ty.as_ref(),
-                                arm.span,
-                                &mut bound_vars,
-                            );
-
-                            let fn_name = self
-                                .tcxt
-                                .var_func
-                                .get_fn_by_span(stmt.span)
-                                .expect("in a function");
-
-                            // Add the bound locals if any
-                            for (variable, ty) in &bound_vars {
-                                self.tcxt
-                                    .var_func
-                                    .func_refs
-                                    .entry(fn_name)
-                                    .or_default()
-                                    .insert(*variable, ty.clone());
-                            }
-
-                            for stmt in arm.blk.stmts.iter() {
-                                self.tcxt.visit_stmt(stmt);
-                                // self.visit_stmt(stmt);
-                            }
-
-                            // TODO: I need to deal with this some way or I will have ghost vars
-                            //
-
-                            // // Remove the bound locals after the arm leaves scope
-                            // for (id, _) in bound_vars.drain_filter(|_, _| true) {
-                            //     self.tcxt
-                            //         .var_func
-                            //         .func_refs
-                            //         .get_mut(&fn_name)
-                            //         .map(|map| map.remove(&id));
-                            // }
-                        }
-                    }
-                    Ty::Int => {
-                        let mut bound_vars = HashMap::default();
-                        for arm in arms {
-                            check_pattern_type(
-                                self.tcxt,
-                                &arm.pat.val,
-                                match_ty.as_ref(),
-                                arm.span,
-                                &mut bound_vars,
-                            );
-                            let fn_name = self
-                                .tcxt
-                                .var_func
-                                .get_fn_by_span(stmt.span)
-                                .expect("in a function");
-
-                            // Add the bound locals if any
-                            for (variable, ty) in &bound_vars {
-                                self.tcxt
-                                    .var_func
-                                    .func_refs
-                                    .entry(fn_name)
-                                    .or_default()
-                                    .insert(*variable, ty.clone());
-                            }
-
-                            for stmt in arm.blk.stmts.iter() {
-                                self.tcxt.visit_stmt(stmt);
-                                // self.visit_stmt(stmt);
-                            }
-
-                            // Remove the bound locals after the arm leaves scope
-                            for (id, _) in bound_vars.drain_filter(|_, _| true) {
-                                self.tcxt
-                                    .var_func
-                                    .func_refs
-                                    .get_mut(&fn_name)
-                                    .map(|map| map.remove(&id));
-                            }
-                        }
-                    }
-                    Ty::Char => todo!(),
-                    Ty::Float => todo!(),
-                    Ty::Bool => todo!(),
-                    _ => panic!(
-                        "{}",
-                        Error::error_with_span(
-                            self.tcxt,
-                            stmt.span,
-                            &format!(
-                                "[E0tc] not a valid match type found: `{}`",
-                                match_ty.map_or("<unknown>".to_owned(), |t| t.to_string())
-                            ),
-                        )
-                    ),
-                }
+                self.check_match(expr, arms, stmt.span);
             }
             Stmt::Ret(expr) => {
                 let mut ret_ty = resolve_ty(self.tcxt, expr, self.tcxt.expr_ty.get(expr));
@@ -325,9 +585,27 @@ impl<'ast> Visit<'ast> for StmtCheck<'_, 'ast, '_> {
                     self.visit_stmt(stmt);
                 }
             }
+            // Where these can be used is checked while walking the function
+            Stmt::Break | Stmt::Defer(_) => {}
             Stmt::AssignOp { lval, rval, op } => todo!(),
-            Stmt::InlineAsm(..) => {
+            Stmt::InlineAsm(asm) => {
                 // TODO: we could type check the ident in here
+                self.check_inline_asm(asm, None);
+            }
+            Stmt::Builtin(Builtin::StaticAssert(cond, _)) => {
+                let cond_ty = self
+                    .tcxt
+                    .expr_ty
+                    .get(&**cond)
+                    .and_then(|t| resolve_ty(self.tcxt, cond, Some(t)));
+                if !matches!(cond_ty, Some(Ty::Bool) | None) {
+                    self.tcxt.errors.push_error(Error::error_with_span(
+                        self.tcxt,
+                        cond.span,
+                        "[E0tc] condition of `@static_assert` must be of type bool",
+                    ));
+                    self.tcxt.errors.poisoned(true);
+                }
             }
             Stmt::Builtin(..) => {
                 // TODO: we could type check the ident in here
@@ -341,13 +619,68 @@ crate fn is_truthy(ty: Option<&Ty>) -> bool {
     if let Some(t) = ty {
         matches!(
             t,
-            Ty::Ptr(_) | Ty::Ref(_) | Ty::ConstStr(..) | Ty::Int | Ty::Char | Ty::Float | Ty::Bool
+            Ty::Ptr(_)
+                | Ty::Ref(_)
+                | Ty::ConstStr(..)
+                | Ty::Int
+                | Ty::SizedInt(_)
+                | Ty::Char
+                | Ty::Float
+                | Ty::Bool
         )
     } else {
         false
     }
 }
 
+/// The conversions `expr as ty` can do.
+///
+/// Numbers convert to each other, `char` and `bool` convert to and from integers and pointers
+/// convert to other pointers and the pointer sized integers `int`, `i64` and `u64`. A `cstr` is a
+/// pointer to its first byte, `*u8`.
+crate fn is_valid_cast(from: &Ty, to: &Ty) -> bool {
+    let is_int = |t: &Ty| matches!(t, Ty::Int | Ty::SizedInt(_));
+    match (from, to) {
+        (Ty::ConstStr(..), Ty::Ptr(t)) => matches!(t.val, Ty::SizedInt(IntTy::U8)),
+        (Ty::Ptr(_), Ty::Ptr(_)) => true,
+        (Ty::Ptr(_), t) | (t, Ty::Ptr(_)) => {
+            matches!(t, Ty::Int | Ty::SizedInt(IntTy::I64 | IntTy::U64))
+        }
+        (Ty::Float, t) | (t, Ty::Float) => is_int(t) || matches!(t, Ty::Float),
+        (Ty::Char | Ty::Bool, t) | (t, Ty::Char | Ty::Bool) => is_int(t) || from.is_ty_eq(to),
+        (f, t) => is_int(f) && is_int(t),
+    }
+}
+
+/// Check the statements and tail expression of a block, returns the type of the block.
+crate fn check_value_block<'ast>(tcxt: &mut TyCheckRes<'ast, '_>, blk: &'ast Block) -> Option<Ty> {
+    for stmt in blk.stmts.iter() {
+        tcxt.visit_stmt(stmt);
+    }
+    if let Some(tail) = &blk.tail {
+        tcxt.errors.poisoned(false);
+        TypeInfer { tcxt }.visit_expr(tail);
+        tcxt.visit_expr(tail);
+    }
+    block_ty(tcxt, blk)
+}
+
+/// The type a block evaluates to.
+///
+/// A block without a tail is `void` unless it never finishes, then it is `!`.
+crate fn block_ty(tcxt: &TyCheckRes<'_, '_>, blk: &Block) -> Option<Ty> {
+    if let Some(tail) = &blk.tail {
+        return resolve_ty(tcxt, tail, tcxt.expr_ty.get(&**tail));
+    }
+    if blk.stmts.iter().any(|s| {
+        matches!(s.val, Stmt::Exit | Stmt::Ret(_) | Stmt::Break) || s.val.has_bottom_type()
+    }) {
+        Some(Ty::Bottom)
+    } else {
+        Some(Ty::Void)
+    }
+}
+
 /// Fill the unused generic types if a variant is missing some.
 ///
 /// `enum result<int, string> foo = result::error("blah");` is an example of generic args that
@@ -381,9 +714,8 @@ fn check_used_enum_generics(
                     .map(|(i, _)| i)
                     .collect::<Vec<_>>();
 
-                if !pos.is_empty()
-                    && pos.iter().all(|idx| rgen.iter().any(|t| gen.get(*idx).is_ty_eq(&Some(t))))
-                {
+                // A variant that uses none of the generics `option::none` fits any of them
+                if pos.iter().all(|idx| rgen.iter().any(|t| gen.get(*idx).is_ty_eq(&Some(t)))) {
                     *rgen = gen.clone();
                 } else {
                     tcxt.errors.push_error(
@@ -458,6 +790,22 @@ fn check_pattern_type(
         tcxt.errors.poisoned(true);
         return;
     };
+
+    // These patterns don't care what type they are matching
+    match pat {
+        Pat::Wild => return,
+        Pat::At { ident, pat } => {
+            bound_vars.insert(*ident, matcher_ty.clone());
+            check_pattern_type(tcxt, &pat.val, ty, span, bound_vars);
+            return;
+        }
+        Pat::Or(alts) => {
+            check_or_pattern(tcxt, alts, ty, span, bound_vars);
+            return;
+        }
+        _ => {}
+    }
+
     match matcher_ty {
         Ty::Array { size, ty: t } => match pat {
             Pat::Enum { path, variant, .. } => {
@@ -497,10 +845,92 @@ fn check_pattern_type(
                     tcxt.errors.poisoned(true);
                 }
             },
+            _ => {
+                tcxt.errors.push_error(Error::error_with_span(
+                    tcxt,
+                    span,
+                    &format!("[E0tc] expected array found `{}`", pat),
+                ));
+                tcxt.errors.poisoned(true);
+            }
         },
-        Ty::Struct { ident: _, gen: _ } => todo!(),
+        Ty::Struct { ident, gen } => {
+            let strc = *tcxt.name_struct.get(ident).expect("matched undefined struct");
+            match pat {
+                Pat::Struct { path, fields, rest } => {
+                    if !(path.segs.len() == 1 && (*ident) == path.segs[0]) {
+                        tcxt.errors.push_error(Error::error_with_span(
+                            tcxt,
+                            span,
+                            &format!("[E0tc] expected struct `{}` found `{}`", ident, path),
+                        ));
+                        tcxt.errors.poisoned(true);
+                        return;
+                    }
+
+                    for field in fields {
+                        let field_ty = if let Some(def) =
+                            strc.fields.iter().find(|f| f.ident == field.ident)
+                        {
+                            match &def.ty.get().val {
+                                Ty::Generic { ident: g, .. } => strc
+                                    .generics
+                                    .iter()
+                                    .position(|gen| gen.ident == *g)
+                                    .and_then(|pos| gen.get(pos))
+                                    .map_or_else(|| def.ty.get().val.clone(), |t| t.val.clone()),
+                                t => t.clone(),
+                            }
+                        } else {
+                            tcxt.errors.push_error(Error::error_with_span(
+                                tcxt,
+                                field.pat.span,
+                                &format!(
+                                    "[E0tc] struct `{}` has no field `{}`",
+                                    ident, field.ident
+                                ),
+                            ));
+                            tcxt.errors.poisoned(true);
+                            continue;
+                        };
+
+                        check_pattern_type(tcxt, &field.pat.val, Some(&field_ty), span, bound_vars);
+                    }
+
+                    let missing = strc
+                        .fields
+                        .iter()
+                        .filter(|f| !fields.iter().any(|pat| pat.ident == f.ident))
+                        .map(|f| format!("`{}`", f.ident))
+                        .collect::<Vec<_>>();
+                    if !rest && !missing.is_empty() {
+                        tcxt.errors.push_error(Error::error_with_span(
+                            tcxt,
+                            span,
+                            &format!(
+                                "[E0tc] pattern does not mention field{} {}\nuse `..` to ignore the rest",
+                                if missing.len() > 1 { "s" } else { "" },
+                                missing.join(", ")
+                            ),
+                        ));
+                        tcxt.errors.poisoned(true);
+                    }
+                }
+                Pat::Bind(Binding::Wild(id)) => {
+                    bound_vars.insert(*id, ty.cloned().unwrap());
+                }
+                _ => {
+                    tcxt.errors.push_error(Error::error_with_span(
+                        tcxt,
+                        span,
+                        &format!("[E0tc] expected struct `{}` found `{}`", ident, pat),
+                    ));
+                    tcxt.errors.poisoned(true);
+                }
+            }
+        }
         Ty::Enum { ident, gen } => {
-            let enm = tcxt.name_enum.get(ident).expect("matched undefined enum");
+            let enm = *tcxt.name_enum.get(ident).expect("matched undefined enum");
             match pat {
                 Pat::Enum { path, variant, items, .. } => {
                     if !(path.segs.len() == 1 && (*ident) == path.segs[0]) {
@@ -534,8 +964,10 @@ fn check_pattern_type(
 
                     for (idx, it) in items.iter().enumerate() {
                         let var_ty = var_ty.types.get(idx).map(|t| {
-                            if let Ty::Generic { .. } = &t.val {
-                                &gen[idx].val
+                            if let Ty::Generic { ident: g, .. } = &t.val {
+                                // `err(E)` is the second generic of `result<T, E>` not the first
+                                let pos = enm.generics.iter().position(|p| p.ident == *g);
+                                &gen[pos.unwrap_or(idx)].val
                             } else {
                                 &t.val
                             }
@@ -544,25 +976,23 @@ fn check_pattern_type(
                         check_pattern_type(tcxt, &it.val, var_ty, span, bound_vars);
                     }
                 }
-                Pat::Array { size: _, items: _ } => todo!(),
-                Pat::Bind(bind) => match bind {
-                    Binding::Wild(id) => {
-                        bound_vars.insert(*id, ty.cloned().unwrap());
-                    }
-                    Binding::Value(val) => {
-                        tcxt.errors.push_error(Error::error_with_span(
-                            tcxt,
-                            span,
-                            &format!("[E0tc] expected enum found `{}`", val),
-                        ));
-                        tcxt.errors.poisoned(true);
-                    }
-                },
+                Pat::Bind(Binding::Wild(id)) => {
+                    bound_vars.insert(*id, ty.cloned().unwrap());
+                }
+                _ => {
+                    tcxt.errors.push_error(Error::error_with_span(
+                        tcxt,
+                        span,
+                        &format!("[E0tc] expected enum found `{}`", pat),
+                    ));
+                    tcxt.errors.poisoned(true);
+                }
             }
         }
         Ty::ConstStr(..) => check_val_pat(tcxt, pat, ty, "string", span, bound_vars),
         Ty::Float => check_val_pat(tcxt, pat, ty, "float", span, bound_vars),
         Ty::Int => check_val_pat(tcxt, pat, ty, "int", span, bound_vars),
+        Ty::SizedInt(ity) => check_val_pat(tcxt, pat, ty, &ity.to_string(), span, bound_vars),
         Ty::Char => check_val_pat(tcxt, pat, ty, "char", span, bound_vars),
         Ty::Bool => check_val_pat(tcxt, pat, ty, "bool", span, bound_vars),
         _ => {
@@ -579,6 +1009,40 @@ fn check_pattern_type(
     }
 }
 
+/// Check each alternative of an or-pattern, they must all bind the same variables.
+fn check_or_pattern(
+    tcxt: &mut TyCheckRes<'_, '_>,
+    alts: &[Pattern],
+    ty: Option<&Ty>,
+    span: Range,
+    bound_vars: &mut HashMap<Ident, Ty>,
+) {
+    let mut first: Option<HashMap<Ident, Ty>> = None;
+    for alt in alts {
+        let mut alt_vars = HashMap::default();
+        check_pattern_type(tcxt, &alt.val, ty, span, &mut alt_vars);
+
+        if let Some(first) = &first {
+            let unbound = first
+                .keys()
+                .filter(|id| !alt_vars.contains_key(id))
+                .chain(alt_vars.keys().filter(|id| !first.contains_key(id)))
+                .next();
+            if let Some(id) = unbound {
+                tcxt.errors.push_error(Error::error_with_span(
+                    tcxt,
+                    alt.span,
+                    &format!("[E0tc] variable `{}` is not bound in all patterns", id),
+                ));
+                tcxt.errors.poisoned(true);
+            }
+        } else {
+            first = Some(alt_vars);
+        }
+    }
+    bound_vars.extend(first.unwrap_or_default());
+}
+
 /// Panic with a good compiler error if the type of `Pat` is not the correct `Binding::Value`.
 fn check_val_pat(
     tcxt: &TyCheckRes<'_, '_>,
@@ -597,7 +1061,46 @@ fn check_val_pat(
             ));
             tcxt.errors.poisoned(true);
         }
-        Pat::Array { .. } => {
+        Pat::Range { start, end } => {
+            if !matches!(ty, Some(Ty::Int | Ty::SizedInt(_) | Ty::Char)) {
+                tcxt.errors.push_error(Error::error_with_span(
+                    tcxt,
+                    span,
+                    &format!(
+                        "[E0tc] only `int` and `char` can be matched by range found `{}`",
+                        expected
+                    ),
+                ));
+                tcxt.errors.poisoned(true);
+                return;
+            }
+            for val in &[start, end] {
+                if Some(&lit_to_type(&val.val)) != ty {
+                    tcxt.errors.push_error(Error::error_with_span(
+                        tcxt,
+                        val.span,
+                        &format!("[E0tc] expected `{}` found `{}`", expected, val),
+                    ));
+                    tcxt.errors.poisoned(true);
+                    return;
+                }
+            }
+            let empty = match (&start.val, &end.val) {
+                (Val::Int(s), Val::Int(e)) => s > e,
+                (Val::SizedInt(s, ity), Val::SizedInt(e, _)) => ity.widen(*s) > ity.widen(*e),
+                (Val::Char(s), Val::Char(e)) => s > e,
+                _ => false,
+            };
+            if empty {
+                tcxt.errors.push_error(Error::error_with_span(
+                    tcxt,
+                    span,
+                    &format!("[E0tc] range pattern `{}` matches nothing, start is after end", pat),
+                ));
+                tcxt.errors.poisoned(true);
+            }
+        }
+        Pat::Array { .. } | Pat::Struct { .. } | Pat::Or(..) | Pat::At { .. } | Pat::Wild => {
             tcxt.errors.push_error(Error::error_with_span(
                 tcxt,
                 span,
@@ -639,6 +1142,11 @@ crate fn resolve_ty(tcxt: &TyCheckRes<'_, '_>, expr: &Expression, ty: Option<&Ty
         | Expr::EnumInit { .. }
         | Expr::ArrayInit { .. }
         | Expr::Builtin(..)
+        | Expr::If { .. }
+        | Expr::Match { .. }
+        | Expr::Cast { .. }
+        | Expr::Try(..)
+        | Expr::InlineAsm { .. }
         | Expr::Value(_) => ty.cloned(),
     }
 }
@@ -708,6 +1216,11 @@ fn lvalue_type(tcxt: &mut TyCheckRes<'_, '_>, lval: &Expression, stmt_span: Rang
         | Expr::EnumInit { .. }
         | Expr::ArrayInit { .. }
         | Expr::Builtin(..)
+        | Expr::If { .. }
+        | Expr::Match { .. }
+        | Expr::Cast { .. }
+        | Expr::Try(..)
+        | Expr::InlineAsm { .. }
         | Expr::Value(_) => {
             panic!(
                 "{}",
@@ -788,6 +1301,11 @@ fn walk_field_access(
         | Expr::EnumInit { .. }
         | Expr::ArrayInit { .. }
         | Expr::Builtin(..)
+        | Expr::If { .. }
+        | Expr::Match { .. }
+        | Expr::Cast { .. }
+        | Expr::Try(..)
+        | Expr::InlineAsm { .. }
         | Expr::Value(_) => {
             tcxt.errors.push_error(
                 Error::error_with_span(tcxt, expr.span, "[E0tc] invalid lValue")
@@ -818,6 +1336,8 @@ crate fn fold_ty(
 ) -> Option<Ty> {
     let res = match (lhs?, rhs?) {
         (Ty::Int, Ty::Int) => math_ops(tcxt, op, Ty::Int, span),
+        // Sized integers never convert implicitly, not even to a wider type
+        (Ty::SizedInt(l), Ty::SizedInt(r)) if l == r => math_ops(tcxt, op, Ty::SizedInt(*l), span),
         (Ty::Float, Ty::Float) => math_ops(tcxt, op, Ty::Float, span),
         // TODO: remove Carr's rules
         (Ty::Int, Ty::Float) => math_ops(tcxt, op, Ty::Float, span),
@@ -938,6 +1458,7 @@ fn lit_to_type(lit: &Val) -> Ty {
     match lit {
         Val::Float(_) => Ty::Float,
         Val::Int(_) => Ty::Int,
+        Val::SizedInt(_, ity) => Ty::SizedInt(*ity),
         Val::Char(_) => Ty::Char,
         Val::Bool(_) => Ty::Bool,
         Val::Str(s) => Ty::ConstStr(s.name().len()),
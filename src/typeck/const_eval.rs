@@ -0,0 +1,892 @@
+//! Compile time evaluation of global `const` items.
+//!
+//! A const initializer that is not a plain literal is evaluated by walking the type checked AST,
+//! calling into any `const fn` it uses. Locals live in one frame per call, control flow (`return`)
+//! and evaluation errors unwind through [`Unwind`]. Evaluation is bounded by [`STEP_LIMIT`] and
+//! [`CALL_DEPTH_LIMIT`] so an infinite loop or runaway recursion becomes an error instead of a
+//! hung compiler.
+//!
+//! The result is kept as a [`ConstVal`] that lowering turns into an initializer made only of
+//! literals, struct, enum and array inits which is what code generation knows how to emit.
+
+use std::convert::TryFrom;
+
+use crate::{
+    ast::{
+        parse::symbol::Ident,
+        types::{
+            BinOp, Binding, Block, Builtin, Const, Expr, Expression, FuncKind, MatchArm, Pat, Path,
+            Range, Stmt, UnOp, Val, DUMMY,
+        },
+    },
+    error::Error,
+    lir,
+    typeck::{scope::ScopedName, TyCheckRes},
+};
+
+/// The most statements and expressions a single const item may evaluate.
+const STEP_LIMIT: usize = 1_000_000;
+/// The deepest `const fn` calls may nest.
+const CALL_DEPTH_LIMIT: usize = 128;
+
+/// A value computed at compile time.
+#[derive(Clone, Debug)]
+crate enum ConstVal {
+    Val(Val),
+    Array(Vec<ConstVal>),
+    /// The fields are kept in the order the struct defines them.
+    Struct {
+        path: Path,
+        fields: Vec<(Ident, ConstVal)>,
+    },
+    Enum {
+        path: Path,
+        variant: Ident,
+        items: Vec<ConstVal>,
+    },
+    /// The result of calling a function that returns nothing.
+    Void,
+}
+
+impl ConstVal {
+    fn description(&self) -> &'static str {
+        match self {
+            ConstVal::Val(Val::Int(_)) => "int",
+            ConstVal::Val(Val::SizedInt(_, ity)) => ity.name(),
+            ConstVal::Val(Val::Float(_)) => "float",
+            ConstVal::Val(Val::Char(_)) => "char",
+            ConstVal::Val(Val::Bool(_)) => "bool",
+            ConstVal::Val(Val::Str(_)) => "string",
+            ConstVal::Array(_) => "array",
+            ConstVal::Struct { .. } => "struct",
+            ConstVal::Enum { .. } => "enum",
+            ConstVal::Void => "void",
+        }
+    }
+}
+
+/// Why evaluation stopped walking the current function.
+enum Unwind {
+    /// A `return` or `exit`, caught by the enclosing call.
+    Return(ConstVal),
+    /// A `break`, caught by the enclosing loop.
+    Break,
+    Error(Range, String),
+}
+
+type EvalResult<T> = Result<T, Unwind>;
+
+fn error<T>(span: Range, msg: String) -> EvalResult<T> {
+    Err(Unwind::Error(span, msg))
+}
+
+/// One step through a place, `a.b[1]` is `[Field(a), Field(b), Index(1)]`.
+enum Proj {
+    Field(Ident),
+    Index(isize),
+}
+
+/// Is this initializer already a value that needs no evaluation.
+crate fn is_literal(expr: &Expr) -> bool {
+    match expr {
+        Expr::Value(_) => true,
+        Expr::ArrayInit { items } => items.iter().all(|ex| is_literal(&ex.val)),
+        _ => false,
+    }
+}
+
+/// Evaluate the initializer of the global `var` and record the result in
+/// [`TyCheckRes::const_vals`], any failure is reported as an error.
+crate fn eval_const(tcxt: &mut TyCheckRes<'_, '_>, var: &Const) {
+    if is_literal(&var.init.val) || tcxt.const_vals.contains_key(&var.ident) {
+        return;
+    }
+
+    let res = Evaluator::new(tcxt).eval_global(var.ident, var.init.span);
+    match res {
+        Ok(val) => {
+            tcxt.const_vals.insert(var.ident, val);
+        }
+        Err(Unwind::Error(span, msg)) => {
+            tcxt.errors.push_error(Error::error_with_span(tcxt, span, &msg));
+        }
+        Err(Unwind::Return(_) | Unwind::Break) => {
+            unreachable!("returns and breaks are caught by the call or loop that made them")
+        }
+    }
+}
+
+/// Evaluate the size of an array type `[size; ty]`, any failure is reported as an error.
+crate fn eval_size(tcxt: &TyCheckRes<'_, '_>, size: &Expression) -> Option<usize> {
+    let (span, msg) = match Evaluator::new(tcxt).eval_expr(size) {
+        Ok(ConstVal::Val(Val::Int(val))) if val >= 0 => return Some(val as usize),
+        Ok(ConstVal::Val(Val::Int(val))) => {
+            (size.span, format!("[E0ce] array size can not be negative found `{}`", val))
+        }
+        Ok(val) => {
+            (size.span, format!("[E0ce] array size must be an `int` found `{}`", val.description()))
+        }
+        Err(Unwind::Error(span, msg)) => (span, msg),
+        Err(Unwind::Return(_) | Unwind::Break) => {
+            unreachable!("returns and breaks are caught by the call or loop that made them")
+        }
+    };
+    tcxt.errors.push_error(Error::error_with_span(tcxt, span, &msg));
+    None
+}
+
+/// The value of an array index if it is a constant expression, any other index is checked at
+/// runtime so failing to evaluate it is not an error.
+crate fn eval_index(tcxt: &TyCheckRes<'_, '_>, idx: &Expression) -> Option<isize> {
+    match Evaluator::new(tcxt).eval_expr(idx) {
+        Ok(ConstVal::Val(Val::Int(val))) => Some(val),
+        _ => None,
+    }
+}
+
+/// Check the condition of `@static_assert(cond, "msg")` holds, a false or non constant condition
+/// is reported as an error.
+crate fn eval_static_assert(tcxt: &TyCheckRes<'_, '_>, cond: &Expression, msg: Ident) {
+    let (span, msg) = match Evaluator::new(tcxt).eval_expr(cond) {
+        Ok(ConstVal::Val(Val::Bool(true))) => return,
+        Ok(ConstVal::Val(Val::Bool(false))) => {
+            (cond.span, format!("[E0ce] static assertion failed: {}", msg))
+        }
+        Ok(val) => (
+            cond.span,
+            format!("[E0ce] static assertion must be a `bool` found `{}`", val.description()),
+        ),
+        Err(Unwind::Error(span, msg)) => (span, msg),
+        Err(Unwind::Return(_) | Unwind::Break) => {
+            unreachable!("returns and breaks are caught by the call or loop that made them")
+        }
+    };
+    tcxt.errors.push_error(Error::error_with_span(tcxt, span, &msg));
+}
+
+struct Evaluator<'a, 'ast, 'input> {
+    tcxt: &'a TyCheckRes<'ast, 'input>,
+    /// The locals of each `const fn` call, the last frame is the current call. The first frame
+    /// holds what a const initializer binds outside of any call, like the arms of a `match`.
+    frames: Vec<Vec<(Ident, ConstVal)>>,
+    steps: usize,
+    /// The global consts we are in the middle of evaluating, used to catch cycles.
+    evaluating: Vec<Ident>,
+}
+
+impl<'a, 'ast, 'input> Evaluator<'a, 'ast, 'input> {
+    fn new(tcxt: &'a TyCheckRes<'ast, 'input>) -> Self {
+        Evaluator { tcxt, frames: vec![vec![]], steps: 0, evaluating: vec![] }
+    }
+
+    fn step(&mut self, span: Range) -> EvalResult<()> {
+        self.steps += 1;
+        if self.steps > STEP_LIMIT {
+            return error(
+                span,
+                format!("[E0ce] constant evaluation took more than {} steps", STEP_LIMIT),
+            );
+        }
+        Ok(())
+    }
+
+    fn eval_global(&mut self, ident: Ident, span: Range) -> EvalResult<ConstVal> {
+        if let Some(val) = self.tcxt.const_vals.get(&ident) {
+            return Ok(val.clone());
+        }
+        if self.evaluating.contains(&ident) {
+            return error(span, format!("[E0ce] cycle detected when evaluating const `{}`", ident));
+        }
+        let var = if let Some(var) = self.tcxt.name_const.get(&ident) {
+            if let Some((_, used)) =
+                self.tcxt.var_func.unsed_vars.get(&ScopedName::global(var.span.file_id, ident))
+            {
+                used.set(true);
+            }
+            *var
+        } else {
+            return error(span, format!("[E0ce] `{}` is not a constant", ident));
+        };
+
+        // Globals never see the locals of whoever referenced them
+        self.evaluating.push(ident);
+        let frames = std::mem::replace(&mut self.frames, vec![vec![]]);
+        let val = self.eval_expr(&var.init);
+        self.frames = frames;
+        self.evaluating.pop();
+        val
+    }
+
+    fn lookup(&mut self, ident: Ident, span: Range) -> EvalResult<ConstVal> {
+        let local = self
+            .frames
+            .last()
+            .and_then(|f| f.iter().rev().find(|(id, _)| *id == ident).map(|(_, v)| v.clone()));
+        match local {
+            Some(val) => Ok(val),
+            None => self.eval_global(ident, span),
+        }
+    }
+
+    fn define(&mut self, ident: Ident, val: ConstVal) {
+        self.frame().push((ident, val));
+    }
+
+    fn frame(&mut self) -> &mut Vec<(Ident, ConstVal)> {
+        self.frames.last_mut().expect("there is always a frame")
+    }
+
+    fn eval_int(&mut self, expr: &'ast Expression) -> EvalResult<isize> {
+        match self.eval_expr(expr)? {
+            ConstVal::Val(Val::Int(i)) => Ok(i),
+            v => error(expr.span, format!("[E0ce] expected `int` found `{}`", v.description())),
+        }
+    }
+
+    fn eval_bool(&mut self, expr: &'ast Expression) -> EvalResult<bool> {
+        match self.eval_expr(expr)? {
+            ConstVal::Val(Val::Bool(b)) => Ok(b),
+            v => error(expr.span, format!("[E0ce] expected `bool` found `{}`", v.description())),
+        }
+    }
+
+    fn eval_expr(&mut self, expr: &'ast Expression) -> EvalResult<ConstVal> {
+        self.step(expr.span)?;
+        Ok(match &expr.val {
+            Expr::Ident(id) => self.lookup(*id, expr.span)?,
+            Expr::Deref { .. } | Expr::AddrOf(_) => {
+                return error(
+                    expr.span,
+                    "[E0ce] pointers can not be used in a constant expression".to_owned(),
+                );
+            }
+            Expr::Array { ident, exprs } => {
+                let mut projs = vec![];
+                for ex in exprs {
+                    projs.push(Proj::Index(self.eval_int(ex)?));
+                }
+                let base = self.lookup(*ident, expr.span)?;
+                project(&base, &projs, expr.span)?.clone()
+            }
+            Expr::Urnary { op, expr: inner } => match (op, self.eval_expr(inner)?) {
+                (UnOp::Not, ConstVal::Val(Val::Bool(b))) => ConstVal::Val(Val::Bool(!b)),
+                (UnOp::Not, ConstVal::Val(Val::Int(i))) => ConstVal::Val(Val::Bool(i == 0)),
+                (UnOp::OnesComp, ConstVal::Val(Val::Int(i))) => ConstVal::Val(Val::Int(!i)),
+                (UnOp::Not, ConstVal::Val(Val::SizedInt(i, _))) => ConstVal::Val(Val::Bool(i == 0)),
+                (UnOp::OnesComp, ConstVal::Val(Val::SizedInt(i, ity))) => {
+                    ConstVal::Val(Val::SizedInt(ity.wrap(!(i as i128)), ity))
+                }
+                (UnOp::OnesComp, ConstVal::Val(Val::Bool(b))) => ConstVal::Val(Val::Bool(!b)),
+                (_, v) => {
+                    return error(
+                        expr.span,
+                        format!("[E0ce] can not apply `{:?}` to `{}`", op, v.description()),
+                    );
+                }
+            },
+            Expr::Binary { op: BinOp::And, lhs, rhs } => {
+                ConstVal::Val(Val::Bool(self.eval_bool(lhs)? && self.eval_bool(rhs)?))
+            }
+            Expr::Binary { op: BinOp::Or, lhs, rhs } => {
+                ConstVal::Val(Val::Bool(self.eval_bool(lhs)? || self.eval_bool(rhs)?))
+            }
+            Expr::Binary { op, lhs, rhs } => {
+                let l = self.eval_expr(lhs)?;
+                let r = self.eval_expr(rhs)?;
+                binop(op, l, r, expr.span)?
+            }
+            Expr::Parens(inner) => self.eval_expr(inner)?,
+            Expr::Call { path, args, .. } => self.eval_call(path, args, expr.span)?,
+            Expr::TraitMeth { trait_, .. } => {
+                return error(
+                    expr.span,
+                    format!(
+                        "[E0ce] can not call trait method `{}` in a constant expression",
+                        trait_
+                    ),
+                );
+            }
+            Expr::FieldAccess { lhs, rhs } => {
+                let base = self.eval_expr(lhs)?;
+                let projs = self.place(rhs)?;
+                project(&base, &projs, expr.span)?.clone()
+            }
+            Expr::StructInit { path, fields } => {
+                let name = path.segs.last().unwrap();
+                let struc = self.tcxt.name_struct.get(name).expect("struct is defined");
+                let mut vals = vec![];
+                for def in &struc.fields {
+                    let init = fields
+                        .iter()
+                        .find(|f| f.ident == def.ident)
+                        .expect("type checking ensures all fields are initialized");
+                    vals.push((def.ident, self.eval_expr(&init.init)?));
+                }
+                ConstVal::Struct { path: path.clone(), fields: vals }
+            }
+            Expr::EnumInit { path, variant, items } => {
+                let mut vals = vec![];
+                for item in items {
+                    vals.push(self.eval_expr(item)?);
+                }
+                ConstVal::Enum { path: path.clone(), variant: *variant, items: vals }
+            }
+            Expr::ArrayInit { items } => {
+                let mut vals = vec![];
+                for item in items {
+                    vals.push(self.eval_expr(item)?);
+                }
+                ConstVal::Array(vals)
+            }
+            Expr::Value(v) => ConstVal::Val(v.val.clone()),
+            Expr::Builtin(Builtin::Bottom) => {
+                return error(
+                    expr.span,
+                    "[E0ce] reached `@bottom` while evaluating a constant".to_owned(),
+                );
+            }
+            Expr::Builtin(
+                bin @ (Builtin::SizeOf(t)
+                | Builtin::AlignOf(t)
+                | Builtin::OffsetOf(t, _)
+                | Builtin::TypeName(t)),
+            ) => {
+                let ty = &t.get().val;
+                if ty.has_generics() {
+                    return error(
+                        expr.span,
+                        format!("[E0ce] `{}` of a generic type is not known yet", bin),
+                    );
+                }
+                if let Builtin::TypeName(_) = bin {
+                    return Ok(ConstVal::Val(Val::Str(Ident::new(DUMMY, &ty.to_string()))));
+                }
+                // Code generation uses the lowered type so the layout has to come from there
+                let layout = lir::lower::Ty::lower(self.tcxt, ty);
+                let val = match bin {
+                    Builtin::SizeOf(_) => layout.size(),
+                    Builtin::AlignOf(_) => layout.align(),
+                    Builtin::OffsetOf(_, field) => match layout.field_offset(*field) {
+                        Some(offset) => offset,
+                        None => {
+                            return error(
+                                expr.span,
+                                format!("[E0ce] no field `{}` found for `{}`", field, ty),
+                            );
+                        }
+                    },
+                    _ => unreachable!("type name is a string"),
+                };
+                ConstVal::Val(Val::Int(val as isize))
+            }
+            Expr::Builtin(Builtin::StaticAssert(..)) => {
+                unreachable!("@static_assert is only parsed as a statement")
+            }
+            Expr::Builtin(Builtin::Line) => {
+                ConstVal::Val(Val::Int(self.tcxt.file_and_line(expr.span).1 as isize))
+            }
+            Expr::Builtin(Builtin::File) => {
+                ConstVal::Val(Val::Str(Ident::new(DUMMY, self.tcxt.file_and_line(expr.span).0)))
+            }
+            Expr::Builtin(Builtin::Len(inner)) => match self.eval_expr(inner)? {
+                ConstVal::Array(items) => ConstVal::Val(Val::Int(items.len() as isize)),
+                v => {
+                    return error(
+                        inner.span,
+                        format!("[E0ce] `@len` expected an array found `{}`", v.description()),
+                    );
+                }
+            },
+            Expr::Builtin(Builtin::CallerLocation) => {
+                return error(
+                    expr.span,
+                    "[E0ce] `@caller_location` can not be used in a constant expression"
+                        .to_owned(),
+                );
+            }
+            Expr::If { cond, blk, els } => {
+                if self.eval_bool(cond)? {
+                    self.eval_block(blk)?
+                } else {
+                    self.eval_block(els)?
+                }
+            }
+            Expr::Match { expr: scrut, arms } => self.eval_match(scrut, arms, expr.span)?,
+            Expr::Cast { expr: inner, ty } => match self.eval_expr(inner)? {
+                ConstVal::Val(v) if v.cast(&ty.val).is_some() => {
+                    ConstVal::Val(v.cast(&ty.val).unwrap())
+                }
+                v => {
+                    return error(
+                        expr.span,
+                        format!("[E0ce] can not cast `{}` to `{}`", v.description(), ty.val),
+                    );
+                }
+            },
+            Expr::Try(_) => unreachable!("`?` is desugared before type checking"),
+            Expr::InlineAsm { .. } => {
+                return error(
+                    expr.span,
+                    "[E0ce] an `asm` block can not be used in a constant expression".to_owned(),
+                );
+            }
+        })
+    }
+
+    fn eval_call(
+        &mut self,
+        path: &Path,
+        args: &'ast [Expression],
+        span: Range,
+    ) -> EvalResult<ConstVal> {
+        let ident = path.segs.last().unwrap();
+        let func = if let Some(func) = self.tcxt.var_func.name_func.get(ident) {
+            *func
+        } else {
+            return error(
+                span,
+                format!("[E0ce] no const fn named `{}` is declared before here", path),
+            );
+        };
+        if !matches!(func.kind, FuncKind::Const) {
+            return error(
+                span,
+                format!("[E0ce] can not call non-const fn `{}` in a constant expression", path),
+            );
+        }
+        if self.frames.len() > CALL_DEPTH_LIMIT {
+            return error(
+                span,
+                format!(
+                    "[E0ce] reached the recursion limit of {} calls evaluating `{}`",
+                    CALL_DEPTH_LIMIT, path
+                ),
+            );
+        }
+
+        let mut frame = vec![];
+        for (param, arg) in func.params.iter().zip(args) {
+            frame.push((param.ident, self.eval_expr(arg)?));
+        }
+
+        self.frames.push(frame);
+        let res = self.eval_block(&func.stmts);
+        self.frames.pop();
+        match res {
+            Ok(_) => Ok(ConstVal::Void),
+            Err(Unwind::Return(val)) => Ok(val),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Evaluate the statements of a block, the value is the block's tail or `void`.
+    ///
+    /// Deferred statements run in reverse order however the block is left, unless evaluation
+    /// failed.
+    fn eval_block(&mut self, blk: &'ast Block) -> EvalResult<ConstVal> {
+        let scope = self.frame().len();
+        let res = self.eval_block_stmts(blk);
+        // Locals of the block go out of scope, an early return pops the whole frame anyway
+        self.frame().truncate(scope);
+        res
+    }
+
+    fn eval_block_stmts(&mut self, blk: &'ast Block) -> EvalResult<ConstVal> {
+        let mut deferred = vec![];
+        let res: EvalResult<ConstVal> = try {
+            for stmt in blk.stmts.iter() {
+                self.step(stmt.span)?;
+                if let Stmt::Defer(stmt) = &stmt.val {
+                    deferred.push(&**stmt);
+                    continue;
+                }
+                self.eval_stmt(&stmt.val, stmt.span)?;
+            }
+            match &blk.tail {
+                Some(tail) => self.eval_expr(tail)?,
+                None => ConstVal::Void,
+            }
+        };
+        if let Err(Unwind::Error(..)) = res {
+            return res;
+        }
+        for stmt in deferred.into_iter().rev() {
+            self.step(stmt.span)?;
+            self.eval_stmt(&stmt.val, stmt.span)?;
+        }
+        res
+    }
+
+    fn eval_stmt(&mut self, stmt: &'ast Stmt, span: Range) -> EvalResult<()> {
+        match stmt {
+            Stmt::Const(var) => {
+                let val = self.eval_expr(&var.init)?;
+                self.define(var.ident, val);
+            }
+            Stmt::Assign { lval, rval, is_let, .. } => {
+                let val = self.eval_expr(rval)?;
+                match &lval.val {
+                    Expr::Ident(id) if *is_let => self.define(*id, val),
+                    _ => self.assign(lval, val)?,
+                }
+            }
+            Stmt::AssignOp { lval, rval, op } => {
+                let op = match op {
+                    BinOp::AddAssign => BinOp::Add,
+                    BinOp::SubAssign => BinOp::Sub,
+                    op => op.clone(),
+                };
+                let l = self.eval_expr(lval)?;
+                let r = self.eval_expr(rval)?;
+                let val = binop(&op, l, r, span)?;
+                self.assign(lval, val)?;
+            }
+            Stmt::Call(expr) | Stmt::TraitMeth(expr) => {
+                self.eval_expr(expr)?;
+            }
+            Stmt::If { cond, blk, els } => {
+                if self.eval_bool(cond)? {
+                    self.eval_block(blk)?;
+                } else if let Some(els) = els {
+                    self.eval_block(els)?;
+                }
+            }
+            Stmt::While { cond, blk } => {
+                while self.eval_bool(cond)? {
+                    match self.eval_block(blk) {
+                        Err(Unwind::Break) => break,
+                        res => res?,
+                    };
+                }
+            }
+            Stmt::Break => return Err(Unwind::Break),
+            Stmt::Match { expr, arms } => {
+                self.eval_match(expr, arms, span)?;
+            }
+            Stmt::InlineAsm(_) => {
+                return error(
+                    span,
+                    "[E0ce] inline assembly can not be used in a constant expression".to_owned(),
+                );
+            }
+            Stmt::Ret(expr) => {
+                let val = self.eval_expr(expr)?;
+                return Err(Unwind::Return(val));
+            }
+            Stmt::Exit => return Err(Unwind::Return(ConstVal::Void)),
+            Stmt::Builtin(Builtin::Bottom) => {
+                return error(
+                    span,
+                    "[E0ce] reached `@bottom` while evaluating a constant".to_owned(),
+                );
+            }
+            Stmt::Builtin(_) => {}
+            Stmt::Block(blk) => {
+                self.eval_block(blk)?;
+            }
+            Stmt::Defer(_) => unreachable!("deferred statements are collected by their block"),
+        }
+        Ok(())
+    }
+
+    fn eval_match(
+        &mut self,
+        expr: &'ast Expression,
+        arms: &'ast [MatchArm],
+        span: Range,
+    ) -> EvalResult<ConstVal> {
+        let val = self.eval_expr(expr)?;
+        let scope = self.frame().len();
+        for arm in arms {
+            let mut binds = vec![];
+            if !pat_matches(&arm.pat.val, &val, &mut binds) {
+                continue;
+            }
+            for (ident, val) in binds {
+                self.define(ident, val);
+            }
+            if let Some(guard) = &arm.guard {
+                if !self.eval_bool(guard)? {
+                    // The next arm must not see what this one bound
+                    self.frame().truncate(scope);
+                    continue;
+                }
+            }
+            let res = self.eval_block(&arm.blk);
+            self.frame().truncate(scope);
+            return res;
+        }
+        error(span, "[E0ce] no match arm matched while evaluating a constant".to_owned())
+    }
+
+    /// Flatten an assignable expression into the projections leading to it.
+    fn place(&mut self, expr: &'ast Expression) -> EvalResult<Vec<Proj>> {
+        Ok(match &expr.val {
+            Expr::Ident(id) => vec![Proj::Field(*id)],
+            Expr::Array { ident, exprs } => {
+                let mut projs = vec![Proj::Field(*ident)];
+                for ex in exprs {
+                    projs.push(Proj::Index(self.eval_int(ex)?));
+                }
+                projs
+            }
+            Expr::FieldAccess { lhs, rhs } => {
+                let mut projs = self.place(lhs)?;
+                projs.extend(self.place(rhs)?);
+                projs
+            }
+            _ => {
+                return error(
+                    expr.span,
+                    "[E0ce] can not assign to this in a constant expression".to_owned(),
+                );
+            }
+        })
+    }
+
+    fn assign(&mut self, lval: &'ast Expression, val: ConstVal) -> EvalResult<()> {
+        let projs = self.place(lval)?;
+        let root = match projs.first() {
+            Some(Proj::Field(id)) => *id,
+            _ => unreachable!("places always start with a variable"),
+        };
+        let frame = self.frame();
+        let slot = if let Some((_, slot)) = frame.iter_mut().rev().find(|(id, _)| *id == root) {
+            slot
+        } else {
+            return error(
+                lval.span,
+                format!("[E0ce] can not assign to `{}` in a constant expression", root),
+            );
+        };
+        *project_mut(slot, &projs[1..], lval.span)? = val;
+        Ok(())
+    }
+}
+
+fn project<'v>(mut val: &'v ConstVal, projs: &[Proj], span: Range) -> EvalResult<&'v ConstVal> {
+    for proj in projs {
+        val = match (proj, val) {
+            (Proj::Field(f), ConstVal::Struct { fields, .. }) => {
+                &fields.iter().find(|(id, _)| id == f).expect("field exists").1
+            }
+            (Proj::Index(idx), ConstVal::Array(items)) => {
+                match usize::try_from(*idx).ok().and_then(|i| items.get(i)) {
+                    Some(item) => item,
+                    None => return out_of_bounds(*idx, items.len(), span),
+                }
+            }
+            _ => unreachable!("type checking ensures projections match the value"),
+        };
+    }
+    Ok(val)
+}
+
+fn project_mut<'v>(
+    mut val: &'v mut ConstVal,
+    projs: &[Proj],
+    span: Range,
+) -> EvalResult<&'v mut ConstVal> {
+    for proj in projs {
+        val = match (proj, val) {
+            (Proj::Field(f), ConstVal::Struct { fields, .. }) => {
+                &mut fields.iter_mut().find(|(id, _)| id == f).expect("field exists").1
+            }
+            (Proj::Index(idx), ConstVal::Array(items)) => {
+                let len = items.len();
+                match usize::try_from(*idx).ok().and_then(move |i| items.get_mut(i)) {
+                    Some(item) => item,
+                    None => return out_of_bounds(*idx, len, span),
+                }
+            }
+            _ => unreachable!("type checking ensures projections match the value"),
+        };
+    }
+    Ok(val)
+}
+
+fn out_of_bounds<T>(idx: isize, len: usize, span: Range) -> EvalResult<T> {
+    error(
+        span,
+        format!("[E0ce] index out of bounds: the length is {} but the index is {}", len, idx),
+    )
+}
+
+/// Does `val` match `pat`, any variables the pattern binds are added to `binds`.
+fn pat_matches(pat: &Pat, val: &ConstVal, binds: &mut Vec<(Ident, ConstVal)>) -> bool {
+    match (pat, val) {
+        (Pat::Wild, _) => true,
+        (Pat::Bind(Binding::Wild(id)), _) => {
+            binds.push((*id, val.clone()));
+            true
+        }
+        (Pat::Bind(Binding::Value(v)), ConstVal::Val(val)) => v.val == *val,
+        (Pat::Range { start, end }, ConstVal::Val(val)) => match (&start.val, &end.val, val) {
+            (Val::Int(s), Val::Int(e), Val::Int(v)) => s <= v && v <= e,
+            (Val::SizedInt(s, ity), Val::SizedInt(e, _), Val::SizedInt(v, _)) => {
+                ity.widen(*s) <= ity.widen(*v) && ity.widen(*v) <= ity.widen(*e)
+            }
+            (Val::Char(s), Val::Char(e), Val::Char(v)) => s <= v && v <= e,
+            _ => false,
+        },
+        (Pat::Or(alts), _) => alts.iter().any(|alt| {
+            // An alternative that fails part way through may have bound some of its variables
+            let bound = binds.len();
+            let matched = pat_matches(&alt.val, val, binds);
+            if !matched {
+                binds.truncate(bound);
+            }
+            matched
+        }),
+        (Pat::At { ident, pat }, _) => {
+            binds.push((*ident, val.clone()));
+            pat_matches(&pat.val, val, binds)
+        }
+        (
+            Pat::Enum { variant, items, .. },
+            ConstVal::Enum { variant: val_variant, items: vals, .. },
+        ) => {
+            variant == val_variant
+                && items.iter().zip(vals).all(|(p, v)| pat_matches(&p.val, v, binds))
+        }
+        (Pat::Struct { fields, .. }, ConstVal::Struct { fields: vals, .. }) => {
+            fields.iter().all(|f| {
+                let val = &vals.iter().find(|(id, _)| *id == f.ident).expect("field exists").1;
+                pat_matches(&f.pat.val, val, binds)
+            })
+        }
+        (Pat::Array { items, .. }, ConstVal::Array(vals)) => {
+            items.iter().zip(vals).all(|(p, v)| pat_matches(&p.val, v, binds))
+        }
+        _ => false,
+    }
+}
+
+fn binop(op: &BinOp, lhs: ConstVal, rhs: ConstVal, span: Range) -> EvalResult<ConstVal> {
+    let overflow = || error(span, format!("[E0ce] attempt to {} with overflow", verb(op)));
+    let val = match (lhs, rhs) {
+        (ConstVal::Val(Val::Int(a)), ConstVal::Val(Val::Int(b))) => match op {
+            BinOp::Div | BinOp::Rem if b == 0 => {
+                return error(span, "[E0ce] attempt to divide by zero".to_owned());
+            }
+            BinOp::Mul => a.checked_mul(b).map_or_else(overflow, |v| Ok(Val::Int(v)))?,
+            BinOp::Div => a.checked_div(b).map_or_else(overflow, |v| Ok(Val::Int(v)))?,
+            BinOp::Rem => a.checked_rem(b).map_or_else(overflow, |v| Ok(Val::Int(v)))?,
+            BinOp::Add => a.checked_add(b).map_or_else(overflow, |v| Ok(Val::Int(v)))?,
+            BinOp::Sub => a.checked_sub(b).map_or_else(overflow, |v| Ok(Val::Int(v)))?,
+            BinOp::LeftShift => u32::try_from(b)
+                .ok()
+                .and_then(|b| a.checked_shl(b))
+                .map_or_else(overflow, |v| Ok(Val::Int(v)))?,
+            BinOp::RightShift => u32::try_from(b)
+                .ok()
+                .and_then(|b| a.checked_shr(b))
+                .map_or_else(overflow, |v| Ok(Val::Int(v)))?,
+            BinOp::Lt => Val::Bool(a < b),
+            BinOp::Le => Val::Bool(a <= b),
+            BinOp::Ge => Val::Bool(a >= b),
+            BinOp::Gt => Val::Bool(a > b),
+            BinOp::Eq => Val::Bool(a == b),
+            BinOp::Ne => Val::Bool(a != b),
+            BinOp::BitAnd => Val::Int(a & b),
+            BinOp::BitXor => Val::Int(a ^ b),
+            BinOp::BitOr => Val::Int(a | b),
+            BinOp::And | BinOp::Or | BinOp::AddAssign | BinOp::SubAssign => {
+                unreachable!("ICE logical and assign operations are handled by the caller")
+            }
+        },
+        (ConstVal::Val(Val::SizedInt(a, ity)), ConstVal::Val(Val::SizedInt(b, rty)))
+            if ity == rty =>
+        {
+            // Widened to `i128` every operation is exact so overflow is just a range check
+            let (a, b) = (ity.widen(a), ity.widen(b));
+            let sized = |v: i128| Val::SizedInt(ity.wrap(v), ity);
+            let checked = |v: i128| if ity.fits(v) { Ok(sized(v)) } else { overflow() };
+            match op {
+                BinOp::Div | BinOp::Rem if b == 0 => {
+                    return error(span, "[E0ce] attempt to divide by zero".to_owned());
+                }
+                BinOp::Mul => checked(a * b)?,
+                BinOp::Div => checked(a / b)?,
+                BinOp::Rem => checked(a % b)?,
+                BinOp::Add => checked(a + b)?,
+                BinOp::Sub => checked(a - b)?,
+                BinOp::LeftShift | BinOp::RightShift
+                    if !(0..(ity.size() * 8) as i128).contains(&b) =>
+                {
+                    overflow()?
+                }
+                BinOp::LeftShift => sized(a << b),
+                BinOp::RightShift => sized(a >> b),
+                BinOp::Lt => Val::Bool(a < b),
+                BinOp::Le => Val::Bool(a <= b),
+                BinOp::Ge => Val::Bool(a >= b),
+                BinOp::Gt => Val::Bool(a > b),
+                BinOp::Eq => Val::Bool(a == b),
+                BinOp::Ne => Val::Bool(a != b),
+                BinOp::BitAnd => sized(a & b),
+                BinOp::BitXor => sized(a ^ b),
+                BinOp::BitOr => sized(a | b),
+                BinOp::And | BinOp::Or | BinOp::AddAssign | BinOp::SubAssign => {
+                    unreachable!("ICE logical and assign operations are handled by the caller")
+                }
+            }
+        }
+        (ConstVal::Val(Val::Float(a)), ConstVal::Val(Val::Float(b))) => match op {
+            BinOp::Mul => Val::Float(a * b),
+            BinOp::Div => Val::Float(a / b),
+            BinOp::Rem => Val::Float(a % b),
+            BinOp::Add => Val::Float(a + b),
+            BinOp::Sub => Val::Float(a - b),
+            BinOp::Lt => Val::Bool(a < b),
+            BinOp::Le => Val::Bool(a <= b),
+            BinOp::Ge => Val::Bool(a >= b),
+            BinOp::Gt => Val::Bool(a > b),
+            BinOp::Eq => Val::Bool(a == b),
+            BinOp::Ne => Val::Bool(a != b),
+            _ => return error(span, format!("[E0ce] can not apply `{:?}` to `float`", op)),
+        },
+        (ConstVal::Val(Val::Char(a)), ConstVal::Val(Val::Char(b))) => match op {
+            BinOp::Lt => Val::Bool(a < b),
+            BinOp::Le => Val::Bool(a <= b),
+            BinOp::Ge => Val::Bool(a >= b),
+            BinOp::Gt => Val::Bool(a > b),
+            BinOp::Eq => Val::Bool(a == b),
+            BinOp::Ne => Val::Bool(a != b),
+            _ => return error(span, format!("[E0ce] can not apply `{:?}` to `char`", op)),
+        },
+        (ConstVal::Val(Val::Bool(a)), ConstVal::Val(Val::Bool(b))) => match op {
+            BinOp::Eq => Val::Bool(a == b),
+            BinOp::Ne => Val::Bool(a != b),
+            BinOp::BitAnd => Val::Bool(a & b),
+            BinOp::BitXor => Val::Bool(a ^ b),
+            BinOp::BitOr => Val::Bool(a | b),
+            _ => return error(span, format!("[E0ce] can not apply `{:?}` to `bool`", op)),
+        },
+        (l, r) => {
+            return error(
+                span,
+                format!(
+                    "[E0ce] can not apply `{:?}` to `{}` and `{}`",
+                    op,
+                    l.description(),
+                    r.description()
+                ),
+            );
+        }
+    };
+    Ok(ConstVal::Val(val))
+}
+
+fn verb(op: &BinOp) -> &'static str {
+    match op {
+        BinOp::Mul => "multiply",
+        BinOp::Div => "divide",
+        BinOp::Rem => "calculate the remainder",
+        BinOp::Add => "add",
+        BinOp::Sub => "subtract",
+        BinOp::LeftShift => "shift left",
+        BinOp::RightShift => "shift right",
+        _ => unreachable!("ICE only arithmetic can overflow"),
+    }
+}
@@ -0,0 +1,438 @@
+//! Match exhaustiveness and reachability checking.
+//!
+//! This is the "usefulness" algorithm from Luc Maranget's "Warnings for pattern matching". A
+//! pattern vector `v` is useful with respect to a matrix of patterns `P` if there is a value that
+//! `v` matches and no row of `P` matches. With that one question we can answer both of ours:
+//!
+//! - an arm is unreachable if its pattern is not useful with respect to the arms above it
+//! - a match is exhaustive if a wildcard `_` is not useful with respect to all the arms
+//!
+//! When the match is not exhaustive we build a witness, a pattern that is not covered, and report
+//! it as code the user could paste into a new arm (`option::none`).
+
+use std::fmt;
+
+use crate::{
+    ast::{
+        parse::symbol::Ident,
+        types::{Binding, Generic, MatchArm, Pat, Range, Ty, Type, Val},
+    },
+    error::Error,
+    typeck::TyCheckRes,
+};
+
+/// The most missing patterns we bother reporting for a single match.
+const MAX_WITNESSES: usize = 3;
+
+/// The head of a pattern, what kind of value it matches ignoring any sub-patterns.
+#[derive(Clone, Debug, PartialEq)]
+enum Ctor {
+    /// An enum variant, by index into the enum's variants.
+    Variant { enm: Ident, variant: Ident, idx: usize },
+    /// `true` or `false`.
+    Bool(bool),
+    /// An array of fixed size, there is only ever one of these per type.
+    Array(usize),
+    /// A struct, like arrays there is only one of these per type. The sub-patterns are the fields
+    /// in the order they are defined.
+    Struct { ident: Ident, fields: Vec<Ident> },
+    /// A literal of a type with too many values to list (int, char, float, string).
+    Value(Val),
+    /// An inclusive range of ints or chars.
+    Range(Val, Val),
+}
+
+impl Ctor {
+    /// Is every value `other` matches also matched by `self`.
+    ///
+    /// This is conservative for ranges, `1..=5` is not covered by `1..=2` and `3..=5` together so
+    /// we may miss an unreachable range arm but we never call a reachable arm unreachable.
+    fn covers(&self, other: &Ctor) -> bool {
+        match (self, other) {
+            (Ctor::Range(lo, hi), Ctor::Value(v)) => {
+                matches!((scalar(lo), scalar(hi), scalar(v)), (Some(lo), Some(hi), Some(v)) if lo <= v && v <= hi)
+            }
+            (Ctor::Range(lo, hi), Ctor::Range(a, b)) => {
+                matches!((scalar(lo), scalar(hi), scalar(a), scalar(b)), (Some(lo), Some(hi), Some(a), Some(b)) if lo <= a && b <= hi)
+            }
+            (Ctor::Value(v), Ctor::Range(a, b)) => a == v && b == v,
+            (a, b) => a == b,
+        }
+    }
+}
+
+/// The ordering of int and char values so we can tell what is in a range.
+fn scalar(val: &Val) -> Option<i128> {
+    match val {
+        Val::Int(i) => Some(*i as i128),
+        Val::SizedInt(i, ity) => Some(ity.widen(*i)),
+        Val::Char(c) => Some(*c as u32 as i128),
+        _ => None,
+    }
+}
+
+/// A deconstructed pattern, either a constructor applied to its sub-patterns or a wildcard.
+#[derive(Clone, Debug)]
+enum DPat {
+    Ctor(Ctor, Vec<DPat>),
+    /// Any of the alternatives `a | b`.
+    Or(Vec<DPat>),
+    /// A variable binding or any other pattern that matches everything.
+    Wild,
+}
+
+impl DPat {
+    fn head(&self) -> Option<&Ctor> {
+        match self {
+            DPat::Ctor(c, _) => Some(c),
+            DPat::Or(_) | DPat::Wild => None,
+        }
+    }
+}
+
+fn join(pats: &[DPat], sep: &str) -> String {
+    pats.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(sep)
+}
+
+impl fmt::Display for DPat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DPat::Wild => write!(f, "_"),
+            DPat::Or(alts) => write!(f, "{}", join(alts, " | ")),
+            DPat::Ctor(Ctor::Variant { enm, variant, .. }, items) => {
+                write!(f, "{}::{}", enm, variant)?;
+                if !items.is_empty() {
+                    write!(f, "({})", join(items, ", "))?;
+                }
+                Ok(())
+            }
+            DPat::Ctor(Ctor::Bool(b), _) => write!(f, "{}", b),
+            DPat::Ctor(Ctor::Array(_), items) => write!(f, "[{}]", join(items, ", ")),
+            DPat::Ctor(Ctor::Struct { ident, fields }, items) => write!(
+                f,
+                "{} {{ {} }}",
+                ident,
+                fields
+                    .iter()
+                    .zip(items)
+                    .map(|(field, p)| format!("{}: {}", field, p))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            DPat::Ctor(Ctor::Value(v), _) => write!(f, "{}", v),
+            DPat::Ctor(Ctor::Range(lo, hi), _) => write!(f, "{}..={}", lo, hi),
+        }
+    }
+}
+
+/// A row of the pattern matrix, one pattern per column.
+type Row = Vec<DPat>;
+
+struct Matcher<'a, 'ast, 'input> {
+    tcxt: &'a TyCheckRes<'ast, 'input>,
+}
+
+impl Matcher<'_, '_, '_> {
+    /// Every constructor of `ty`, `None` if there are too many to list.
+    fn all_ctors(&self, ty: &Ty) -> Option<Vec<Ctor>> {
+        match ty {
+            Ty::Enum { ident, .. } => {
+                let enm = self.tcxt.name_enum.get(ident)?;
+                Some(
+                    enm.variants
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, v)| Ctor::Variant { enm: *ident, variant: v.ident, idx })
+                        .collect(),
+                )
+            }
+            Ty::Struct { ident, .. } => {
+                let strc = self.tcxt.name_struct.get(ident)?;
+                Some(vec![Ctor::Struct {
+                    ident: *ident,
+                    fields: strc.fields.iter().map(|f| f.ident).collect(),
+                }])
+            }
+            Ty::Bool => Some(vec![Ctor::Bool(true), Ctor::Bool(false)]),
+            Ty::Array { size, .. } => Some(vec![Ctor::Array(*size)]),
+            _ => None,
+        }
+    }
+
+    /// The types of the sub-patterns of `ctor`, with any generics substituted.
+    fn field_tys(&self, ty: &Ty, ctor: &Ctor) -> Vec<Ty> {
+        match (ty, ctor) {
+            (Ty::Enum { ident, gen }, Ctor::Variant { idx, .. }) => {
+                let enm =
+                    if let Some(e) = self.tcxt.name_enum.get(ident) { e } else { return vec![] };
+                enm.variants
+                    .get(*idx)
+                    .map(|v| v.types.iter().map(|t| subst(&t.val, &enm.generics, gen)).collect())
+                    .unwrap_or_default()
+            }
+            (Ty::Struct { ident, gen }, Ctor::Struct { .. }) => {
+                let strc =
+                    if let Some(s) = self.tcxt.name_struct.get(ident) { s } else { return vec![] };
+                strc.fields.iter().map(|f| subst(&f.ty.get().val, &strc.generics, gen)).collect()
+            }
+            (Ty::Array { size, ty }, Ctor::Array(_)) => vec![ty.val.clone(); *size],
+            _ => vec![],
+        }
+    }
+
+    /// Deconstruct `pat` which matches values of `ty`.
+    ///
+    /// Missing sub-patterns (`option::some` or fields left out by `..`) become wildcards so every
+    /// constructor always has one sub-pattern per field.
+    fn lower(&self, pat: &Pat, ty: &Ty) -> DPat {
+        let (ctor, items) = match (pat, ty) {
+            (Pat::Enum { variant, items, .. }, Ty::Enum { ident, .. }) => {
+                let idx = self
+                    .tcxt
+                    .name_enum
+                    .get(ident)
+                    .and_then(|e| e.variants.iter().position(|v| v.ident == *variant))
+                    .unwrap_or(usize::MAX);
+                let ctor = Ctor::Variant { enm: *ident, variant: *variant, idx };
+                (ctor, items.iter().map(|p| Some(&p.val)).collect::<Vec<_>>())
+            }
+            (Pat::Array { size, items }, _) => {
+                (Ctor::Array(*size), items.iter().map(|p| Some(&p.val)).collect())
+            }
+            (Pat::Struct { fields, .. }, Ty::Struct { .. }) => {
+                let ctor = if let Some(c) = self.all_ctors(ty).and_then(|mut c| c.pop()) {
+                    c
+                } else {
+                    return DPat::Wild;
+                };
+                let items = if let Ctor::Struct { fields: defs, .. } = &ctor {
+                    defs.iter()
+                        .map(|def| fields.iter().find(|f| f.ident == *def).map(|f| &f.pat.val))
+                        .collect()
+                } else {
+                    vec![]
+                };
+                (ctor, items)
+            }
+            (Pat::Range { start, end }, _) => {
+                (Ctor::Range(start.val.clone(), end.val.clone()), vec![])
+            }
+            (Pat::Bind(Binding::Value(val)), _) => match &val.val {
+                Val::Bool(b) => (Ctor::Bool(*b), vec![]),
+                v => (Ctor::Value(v.clone()), vec![]),
+            },
+            (Pat::Or(alts), _) => {
+                return DPat::Or(alts.iter().map(|p| self.lower(&p.val, ty)).collect());
+            }
+            (Pat::At { pat, .. }, _) => return self.lower(&pat.val, ty),
+            _ => return DPat::Wild,
+        };
+
+        let tys = self.field_tys(ty, &ctor);
+        let items = tys
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| {
+                items.get(i).copied().flatten().map_or(DPat::Wild, |p| self.lower(p, ty))
+            })
+            .collect();
+        DPat::Ctor(ctor, items)
+    }
+
+    /// Keep the rows whose first column could match `ctor`, replacing that column with the
+    /// constructor's fields.
+    fn specialize(&self, rows: &[Row], ctor: &Ctor, arity: usize) -> Vec<Row> {
+        rows.iter()
+            .flat_map(expand_or)
+            .filter_map(|row| specialize_row(&row, ctor, arity))
+            .collect()
+    }
+
+    /// Keep the rows whose first column is a wildcard, minus that column.
+    fn default_rows(&self, rows: &[Row]) -> Vec<Row> {
+        rows.iter()
+            .flat_map(expand_or)
+            .filter(|row| matches!(row[0], DPat::Wild))
+            .map(|row| row[1..].to_vec())
+            .collect()
+    }
+
+    /// The constructors in the first column of `rows`, looking inside or-patterns.
+    fn heads(&self, rows: &[Row]) -> Vec<Ctor> {
+        rows.iter().flat_map(expand_or).filter_map(|r| r[0].head().cloned()).collect()
+    }
+
+    /// If every constructor of `ty` appears in the first column of `rows` return them all.
+    fn complete_signature(&self, rows: &[Row], ty: &Ty) -> Option<Vec<Ctor>> {
+        let all = self.all_ctors(ty)?;
+        let heads = self.heads(rows);
+        if all.iter().all(|c| heads.contains(c)) {
+            Some(all)
+        } else {
+            None
+        }
+    }
+
+    /// Is there a value matched by `v` that no row of `rows` matches.
+    fn is_useful(&self, rows: &[Row], v: &[DPat], tys: &[Ty]) -> bool {
+        if tys.is_empty() {
+            return rows.is_empty();
+        }
+
+        match &v[0] {
+            DPat::Or(alts) => alts.iter().any(|alt| {
+                self.is_useful(rows, &[vec![alt.clone()].as_slice(), &v[1..]].concat(), tys)
+            }),
+            DPat::Ctor(ctor, _) => {
+                let fields = self.field_tys(&tys[0], ctor);
+                let arity = fields.len();
+                let spec = self.specialize(rows, ctor, arity);
+                let v =
+                    if let Some(v) = specialize_row(v, ctor, arity) { v } else { return false };
+                self.is_useful(&spec, &v, &[fields.as_slice(), &tys[1..]].concat())
+            }
+            DPat::Wild => {
+                if let Some(ctors) = self.complete_signature(rows, &tys[0]) {
+                    ctors.iter().any(|ctor| {
+                        let fields = self.field_tys(&tys[0], ctor);
+                        let arity = fields.len();
+                        let spec = self.specialize(rows, ctor, arity);
+                        let v = specialize_row(v, ctor, arity).unwrap();
+                        self.is_useful(&spec, &v, &[fields.as_slice(), &tys[1..]].concat())
+                    })
+                } else {
+                    self.is_useful(&self.default_rows(rows), &v[1..], &tys[1..])
+                }
+            }
+        }
+    }
+
+    /// Find a pattern vector (one per column) that no row of `rows` matches.
+    fn witness(&self, rows: &[Row], tys: &[Ty]) -> Option<Row> {
+        if tys.is_empty() {
+            return if rows.is_empty() { Some(vec![]) } else { None };
+        }
+
+        if let Some(ctors) = self.complete_signature(rows, &tys[0]) {
+            ctors.iter().find_map(|ctor| {
+                let fields = self.field_tys(&tys[0], ctor);
+                let arity = fields.len();
+                let spec = self.specialize(rows, ctor, arity);
+                let mut wit = self.witness(&spec, &[fields.as_slice(), &tys[1..]].concat())?;
+                let rest = wit.split_off(arity);
+                Some([vec![DPat::Ctor(ctor.clone(), wit)], rest].concat())
+            })
+        } else {
+            let rest = self.witness(&self.default_rows(rows), &tys[1..])?;
+            // Name a constructor that is missing if we can, otherwise anything will do
+            let heads = self.heads(rows);
+            let head = self
+                .all_ctors(&tys[0])
+                .and_then(|all| all.into_iter().find(|c| !heads.contains(c)))
+                .map_or(DPat::Wild, |ctor| {
+                    let arity = self.field_tys(&tys[0], &ctor).len();
+                    DPat::Ctor(ctor, vec![DPat::Wild; arity])
+                });
+            Some([vec![head], rest].concat())
+        }
+    }
+}
+
+/// Substitute the generics of an enum or struct definition with the types it was used with.
+fn subst(ty: &Ty, generics: &[Generic], gen: &[Type]) -> Ty {
+    match ty {
+        Ty::Generic { ident, .. } => generics
+            .iter()
+            .position(|def| def.ident == *ident)
+            .and_then(|pos| gen.get(pos))
+            .map_or_else(|| ty.clone(), |t| t.val.clone()),
+        t => t.clone(),
+    }
+}
+
+/// Split a row that starts with an or-pattern into one row per alternative.
+fn expand_or(row: &Row) -> Vec<Row> {
+    match &row[0] {
+        DPat::Or(alts) => alts
+            .iter()
+            .flat_map(|alt| expand_or(&[vec![alt.clone()].as_slice(), &row[1..]].concat()))
+            .collect(),
+        _ => vec![row.clone()],
+    }
+}
+
+/// `row` must not start with an or-pattern, see `expand_or`.
+fn specialize_row(row: &[DPat], ctor: &Ctor, arity: usize) -> Option<Row> {
+    match &row[0] {
+        DPat::Ctor(c, items) if c.covers(ctor) => Some([items.as_slice(), &row[1..]].concat()),
+        DPat::Ctor(..) => None,
+        DPat::Or(_) => unreachable!("or-patterns are expanded before specializing"),
+        DPat::Wild => Some([vec![DPat::Wild; arity].as_slice(), &row[1..]].concat()),
+    }
+}
+
+/// Check that `arms` cover every value of `ty` and that every arm can be reached.
+///
+/// Missing patterns are errors, unreachable arms are warnings.
+crate fn check_match(tcxt: &TyCheckRes<'_, '_>, ty: &Ty, arms: &[MatchArm], span: Range) {
+    let cx = Matcher { tcxt };
+    let tys = vec![ty.clone()];
+
+    let mut rows: Vec<Row> = vec![];
+    for arm in arms {
+        let row = vec![cx.lower(&arm.pat.val, ty)];
+        if !cx.is_useful(&rows, &row, &tys) {
+            tcxt.errors.push_warning(Error::error_with_span(
+                tcxt,
+                arm.pat.span,
+                &format!("[W0tc] unreachable pattern `{}`", arm.pat.val),
+            ));
+        }
+        // A guarded arm may not match even when the pattern does so it covers nothing
+        if arm.guard.is_none() {
+            rows.push(row);
+        }
+    }
+
+    let mut missing = vec![];
+    while let Some(wit) = cx.witness(&rows, &tys) {
+        missing.push(wit[0].to_string());
+        if missing.len() > MAX_WITNESSES {
+            break;
+        }
+        rows.push(wit);
+    }
+
+    if !missing.is_empty() {
+        let pats = if missing.len() > MAX_WITNESSES {
+            format!(
+                "{} and more",
+                missing[..MAX_WITNESSES]
+                    .iter()
+                    .map(|p| format!("`{}`", p))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        } else {
+            missing.iter().map(|p| format!("`{}`", p)).collect::<Vec<_>>().join(", ")
+        };
+        tcxt.errors.push_error(Error::error_with_span(
+            tcxt,
+            span,
+            &format!(
+                "[E0tc] non-exhaustive match, pattern{} {} not covered",
+                plural(&missing),
+                pats
+            ),
+        ));
+        tcxt.errors.poisoned(true);
+    }
+}
+
+fn plural(list: &[String]) -> &'static str {
+    if list.len() > 1 {
+        "s"
+    } else {
+        ""
+    }
+}
@@ -79,7 +79,7 @@ impl Eq for GenericArgument<'_> {}
 #[derive(Debug, Default, PartialEq, Eq)]
 crate struct GenericParam {
     /// Generic type name `T` to possible bounds `T: add`.
-    generics: HashMap<Ident, Option<Path>>,
+    generics: HashMap<Ident, Vec<Path>>,
     /// Any dependent generic types. When monomorphizing these will be walked to create
     /// mono variants of each type.
     ///
@@ -104,8 +104,8 @@ impl<'a> Iterator for GenericParamIter<'a> {
 }
 
 impl GenericParam {
-    fn insert_generic(&mut self, id: Ident, bound: Option<Path>) {
-        self.generics.insert(id, bound);
+    fn insert_generic(&mut self, id: Ident, bounds: Vec<Path>) {
+        self.generics.insert(id, bounds);
     }
 
     crate fn child_iter(&self) -> GenericParamIter {
@@ -150,8 +150,8 @@ impl<'ast> GenericResolver<'ast> {
 
     crate fn collect_generic_params(&mut self, node: &Node, ty: &Ty) {
         match ty {
-            Ty::Generic { ident, bound } => {
-                self.item_generics.entry(*node).or_default().insert_generic(*ident, bound.clone());
+            Ty::Generic { ident, bounds } => {
+                self.item_generics.entry(*node).or_default().insert_generic(*ident, bounds.clone());
             }
             Ty::Array { size: _, ty: _ } => todo!(),
             Ty::Struct { ident: _, gen } => {
@@ -183,14 +183,14 @@ impl<'ast> GenericResolver<'ast> {
         stack: &[Node],
         _expr: &[TyRegion<'ast>],
         id: Ident,
-        bound: Option<Path>,
+        bounds: Vec<Path>,
     ) -> Option<()> {
         // TODO: can this be more than 2 deep??
         let mut iter = stack.iter();
         let gp = self.item_generics.get_mut(iter.next()?)?;
 
         let mut generics = HashMap::default();
-        generics.insert(id.to_owned(), bound);
+        generics.insert(id.to_owned(), bounds);
 
         gp.children.push((*iter.next()?, GenericParam { generics, children: vec![] }));
 
@@ -235,18 +235,26 @@ impl<'ast> GenericResolver<'ast> {
     ) {
         // println!("collect {:?} {:?}", ty, stack);
         match &ty {
-            Ty::Generic { ident, bound } => {
-                self.push_generic_child(stack, exprs, *ident, bound.clone());
+            Ty::Generic { ident, bounds } => {
+                self.push_generic_child(stack, exprs, *ident, bounds.clone());
             }
             Ty::Array { size: _, ty } => {
                 self.collect_generic_usage(&ty.val, instance_id, gen_idx, exprs, stack)
             }
+            Ty::ConstArray { size, ty } => {
+                if let Expr::Ident(ident) = &size.val {
+                    self.push_generic_child(stack, exprs, *ident, vec![]);
+                }
+                if ty.val.has_generics() {
+                    self.collect_generic_usage(&ty.val, instance_id, gen_idx, exprs, stack)
+                }
+            }
             Ty::Struct { ident: struct_name, gen } => {
                 if gen.iter().any(|t| t.val.has_generics()) {
                     for t in gen.iter() {
-                        if let Ty::Generic { ident, bound } = &t.val {
+                        if let Ty::Generic { ident, bounds } = &t.val {
                             stack.push(Node::Struct(*struct_name));
-                            self.push_generic_child(stack, exprs, *ident, bound.clone());
+                            self.push_generic_child(stack, exprs, *ident, bounds.clone());
                         } else {
                             self.collect_generic_usage(&t.val, instance_id, gen_idx, exprs, stack);
                         }
@@ -259,9 +267,9 @@ impl<'ast> GenericResolver<'ast> {
             Ty::Enum { ident: enum_name, gen } => {
                 if gen.iter().any(|t| t.val.has_generics()) {
                     for t in gen.iter() {
-                        if let Ty::Generic { ident, bound } = &t.val {
+                        if let Ty::Generic { ident, bounds } = &t.val {
                             stack.push(Node::Enum(*enum_name));
-                            self.push_generic_child(stack, exprs, *ident, bound.clone());
+                            self.push_generic_child(stack, exprs, *ident, bounds.clone());
                         } else {
                             self.collect_generic_usage(&t.val, instance_id, gen_idx, exprs, stack);
                         }
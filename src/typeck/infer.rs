@@ -10,18 +10,19 @@ use crate::{
     ast::{
         parse::{symbol::Ident, ParseResult},
         types::{
-            to_rng, Adt, BinOp, Binding, Block, Const, Decl, Declaration, Enum, Expr, Expression,
-            Field, FieldInit, Func, Generic, Impl, MatchArm, Param, Pat, Path, Range, Spany,
-            Statement, Stmt, Struct, Trait, Ty, Type, TypeEquality, UnOp, Val, Variant, DUMMY,
+            to_rng, Adt, BinOp, Binding, Block, Builtin, Const, Decl, Declaration, Enum, Expr,
+            Expression, Field, FieldInit, Func, Generic, Impl, MatchArm, Param, Pat, Path, Range,
+            Spany, Statement, Stmt, Struct, Trait, Ty, Type, TypeEquality, UnOp, Val, Variant,
+            DUMMY,
         },
     },
     error::Error,
     typeck::{
-        check::{fold_ty, resolve_ty},
+        check::{check_value_block, fold_ty, resolve_ty, StmtCheck},
         check_field_access,
         generic::{Node, TyRegion},
         scope::ScopedName,
-        TyCheckRes,
+        subs_type_args, TyCheckRes,
     },
     visit::Visit,
 };
@@ -36,7 +37,7 @@ impl<'ast> TypeInfer<'_, 'ast, '_> {
     fn unify(&self, ty: Option<&Ty>, with: Option<&Ty>) -> Option<Ty> {
         match (ty, with) {
             (Some(t1), Some(t2)) => match (t1, t2) {
-                (Ty::Generic { ident: i1, bound: b1 }, Ty::Generic { ident: i2, bound: b2 }) => {
+                (Ty::Generic { ident: i1, bounds: b1 }, Ty::Generic { ident: i2, bounds: b2 }) => {
                     todo!()
                 }
                 (Ty::Array { size: s1, ty: ty1 }, Ty::Array { size: s2, ty: ty2 }) => {
@@ -99,6 +100,7 @@ impl<'ast> TypeInfer<'_, 'ast, '_> {
                 // TODO: hmmmm
                 (Ty::ConstStr(..), Ty::ConstStr(..)) => Some(Ty::ConstStr(0)),
                 (Ty::Int, Ty::Int) => Some(Ty::Int),
+                (Ty::SizedInt(i1), Ty::SizedInt(i2)) if i1 == i2 => Some(Ty::SizedInt(*i1)),
                 (Ty::Char, Ty::Char) => Some(Ty::Char),
                 (Ty::Float, Ty::Float) => Some(Ty::Float),
                 (Ty::Bool, Ty::Bool) => Some(Ty::Bool),
@@ -227,6 +229,11 @@ impl<'ast> TypeInfer<'_, 'ast, '_> {
             | Expr::EnumInit { .. }
             | Expr::ArrayInit { .. }
             | Expr::Builtin(..)
+            | Expr::If { .. }
+            | Expr::Match { .. }
+            | Expr::Cast { .. }
+            | Expr::Try(..)
+            | Expr::InlineAsm { .. }
             | Expr::Value(_) => {
                 self.tcxt.errors.push_error(
                     Error::error_with_span(self.tcxt, parent.span, "[E0i] invalid lValue")
@@ -248,7 +255,8 @@ impl<'ast> Visit<'ast> for TypeInfer<'_, 'ast, '_> {
                 let ty = if let Some(t) =
                     self.tcxt.expr_ty.get(rval).or_else(|| given_ty.as_ref().map(|t| &t.val))
                 {
-                    t.clone()
+                    // `let x = *ptr;` is the type behind the pointer not a deref of it
+                    t.resolve().unwrap_or_else(|| t.clone())
                 } else {
                     self.tcxt.errors.push_error(Error::error_with_span(
                         self.tcxt,
@@ -376,14 +384,23 @@ impl<'ast> Visit<'ast> for TypeInfer<'_, 'ast, '_> {
             Stmt::Ret(expr) => {
                 self.visit_expr(expr);
             }
-            Stmt::Exit => {}
+            Stmt::Exit | Stmt::Break => {}
+            Stmt::Defer(_) => {
+                // DO NOT WALK DEEPER the calling method is doing the walking
+            }
             Stmt::Block(blk) => {
                 for stmt in blk.stmts.iter() {
                     self.visit_stmt(stmt);
                 }
             }
-            Stmt::InlineAsm(..) => {
+            Stmt::InlineAsm(asm) => {
                 // TODO: we could type check the ident in here
+                for op in asm.inputs.iter().chain(&asm.outputs) {
+                    self.visit_expr(&op.expr);
+                }
+            }
+            Stmt::Builtin(Builtin::StaticAssert(cond, _)) => {
+                self.visit_expr(cond);
             }
             Stmt::Builtin(..) => {
                 // TODO: we could type check the ident in here
@@ -440,9 +457,11 @@ impl<'ast> Visit<'ast> for TypeInfer<'_, 'ast, '_> {
             }
             Expr::Urnary { op, expr: ex } => {
                 self.visit_expr(ex);
-                let exprty = self.tcxt.expr_ty.get(&**ex);
+                // `!*flag` is a `bool` not a reference to one
+                let exprty =
+                    self.tcxt.expr_ty.get(&**ex).map(|t| t.resolve().unwrap_or_else(|| t.clone()));
 
-                if let Some(ty) = exprty.cloned() {
+                if let Some(ty) = exprty {
                     self.tcxt.expr_ty.insert(expr, ty);
                 }
             }
@@ -493,9 +512,7 @@ impl<'ast> Visit<'ast> for TypeInfer<'_, 'ast, '_> {
                         for (arg, param) in args.iter().zip(&func.params) {
                             if let Some(expr_ty) = self.tcxt.expr_ty.get(&arg) {
                                 if param.ty.get().val.has_generics() {
-                                    if let Some(ty_gen_pair) =
-                                        peel_out_ty(Some(expr_ty), &param.ty.get().val)
-                                    {
+                                    for ty_gen_pair in peel_out_tys(expr_ty, &param.ty.get().val) {
                                         // We already collected info for this generic
                                         if infered_ty_args.iter().any(|(_, g)| ty_gen_pair.1 == *g)
                                         {
@@ -566,9 +583,10 @@ impl<'ast> Visit<'ast> for TypeInfer<'_, 'ast, '_> {
                             });
 
                             for (idx, param) in params {
-                                let expr_ty = self.tcxt.expr_ty.get(&args[idx]);
-                                if let Some((ty, gen)) = peel_out_ty(expr_ty, &param.ty.get().val) {
-                                    subed_ty.subst_generic(gen, &ty);
+                                if let Some(expr_ty) = self.tcxt.expr_ty.get(&args[idx]) {
+                                    for (ty, gen) in peel_out_tys(expr_ty, &param.ty.get().val) {
+                                        subed_ty.subst_generic(gen, &ty);
+                                    }
                                 }
                             }
                         // There are type args to use yay!
@@ -602,13 +620,14 @@ impl<'ast> Visit<'ast> for TypeInfer<'_, 'ast, '_> {
                 let opt_imp = self
                     .tcxt
                     .trait_solve
-                    .impls
-                    .get(trait_)
-                    .unwrap()
-                    .get(&type_args.iter().map(|t| &t.val).collect::<Vec<_>>());
+                    .impl_for(trait_, &type_args.iter().map(|t| &t.val).collect::<Vec<_>>());
 
                 if let Some(imp) = opt_imp {
                     self.tcxt.expr_ty.insert(expr, imp.method.ret.get().val.clone());
+                } else if let Some(def) = self.tcxt.trait_solve.traits.get(trait_) {
+                    // `<<T>::meth>` in a generic function is solved after monomorphization
+                    let ret = subs_type_args(&def.method.return_ty().val, type_args, &def.generics);
+                    self.tcxt.expr_ty.insert(expr, ret);
                 }
             }
             Expr::FieldAccess { lhs, rhs } => {
@@ -713,23 +732,86 @@ impl<'ast> Visit<'ast> for TypeInfer<'_, 'ast, '_> {
                     Ty::Array { size, ty: box ty.unwrap_or(Ty::Void).into_spanned(DUMMY) },
                 );
             }
+            Expr::If { cond, blk, els } => {
+                self.visit_expr(cond);
+                // The branches are checked here since they can only be reached through the
+                // expression, `TyCheckRes::visit_expr` makes sure the types agree
+                let tys =
+                    vec![check_value_block(self.tcxt, blk), check_value_block(self.tcxt, els)];
+                self.tcxt.expr_ty.insert(expr, branch_ty(tys));
+            }
+            Expr::Match { expr: ex, arms } => {
+                self.visit_expr(ex);
+                if self.tcxt.expr_ty.get(&**ex).is_none() {
+                    return;
+                }
+                let tys = StmtCheck { tcxt: self.tcxt }.check_match(ex, arms, expr.span);
+                self.tcxt.expr_ty.insert(expr, branch_ty(tys));
+            }
             Expr::Value(val) => {
                 self.tcxt.expr_ty.insert(expr, val.val.to_type());
             }
             Expr::Builtin(b) => {
+                // Generics were resolved before inference, any other path is a user defined type.
+                // This has to happen before the expression is hashed
+                if let Some(t) = b.type_arg() {
+                    if let Ty::Path(..) = &t.get().val {
+                        if let Some(res) = self.tcxt.name_res.resolve_name(&t.get().val, self.tcxt)
+                        {
+                            t.set(res.into_spanned(t.get().span));
+                        }
+                    }
+                }
+                if let Builtin::Len(arr) = b {
+                    self.visit_expr(arr);
+                    match self.tcxt.expr_ty.get(&**arr) {
+                        Some(Ty::Array { .. } | Ty::ConstArray { .. } | Ty::Slice(_)) => {}
+                        ty => {
+                            self.tcxt.errors.push_error(Error::error_with_span(
+                                self.tcxt,
+                                arr.span,
+                                &format!(
+                                    "[E0i] `@len` expected an array or slice found `{}`",
+                                    ty.map_or("<unknown>".to_owned(), |t| t.to_string())
+                                ),
+                            ));
+                            self.tcxt.errors.poisoned(true);
+                        }
+                    }
+                }
                 self.tcxt.expr_ty.insert(expr, b.type_of());
             }
+            Expr::Cast { expr: ex, ty } => {
+                self.visit_expr(ex);
+                self.tcxt.expr_ty.insert(expr, ty.val.clone());
+            }
+            Expr::Try(ex) => {
+                // A `?` that was not desugared has no type, checking reports it
+                self.visit_expr(ex);
+            }
+            Expr::InlineAsm { asm, ty } => {
+                for op in asm.inputs.iter().chain(&asm.outputs) {
+                    self.visit_expr(&op.expr);
+                }
+                self.tcxt.expr_ty.insert(expr, ty.val.clone());
+            }
         }
     }
 }
 
+/// The type of an `if` or `match` expression is the type of the first branch that produces a
+/// value, if every branch diverges so does the whole expression.
+fn branch_ty(tys: Vec<Option<Ty>>) -> Ty {
+    tys.into_iter().flatten().find(|t| !matches!(t, Ty::Bottom)).unwrap_or(Ty::Bottom)
+}
+
 fn fetch_fields<'a>(lhs_ty: &Ty, span: Range, tcxt: &mut TyCheckRes<'a, '_>) -> Option<&'a Struct> {
     match lhs_ty {
         Ty::Struct { ident, gen } => tcxt.name_struct.get(ident).copied(),
         Ty::Path(path) => tcxt.name_struct.get(&path.local_ident()).copied(),
         Ty::Ptr(inner) => fetch_fields(&inner.val, span, tcxt),
         Ty::Ref(_) => todo!("{:?}", lhs_ty),
-        Ty::Generic { ident, bound } => None,
+        Ty::Generic { ident, bounds } => None,
         Ty::Array { size, ty } => todo!(),
         _ => {
             tcxt.errors.push_error(Error::error_with_span(
@@ -748,6 +830,10 @@ crate fn peel_out_ty(exty: Option<&Ty>, has_gen: &Ty) -> Option<(Ty, Ident)> {
     match (exty?, has_gen) {
         (t, Ty::Generic { ident, .. }) => Some((t.clone(), *ident)),
         (Ty::Array { ty: t1, .. }, Ty::Array { ty: t2, .. }) => peel_out_ty(Some(&t1.val), &t2.val),
+        (Ty::Array { size, .. }, Ty::ConstArray { size: gen_size, .. }) => match &gen_size.val {
+            Expr::Ident(gen) => Some((Ty::ConstArg(*size), *gen)),
+            _ => None,
+        },
         (Ty::Struct { ident: i1, gen: g1 }, Ty::Struct { ident: i2, gen: g2 }) if i1 == i2 => {
             g1.iter().zip(g2).find_map(|(a, b)| peel_out_ty(Some(&a.val), &b.val))
         }
@@ -760,3 +846,27 @@ crate fn peel_out_ty(exty: Option<&Ty>, has_gen: &Ty) -> Option<(Ty, Ident)> {
         _ => None,
     }
 }
+
+/// Return every concrete type and matching generic, unlike `peel_out_ty` an array `[N; T]` finds
+/// both `N` and `T` and a struct `map<K, V>` finds both `K` and `V`.
+crate fn peel_out_tys(exty: &Ty, has_gen: &Ty) -> Vec<(Ty, Ident)> {
+    match (exty, has_gen) {
+        (Ty::Struct { ident: i1, gen: g1 }, Ty::Struct { ident: i2, gen: g2 })
+        | (Ty::Enum { ident: i1, gen: g1 }, Ty::Enum { ident: i2, gen: g2 })
+            if i1 == i2 =>
+        {
+            g1.iter().zip(g2).flat_map(|(a, b)| peel_out_tys(&a.val, &b.val)).collect()
+        }
+        (Ty::Array { size, ty: t1 }, Ty::ConstArray { size: gen_size, ty: t2 }) => {
+            let mut found = peel_out_tys(&t1.val, &t2.val);
+            if let Expr::Ident(gen) = &gen_size.val {
+                found.insert(0, (Ty::ConstArg(*size), *gen));
+            }
+            found
+        }
+        (Ty::Array { ty: t1, .. }, Ty::Array { ty: t2, .. })
+        | (Ty::Ptr(t1), Ty::Ptr(t2))
+        | (Ty::Ref(t1), Ty::Ref(t2)) => peel_out_tys(&t1.val, &t2.val),
+        _ => peel_out_ty(Some(exty), has_gen).into_iter().collect(),
+    }
+}
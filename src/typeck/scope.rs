@@ -9,7 +9,7 @@ use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet, FxHasher};
 use crate::{
     ast::{
         parse::symbol::Ident,
-        types::{Const, Expr, Path, Range, Spany, Statement, Stmt, Ty, DUMMY},
+        types::{Const, Expr, Generic, Path, Range, Spany, Statement, Stmt, Ty, Type, DUMMY},
     },
     typeck::{TyCheckRes, Visit},
 };
@@ -105,8 +105,10 @@ crate struct ScopeWalker {
 
 impl ScopeWalker {
     crate fn add_file_scopes(&mut self, files: &HashMap<u64, &str>) {
+        // The items of the files already visited are kept, an import visits the imported file
+        // in the middle of the importing one
         for k in files.keys() {
-            self.global_scope.insert(*k, ScopeContents::default());
+            self.global_scope.entry(*k).or_default();
         }
     }
 
@@ -128,6 +130,16 @@ impl ScopeWalker {
         }
     }
 
+    /// Make the item `name` declared in the file `from` usable in the file `into` as if it was
+    /// declared there.
+    crate fn import_decl(&mut self, from: u64, into: u64, name: Ident) {
+        let items =
+            self.global_scope.get(&from).and_then(|scope| scope.contents.get(&name)).cloned();
+        if let (Some(items), Some(scope)) = (items, self.global_scope.get_mut(&into)) {
+            scope.contents.entry(name).or_insert(items);
+        }
+    }
+
     crate fn add_item(&mut self, file: u64, in_scope: Scope, item: ItemIn) {
         if let Some(items) = self.global_scope.get_mut(&file) {
             match items.contents.entry(in_scope.ident()) {
@@ -184,34 +196,41 @@ impl ScopeWalker {
     crate fn type_from_path(&self, path: &Path, tctx: &TyCheckRes<'_, '_>) -> Option<Ty> {
         let mut p = path.clone();
         let item = p.segs.first()?;
-        self.global_scope.get(&path.span.file_id)?.contents.get(item).and_then(|scope| {
+        // Imported items were added to the scope of the importing file
+        let scope = self.global_scope.get(&path.span.file_id)?.contents.get(item);
+        // Type arguments given with the path replace the generic parameters of the def
+        let gen_args = |generics: &[Generic]| -> Vec<Type> {
+            if path.gen.len() == generics.len() {
+                path.gen
+                    .iter()
+                    .map(|t| {
+                        self.resolve_name(&t.val, tctx)
+                            .map_or_else(|| t.clone(), |res| res.into_spanned(t.span))
+                    })
+                    .collect()
+            } else {
+                generics
+                    .iter()
+                    .map(|g| {
+                        Ty::Generic { ident: g.ident, bounds: g.bounds.clone() }.into_spanned(DUMMY)
+                    })
+                    .collect()
+            }
+        };
+        scope.and_then(|scope| {
             match scope.parent {
                 Scope::Trait { file, trait_ } => todo!(),
                 Scope::Impl { file, imp } => todo!(),
                 // TODO: just make fn a type already
                 Scope::Func { file, func } => None,
-                Scope::Struct { file, adt } => tctx.name_struct.get(&adt).map(|it| Ty::Struct {
-                    ident: it.ident,
-                    gen: it
-                        .generics
-                        .iter()
-                        .map(|g| {
-                            Ty::Generic { ident: g.ident, bound: g.bound.clone() }
-                                .into_spanned(DUMMY)
-                        })
-                        .collect(),
-                }),
-                Scope::Enum { file, adt } => tctx.name_enum.get(&adt).map(|it| Ty::Enum {
-                    ident: it.ident,
-                    gen: it
-                        .generics
-                        .iter()
-                        .map(|g| {
-                            Ty::Generic { ident: g.ident, bound: g.bound.clone() }
-                                .into_spanned(DUMMY)
-                        })
-                        .collect(),
-                }),
+                Scope::Struct { file, adt } => tctx
+                    .name_struct
+                    .get(&adt)
+                    .map(|it| Ty::Struct { ident: it.ident, gen: gen_args(&it.generics) }),
+                Scope::Enum { file, adt } => tctx
+                    .name_enum
+                    .get(&adt)
+                    .map(|it| Ty::Enum { ident: it.ident, gen: gen_args(&it.generics) }),
                 Scope::Global { file, name } => tctx.global.get(&name).cloned(),
                 Scope::Block(_) => todo!(),
             }
@@ -62,6 +62,26 @@ impl<'ast> TraitSolve<'ast> {
         Ok(())
     }
 
+    /// The implementation of `trait_` for `type_args`.
+    ///
+    /// The type arguments of an impl are not resolved, `impl allocator<heap>` is found for the
+    /// struct `heap` by name.
+    crate fn impl_for(&self, trait_: &Path, type_args: &[&Ty]) -> Option<&'ast Impl> {
+        let impls = self.impls.get(trait_)?;
+        impls.get(type_args).copied().or_else(|| {
+            impls.iter().find_map(|(tys, imp)| {
+                (tys.len() == type_args.len()
+                    && tys.iter().zip(type_args).all(|(t, arg)| match (t, arg) {
+                        (Ty::Path(path), Ty::Struct { ident, .. } | Ty::Enum { ident, .. }) => {
+                            path.segs.last() == Some(ident)
+                        }
+                        (t, arg) => t == arg,
+                    }))
+                .then_some(*imp)
+            })
+        })
+    }
+
     #[allow(clippy::wrong_self_convention)]
     crate fn to_solve(
         &mut self,
@@ -139,10 +139,16 @@ crate fn walk_params<'ast, V: Visit<'ast>>(visit: &mut V, params: &[Param]) {
 }
 
 crate fn walk_match_arm<'ast, V: Visit<'ast>>(visit: &mut V, arms: &'ast [MatchArm]) {
-    for MatchArm { pat: _, blk: Block { stmts, .. }, .. } in arms {
+    for MatchArm { pat: _, guard, blk: Block { stmts, tail, .. }, .. } in arms {
+        if let Some(guard) = guard {
+            visit.visit_expr(guard);
+        }
         for stmt in stmts.iter() {
             visit.visit_stmt(stmt);
         }
+        if let Some(tail) = tail {
+            visit.visit_expr(tail);
+        }
     }
 }
 
@@ -178,7 +184,8 @@ crate fn walk_stmt<'ast, V: Visit<'ast>>(visit: &mut V, stmt: &'ast Statement) {
             visit.visit_match_arm(arms);
         }
         Stmt::Ret(expr) => visit.visit_expr(expr),
-        Stmt::Exit => {}
+        Stmt::Exit | Stmt::Break => {}
+        Stmt::Defer(stmt) => visit.visit_stmt(stmt),
         Stmt::Block(Block { stmts, .. }) => {
             for stmt in stmts.iter() {
                 visit.visit_stmt(stmt);
@@ -188,7 +195,12 @@ crate fn walk_stmt<'ast, V: Visit<'ast>>(visit: &mut V, stmt: &'ast Statement) {
             visit.visit_expr(lval);
             visit.visit_expr(rval);
         }
-        Stmt::InlineAsm(asm) => {}
+        Stmt::InlineAsm(asm) => {
+            for op in asm.inputs.iter().chain(&asm.outputs) {
+                visit.visit_expr(&op.expr);
+            }
+        }
+        Stmt::Builtin(Builtin::StaticAssert(cond, _)) => visit.visit_expr(cond),
         Stmt::Builtin(builtin) => {}
     }
 }
@@ -250,7 +262,30 @@ crate fn walk_expr<'ast, V: Visit<'ast>>(visit: &mut V, expr: &'ast Expression)
         Expr::Value(_) => {
             // visit.visit_value(val);
         }
+        Expr::Builtin(Builtin::Len(expr)) => visit.visit_expr(expr),
         Expr::Builtin(..) => {}
+        Expr::If { cond, blk, els } => {
+            visit.visit_expr(cond);
+            for Block { stmts, tail, .. } in [blk, els] {
+                for stmt in stmts.iter() {
+                    visit.visit_stmt(stmt);
+                }
+                if let Some(tail) = tail {
+                    visit.visit_expr(tail);
+                }
+            }
+        }
+        Expr::Match { expr, arms } => {
+            visit.visit_expr(expr);
+            visit.visit_match_arm(arms);
+        }
+        Expr::Cast { expr, ty: _ } => visit.visit_expr(expr),
+        Expr::Try(expr) => visit.visit_expr(expr),
+        Expr::InlineAsm { asm, ty: _ } => {
+            for op in asm.inputs.iter().chain(&asm.outputs) {
+                visit.visit_expr(&op.expr);
+            }
+        }
     }
 }
 
@@ -389,20 +424,29 @@ crate fn walk_mut_params<'ast, V: VisitMut<'ast>>(visit: &mut V, params: &'ast m
 }
 
 crate fn walk_mut_match_arm<'ast, V: VisitMut<'ast>>(visit: &mut V, arms: &'ast mut [MatchArm]) {
-    for MatchArm { pat: _, blk: Block { stmts, .. }, .. } in arms {
+    for MatchArm { pat: _, guard, blk: Block { stmts, tail, .. }, .. } in arms {
+        if let Some(guard) = guard {
+            visit.visit_expr(guard);
+        }
         for stmt in stmts.iter_mut() {
             visit.visit_stmt(stmt);
         }
+        if let Some(tail) = tail {
+            visit.visit_expr(tail);
+        }
     }
 }
 
 crate fn walk_mut_stmt<'ast, V: VisitMut<'ast>>(visit: &mut V, stmt: &'ast mut Statement) {
     match &mut stmt.val {
         Stmt::Const(var) => visit.visit_var(var),
-        Stmt::Assign { lval, rval, .. } => {
+        Stmt::Assign { lval, rval, ty, .. } => {
             // visit.visit_ident(ident);
             visit.visit_expr(lval);
             visit.visit_expr(rval);
+            if let Some(ty) = ty {
+                visit.visit_ty(ty);
+            }
         }
         Stmt::Call(expr) => visit.visit_expr(expr),
         Stmt::TraitMeth(expr) => visit.visit_expr(expr),
@@ -428,7 +472,8 @@ crate fn walk_mut_stmt<'ast, V: VisitMut<'ast>>(visit: &mut V, stmt: &'ast mut S
             visit.visit_match_arm(arms);
         }
         Stmt::Ret(expr) => visit.visit_expr(expr),
-        Stmt::Exit => {}
+        Stmt::Exit | Stmt::Break => {}
+        Stmt::Defer(stmt) => visit.visit_stmt(stmt),
         Stmt::Block(Block { stmts, .. }) => {
             for stmt in stmts.iter_mut() {
                 visit.visit_stmt(stmt);
@@ -438,10 +483,18 @@ crate fn walk_mut_stmt<'ast, V: VisitMut<'ast>>(visit: &mut V, stmt: &'ast mut S
             visit.visit_expr(lval);
             visit.visit_expr(rval);
         }
-        Stmt::InlineAsm(asm) => {}
+        Stmt::InlineAsm(asm) => {
+            for op in asm.inputs.iter_mut().chain(&mut asm.outputs) {
+                visit.visit_expr(&mut op.expr);
+            }
+        }
         Stmt::Builtin(builtin) => match builtin {
-            Builtin::Bottom => {}
-            Builtin::SizeOf(t) => visit.visit_ty(t.get_mut()),
+            Builtin::Bottom | Builtin::Line | Builtin::File | Builtin::CallerLocation => {}
+            Builtin::SizeOf(t)
+            | Builtin::AlignOf(t)
+            | Builtin::OffsetOf(t, _)
+            | Builtin::TypeName(t) => visit.visit_ty(t.get_mut()),
+            Builtin::StaticAssert(cond, _) | Builtin::Len(cond) => visit.visit_expr(cond),
         },
     }
 }
@@ -503,7 +556,40 @@ crate fn walk_mut_expr<'ast, V: VisitMut<'ast>>(visit: &mut V, expr: &'ast mut E
         Expr::Value(_) => {
             // visit.visit_value(val);
         }
-        Expr::Builtin(..) => {}
+        Expr::Builtin(
+            Builtin::SizeOf(t)
+            | Builtin::AlignOf(t)
+            | Builtin::OffsetOf(t, _)
+            | Builtin::TypeName(t),
+        ) => visit.visit_ty(t.get_mut()),
+        Expr::Builtin(Builtin::StaticAssert(cond, _) | Builtin::Len(cond)) => {
+            visit.visit_expr(cond)
+        }
+        Expr::Builtin(
+            Builtin::Bottom | Builtin::Line | Builtin::File | Builtin::CallerLocation,
+        ) => {}
+        Expr::If { cond, blk, els } => {
+            visit.visit_expr(cond);
+            for Block { stmts, tail, .. } in [blk, els] {
+                for stmt in stmts.iter_mut() {
+                    visit.visit_stmt(stmt);
+                }
+                if let Some(tail) = tail {
+                    visit.visit_expr(tail);
+                }
+            }
+        }
+        Expr::Match { expr, arms } => {
+            visit.visit_expr(expr);
+            visit.visit_match_arm(arms);
+        }
+        Expr::Cast { expr, ty: _ } => visit.visit_expr(expr),
+        Expr::Try(expr) => visit.visit_expr(expr),
+        Expr::InlineAsm { asm, ty: _ } => {
+            for op in asm.inputs.iter_mut().chain(&mut asm.outputs) {
+                visit.visit_expr(&mut op.expr);
+            }
+        }
     }
 }
 
@@ -636,7 +722,7 @@ impl<'ast> Visit<'ast> for DotWalker {
     }
 
     fn visit_match_arm(&mut self, arms: &'ast [MatchArm]) {
-        for MatchArm { pat, blk, span } in arms {
+        for MatchArm { pat, blk, .. } in arms {
             self.walk_deeper(
                 |this| {
                     writeln!(
@@ -0,0 +1,93 @@
+// The C side of abi.cm, linked into the program by the builder.
+#include <stdio.h>
+
+struct point {
+    long x;
+    long y;
+};
+
+struct mixed {
+    double f;
+    long i;
+};
+
+struct big {
+    long a;
+    long b;
+    long c;
+};
+
+// 7 bytes of padding after `a`, 16 bytes in two INTEGER eightbytes
+struct padded {
+    unsigned char a;
+    long b;
+};
+
+// An INTEGER eightbyte then an SSE eightbyte
+struct int_float {
+    int a;
+    double b;
+};
+
+_Static_assert(sizeof(struct padded) == 16, "padded is 16 bytes");
+_Static_assert(sizeof(struct int_float) == 16, "int_float is 16 bytes");
+
+long sum_ints(long a, long b, long c, long d, long e, long f, long g, long h) {
+    return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h;
+}
+
+double sum_floats(double a, double b, double c, double d, double e, double f, double g,
+                  double h, double i, double j) {
+    return a + b + c + d + e + f + g + h + i + j * 10.0;
+}
+
+long point_dot(struct point p, struct point q) {
+    return p.x * q.x + p.y * q.y;
+}
+
+struct point point_make(long x, long y) {
+    struct point p = {x, y};
+    return p;
+}
+
+double mixed_total(struct mixed m) {
+    return m.f + (double)m.i;
+}
+
+struct mixed mixed_make(double f, long i) {
+    struct mixed m = {f, i};
+    return m;
+}
+
+struct big big_make(long a, long b, long c) {
+    struct big x = {a, b, c};
+    return x;
+}
+
+long big_sum(long a, long b, long c, long d, long e, long f, struct big x, long g) {
+    return a + b + c + d + e + f + g + x.a * 100 + x.b * 10 + x.c;
+}
+
+long call_back(long (*cb)(struct point, struct big, double), long x) {
+    struct point p = {x, x + 1};
+    struct big b = {x, 2 * x, 3 * x};
+    return cb(p, b, 0.5);
+}
+
+long padded_sum(struct padded p, struct padded q) {
+    return p.a * 1000 + p.b * 100 + q.a * 10 + q.b;
+}
+
+struct padded padded_make(unsigned char a, long b) {
+    struct padded p = {a, b};
+    return p;
+}
+
+double int_float_total(struct int_float x) {
+    return (double)x.a + x.b;
+}
+
+struct int_float int_float_make(int a, double b) {
+    struct int_float x = {a, b};
+    return x;
+}